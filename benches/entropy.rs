@@ -0,0 +1,71 @@
+//! Compares counting the bytes for the entropy in an array, as `ttare::entropy` does, against
+//! counting them in a hash map.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rand::{rngs::StdRng, RngCore, SeedableRng};
+use rustc_hash::FxHashMap;
+
+/// The size of each input.
+const INPUT_LEN: usize = 1024 * 1024;
+
+/// The entropy of `bytes`, with the counts kept in a hash map.
+fn hash_map_entropy(bytes: &[u8]) -> f32 {
+    if bytes.is_empty() {
+        return 0.0;
+    }
+
+    let mut counts: FxHashMap<u8, u64> = FxHashMap::default();
+    for &byte in bytes {
+        *counts.entry(byte).or_insert(0) += 1;
+    }
+
+    // Sum in the order of the bytes like the array does, so that both give the same result
+    let mut counts: Vec<(u8, u64)> = counts.into_iter().collect();
+    counts.sort_unstable();
+
+    let total = bytes.len() as f64;
+    let entropy: f64 = counts
+        .iter()
+        .map(|&(_, count)| {
+            let p = count as f64 / total;
+            p * (total / count as f64).log2()
+        })
+        .fold(0.0, |sum, term| sum + term);
+
+    (entropy as f32).clamp(0.0, 8.0)
+}
+
+fn inputs() -> Vec<(&'static str, Vec<u8>)> {
+    let mut random = vec![0; INPUT_LEN];
+    StdRng::seed_from_u64(0x0074_7461_7265).fill_bytes(&mut random);
+
+    let line = b"the quick brown fox jumps over the lazy dog\n";
+    let text = line.iter().copied().cycle().take(INPUT_LEN).collect();
+
+    vec![
+        ("random", random),
+        ("zero", vec![0; INPUT_LEN]),
+        ("text", text),
+    ]
+}
+
+fn entropy(c: &mut Criterion) {
+    let mut group = c.benchmark_group("entropy");
+    group.throughput(Throughput::Bytes(INPUT_LEN as u64));
+
+    for (name, input) in inputs() {
+        assert_eq!(ttare::entropy(&input), hash_map_entropy(&input), "{name}");
+
+        group.bench_with_input(BenchmarkId::new("array", name), &input, |b, input| {
+            b.iter(|| ttare::entropy(black_box(input)))
+        });
+        group.bench_with_input(BenchmarkId::new("hash_map", name), &input, |b, input| {
+            b.iter(|| hash_map_entropy(black_box(input)))
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, entropy);
+criterion_main!(benches);
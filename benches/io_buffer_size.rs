@@ -0,0 +1,46 @@
+//! Compares the sizes of the buffers that the files are read through and the archive is written
+//! through, on files that are stored as-is so that the IO isn't hidden behind the compression.
+
+use std::{fs, path::PathBuf};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rand::{rngs::StdRng, RngCore, SeedableRng};
+use tempfile::TempDir;
+use ttare::{CompressOptions, IO_BUFFER_SIZE, READ_ONCE_FILE_BYTES};
+
+fn io_buffer_size(c: &mut Criterion) {
+    let dir = TempDir::new().unwrap();
+
+    // Larger than what is read once, so they are read again to be added to the archive
+    let len = 4 * READ_ONCE_FILE_BYTES as usize;
+    let mut rng = StdRng::seed_from_u64(0x0074_7461_7265);
+    let files: Vec<PathBuf> = (0..16)
+        .map(|i| {
+            let path = dir.path().join(format!("{i}.bin"));
+            let mut random = vec![0; len];
+            rng.fill_bytes(&mut random);
+            fs::write(&path, random).unwrap();
+            path
+        })
+        .collect();
+    let output = dir.path().join("archive.ttare");
+
+    let mut group = c.benchmark_group("io_buffer_size");
+    group.sample_size(20);
+    group.throughput(Throughput::Bytes((files.len() * len) as u64));
+
+    for size in [8 * 1024, 64 * 1024, IO_BUFFER_SIZE, 1024 * 1024] {
+        let opts = CompressOptions {
+            io_buffer_size: size,
+            ..CompressOptions::default()
+        };
+        group.bench_with_input(BenchmarkId::from_parameter(size), &opts, |b, opts| {
+            b.iter(|| ttare::compress(&files, &output, opts.clone()).unwrap())
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, io_buffer_size);
+criterion_main!(benches);
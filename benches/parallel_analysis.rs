@@ -0,0 +1,66 @@
+//! Analyzes many files on thread pools of several sizes, as `--jobs` picks, to show how analysis
+//! scales with the number of cores.
+
+use std::{fs, path::PathBuf, thread};
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rand::{rngs::StdRng, RngCore, SeedableRng};
+use tempfile::TempDir;
+use ttare::CompressOptions;
+
+/// How many files are analyzed.
+const FILES: usize = 1024;
+
+/// The size of each file.
+const FILE_LEN: usize = 64 * 1024;
+
+fn parallel_analysis(c: &mut Criterion) {
+    let dir = TempDir::new().unwrap();
+
+    // Text files, and random ones that are stored as-is
+    let mut rng = StdRng::seed_from_u64(0x0074_7461_7265);
+    let files: Vec<PathBuf> = (0..FILES)
+        .map(|i| {
+            let path = dir.path().join(format!("{i}.bin"));
+            if i % 2 == 0 {
+                let mut random = vec![0; FILE_LEN];
+                rng.fill_bytes(&mut random);
+                fs::write(&path, random).unwrap();
+            } else {
+                let line = format!("line {i} of a file analyzed in parallel\n");
+                fs::write(&path, line.repeat(FILE_LEN / line.len())).unwrap();
+            }
+            path
+        })
+        .collect();
+    let input_bytes: u64 = files
+        .iter()
+        .map(|path| fs::metadata(path).unwrap().len())
+        .sum();
+    let opts = CompressOptions::default();
+
+    let mut group = c.benchmark_group("parallel_analysis");
+    group.sample_size(20);
+    group.throughput(Throughput::Bytes(input_bytes));
+
+    // Past the number of cores, the time should stop going down
+    let cores = thread::available_parallelism().map_or(1, |cores| cores.get());
+    let mut jobs = vec![1, 2, 4, 8, cores];
+    jobs.sort_unstable();
+    jobs.dedup();
+
+    for jobs in jobs {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(jobs)
+            .build()
+            .unwrap();
+        group.bench_with_input(BenchmarkId::from_parameter(jobs), &files, |b, files| {
+            b.iter(|| pool.install(|| black_box(ttare::analyze_files(files, &opts).unwrap())))
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, parallel_analysis);
+criterion_main!(benches);
@@ -0,0 +1,66 @@
+//! Compares compressing each file on its own on a single thread against the whole thread pool.
+
+use std::{fs, io, path::PathBuf};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use tempfile::TempDir;
+use ttare::{CompressOptions, EntropyAnalysis};
+
+/// Writes compressible files to a new directory, which becomes the current directory since the
+/// archive only holds relative paths.
+fn compressible_files(count: usize, len: usize) -> (TempDir, Vec<PathBuf>) {
+    let dir = TempDir::new().unwrap();
+    std::env::set_current_dir(dir.path()).unwrap();
+
+    let files = (0..count)
+        .map(|i| {
+            let path = PathBuf::from(format!("{i}.txt"));
+            let line = format!("line {i} of a compressible benchmark file\n");
+            fs::write(&path, line.repeat(len / line.len())).unwrap();
+            path
+        })
+        .collect();
+    (dir, files)
+}
+
+fn per_file_compression(c: &mut Criterion) {
+    let (_dir, files) = compressible_files(64, 1024 * 1024);
+    let opts = CompressOptions {
+        per_file_compression: true,
+        ..CompressOptions::default()
+    };
+
+    let analyses = ttare::analyze_files(&files, &opts).unwrap();
+    assert!(analyses
+        .iter()
+        .all(|analysis| analysis.decision == EntropyAnalysis::Compress));
+    let input_bytes: u64 = files
+        .iter()
+        .map(|path| fs::metadata(path).unwrap().len())
+        .sum();
+
+    let mut group = c.benchmark_group("per_file_compression");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(input_bytes));
+
+    for (name, threads) in [
+        ("sequential", 1),
+        ("parallel", rayon::current_num_threads()),
+    ] {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .unwrap();
+
+        group.bench_with_input(BenchmarkId::new(name, threads), &pool, |b, pool| {
+            b.iter(|| {
+                pool.install(|| ttare::compress_to(&files, io::sink(), opts.clone()).unwrap())
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, per_file_compression);
+criterion_main!(benches);
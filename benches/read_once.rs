@@ -0,0 +1,112 @@
+//! Compares sampling a file for its entropy then reading it again to archive it, as ttare does for
+//! large files, against reading it into memory once and sampling that, as it does for small ones.
+
+use std::{
+    fs::{self, File},
+    io::{self, Cursor, Read, Seek, SeekFrom},
+    path::PathBuf,
+};
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use tempfile::TempDir;
+use ttare::{classify, CompressOptions, READ_ONCE_FILE_BYTES};
+
+/// Counts the bytes read through it.
+struct CountingReader<R> {
+    inner: R,
+    read: u64,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.read += read as u64;
+        Ok(read)
+    }
+}
+
+impl<R: Seek> Seek for CountingReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+/// Samples the file, then reads it again from the start, returning how many bytes were read.
+fn two_passes(path: &PathBuf, opts: &CompressOptions) -> u64 {
+    let mut file = CountingReader {
+        inner: File::open(path).unwrap(),
+        read: 0,
+    };
+    black_box(classify(&mut file, opts).unwrap());
+    file.seek(SeekFrom::Start(0)).unwrap();
+    io::copy(&mut file, &mut io::sink()).unwrap();
+    file.read
+}
+
+/// Reads the file into memory, then samples it and reads it from there, returning how many bytes
+/// were read from the file.
+fn one_pass(path: &PathBuf, opts: &CompressOptions) -> u64 {
+    let mut file = CountingReader {
+        inner: File::open(path).unwrap(),
+        read: 0,
+    };
+    let mut data = vec![];
+    file.read_to_end(&mut data).unwrap();
+    black_box(classify(&mut Cursor::new(&data), opts).unwrap());
+    io::copy(&mut data.as_slice(), &mut io::sink()).unwrap();
+    file.read
+}
+
+fn read_once(c: &mut Criterion) {
+    let dir = TempDir::new().unwrap();
+    let opts = CompressOptions::default();
+
+    let mut group = c.benchmark_group("read_once");
+    group.sample_size(20);
+
+    for len in [16 * 1024, 256 * 1024, READ_ONCE_FILE_BYTES as usize] {
+        let files: Vec<PathBuf> = (0..32)
+            .map(|i| {
+                let path = dir.path().join(format!("{len}-{i}.txt"));
+                let line = format!("line {i} of a file read once\n");
+                fs::write(&path, line.repeat(len / line.len())).unwrap();
+                path
+            })
+            .collect();
+        let input_bytes: u64 = files
+            .iter()
+            .map(|path| fs::metadata(path).unwrap().len())
+            .sum();
+        group.throughput(Throughput::Bytes(input_bytes));
+
+        let read = |pass: fn(&PathBuf, &CompressOptions) -> u64| -> u64 {
+            files.iter().map(|path| pass(path, &opts)).sum()
+        };
+        println!(
+            "{len} byte files: {} bytes read with two passes, {} with one",
+            read(two_passes),
+            read(one_pass)
+        );
+
+        for (name, pass) in [
+            (
+                "two_passes",
+                two_passes as fn(&PathBuf, &CompressOptions) -> u64,
+            ),
+            ("one_pass", one_pass),
+        ] {
+            group.bench_with_input(BenchmarkId::new(name, len), &files, |b, files| {
+                b.iter(|| {
+                    for path in files {
+                        pass(path, &opts);
+                    }
+                })
+            });
+        }
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, read_once);
+criterion_main!(benches);
@@ -0,0 +1,73 @@
+//! Compares the archives of many small files written with and without the blocks of zeros that end
+//! their tars, printing how many bytes leaving them out saves.
+
+use std::{fs, io, path::PathBuf};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rand::{rngs::StdRng, RngCore, SeedableRng};
+use tempfile::TempDir;
+use ttare::CompressOptions;
+
+fn strip_trailer(c: &mut Criterion) {
+    let dir = TempDir::new().unwrap();
+
+    // Small text files, and a few random ones that end up stored as-is in the root tar
+    let mut rng = StdRng::seed_from_u64(0x0074_7461_7265);
+    let files: Vec<PathBuf> = (0..256)
+        .map(|i| {
+            let path = dir.path().join(format!("{i}.bin"));
+            if i % 8 == 0 {
+                let mut random = vec![0; 700];
+                rng.fill_bytes(&mut random);
+                fs::write(&path, random).unwrap();
+            } else {
+                fs::write(&path, format!("config {i}\nkey = value {i}\n").repeat(4)).unwrap();
+            }
+            path
+        })
+        .collect();
+    let input_bytes: u64 = files
+        .iter()
+        .map(|path| fs::metadata(path).unwrap().len())
+        .sum();
+
+    let mut group = c.benchmark_group("strip_trailer");
+    group.sample_size(20);
+    group.throughput(Throughput::Bytes(input_bytes));
+
+    for per_file_compression in [false, true] {
+        let layout = if per_file_compression {
+            "per_file"
+        } else {
+            "solid"
+        };
+        let options = |strip_trailer| CompressOptions {
+            per_file_compression,
+            strip_trailer,
+            ..CompressOptions::default()
+        };
+        let size = |opts| {
+            ttare::compress_to(&files, io::sink(), opts)
+                .unwrap()
+                .archive_bytes
+        };
+        let (kept, stripped) = (size(options(false)), size(options(true)));
+        println!(
+            "{layout}: {kept} bytes with the trailers, {stripped} without, {} saved of {input_bytes} bytes of files",
+            kept - stripped
+        );
+
+        for strip_trailer in [false, true] {
+            let opts = options(strip_trailer);
+            let id = BenchmarkId::new(layout, strip_trailer);
+            group.bench_with_input(id, &opts, |b, opts| {
+                b.iter(|| ttare::compress_to(&files, io::sink(), opts.clone()).unwrap())
+            });
+        }
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, strip_trailer);
+criterion_main!(benches);
@@ -0,0 +1,166 @@
+use std::{
+    fs::{self, File},
+    path::{Path, PathBuf},
+};
+
+use rustc_hash::FxHashSet;
+use tar::Archive;
+use tempfile::NamedTempFile;
+
+use crate::{
+    dedup::DedupManifest, entry_name, error::IoContext, hard_link_target, meta::ArchiveMeta,
+    normalize_entry_path, plain, progress::Progress, root_entry_kind, split, ArchiveWriter,
+    CompressOptions, CompressSummary, DiskPaths, EntropyAnalysis, Result, RootEntry, TtareError,
+    Xattrs,
+};
+
+/// Adds `paths` to the ttare archive at `archive`, rewriting it.
+///
+/// The new files are classified with the codec, threshold, sampling and rules recorded in the
+/// archive, or with `opts` if it has no record. The old archive is only replaced once the new one
+/// is complete, and nothing changes if any of the files is already in it. A plain tar.gz can't be
+/// appended to, since the files would have to be classified again to keep it plain, and neither
+/// can a split archive, given either its first part or the path it was split from.
+pub fn append(archive: &Path, paths: &[PathBuf], opts: CompressOptions) -> Result<CompressSummary> {
+    if split::is_split(archive) || (!archive.exists() && split::part_path(archive, 1).exists()) {
+        return Err(TtareError::AppendConflict("a split archive"));
+    }
+    let mut input = File::open(archive).with_path("Could not open", archive)?;
+    if plain::starts_like_gzip(&mut input).with_path("Could not read", archive)? {
+        return Err(TtareError::AppendConflict("a plain tar.gz"));
+    }
+    let permissions = input.metadata()?.permissions();
+
+    let dir = match archive.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let output =
+        NamedTempFile::new_in(dir).with_path("Could not create a temporary file in", dir)?;
+
+    let summary = rewrite(input, output.as_file(), paths, opts)?;
+
+    fs::set_permissions(output.path(), permissions)?;
+    output
+        .persist(archive)
+        .map_err(|e| e.error)
+        .with_path("Could not replace", archive)?;
+
+    Ok(summary)
+}
+
+/// Copies the archive read from `input` to `output`, adding `paths` at the end.
+fn rewrite(
+    input: File,
+    output: &File,
+    paths: &[PathBuf],
+    mut opts: CompressOptions,
+) -> Result<CompressSummary> {
+    let mut archive = Archive::new(plain::root_tar(input)?);
+    let mut entries = archive.entries()?.peekable();
+
+    // The metadata comes first, and says how the new archive has to be written
+    let mut meta = None;
+    if let Some(Ok(entry)) = entries.peek_mut() {
+        if root_entry_kind(entry)? == RootEntry::Meta {
+            let meta = meta.insert(ArchiveMeta::read(entry)?);
+            meta.apply_to(&mut opts)?;
+            entries.next();
+        }
+    }
+
+    let mut paths = DiskPaths::split(paths, &opts);
+    let mut writer = ArchiveWriter::new(output, &opts, Progress::new(opts.progress, &paths.files))?;
+    let mut existing = FxHashSet::default();
+    let mut existing_dirs = FxHashSet::default();
+
+    for entry in entries {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        let mut header = entry.header().clone();
+
+        match root_entry_kind(&mut entry)? {
+            RootEntry::Meta => {}
+            RootEntry::Member(codec) => {
+                let dictionary = opts.zstd_dictionary.as_ref();
+                let decoder = ArchiveMeta::member_decoder(meta.as_ref(), codec, dictionary, entry)?;
+                let mut tar = Archive::new(decoder);
+                for inner in tar.entries()? {
+                    let mut inner = inner?;
+                    let path = inner.path()?.into_owned();
+                    let mut header = inner.header().clone();
+                    let xattrs = Xattrs::of_entry(&mut inner)?;
+                    let decision = EntropyAnalysis::Compress;
+                    writer.append(decision, &mut header, &path, &xattrs, &mut inner)?;
+                    existing.insert(normalize_entry_path(&path));
+                }
+            }
+            RootEntry::Compressed(file) => {
+                header.set_size(file.size);
+                let xattrs = Xattrs::of_entry(&mut entry)?;
+                let data = file.codec.decoder(entry)?;
+                let decision = EntropyAnalysis::Compress;
+                writer.append(decision, &mut header, &file.path, &xattrs, data)?;
+                existing.insert(normalize_entry_path(&file.path));
+            }
+            RootEntry::Dedup => {
+                let copies = DedupManifest::read(entry)?.copies;
+                existing.extend(copies.iter().map(|(copy, _)| normalize_entry_path(copy)));
+                writer.summary.deduplicated_files += copies.len();
+                writer.copies.copies.extend(copies);
+            }
+            // The index is written again for the new archive, if it is kept
+            RootEntry::Checksum | RootEntry::Manifest | RootEntry::Index => {}
+            RootEntry::Directory => {
+                writer.append_dir(&mut header, &path, &Xattrs::of_entry(&mut entry)?)?;
+                existing_dirs.insert(normalize_entry_path(&path));
+            }
+            RootEntry::Symlink => {
+                let xattrs = Xattrs::of_entry(&mut entry)?;
+                let target = entry.link_name()?.ok_or_else(|| {
+                    TtareError::CorruptArchive(format!("{} links to nothing", path.display()))
+                })?;
+                writer.append_symlink(&mut header, &path, &target, &xattrs)?;
+                existing.insert(normalize_entry_path(&path));
+            }
+            RootEntry::HardLink => {
+                let target = hard_link_target(&entry, &path)?;
+                writer.append_hard_link(&mut header, &path, &target)?;
+                writer.summary.hard_links += 1;
+                existing.insert(normalize_entry_path(&path));
+            }
+            RootEntry::File => {
+                let xattrs = Xattrs::of_entry(&mut entry)?;
+                let decision = EntropyAnalysis::DontCompress;
+                writer.append(decision, &mut header, &path, &xattrs, entry)?;
+                existing.insert(normalize_entry_path(&path));
+            }
+        }
+    }
+
+    // Adding a file twice would shadow the first one when decompressing
+    let base_dir = opts.base_dir.as_deref();
+    let mut duplicates = vec![];
+    for path in paths.symlinks.iter().chain(&paths.files) {
+        if let Some(name) = entry_name(path, base_dir, &opts.name_map)? {
+            if !existing.insert(name) {
+                duplicates.push(path.clone());
+            }
+        }
+    }
+    if !duplicates.is_empty() {
+        return Err(TtareError::DuplicatePaths(duplicates));
+    }
+
+    // Directories can't shadow anything, so the ones that are already there are just left out
+    paths
+        .dirs
+        .retain(|dir| match entry_name(dir, base_dir, &opts.name_map) {
+            Ok(Some(name)) => existing_dirs.insert(name),
+            // Left for `append_paths` to skip or fail on
+            _ => true,
+        });
+
+    writer.append_paths(&paths, &opts)?;
+    writer.finish()
+}
@@ -0,0 +1,116 @@
+use std::{
+    error::Error,
+    fmt,
+    io::{self, Read},
+    path::Path,
+    sync::RwLock,
+};
+
+use clap::ValueEnum;
+
+use crate::TtareError;
+
+/// How many times a file that changed while it was read is read again with `OnChange::Retry`,
+/// before giving up on it.
+pub const ON_CHANGE_RETRIES: usize = 3;
+
+/// What to do with a file that doesn't have the size it had when its metadata was read anymore
+/// once its contents are read into the archive, such as a log that is written to meanwhile.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OnChange {
+    /// Reads the file again from the start, up to `ON_CHANGE_RETRIES` times, then fails.
+    Retry,
+
+    /// Leaves the file out of the archive with a warning, like `skip_errors` does.
+    Skip,
+
+    /// Fails the whole archive, leaving no corrupt archive behind.
+    #[default]
+    Fail,
+}
+
+/// What `SizedReader` fails with when what it reads doesn't have the size it was given.
+#[derive(Debug)]
+struct Changed;
+
+impl fmt::Display for Changed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the file changed while it was read")
+    }
+}
+
+impl Error for Changed {}
+
+/// Reads exactly `size` bytes from `inner`, failing if it ends before them or goes on after them.
+///
+/// A tar entry is as long as its header says, so a reader that gives more or fewer bytes would
+/// leave the entries after it unreadable.
+pub(crate) struct SizedReader<R> {
+    inner: R,
+    size: u64,
+    read: u64,
+}
+
+impl<R: Read> SizedReader<R> {
+    pub(crate) fn new(inner: R, size: u64) -> Self {
+        SizedReader {
+            inner,
+            size,
+            read: 0,
+        }
+    }
+}
+
+impl<R: Read> Read for SizedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        if self.read == self.size {
+            return match self.inner.read(&mut [0])? {
+                0 => Ok(0),
+                _ => Err(io::Error::new(io::ErrorKind::InvalidData, Changed)),
+            };
+        }
+
+        let len = buf.len().min((self.size - self.read) as usize);
+        match self.inner.read(&mut buf[..len])? {
+            0 => Err(io::Error::new(io::ErrorKind::UnexpectedEof, Changed)),
+            read => {
+                self.read += read as u64;
+                Ok(read)
+            }
+        }
+    }
+}
+
+/// Tells that the file at `path` changed while it was read, if that is what `error` is about.
+pub(crate) fn name_change(error: TtareError, path: &Path) -> TtareError {
+    match error {
+        TtareError::Io { source, .. }
+            if source.get_ref().is_some_and(|inner| inner.is::<Changed>()) =>
+        {
+            TtareError::FileChanged(path.to_path_buf())
+        }
+        error => error,
+    }
+}
+
+/// The function called with each file read from disk, see `set_before_read_hook`.
+static BEFORE_READ: RwLock<Option<fn(&Path)>> = RwLock::new(None);
+
+/// Has `hook` called with the path of each file read from disk into the archive, once its size
+/// is known and right before its contents are read. This is only there for tests, to change a
+/// file at the worst time.
+#[doc(hidden)]
+pub fn set_before_read_hook(hook: Option<fn(&Path)>) {
+    *BEFORE_READ.write().unwrap() = hook;
+}
+
+/// Calls the hook set by `set_before_read_hook`, if any.
+pub(crate) fn before_read(path: &Path) {
+    if let Some(hook) = *BEFORE_READ.read().unwrap() {
+        hook(path);
+    }
+}
@@ -0,0 +1,525 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, BufWriter, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use crc32fast::Hasher;
+use serde::{Deserialize, Serialize};
+use tracing::info_span;
+
+use crate::{
+    analyze_files_skipping,
+    error::IoContext,
+    index::IndexEntry,
+    manifest::FileChecksum,
+    member_encoder,
+    meta::ArchiveMeta,
+    partial_path,
+    plain::RootWriter,
+    progress::Progress,
+    root_position,
+    spool::SpoolLocation,
+    throttle::Throttle,
+    walk::{json_path, path_to_bytes},
+    ArchiveWriter, CompressOptions, CompressSummary, Deduplicator, DiskPaths, Layout, MemberWriter,
+    Result, TtareError, PARTIAL_EXTENSION, READ_ONCE_BUDGET_BYTES,
+};
+
+/// How many files are added to the archive between two records of the checkpoint, at most.
+const RECORD_FILES: usize = 64;
+
+/// How many bytes of files are added to the archive between two records of the checkpoint, at
+/// most.
+const RECORD_BYTES: u64 = 64 * 1024 * 1024;
+
+/// What the first line of a checkpoint says about the run that wrote it, to tell that the run
+/// resuming it is the same.
+///
+/// A checkpoint is a file of JSON lines: this one, then a `CheckpointRecord` each time enough
+/// files were added. Records are only ever appended, so that an interrupted write only loses the
+/// last one, and each holds what was added since the one before, so that writing one doesn't take
+/// longer as the archive grows.
+#[derive(Debug, Serialize, Deserialize)]
+struct CheckpointStart {
+    /// The metadata at the front of the archive, which holds the options that decide how the files
+    /// are classified and stored.
+    meta: ArchiveMeta,
+
+    /// How many files there are to add.
+    files: usize,
+
+    /// The CRC32 of the paths of the directories, symlinks and files to add, in the order they
+    /// are added.
+    paths_crc32: u32,
+
+    /// The modification time of the entries that ttare adds, which a resumed run keeps.
+    mtime: u64,
+}
+
+/// How far the archive got when a record was written to the checkpoint.
+#[derive(Debug, Serialize, Deserialize)]
+struct CheckpointRecord {
+    /// How many of the files were added or left out, in the order they are added.
+    files_done: usize,
+
+    /// How long the partial archive and the staged tar of the compressed member are.
+    root_bytes: u64,
+    member_bytes: u64,
+
+    /// What the summary counted so far.
+    input_bytes: u64,
+    compressed_files: usize,
+    stored_files: usize,
+    deduplicated_files: usize,
+    hard_links: usize,
+    compressed_input_bytes: u64,
+    compressed_output_bytes: u64,
+
+    /// What the writer knows of the compressed member so far.
+    member_files: usize,
+    member_input_bytes: u64,
+    member_has_reserved_names: bool,
+
+    /// Whether the user was already told that a leading `/` or `..` was removed from a name.
+    stripped_names: bool,
+
+    /// What was added since the previous record.
+    added: Added,
+}
+
+/// What the files added to the archive since a record were recorded as.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Added {
+    skipped: Vec<StoredPath>,
+    index: Vec<IndexEntry>,
+    checksums: Vec<FileChecksum>,
+
+    /// The copies, and the files they are copies of.
+    copies: Vec<(StoredPath, StoredPath)>,
+
+    /// The first paths of the files with more than one, by their device and inode.
+    first_links: Vec<(u64, u64, StoredPath)>,
+
+    /// The originals that the later files can be copies of, by their size and hash.
+    originals: Vec<(u64, u64, StoredPath)>,
+}
+
+impl Added {
+    /// Adds what was added after this, in `next`.
+    fn append(&mut self, next: Added) {
+        self.skipped.extend(next.skipped);
+        self.index.extend(next.index);
+        self.checksums.extend(next.checksums);
+        self.copies.extend(next.copies);
+        self.first_links.extend(next.first_links);
+        self.originals.extend(next.originals);
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredPath(#[serde(with = "json_path")] PathBuf);
+
+/// What a checkpoint says about the run that wrote it.
+struct Resumed {
+    start: CheckpointStart,
+
+    /// The last record, whose `added` holds what every record added, if there is one.
+    record: Option<CheckpointRecord>,
+
+    /// Where the last record that was written in full ends in the checkpoint.
+    end: u64,
+}
+
+/// The checkpoint of a run that can be resumed, which a record is appended to each time enough
+/// files were added.
+pub(crate) struct Checkpoint {
+    file: File,
+    path: PathBuf,
+
+    /// How many files the run being resumed had done, ahead of the files given to
+    /// `ArchiveWriter::append_files`.
+    resumed_files: usize,
+
+    /// How many of the files given to `append_files` were done, and how many bytes of files the
+    /// summary counted, as of the last record.
+    recorded_files: usize,
+    recorded_bytes: u64,
+
+    /// How many skipped files, index entries, checksums and copies the records hold.
+    recorded_skipped: usize,
+    recorded_index: usize,
+    recorded_checksums: usize,
+    recorded_copies: usize,
+
+    /// The first paths of the files with more than one found since the last record, by their
+    /// device and inode.
+    pub(crate) first_links: Vec<((u64, u64), PathBuf)>,
+}
+
+impl Checkpoint {
+    fn new(file: File, path: &Path) -> Self {
+        Checkpoint {
+            file,
+            path: path.to_path_buf(),
+            resumed_files: 0,
+            recorded_files: 0,
+            recorded_bytes: 0,
+            recorded_skipped: 0,
+            recorded_index: 0,
+            recorded_checksums: 0,
+            recorded_copies: 0,
+            first_links: vec![],
+        }
+    }
+
+    /// Whether enough files were added since the last record for another one, once `done` of
+    /// the files given to `append_files` were done and `input_bytes` of files were counted.
+    pub(crate) fn is_due(&self, done: usize, input_bytes: u64) -> bool {
+        done - self.recorded_files >= RECORD_FILES
+            || input_bytes - self.recorded_bytes >= RECORD_BYTES
+    }
+
+    /// Appends a record of what `writer` added so far, once `done` of the files given to
+    /// `append_files` were done. What the record says was written has to be written already.
+    pub(crate) fn record<W: Write>(
+        &mut self,
+        writer: &mut ArchiveWriter<W>,
+        done: usize,
+    ) -> Result<()> {
+        let index = writer.index.as_ref().map_or(&[][..], |index| &index.files);
+        let checksums = writer
+            .manifest
+            .as_ref()
+            .map_or(&[][..], |manifest| &manifest.files);
+        let summary = &writer.summary;
+        let added = Added {
+            skipped: stored(&summary.skipped[self.recorded_skipped..]),
+            index: index[self.recorded_index..].to_vec(),
+            checksums: checksums[self.recorded_checksums..].to_vec(),
+            copies: writer.copies.copies[self.recorded_copies..]
+                .iter()
+                .map(|(copy, original)| (StoredPath(copy.clone()), StoredPath(original.clone())))
+                .collect(),
+            first_links: self
+                .first_links
+                .drain(..)
+                .map(|((dev, ino), path)| (dev, ino, StoredPath(path)))
+                .collect(),
+            originals: writer
+                .deduplicator
+                .as_mut()
+                .map(Deduplicator::take_added)
+                .unwrap_or_default()
+                .into_iter()
+                .map(|(len, hash, path)| (len, hash, StoredPath(path)))
+                .collect(),
+        };
+        let record = CheckpointRecord {
+            files_done: self.resumed_files + done,
+            root_bytes: root_position(&writer.root_tar),
+            member_bytes: writer.compress_tar.get_ref().inner.written,
+            input_bytes: summary.input_bytes,
+            compressed_files: summary.compressed_files,
+            stored_files: summary.stored_files,
+            deduplicated_files: summary.deduplicated_files,
+            hard_links: summary.hard_links,
+            compressed_input_bytes: summary.compressed_input_bytes,
+            compressed_output_bytes: summary.compressed_output_bytes,
+            member_files: writer.member_files,
+            member_input_bytes: writer.member_input_bytes,
+            member_has_reserved_names: writer.member_has_reserved_names,
+            stripped_names: writer.stripped_names,
+            added,
+        };
+        self.append(&record)?;
+
+        self.recorded_files = done;
+        self.recorded_bytes = summary.input_bytes;
+        self.recorded_skipped = summary.skipped.len();
+        self.recorded_index = index.len();
+        self.recorded_checksums = checksums.len();
+        self.recorded_copies = writer.copies.copies.len();
+        Ok(())
+    }
+
+    /// Appends `line` to the checkpoint as a line of JSON, in a single write so that an
+    /// interruption can only cut it short.
+    fn append(&mut self, line: &impl Serialize) -> Result<()> {
+        let mut line = serde_json::to_vec(line)
+            .map_err(io::Error::other)
+            .with_path("Could not write", &self.path)?;
+        line.push(b'\n');
+        self.file
+            .write_all(&line)
+            .with_path("Could not write", &self.path)
+    }
+
+    /// Sets `writer` back to where the run being resumed was at its last `record`, whose `added`
+    /// holds what every record added.
+    fn restore<W: Write>(&mut self, writer: &mut ArchiveWriter<W>, record: CheckpointRecord) {
+        if let RootWriter::Tar(output) = writer.root_tar.get_mut().inner.get_mut() {
+            output.written = record.root_bytes;
+        }
+        writer.compress_tar.get_mut().inner.written = record.member_bytes;
+
+        let summary = &mut writer.summary;
+        summary.input_bytes = record.input_bytes;
+        summary.compressed_files = record.compressed_files;
+        summary.stored_files = record.stored_files;
+        summary.deduplicated_files = record.deduplicated_files;
+        summary.hard_links = record.hard_links;
+        summary.compressed_input_bytes = record.compressed_input_bytes;
+        summary.compressed_output_bytes = record.compressed_output_bytes;
+        writer.member_files = record.member_files;
+        writer.member_input_bytes = record.member_input_bytes;
+        writer.member_has_reserved_names = record.member_has_reserved_names;
+        writer.stripped_names = record.stripped_names;
+
+        let added = record.added;
+        summary.skipped = added.skipped.into_iter().map(|path| path.0).collect();
+        if let Some(index) = &mut writer.index {
+            index.files = added.index;
+        }
+        if let Some(manifest) = &mut writer.manifest {
+            manifest.files = added.checksums;
+        }
+        writer.copies.copies = added
+            .copies
+            .into_iter()
+            .map(|(copy, original)| (copy.0, original.0))
+            .collect();
+        writer.first_links = added
+            .first_links
+            .into_iter()
+            .map(|(dev, ino, path)| ((dev, ino), path.0))
+            .collect();
+        if let Some(deduplicator) = &mut writer.deduplicator {
+            for (len, hash, path) in added.originals {
+                deduplicator.restore(len, hash, path.0);
+            }
+        }
+
+        self.resumed_files = record.files_done;
+        self.recorded_bytes = summary.input_bytes;
+        self.recorded_skipped = summary.skipped.len();
+        self.recorded_index = writer.index.as_ref().map_or(0, |index| index.files.len());
+        self.recorded_checksums = writer
+            .manifest
+            .as_ref()
+            .map_or(0, |manifest| manifest.files.len());
+        self.recorded_copies = writer.copies.copies.len();
+    }
+}
+
+/// Compresses `files` into a new ttare archive at `output`, recording how far it got in the
+/// `checkpoint`, or going on from where the checkpoint says when `resume` is set, see
+/// `CompressOptions::checkpoint`.
+pub(crate) fn compress(
+    files: &[PathBuf],
+    output: &Path,
+    checkpoint: &Path,
+    opts: &CompressOptions,
+) -> Result<CompressSummary> {
+    let _span = info_span!("compress").entered();
+    if opts.split_size.is_some() {
+        return Err(TtareError::CheckpointConflict("a split archive"));
+    }
+    if opts.plain_targz {
+        return Err(TtareError::CheckpointConflict("a plain tar.gz"));
+    }
+    if !opts.overwrite && fs::symlink_metadata(output).is_ok() {
+        return Err(TtareError::OutputExists(output.to_path_buf()));
+    }
+
+    let paths = DiskPaths::ordered(files, opts)?;
+    let mismatch = || TtareError::CheckpointMismatch(checkpoint.to_path_buf());
+    let start = CheckpointStart {
+        meta: ArchiveMeta::new(opts),
+        files: paths.files.len(),
+        paths_crc32: paths_crc32(&paths)?,
+        mtime: opts.entry_mtime(),
+    };
+    let resumed = opts.resume.then(|| read(checkpoint)).transpose()?;
+    if let Some(Resumed { start: resumed, .. }) = &resumed {
+        if resumed.meta != start.meta
+            || resumed.files != start.files
+            || resumed.paths_crc32 != start.paths_crc32
+        {
+            return Err(mismatch());
+        }
+    }
+
+    // Whatever was written after the last record is written again
+    let record = resumed.as_ref().and_then(|resumed| resumed.record.as_ref());
+    let partial = partial_path(output);
+    let staged = staged_member_path(output);
+    let root = open_at(&partial, record.map(|record| record.root_bytes), checkpoint)?;
+    let stage = open_at(
+        &staged,
+        record.map(|record| record.member_bytes),
+        checkpoint,
+    )?;
+    let done = record.map_or(0, |record| record.files_done);
+    let remaining = paths.files.get(done..).ok_or_else(mismatch)?;
+
+    let spools = SpoolLocation::TempDir(opts.temp_dir.clone());
+    let member = MemberWriter::Staged {
+        stage: BufWriter::with_capacity(opts.io_buffer_size, stage),
+        encoder: member_encoder(&spools, opts)?,
+    };
+    let progress = Progress::new(opts.progress, remaining);
+    let throttle = Throttle::new(opts.throttle_bytes_per_sec);
+    let mut writer = ArchiveWriter::with_member(
+        root,
+        opts,
+        progress,
+        spools,
+        throttle,
+        Layout::Ttare,
+        member,
+    )?;
+    if opts.dedup {
+        writer.deduplicator = Some(Deduplicator::keeping_added());
+    }
+
+    match resumed {
+        Some(Resumed { start, record, end }) => {
+            writer.mtime = start.mtime;
+            let mut checkpoint =
+                Checkpoint::new(open_at(checkpoint, Some(end), checkpoint)?, checkpoint);
+            match record {
+                Some(record) => {
+                    checkpoint.restore(&mut writer, record);
+                    writer.checkpoint = Some(checkpoint);
+                    let analyzed = analyze_files_skipping(
+                        remaining,
+                        opts,
+                        &writer.progress,
+                        &writer.throttle,
+                        READ_ONCE_BUDGET_BYTES,
+                    )?;
+                    writer.append_files(remaining, analyzed, opts)?;
+                }
+                // Nothing was recorded before the run was interrupted, so it starts over
+                None => {
+                    writer.checkpoint = Some(checkpoint);
+                    writer.append_meta(opts)?;
+                    writer.append_paths(&paths, opts)?;
+                }
+            }
+        }
+        None => {
+            let file = File::create(checkpoint).with_path("Could not create", checkpoint)?;
+            let mut checkpoint = Checkpoint::new(file, checkpoint);
+            writer.mtime = start.mtime;
+            checkpoint.append(&start)?;
+            writer.checkpoint = Some(checkpoint);
+            writer.append_meta(opts)?;
+            writer.append_paths(&paths, opts)?;
+        }
+    }
+
+    let summary = writer.finish()?;
+    fs::rename(&partial, output).with_path("Could not rename the archive to", output)?;
+    let _ = fs::remove_file(&staged);
+    let _ = fs::remove_file(checkpoint);
+    Ok(summary)
+}
+
+/// Where the tar of the compressed member of the archive at `output` is staged while it can be
+/// resumed, such as `archive.ttare.member.partial`.
+fn staged_member_path(output: &Path) -> PathBuf {
+    let mut path = output.as_os_str().to_owned();
+    path.push(".member");
+    path.push(PARTIAL_EXTENSION);
+    PathBuf::from(path)
+}
+
+/// Opens the file at `path` to write it from `len` bytes on, dropping what comes after them, or
+/// creates it empty without a `len`. Fails with a mismatch of the `checkpoint` if the file is
+/// shorter than that.
+fn open_at(path: &Path, len: Option<u64>, checkpoint: &Path) -> Result<File> {
+    let Some(len) = len else {
+        return OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .with_path("Could not create", path);
+    };
+
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .with_path("Could not open", path)?;
+    if file.metadata().with_path("Could not read", path)?.len() < len {
+        return Err(TtareError::CheckpointMismatch(checkpoint.to_path_buf()));
+    }
+    file.set_len(len).with_path("Could not write", path)?;
+    file.seek(SeekFrom::End(0))
+        .with_path("Could not write", path)?;
+    Ok(file)
+}
+
+/// Reads the checkpoint at `path`, up to the last record that was written in full.
+fn read(path: &Path) -> Result<Resumed> {
+    let mismatch = || TtareError::CheckpointMismatch(path.to_path_buf());
+    let mut lines = BufReader::new(File::open(path).with_path("Could not open", path)?);
+    let mut line = vec![];
+    let mut next_line = |line: &mut Vec<u8>| -> Result<bool> {
+        line.clear();
+        lines
+            .read_until(b'\n', line)
+            .with_path("Could not read", path)?;
+        // The last line can be cut short by the interruption
+        Ok(line.ends_with(b"\n"))
+    };
+
+    if !next_line(&mut line)? {
+        return Err(mismatch());
+    }
+    let start: CheckpointStart = serde_json::from_slice(&line).map_err(|_| mismatch())?;
+    let mut end = line.len() as u64;
+
+    let mut last: Option<CheckpointRecord> = None;
+    while next_line(&mut line)? {
+        let Ok(mut record) = serde_json::from_slice::<CheckpointRecord>(&line) else {
+            break;
+        };
+        end += line.len() as u64;
+        if let Some(last) = last {
+            let mut added = last.added;
+            added.append(record.added);
+            record.added = added;
+        }
+        last = Some(record);
+    }
+
+    Ok(Resumed {
+        start,
+        record: last,
+        end,
+    })
+}
+
+/// The CRC32 of the paths of the directories, symlinks and files to add, in the order they are
+/// added, to tell that a resumed run is given the same ones.
+fn paths_crc32(paths: &DiskPaths) -> Result<u32> {
+    let mut hasher = Hasher::new();
+    for group in [&paths.dirs, &paths.symlinks, &paths.files] {
+        for path in group {
+            hasher.update(&path_to_bytes(path)?);
+            hasher.update(b"\0");
+        }
+        // No path is empty, so this tells where a group ends
+        hasher.update(b"\0");
+    }
+    Ok(hasher.finalize())
+}
+
+fn stored(paths: &[PathBuf]) -> Vec<StoredPath> {
+    paths.iter().cloned().map(StoredPath).collect()
+}
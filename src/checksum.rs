@@ -0,0 +1,63 @@
+use std::io::{self, Read, Write};
+
+use crc32fast::Hasher;
+
+/// A writer that computes the CRC32 of everything written through it.
+pub(crate) struct Crc32Writer<W> {
+    inner: W,
+    hasher: Hasher,
+}
+
+impl<W: Write> Crc32Writer<W> {
+    pub(crate) fn new(inner: W) -> Self {
+        Crc32Writer {
+            inner,
+            hasher: Hasher::new(),
+        }
+    }
+
+    /// Returns the inner writer and the CRC32 of what was written to it.
+    pub(crate) fn finish(self) -> (W, u32) {
+        (self.inner, self.hasher.finalize())
+    }
+}
+
+impl<W: Write> Write for Crc32Writer<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A reader that computes the CRC32 of everything read through it.
+pub(crate) struct Crc32Reader<R> {
+    inner: R,
+    hasher: Hasher,
+}
+
+impl<R: Read> Crc32Reader<R> {
+    pub(crate) fn new(inner: R) -> Self {
+        Crc32Reader {
+            inner,
+            hasher: Hasher::new(),
+        }
+    }
+
+    /// The CRC32 of what was read so far.
+    pub(crate) fn crc32(&self) -> u32 {
+        self.hasher.clone().finalize()
+    }
+}
+
+impl<R: Read> Read for Crc32Reader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
@@ -0,0 +1,239 @@
+use std::io::{self, BufReader, Read, Write};
+
+use brotli::{CompressorWriter, Decompressor};
+use clap::ValueEnum;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use serde::{Deserialize, Serialize};
+use xz2::{read::XzDecoder, write::XzEncoder};
+
+use crate::{Result, TtareError, ZstdDictionary, ENTROPY_THRESHOLD};
+
+/// The name of the internal file in the tar archive that contains the files that were compressed with gzip.
+pub(crate) const TTARE_COMPRESS_FILE_NAME: &str = ".ttare.tar.gz";
+
+/// The name of the internal file in the tar archive that contains the files that were compressed with zstd.
+pub(crate) const TTARE_ZSTD_COMPRESS_FILE_NAME: &str = ".ttare.tar.zst";
+
+/// The name of the internal file in the tar archive that contains the files that were compressed
+/// with xz.
+pub(crate) const TTARE_XZ_COMPRESS_FILE_NAME: &str = ".ttare.tar.xz";
+
+/// The name of the internal file in the tar archive that contains the files that were compressed
+/// with brotli.
+pub(crate) const TTARE_BROTLI_COMPRESS_FILE_NAME: &str = ".ttare.tar.br";
+
+/// The size of the buffers brotli compresses and decompresses through.
+const BROTLI_BUFFER_SIZE: usize = 64 * 1024;
+
+/// The base 2 logarithm of brotli's window size, the largest it takes without its large window
+/// extension, which not every decoder reads.
+const BROTLI_WINDOW_BITS: u32 = 24;
+
+/// The codec used to compress the internal tar of compressible files.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    /// Fast and readable everywhere.
+    #[default]
+    Gzip,
+
+    /// Compresses better than gzip, and much faster at both compressing and decompressing.
+    Zstd,
+
+    /// Compresses best, but is several times slower than gzip at compressing.
+    Xz,
+
+    /// Compresses text such as web assets much better than gzip, with a built-in dictionary of
+    /// common words and markup, and decompresses about as fast.
+    Brotli,
+}
+
+impl Codec {
+    const ALL: [Codec; 4] = [Codec::Gzip, Codec::Zstd, Codec::Xz, Codec::Brotli];
+
+    /// The name of the internal file that holds the files compressed with this codec.
+    pub fn member_name(self) -> &'static str {
+        match self {
+            Codec::Gzip => TTARE_COMPRESS_FILE_NAME,
+            Codec::Zstd => TTARE_ZSTD_COMPRESS_FILE_NAME,
+            Codec::Xz => TTARE_XZ_COMPRESS_FILE_NAME,
+            Codec::Brotli => TTARE_BROTLI_COMPRESS_FILE_NAME,
+        }
+    }
+
+    /// The name of the codec, as given to `--codec`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Codec::Gzip => "gzip",
+            Codec::Zstd => "zstd",
+            Codec::Xz => "xz",
+            Codec::Brotli => "brotli",
+        }
+    }
+
+    /// The entropy threshold used with this codec when none is given: 6.5 for gzip, and 7.0 for
+    /// the others, which still gain something on data that gzip can barely shrink.
+    pub fn default_entropy_threshold(self) -> f32 {
+        match self {
+            Codec::Gzip => ENTROPY_THRESHOLD,
+            Codec::Zstd | Codec::Xz | Codec::Brotli => 7.0,
+        }
+    }
+
+    /// The highest compression level of this codec: 19 for zstd, 11 for brotli's quality, and 9
+    /// for the others.
+    pub fn max_level(self) -> u32 {
+        match self {
+            Codec::Gzip | Codec::Xz => 9,
+            Codec::Zstd => 19,
+            Codec::Brotli => 11,
+        }
+    }
+
+    /// The level that compresses the fastest: 0 for xz and brotli, and 1 for gzip, whose 0 stores
+    /// the data as-is, and for zstd, whose 0 is its default level.
+    pub(crate) fn fastest_level(self) -> u32 {
+        match self {
+            Codec::Gzip | Codec::Zstd => 1,
+            Codec::Xz | Codec::Brotli => 0,
+        }
+    }
+
+    /// Finds the codec named `name`, if any.
+    pub fn from_name(name: &str) -> Option<Codec> {
+        Codec::ALL.into_iter().find(|codec| codec.name() == name)
+    }
+
+    /// The suffix of the files compressed on their own with this codec.
+    pub fn suffix(self) -> &'static str {
+        match self {
+            Codec::Gzip => ".gz",
+            Codec::Zstd => ".zst",
+            Codec::Xz => ".xz",
+            Codec::Brotli => ".br",
+        }
+    }
+
+    /// Finds the codec whose internal file is named `name`, if any.
+    pub fn from_member_name(name: &str) -> Option<Codec> {
+        Codec::ALL
+            .into_iter()
+            .find(|codec| codec.member_name() == name)
+    }
+
+    /// Wraps `writer` in an encoder for this codec, which compresses with `dictionary` if there is
+    /// one. Only zstd takes a dictionary. A missing level uses the codec's default level.
+    pub(crate) fn encoder<W: Write>(
+        self,
+        writer: W,
+        level: Option<u32>,
+        dictionary: Option<&ZstdDictionary>,
+    ) -> Result<Encoder<W>> {
+        if let Some(level) = level.filter(|&level| level > self.max_level()) {
+            return Err(TtareError::InvalidCompressionLevel { codec: self, level });
+        }
+        if dictionary.is_some() && self != Codec::Zstd {
+            return Err(TtareError::DictionaryWithoutZstd(self));
+        }
+
+        Ok(match self {
+            // The gzip header's timestamp is left at 0, so the same input always compresses the same
+            Codec::Gzip => {
+                let compression = level.map(Compression::new).unwrap_or_default();
+                Encoder::Gzip(GzEncoder::new(writer, compression))
+            }
+            // zstd treats level 0 as its default level
+            Codec::Zstd => {
+                let level = level.unwrap_or(0) as i32;
+                Encoder::Zstd(match dictionary {
+                    Some(dictionary) => {
+                        zstd::Encoder::with_dictionary(writer, level, dictionary.data())?
+                    }
+                    None => zstd::Encoder::new(writer, level)?,
+                })
+            }
+            // The levels are xz's presets, where 6 is the default
+            Codec::Xz => Encoder::Xz(XzEncoder::new(writer, level.unwrap_or(6))),
+            // The levels are brotli's qualities. Its own default of 11 is a hundred times slower
+            // than 6, for a few percent.
+            Codec::Brotli => Encoder::Brotli(Box::new(CompressorWriter::new(
+                writer,
+                BROTLI_BUFFER_SIZE,
+                level.unwrap_or(6),
+                BROTLI_WINDOW_BITS,
+            ))),
+        })
+    }
+
+    /// Wraps `reader` in a decoder for this codec.
+    pub(crate) fn decoder<'a>(self, reader: impl Read + 'a) -> Result<Box<dyn Read + 'a>> {
+        Ok(match self {
+            Codec::Gzip => Box::new(GzDecoder::new(reader)),
+            Codec::Zstd => Box::new(zstd::Decoder::new(reader)?),
+            Codec::Xz => Box::new(XzDecoder::new(reader)),
+            Codec::Brotli => Box::new(Decompressor::new(reader, BROTLI_BUFFER_SIZE)),
+        })
+    }
+
+    /// Wraps `reader` in a decoder for this codec, for data compressed with `dictionary` if there
+    /// is one.
+    pub(crate) fn decoder_with_dictionary<'a>(
+        self,
+        reader: impl Read + 'a,
+        dictionary: Option<&ZstdDictionary>,
+    ) -> Result<Box<dyn Read + 'a>> {
+        match (self, dictionary) {
+            (Codec::Zstd, Some(dictionary)) => Ok(Box::new(zstd::Decoder::with_dictionary(
+                BufReader::new(reader),
+                dictionary.data(),
+            )?)),
+            _ => self.decoder(reader),
+        }
+    }
+}
+
+/// A compressing writer for one of the codecs.
+pub(crate) enum Encoder<W: Write> {
+    Gzip(GzEncoder<W>),
+    Zstd(zstd::Encoder<'static, W>),
+    Xz(XzEncoder<W>),
+    Brotli(Box<CompressorWriter<W>>),
+}
+
+impl<W: Write> Encoder<W> {
+    /// Writes out the end of the compressed stream, returning the inner writer.
+    pub(crate) fn finish(self) -> io::Result<W> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.finish(),
+            Encoder::Zstd(encoder) => encoder.finish(),
+            Encoder::Xz(encoder) => encoder.finish(),
+            // brotli ignores the errors of writing the end of the stream, so it's flushed first,
+            // and the few bytes of the end go to the `BufWriter` that every encoder writes to,
+            // whose flush reports what went wrong
+            Encoder::Brotli(mut encoder) => {
+                encoder.flush()?;
+                Ok(encoder.into_inner())
+            }
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.write(buf),
+            Encoder::Zstd(encoder) => encoder.write(buf),
+            Encoder::Xz(encoder) => encoder.write(buf),
+            Encoder::Brotli(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.flush(),
+            Encoder::Zstd(encoder) => encoder.flush(),
+            Encoder::Xz(encoder) => encoder.flush(),
+            Encoder::Brotli(encoder) => encoder.flush(),
+        }
+    }
+}
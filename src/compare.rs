@@ -0,0 +1,150 @@
+use std::{
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use rustc_hash::FxHashMap;
+use tar::{Archive, Entry};
+
+use crate::{
+    dedup::DedupManifest, error::IoContext, hard_link_target, manifest::crc32_of,
+    meta::ArchiveMeta, normalize_entry_path, plain, root_entry_kind, split, Result, RootEntry,
+    TtareError,
+};
+
+/// How the files in one ttare archive differ from the files in another, each sorted by path.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Comparison {
+    /// The paths that are only in the second archive.
+    pub added: Vec<PathBuf>,
+
+    /// The paths that are only in the first archive.
+    pub removed: Vec<PathBuf>,
+
+    /// The paths that are in both archives, with other contents or as another kind of entry.
+    pub changed: Vec<PathBuf>,
+}
+
+impl Comparison {
+    /// Whether both archives hold the same files.
+    pub fn is_same(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// What an archive holds at a path, as far as comparing archives goes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Contents {
+    Directory,
+    Symlink(PathBuf),
+    File { size: u64, crc32: u32 },
+}
+
+/// Compares the files in the ttare archives at `first` and `second`, decompressing both.
+///
+/// Only the paths and what is stored under them count: the files' contents, the symlinks'
+/// targets and which paths are directories. Modification times, permissions and owners are
+/// ignored, and so is how the archives were written, such as their codec, which files were
+/// compressed, and whether identical files were stored once.
+pub fn compare(first: &Path, second: &Path) -> Result<Comparison> {
+    let first = archive_contents(first)?;
+    let mut second = archive_contents(second)?;
+
+    let mut comparison = Comparison::default();
+    for (path, contents) in first {
+        match second.remove(&path) {
+            Some(other) if other == contents => {}
+            Some(_) => comparison.changed.push(path),
+            None => comparison.removed.push(path),
+        }
+    }
+    comparison.added.extend(second.into_keys());
+
+    comparison.added.sort();
+    comparison.removed.sort();
+    comparison.changed.sort();
+    Ok(comparison)
+}
+
+/// Reads what the archive at `input` holds under each path.
+fn archive_contents(input: &Path) -> Result<FxHashMap<PathBuf, Contents>> {
+    let mut archive = Archive::new(plain::root_tar(split::open_archive(input)?)?);
+    let mut contents = FxHashMap::default();
+    let mut meta = None;
+    let mut dedup = DedupManifest::default();
+    let mut hard_links = vec![];
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = normalize_entry_path(&entry.path()?);
+
+        match root_entry_kind(&mut entry)? {
+            RootEntry::Meta => meta = Some(ArchiveMeta::read(entry)?),
+            RootEntry::Member(codec) => {
+                let decoder = ArchiveMeta::member_decoder(meta.as_ref(), codec, None, entry)?;
+                let mut tar = Archive::new(decoder);
+                for inner in tar.entries()? {
+                    let inner = inner?;
+                    let path = normalize_entry_path(&inner.path()?);
+                    let inner_contents = entry_contents(&path, inner)?;
+                    contents.insert(path, inner_contents);
+                }
+            }
+            RootEntry::Compressed(file) => {
+                let path = normalize_entry_path(&file.path);
+                let crc32 = crc32_of(file.codec.decoder(entry)?)
+                    .with_path("Could not decompress", &path)?;
+                contents.insert(
+                    path,
+                    Contents::File {
+                        size: file.size,
+                        crc32,
+                    },
+                );
+            }
+            RootEntry::Dedup => dedup = DedupManifest::read(entry)?,
+            RootEntry::HardLink => {
+                hard_links.push((path.clone(), hard_link_target(&entry, &path)?))
+            }
+            RootEntry::Checksum | RootEntry::Manifest | RootEntry::Index => {}
+            RootEntry::Directory | RootEntry::Symlink | RootEntry::File => {
+                let entry_contents = entry_contents(&path, entry)?;
+                contents.insert(path, entry_contents);
+            }
+        }
+    }
+
+    // A hard link holds what the file it links to holds, which can be a copy itself
+    dedup.copies.extend(hard_links);
+    for (copy, original) in dedup.copies {
+        let original = contents
+            .get(&normalize_entry_path(&original))
+            .cloned()
+            .ok_or_else(|| {
+                TtareError::CorruptArchive(format!(
+                    "{} is a copy of a missing file",
+                    copy.display()
+                ))
+            })?;
+        contents.insert(normalize_entry_path(&copy), original);
+    }
+
+    Ok(contents)
+}
+
+/// Reads what a tar entry holds, which is at `path`.
+fn entry_contents<R: Read>(path: &Path, entry: Entry<'_, R>) -> Result<Contents> {
+    let entry_type = entry.header().entry_type();
+    if entry_type.is_dir() {
+        Ok(Contents::Directory)
+    } else if entry_type.is_symlink() {
+        let target = entry.link_name()?.ok_or_else(|| {
+            TtareError::CorruptArchive(format!("{} links to nothing", path.display()))
+        })?;
+        Ok(Contents::Symlink(target.into_owned()))
+    } else {
+        let size = entry.size();
+        let crc32 = crc32_of(entry).with_path("Could not read", path)?;
+        Ok(Contents::File { size, crc32 })
+    }
+}
@@ -0,0 +1,180 @@
+use std::{
+    fs::File,
+    hash::Hasher,
+    io::{self, BufReader, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use rustc_hash::{FxHashMap, FxHasher};
+
+use crate::{
+    error::IoContext,
+    normalize_entry_path,
+    walk::{path_from_bytes, path_to_bytes},
+    Result,
+};
+
+/// The size of the chunks that files are hashed and compared in.
+const DEDUP_CHUNK_SIZE: u64 = 64 * 1024;
+
+/// Finds the files whose contents were already added to the archive.
+///
+/// Files are grouped by their size and the hash of their contents, and a file is only a duplicate
+/// of another if their contents compare equal, so a hash collision can't lose data.
+#[derive(Default)]
+pub(crate) struct Deduplicator {
+    originals: FxHashMap<(u64, u64), Vec<PathBuf>>,
+
+    /// The originals recorded since `take_added` was last called, by their size and hash, when
+    /// they are kept for a checkpoint.
+    added: Option<Vec<(u64, u64, PathBuf)>>,
+}
+
+impl Deduplicator {
+    /// Creates a deduplicator that keeps the originals it records until `take_added` is called.
+    pub(crate) fn keeping_added() -> Self {
+        Deduplicator {
+            added: Some(vec![]),
+            ..Deduplicator::default()
+        }
+    }
+
+    /// The originals recorded since this was last called, by their size and hash.
+    pub(crate) fn take_added(&mut self) -> Vec<(u64, u64, PathBuf)> {
+        self.added.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    /// Records the file at `path`, of `len` bytes and with contents of `hash`, as an original, as
+    /// `original_of` did in the run being resumed.
+    pub(crate) fn restore(&mut self, len: u64, hash: u64, path: PathBuf) {
+        self.originals.entry((len, hash)).or_default().push(path);
+    }
+
+    /// Returns the file that `file` at `path`, of `len` bytes, is a copy of, or records it as an
+    /// original.
+    ///
+    /// `file` is rewound either way, so it can be added to the archive.
+    pub(crate) fn original_of(
+        &mut self,
+        path: &Path,
+        len: u64,
+        file: &mut (impl Read + Seek),
+    ) -> Result<Option<PathBuf>> {
+        let key = (
+            len,
+            hash_contents(&mut *file).with_path("Could not read", path)?,
+        );
+        let candidates = self.originals.entry(key).or_default();
+
+        for candidate in candidates.iter() {
+            file.seek(SeekFrom::Start(0))?;
+            let original = File::open(candidate).with_path("Could not open", candidate)?;
+            if same_contents(&mut *file, original).with_path("Could not compare", path)? {
+                file.seek(SeekFrom::Start(0))?;
+                return Ok(Some(candidate.clone()));
+            }
+        }
+
+        candidates.push(path.to_path_buf());
+        if let Some(added) = &mut self.added {
+            added.push((key.0, key.1, path.to_path_buf()));
+        }
+        file.seek(SeekFrom::Start(0))?;
+        Ok(None)
+    }
+}
+
+/// Hashes everything read from `reader`.
+fn hash_contents(reader: impl Read) -> io::Result<u64> {
+    let mut reader = BufReader::new(reader);
+    let mut hasher = FxHasher::default();
+    let mut chunk = vec![];
+
+    loop {
+        // Always hash whole chunks, so the hash doesn't depend on how the reads are split
+        chunk.clear();
+        reader
+            .by_ref()
+            .take(DEDUP_CHUNK_SIZE)
+            .read_to_end(&mut chunk)?;
+        if chunk.is_empty() {
+            return Ok(hasher.finish());
+        }
+        hasher.write(&chunk);
+    }
+}
+
+/// Compares everything read from `a` and `b`.
+fn same_contents(a: impl Read, b: impl Read) -> io::Result<bool> {
+    let mut a = BufReader::new(a);
+    let mut b = BufReader::new(b);
+    let mut chunk_a = vec![];
+    let mut chunk_b = vec![];
+
+    loop {
+        chunk_a.clear();
+        chunk_b.clear();
+        a.by_ref()
+            .take(DEDUP_CHUNK_SIZE)
+            .read_to_end(&mut chunk_a)?;
+        b.by_ref()
+            .take(DEDUP_CHUNK_SIZE)
+            .read_to_end(&mut chunk_b)?;
+        if chunk_a != chunk_b {
+            return Ok(false);
+        }
+        if chunk_a.is_empty() {
+            return Ok(true);
+        }
+    }
+}
+
+/// The files that were left out of the archive because they are copies of another file, as
+/// `(copy, original)` pairs.
+///
+/// It is stored as the copy and the original of each pair, each followed by a NUL byte.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct DedupManifest {
+    pub(crate) copies: Vec<(PathBuf, PathBuf)>,
+}
+
+impl DedupManifest {
+    pub(crate) fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut bytes = vec![];
+        for (copy, original) in &self.copies {
+            for path in [copy, original] {
+                bytes.extend_from_slice(&path_to_bytes(&normalize_entry_path(path))?);
+                bytes.push(b'\0');
+            }
+        }
+        Ok(bytes)
+    }
+
+    pub(crate) fn read(mut reader: impl Read) -> Result<Self> {
+        let mut bytes = vec![];
+        reader
+            .read_to_end(&mut bytes)
+            .with_action("Could not read the list of duplicate files")?;
+
+        let paths = bytes
+            .split(|&byte| byte == b'\0')
+            .filter(|path| !path.is_empty())
+            .map(path_from_bytes)
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(DedupManifest {
+            copies: paths
+                .chunks_exact(2)
+                .map(|pair| (pair[0].clone(), pair[1].clone()))
+                .collect(),
+        })
+    }
+
+    /// The original of the file at `path`, if it is a copy.
+    pub(crate) fn original_of(&self, path: &Path) -> Option<&Path> {
+        self.copies
+            .iter()
+            .find(|(copy, _)| copy == path)
+            .map(|(_, original)| original.as_path())
+    }
+}
@@ -0,0 +1,46 @@
+use std::{fmt, fs, path::Path};
+
+use crate::{error::IoContext, Result};
+
+/// A zstd dictionary, which primes zstd with what many small files have in common, such as the
+/// keys of JSON records, so that even the first of them compress well.
+///
+/// The compressed member of an archive can only be read with the dictionary it was compressed
+/// with, so the archive records its digest, the CRC32 of its contents.
+#[derive(Clone, PartialEq, Eq)]
+pub struct ZstdDictionary {
+    data: Vec<u8>,
+    digest: String,
+}
+
+impl ZstdDictionary {
+    /// A dictionary of `data`, either trained by `zstd --train` or raw content.
+    pub fn new(data: Vec<u8>) -> Self {
+        let digest = format!("{:08x}", crc32fast::hash(&data));
+        ZstdDictionary { data, digest }
+    }
+
+    /// Reads the dictionary in the file at `path`.
+    pub fn open(path: &Path) -> Result<Self> {
+        let data = fs::read(path).with_path("Could not read the dictionary", path)?;
+        Ok(Self::new(data))
+    }
+
+    /// The digest that archives compressed with this dictionary record.
+    pub fn digest(&self) -> &str {
+        &self.digest
+    }
+
+    pub(crate) fn data(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl fmt::Debug for ZstdDictionary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ZstdDictionary")
+            .field("len", &self.data.len())
+            .field("digest", &self.digest)
+            .finish()
+    }
+}
@@ -0,0 +1,591 @@
+use std::{
+    ffi::OsStr,
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use clap::ValueEnum;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+use crate::{CompressOptions, CountingWriter, Result};
+
+/// For each file, analysis of the file's entropy is computed, and a decision to either compress or not compress the file is made.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EntropyAnalysis {
+    Compress,
+    DontCompress,
+}
+
+/// How the entropy of a file is estimated, in bits per byte, before comparing it to the threshold.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Estimator {
+    /// The Shannon entropy of the bytes, which only counts how often each byte value appears, so
+    /// it misses repeated sequences of bytes that are each as likely as any other.
+    #[default]
+    Shannon,
+
+    /// How many bits each byte takes once the sample is compressed with the codec at its fastest
+    /// level, which is slower but sees what the codec will actually gain.
+    Probe,
+}
+
+/// The threshold of the entropy, in bits per byte, at which any file with entropy above this
+/// threshold will not be compressed with gzip. The other codecs have their own, see
+/// `Codec::default_entropy_threshold`.
+pub const ENTROPY_THRESHOLD: f32 = 6.5f32;
+
+/// The highest entropy there is, in bits per byte, of bytes that take each of their 256 values as
+/// often.
+pub const MAX_ENTROPY: f32 = 8.0f32;
+
+/// The threshold, in bits per byte, that is `percentage` percent of `MAX_ENTROPY`, so that `81.25`
+/// is `ENTROPY_THRESHOLD`.
+pub fn threshold_from_percentage(percentage: f32) -> f32 {
+    MAX_ENTROPY * percentage / 100.0
+}
+
+/// The percentage of the file to sample to compute the entropy.
+pub const ENTROPY_SAMPLING: f32 = 0.5f32;
+
+/// The extensions of formats that are already compressed, whose files are stored as-is without
+/// reading them.
+pub const INCOMPRESSIBLE_EXTENSIONS: &[&str] = &[
+    "7z", "avif", "br", "bz2", "flac", "gif", "gz", "heic", "jpeg", "jpg", "lz4", "lzma", "m4a",
+    "mkv", "mov", "mp3", "mp4", "ogg", "opus", "png", "rar", "tgz", "webm", "webp", "woff2", "xz",
+    "zip", "zst",
+];
+
+/// The smallest sample taken from a file, unless the file is smaller, so that even small files
+/// get a meaningful histogram.
+pub const MIN_SAMPLE_BYTES: u64 = 64 * 1024;
+
+/// The size below which files are always compressed, bundled with the others in the compressed
+/// member, whatever their entropy. The entropy of a few bytes says little, and a tar header and
+/// its padding take up to a KiB for each of them, which only compresses away in the member.
+pub const SMALL_FILE_BYTES: u64 = 512;
+
+/// The share of a file that has to be in compressible windows for it to be compressed, when its
+/// entropy is computed over windows.
+pub const COMPRESSIBLE_FRACTION: f32 = 0.5;
+
+/// The size of each chunk read from the file when sampling it to compute the entropy.
+const ENTROPY_CHUNK_SIZE: usize = 4 * 1024;
+
+/// How many bytes from the start of a file are read to sniff its format.
+const SNIFF_BYTES: u64 = 8 * 1024;
+
+/// The seed of the RNG used to pick which chunks are sampled, so a file is always sampled the same way.
+const ENTROPY_SAMPLING_SEED: u64 = 0x0074_7461_7265;
+
+/// Samples `reader` and decides whether its contents are worth compressing.
+///
+/// With `sniff`, contents that start like a format that is already compressed aren't sampled, see
+/// `sniffs_incompressible`. The reader is left at an unspecified position.
+pub fn analyze_entropy<R: Read + Seek>(
+    reader: &mut R,
+    opts: &CompressOptions,
+) -> Result<EntropyAnalysis> {
+    if sniffs_incompressible(reader, opts)? {
+        return Ok(EntropyAnalysis::DontCompress);
+    }
+    Ok(classify(reader, opts)?.1)
+}
+
+/// Samples `reader`, returning its entropy and whether its contents are worth compressing.
+///
+/// The whole contents are read instead of a sample when `full_entropy` is set. The entropy is
+/// estimated by `estimator`, and `window_bytes` only applies to the Shannon entropy. With
+/// `window_bytes`, the contents are compressed when at least `compressible_fraction` of the full
+/// windows of what was read have an entropy below the threshold, whatever the entropy of the
+/// whole. A last window that isn't full is left out, and contents shorter than a window are judged
+/// as a whole. Empty contents have an entropy of `0.0` but are never compressed, since there is
+/// nothing to gain. The reader is left at an unspecified position.
+pub fn classify<R: Read + Seek>(
+    reader: &mut R,
+    opts: &CompressOptions,
+) -> Result<(f32, EntropyAnalysis)> {
+    if reader.seek(SeekFrom::End(0))? == 0 {
+        return Ok((0.0, EntropyAnalysis::DontCompress));
+    }
+
+    if opts.estimator == Estimator::Probe {
+        let entropy = if opts.full_entropy {
+            reader.seek(SeekFrom::Start(0))?;
+            probe_entropy(reader, opts)?
+        } else {
+            probe_entropy(sample(reader, opts)?.as_slice(), opts)?
+        };
+        return Ok((entropy, decide(entropy, opts)));
+    }
+
+    if opts.window_bytes.is_none() {
+        let entropy = if opts.full_entropy {
+            full_entropy(reader)?
+        } else {
+            sample_entropy(reader, opts)?
+        };
+        return Ok((entropy, decide(entropy, opts)));
+    }
+
+    let mut counter = EntropyCounter::new(opts);
+    if opts.full_entropy {
+        reader.seek(SeekFrom::Start(0))?;
+        let mut histogram = HistogramReader {
+            inner: reader,
+            counter,
+        };
+        io::copy(&mut histogram, &mut io::sink())?;
+        counter = histogram.counter;
+    } else {
+        counter.add(&sample(reader, opts)?);
+    }
+    Ok(counter.classify(opts))
+}
+
+/// Whether `path` has the extension of a format that is already compressed, so its entropy doesn't
+/// need to be computed.
+///
+/// Extensions are compared without regard to case, against `INCOMPRESSIBLE_EXTENSIONS` and
+/// `incompressible_extensions`. This is always false when `extension_shortcut` isn't set.
+pub fn has_incompressible_extension(path: &Path, opts: &CompressOptions) -> bool {
+    if !opts.extension_shortcut {
+        return false;
+    }
+
+    let Some(extension) = path.extension().and_then(OsStr::to_str) else {
+        return false;
+    };
+
+    is_incompressible_extension(extension, opts)
+}
+
+/// Whether `reader`'s contents start like a format that is already compressed, whatever the
+/// extension of their file, so their entropy doesn't need to be computed.
+///
+/// The format is recognized by its magic bytes, and counts as compressed when its usual extension
+/// is one of `INCOMPRESSIBLE_EXTENSIONS` or `incompressible_extensions`. This is always false
+/// when `sniff` isn't set. The reader is left at its start.
+pub fn sniffs_incompressible<R: Read + Seek>(
+    reader: &mut R,
+    opts: &CompressOptions,
+) -> Result<bool> {
+    if !opts.sniff {
+        return Ok(false);
+    }
+
+    reader.seek(SeekFrom::Start(0))?;
+    let mut head = Vec::with_capacity(SNIFF_BYTES as usize);
+    reader.by_ref().take(SNIFF_BYTES).read_to_end(&mut head)?;
+    reader.seek(SeekFrom::Start(0))?;
+
+    Ok(infer::get(&head).is_some_and(|kind| {
+        log::debug!("sniffed {}", kind.mime_type());
+        is_incompressible_extension(kind.extension(), opts)
+    }))
+}
+
+/// Whether files with `extension` are known to be already compressed.
+fn is_incompressible_extension(extension: &str, opts: &CompressOptions) -> bool {
+    INCOMPRESSIBLE_EXTENSIONS
+        .iter()
+        .copied()
+        .chain(
+            opts.incompressible_extensions
+                .iter()
+                .map(|known| known.trim_start_matches('.')),
+        )
+        .any(|known| known.eq_ignore_ascii_case(extension))
+}
+
+/// Decides whether contents with the given entropy are worth compressing with the codec in `opts`.
+pub fn decide(entropy: f32, opts: &CompressOptions) -> EntropyAnalysis {
+    if entropy > opts.threshold() {
+        EntropyAnalysis::DontCompress
+    } else {
+        EntropyAnalysis::Compress
+    }
+}
+
+/// Suggests an entropy threshold that separates the compressible files from the incompressible
+/// ones, given their entropies.
+///
+/// The threshold is placed in the middle of the largest gap between sorted entropies. There is no
+/// suggestion when fewer than two distinct entropies are given.
+pub fn suggest_threshold(entropies: &[f32]) -> Option<f32> {
+    let mut sorted = entropies.to_vec();
+    sorted.sort_by(f32::total_cmp);
+
+    sorted
+        .windows(2)
+        .filter(|pair| pair[1] > pair[0])
+        .max_by(|a, b| (a[1] - a[0]).total_cmp(&(b[1] - b[0])))
+        .map(|pair| (pair[0] + pair[1]) / 2.0)
+}
+
+/// Computes the entropy of a sample of `reader`'s contents, as set by `opts`.
+///
+/// The sample is `sample_percentage` of the contents, but no less than `min_sample_bytes` and no
+/// more than `max_sample_bytes`. The reader is left at an unspecified position.
+pub fn sample_entropy<R: Read + Seek>(reader: &mut R, opts: &CompressOptions) -> Result<f32> {
+    Ok(sample_histogram(reader, opts)?.entropy())
+}
+
+/// Counts the bytes of the sample of `reader`'s contents that `sample_entropy` computes the
+/// entropy of. The reader is left at an unspecified position.
+pub fn sample_histogram<R: Read + Seek>(
+    reader: &mut R,
+    opts: &CompressOptions,
+) -> Result<Histogram> {
+    Ok(Histogram::of(&sample(reader, opts)?))
+}
+
+/// Reads the sample of `reader`'s contents that `sample_entropy` computes the entropy of.
+pub(crate) fn sample<R: Read + Seek>(reader: &mut R, opts: &CompressOptions) -> Result<Vec<u8>> {
+    opts.check_sampling()?;
+
+    let file_len = reader.seek(SeekFrom::End(0))?;
+    reader.seek(SeekFrom::Start(0))?;
+
+    let entropy_sampling = opts.sample_percentage.min(1.0);
+    let mut sample_len = ((file_len as f32 * entropy_sampling) as u64).max(opts.min_sample_bytes);
+    if let Some(max_sample_bytes) = opts.max_sample_bytes {
+        sample_len = sample_len.min(max_sample_bytes);
+    }
+
+    // A sample can't be larger than the file itself
+    let file_len = file_len as usize;
+    let entropy_bytes_len = sample_len.min(file_len as u64) as usize;
+
+    sample_chunks(reader, file_len, entropy_bytes_len)
+}
+
+/// Compresses everything read from `reader` with the codec in `opts` at its fastest level, and
+/// returns how many bits each byte took, capped at `8.0` for contents that didn't compress.
+pub(crate) fn probe_entropy<R: Read>(mut reader: R, opts: &CompressOptions) -> Result<f32> {
+    let codec = opts.codec;
+    let mut encoder = codec.encoder(
+        CountingWriter::new(io::sink()),
+        Some(codec.fastest_level()),
+        None,
+    )?;
+    let read = io::copy(&mut reader, &mut encoder)?;
+    let compressed = encoder.finish()?.written;
+    if read == 0 {
+        return Ok(0.0);
+    }
+
+    Ok(((compressed as f64 * 8.0 / read as f64) as f32).min(8.0))
+}
+
+/// Computes the entropy of all of `reader`'s contents, which is slower than sampling them but can't
+/// be fooled by parts of the file that the sample misses.
+///
+/// The reader is left at an unspecified position.
+pub fn full_entropy<R: Read + Seek>(reader: &mut R) -> Result<f32> {
+    Ok(full_histogram(reader)?.entropy())
+}
+
+/// Counts all of the bytes of `reader`'s contents, which `full_entropy` computes the entropy of.
+/// The reader is left at an unspecified position.
+pub fn full_histogram<R: Read + Seek>(reader: &mut R) -> Result<Histogram> {
+    reader.seek(SeekFrom::Start(0))?;
+    Ok(stream_histogram(reader)?)
+}
+
+/// Counts the bytes of everything read from `reader`, without keeping it in memory.
+fn stream_histogram<R: Read>(mut reader: R) -> io::Result<Histogram> {
+    let mut counts = ByteCounts::default();
+    let mut buffer = vec![0; 64 * 1024];
+
+    loop {
+        match reader.read(&mut buffer) {
+            Ok(0) => return Ok(counts.histogram()),
+            Ok(read) => counts.add(&buffer[..read]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Reads up to `sample_len` bytes from the reader, made of chunks picked at a random offset within
+/// evenly sized strides of the file, so that the whole file is represented in the sample.
+///
+/// Fewer bytes are returned if the file turns out to be shorter than `file_len`.
+fn sample_chunks<R: Read + Seek>(
+    reader: &mut R,
+    file_len: usize,
+    sample_len: usize,
+) -> Result<Vec<u8>> {
+    let mut entropy_bytes = Vec::with_capacity(sample_len);
+
+    // There is nothing to spread out if the sample fits in a single chunk, or is the whole file
+    if sample_len <= ENTROPY_CHUNK_SIZE || sample_len >= file_len {
+        reader
+            .by_ref()
+            .take(sample_len as u64)
+            .read_to_end(&mut entropy_bytes)?;
+        return Ok(entropy_bytes);
+    }
+
+    let mut rng = StdRng::seed_from_u64(ENTROPY_SAMPLING_SEED);
+    let chunk_count = sample_len.div_ceil(ENTROPY_CHUNK_SIZE);
+    let stride = file_len / chunk_count;
+
+    for i in 0..chunk_count {
+        let chunk_len = ENTROPY_CHUNK_SIZE.min(sample_len - i * ENTROPY_CHUNK_SIZE);
+        let slack = stride.saturating_sub(chunk_len);
+        let offset = i * stride + rng.gen_range(0..=slack);
+
+        reader.seek(SeekFrom::Start(offset as u64))?;
+        reader
+            .by_ref()
+            .take(chunk_len as u64)
+            .read_to_end(&mut entropy_bytes)?;
+    }
+
+    Ok(entropy_bytes)
+}
+
+/// Computes the Shannon entropy of the bytes, in bits per byte.
+///
+/// The result is always within `[0.0, 8.0]`, and is `0.0` for empty input.
+pub fn entropy(entropy_bytes: &[u8]) -> f32 {
+    Histogram::of(entropy_bytes).entropy()
+}
+
+/// How many times each byte value appears in some contents, which their entropy is computed from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Histogram {
+    counts: [u64; 256],
+    total: u64,
+}
+
+impl Histogram {
+    /// Counts the bytes of `bytes`.
+    pub fn of(bytes: &[u8]) -> Self {
+        let mut counts = ByteCounts::default();
+        counts.add(bytes);
+        counts.histogram()
+    }
+
+    /// How many times each byte value appears, indexed by the byte.
+    pub fn counts(&self) -> &[u64; 256] {
+        &self.counts
+    }
+
+    /// How many bytes were counted.
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// The Shannon entropy of the bytes counted, in bits per byte.
+    ///
+    /// The terms are summed in the order of the bytes, in `f64`, so the same contents always give
+    /// the same entropy down to the bit. Each term is written as `p * log2(1 / p)` rather than
+    /// `-p * log2(p)`, so that contents of a single byte value give `0.0` instead of `-0.0`.
+    pub fn entropy(&self) -> f32 {
+        if self.total == 0 {
+            return 0.0;
+        }
+
+        let total = self.total as f64;
+
+        // There are at most 256 distinct bytes, so this isn't worth parallelizing
+        let entropy: f64 = self
+            .counts
+            .iter()
+            .filter(|&&count| count > 0)
+            .map(|&count| {
+                let p = count as f64 / total;
+                p * (total / count as f64).log2()
+            })
+            .fold(0.0, |sum, term| sum + term);
+
+        (entropy as f32).clamp(0.0, 8.0)
+    }
+
+    /// Writes the counts as CSV, with a `byte,count` header and a row for each of the 256 byte
+    /// values, including those that don't appear, so that they can be plotted as they are.
+    pub fn write_csv(&self, mut writer: impl Write) -> io::Result<()> {
+        writeln!(writer, "byte,count")?;
+        for (byte, count) in self.counts.iter().enumerate() {
+            writeln!(writer, "{byte},{count}")?;
+        }
+        writer.flush()
+    }
+}
+
+/// Counts the bytes read through it, so that the entropy of a stream can be computed while it is
+/// copied, instead of reading it again.
+pub(crate) struct HistogramReader<R> {
+    inner: R,
+    counter: EntropyCounter,
+}
+
+impl<R> HistogramReader<R> {
+    /// Counts what is read from `inner` the way `opts` classifies contents.
+    pub(crate) fn new(inner: R, opts: &CompressOptions) -> Self {
+        HistogramReader {
+            inner,
+            counter: EntropyCounter::new(opts),
+        }
+    }
+
+    /// Counts `bytes` as if they had been read through it.
+    pub(crate) fn count(&mut self, bytes: &[u8]) {
+        self.counter.add(bytes);
+    }
+
+    /// The entropy of the bytes counted so far, and whether they are worth compressing.
+    pub(crate) fn classify(&self, opts: &CompressOptions) -> (f32, EntropyAnalysis) {
+        self.counter.classify(opts)
+    }
+}
+
+impl<R: Read> Read for HistogramReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.counter.add(&buf[..read]);
+        Ok(read)
+    }
+}
+
+/// Counts contents a piece at a time, for their entropy and, when `window_bytes` is set, for how
+/// many of them are in windows that are compressible on their own.
+struct EntropyCounter {
+    counts: ByteCounts,
+    windows: Option<WindowCounts>,
+}
+
+impl EntropyCounter {
+    fn new(opts: &CompressOptions) -> Self {
+        EntropyCounter {
+            counts: ByteCounts::default(),
+            windows: opts.window_bytes.map(|window_bytes| WindowCounts {
+                window_bytes: window_bytes.get(),
+                threshold: opts.threshold(),
+                window: ByteCounts::default(),
+                compressible_bytes: 0,
+                full_bytes: 0,
+            }),
+        }
+    }
+
+    fn add(&mut self, bytes: &[u8]) {
+        self.counts.add(bytes);
+        if let Some(windows) = &mut self.windows {
+            windows.add(bytes);
+        }
+    }
+
+    /// The entropy of the contents counted, and whether they are worth compressing.
+    fn classify(&self, opts: &CompressOptions) -> (f32, EntropyAnalysis) {
+        let entropy = self.counts.entropy();
+        let Some(fraction) = self
+            .windows
+            .as_ref()
+            .and_then(WindowCounts::compressible_fraction)
+        else {
+            return (entropy, decide(entropy, opts));
+        };
+
+        let decision = if fraction >= f64::from(opts.compressible_fraction) {
+            EntropyAnalysis::Compress
+        } else {
+            EntropyAnalysis::DontCompress
+        };
+        (entropy, decision)
+    }
+}
+
+/// Splits contents into consecutive windows, counting the bytes of those whose entropy is at most
+/// the threshold.
+struct WindowCounts {
+    window_bytes: u64,
+    threshold: f32,
+
+    /// The counts of the window being filled.
+    window: ByteCounts,
+
+    /// The bytes in the windows filled so far that are compressible.
+    compressible_bytes: u64,
+
+    /// The bytes in the windows filled so far.
+    full_bytes: u64,
+}
+
+impl WindowCounts {
+    fn add(&mut self, mut bytes: &[u8]) {
+        while !bytes.is_empty() {
+            let room = (self.window_bytes - self.window.total).min(bytes.len() as u64) as usize;
+            self.window.add(&bytes[..room]);
+            bytes = &bytes[room..];
+
+            if self.window.total == self.window_bytes {
+                if self.window.entropy() <= self.threshold {
+                    self.compressible_bytes += self.window_bytes;
+                }
+                self.full_bytes += self.window_bytes;
+                self.window = ByteCounts::default();
+            }
+        }
+    }
+
+    /// The share of the windows filled so far that is compressible, or `None` if none was filled.
+    /// The last window is left out until it's full, since the few bytes it may hold always have a
+    /// low entropy, even when they are random.
+    fn compressible_fraction(&self) -> Option<f64> {
+        (self.full_bytes > 0).then(|| self.compressible_bytes as f64 / self.full_bytes as f64)
+    }
+}
+
+/// How many times each byte was seen, so the entropy of contents can be computed a piece at a
+/// time.
+///
+/// The counts are kept in arrays indexed by the byte, which `benches/entropy.rs` shows is several
+/// times faster than a hash map. Consecutive bytes are counted in different arrays, so that a run
+/// of the same byte doesn't make each increment wait for the one before it. They are 64-bit, since
+/// whole files can be counted.
+struct ByteCounts {
+    counts: [[u64; 256]; 4],
+    total: u64,
+}
+
+impl Default for ByteCounts {
+    fn default() -> Self {
+        ByteCounts {
+            counts: [[0; 256]; 4],
+            total: 0,
+        }
+    }
+}
+
+impl ByteCounts {
+    fn add(&mut self, bytes: &[u8]) {
+        let mut chunks = bytes.chunks_exact(4);
+        for chunk in &mut chunks {
+            for (counts, &byte) in self.counts.iter_mut().zip(chunk) {
+                counts[byte as usize] += 1;
+            }
+        }
+        for &byte in chunks.remainder() {
+            self.counts[0][byte as usize] += 1;
+        }
+        self.total += bytes.len() as u64;
+    }
+
+    /// The counts of the bytes seen so far, added up across the arrays.
+    fn histogram(&self) -> Histogram {
+        Histogram {
+            counts: std::array::from_fn(|byte| self.counts.iter().map(|counts| counts[byte]).sum()),
+            total: self.total,
+        }
+    }
+
+    /// The Shannon entropy of the bytes seen, in bits per byte.
+    fn entropy(&self) -> f32 {
+        self.histogram().entropy()
+    }
+}
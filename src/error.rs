@@ -0,0 +1,224 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use thiserror::Error;
+
+use crate::Codec;
+
+/// The errors returned by ttare.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum TtareError {
+    /// An I/O error, with what was being done and to which path, when known.
+    #[error("{}", describe_io(.action, .path.as_deref()))]
+    Io {
+        action: &'static str,
+        path: Option<PathBuf>,
+        #[source]
+        source: io::Error,
+    },
+
+    /// A directory was given to compress without walking directories.
+    #[error("{} is a directory, pass --recursive to add its contents", .0.display())]
+    IsADirectory(PathBuf),
+
+    /// A glob given to filter directories is invalid.
+    #[error("Invalid glob {glob}")]
+    InvalidGlob {
+        glob: String,
+        #[source]
+        source: globset::Error,
+    },
+
+    /// A line of a rules file isn't a glob and a decision.
+    #[error(
+        "Invalid rule on line {line}: {text:?}, expected PATTERN = compress or PATTERN = store"
+    )]
+    InvalidRule { line: usize, text: String },
+
+    /// The sample percentage isn't a positive number.
+    #[error("The sample percentage must be a positive number, not {0}")]
+    InvalidSamplePercentage(f32),
+
+    /// The largest sample is empty, or smaller than the smallest sample.
+    #[error(
+        "The maximum sample size must be positive and at least the minimum sample size of {min} bytes, not {max} bytes"
+    )]
+    InvalidSampleBounds { min: u64, max: u64 },
+
+    /// The share of a file that has to be compressible isn't between 0 and 1.
+    #[error("The compressible fraction must be between 0 and 1, not {0}")]
+    InvalidCompressibleFraction(f32),
+
+    /// The compression level is higher than the codec's highest.
+    #[error("The compression level of {} goes up to {}, not {level}", .codec.name(), .codec.max_level())]
+    InvalidCompressionLevel { codec: Codec, level: u32 },
+
+    /// A dictionary was given to a codec that doesn't take one.
+    #[error("Only zstd can compress with a dictionary, not {}", .0.name())]
+    DictionaryWithoutZstd(Codec),
+
+    /// The compressed member was compressed with a zstd dictionary, which wasn't given.
+    #[error("The archive was compressed with the zstd dictionary {0}, which is needed to read it")]
+    MissingDictionary(String),
+
+    /// The compressed member was compressed with another zstd dictionary than the one given.
+    #[error("The archive was compressed with the zstd dictionary {expected}, not {given}")]
+    WrongDictionary { expected: String, given: String },
+
+    /// A path can't be stored, or read back, on this platform.
+    #[error("{} is not UTF-8", .0.display())]
+    NotUtf8(PathBuf),
+
+    /// The file asked for isn't in the archive.
+    #[error("{} not found in archive", .0.display())]
+    NotFound(PathBuf),
+
+    /// The archive was expected to hold a single file, but holds this many.
+    #[error("The archive holds {0} files instead of one, give the path of the file to write")]
+    NotSingleFile(usize),
+
+    /// Files can't be added to an archive that already has files at the same paths.
+    #[error("Already in the archive: {}", describe_paths(.0))]
+    DuplicatePaths(Vec<PathBuf>),
+
+    /// Files in the archive don't match the CRC32 recorded for them, or couldn't be read.
+    #[error("These files don't match their checksum: {}", describe_paths(.0))]
+    ChecksumMismatch(Vec<PathBuf>),
+
+    #[error("These files don't match the archive that was just written: {}", describe_paths(.0))]
+    SourceMismatch(Vec<PathBuf>),
+
+    /// The archive has a checksum for a compressed member that isn't there.
+    #[error("The archive has a checksum but no compressed member")]
+    MissingInnerMember,
+
+    /// The archive was written in a newer format than this version of ttare reads.
+    #[error(
+        "The archive uses format version {version}, but this version of ttare only reads up to version {supported}"
+    )]
+    UnsupportedVersion { version: u32, supported: u32 },
+
+    /// The metadata at the front of the archive can't be read.
+    #[error("Could not read the archive metadata")]
+    InvalidMetadata(#[source] serde_json::Error),
+
+    /// A part of a split archive isn't there.
+    #[error("{} is missing, the archive is split into more parts", .0.display())]
+    MissingPart(PathBuf),
+
+    /// The archive, or a file extracted from one, would replace a file that is already there.
+    #[error("{} already exists", .0.display())]
+    OutputExists(PathBuf),
+
+    /// An entry of the archive would be extracted outside of the output directory.
+    #[error("{} would be extracted outside of the output directory", .0.display())]
+    UnsafePath(PathBuf),
+
+    /// A file to compress isn't in the base directory that the entries are named relative to.
+    #[error("{} is not in the base directory {}", .path.display(), .base_dir.display())]
+    OutsideBaseDir { path: PathBuf, base_dir: PathBuf },
+
+    /// A file to compress is a ttare archive itself.
+    #[error("{} is a ttare archive, pass --allow-nested to archive it anyway", .0.display())]
+    NestedArchive(PathBuf),
+
+    /// More than one file to compress would be stored under the same name.
+    #[error("These names are given to more than one file: {}", describe_paths(.0))]
+    NameCollision(Vec<PathBuf>),
+
+    /// A file didn't have the size it had when its metadata was read anymore once it was read.
+    #[error("{} changed while it was read", .0.display())]
+    FileChanged(PathBuf),
+
+    /// An option was given with `plain_targz` that a plain tar.gz has no room for.
+    #[error("A plain tar.gz can't be written with {0}")]
+    PlainTargzConflict(&'static str),
+
+    /// An option was given with `checkpoint` that a run that can be resumed has no room for.
+    #[error("A checkpoint can't be written with {0}")]
+    CheckpointConflict(&'static str),
+
+    /// The checkpoint to resume was written for other files or options, or for another archive.
+    #[error("{} was written for other files or options, so it can't be resumed", .0.display())]
+    CheckpointMismatch(PathBuf),
+
+    /// Files were appended to an archive that ttare can't add files to.
+    #[error("Files can't be appended to {0}")]
+    AppendConflict(&'static str),
+
+    /// Extracting an archive on a best-effort basis left out these entries.
+    #[error("Could not recover: {}", .0.join(", "))]
+    Unrecoverable(Vec<String>),
+
+    /// The archive doesn't hold what ttare writes.
+    #[error("The archive is corrupt: {0}")]
+    CorruptArchive(String),
+}
+
+/// A `Result` whose error is a `TtareError`.
+pub type Result<T, E = TtareError> = std::result::Result<T, E>;
+
+impl From<io::Error> for TtareError {
+    fn from(source: io::Error) -> Self {
+        TtareError::Io {
+            action: "I/O error",
+            path: None,
+            source,
+        }
+    }
+}
+
+fn describe_io(action: &str, path: Option<&Path>) -> String {
+    match path {
+        Some(path) => format!("{} {}", action, path.display()),
+        None => action.to_string(),
+    }
+}
+
+fn describe_paths(paths: &[PathBuf]) -> String {
+    paths
+        .iter()
+        .map(|path| path.display().to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Describes what was being done when an I/O error happened, like `eyre::Context` does.
+pub(crate) trait IoContext<T> {
+    /// Attaches what was being done, and to which path, to an I/O error that doesn't say yet.
+    fn with_path(self, action: &'static str, path: &Path) -> Result<T>;
+
+    /// Attaches what was being done to an I/O error that doesn't say yet.
+    fn with_action(self, action: &'static str) -> Result<T>;
+}
+
+impl<T, E: Into<TtareError>> IoContext<T> for std::result::Result<T, E> {
+    fn with_path(self, action: &'static str, path: &Path) -> Result<T> {
+        self.map_err(|e| match e.into() {
+            TtareError::Io {
+                path: None, source, ..
+            } => TtareError::Io {
+                action,
+                path: Some(path.to_path_buf()),
+                source,
+            },
+            e => e,
+        })
+    }
+
+    fn with_action(self, action: &'static str) -> Result<T> {
+        self.map_err(|e| match e.into() {
+            TtareError::Io {
+                path: None, source, ..
+            } => TtareError::Io {
+                action,
+                path: None,
+                source,
+            },
+            e => e,
+        })
+    }
+}
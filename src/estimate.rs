@@ -0,0 +1,130 @@
+use std::{
+    fs::File,
+    io::Cursor,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use rayon::prelude::*;
+use serde::Serialize;
+
+use crate::{
+    analyze_files,
+    entropy::{probe_entropy, sample},
+    error::IoContext,
+    CompressOptions, EntropyAnalysis, Result, TAR_BLOCK_BYTES,
+};
+
+/// How much of its size a file that is compressed without its entropy being computed, such as a
+/// small file, is assumed to keep.
+const UNPROBED_RATIO: f64 = 0.5;
+
+/// What `estimate` expects a `compress` run to write, which is only a ballpark figure.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct SizeEstimate {
+    /// The total size of the files.
+    pub input_bytes: u64,
+
+    /// The number of files that would be compressed.
+    pub compressed_files: usize,
+
+    /// The total size of the files that would be compressed, before compression.
+    pub compressed_input_bytes: u64,
+
+    /// What the files that would be compressed are expected to take once compressed.
+    pub compressed_output_bytes: u64,
+
+    /// The expected size of the whole archive, with its tar headers.
+    pub archive_bytes: u64,
+
+    /// `archive_bytes` divided by `input_bytes`, or `None` if there is no input.
+    pub ratio: Option<f64>,
+
+    /// How long compressing the files is expected to take, in seconds.
+    pub compress_seconds: f64,
+}
+
+/// Estimates the size of the archive that `compress` would write of `files`, and how long it
+/// would take, without writing anything.
+///
+/// The files are analyzed as `compress` would, then the sample of each file that would be
+/// compressed is compressed with the codec at its fastest level, and the file is expected to
+/// shrink as much as its sample did. This can be far off for files whose sample isn't like the
+/// rest of them, and the archive usually ends up smaller, since files compress better together
+/// and at the codec's usual level. How long compressing takes is extrapolated from how fast the
+/// samples were compressed, on one thread for the compressed member, or on all of them when
+/// compressing each file on its own, so it's an underestimate at higher levels.
+pub fn estimate(files: &[PathBuf], opts: &CompressOptions) -> Result<SizeEstimate> {
+    let analyses = analyze_files(files, opts)?;
+
+    // The size each file is expected to take, along with the bytes probed and how long it took,
+    // if it was probed
+    let projected: Vec<(u64, u64, Duration)> = analyses
+        .par_iter()
+        .map(|analysis| match analysis.decision {
+            EntropyAnalysis::DontCompress => Ok((analysis.size, 0, Duration::ZERO)),
+            EntropyAnalysis::Compress if analysis.entropy.is_none() => {
+                let size = (analysis.size as f64 * UNPROBED_RATIO) as u64;
+                Ok((size, 0, Duration::ZERO))
+            }
+            EntropyAnalysis::Compress => {
+                let path = &analysis.path;
+                let mut file = File::open(path).with_path("Could not open", path)?;
+                let sample = sample(&mut file, opts)?;
+                let started = Instant::now();
+                let bits = probe_entropy(Cursor::new(&sample), opts)?;
+                let size = (analysis.size as f64 * f64::from(bits) / 8.0) as u64;
+                Ok((size, sample.len() as u64, started.elapsed()))
+            }
+        })
+        .collect::<Result<_>>()?;
+
+    let mut estimate = SizeEstimate::default();
+    let (mut probed_bytes, mut probe_time) = (0, Duration::ZERO);
+    // The metadata, and the two blocks that end the tar unless they are left out
+    let mut archive_bytes = 2 * TAR_BLOCK_BYTES;
+    if !opts.strip_trailer {
+        archive_bytes += 2 * TAR_BLOCK_BYTES;
+    }
+    for (analysis, (size, probed, elapsed)) in analyses.iter().zip(projected) {
+        estimate.input_bytes += analysis.size;
+        match analysis.decision {
+            EntropyAnalysis::DontCompress => {
+                archive_bytes += TAR_BLOCK_BYTES + size.next_multiple_of(TAR_BLOCK_BYTES);
+            }
+            EntropyAnalysis::Compress => {
+                estimate.compressed_files += 1;
+                estimate.compressed_input_bytes += analysis.size;
+                estimate.compressed_output_bytes += size;
+                // With the PAX extensions that tell its codec and size
+                if opts.per_file_compression {
+                    archive_bytes += 3 * TAR_BLOCK_BYTES + size.next_multiple_of(TAR_BLOCK_BYTES);
+                }
+            }
+        }
+        probed_bytes += probed;
+        probe_time += elapsed;
+    }
+    if estimate.compressed_files > 0 && !opts.per_file_compression {
+        // The checksum and the compressed member, whose own headers compress away
+        let member = estimate.compressed_output_bytes;
+        archive_bytes += 3 * TAR_BLOCK_BYTES + member.next_multiple_of(TAR_BLOCK_BYTES);
+    }
+    estimate.archive_bytes = archive_bytes;
+    if estimate.input_bytes > 0 {
+        estimate.ratio = Some(archive_bytes as f64 / estimate.input_bytes as f64);
+    }
+
+    if probed_bytes > 0 {
+        let threads = if opts.per_file_compression {
+            rayon::current_num_threads()
+        } else {
+            1
+        };
+        let seconds_per_byte = probe_time.as_secs_f64() / probed_bytes as f64;
+        estimate.compress_seconds =
+            seconds_per_byte * estimate.compressed_input_bytes as f64 / threads as f64;
+    }
+
+    Ok(estimate)
+}
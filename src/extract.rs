@@ -0,0 +1,171 @@
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use tar::Archive;
+
+use crate::{
+    dedup::DedupManifest,
+    error::IoContext,
+    hard_link_target,
+    index::{self, IndexedArchive, Location},
+    list,
+    meta::ArchiveMeta,
+    normalize_entry_path, plain, root_entry_kind, split, Result, RootEntry, TtareError,
+};
+
+/// Writes the contents of the file at `path` in the ttare archive at `input` to `output`.
+///
+/// Raw files are streamed straight out of the root tar, and the compressed member is only
+/// decompressed when the file wasn't found among the raw files. With an index, the file is read
+/// straight from where it is, and the compressed member is only decompressed up to the file.
+pub fn extract<W: Write>(input: &Path, path: &Path, mut output: W) -> Result<()> {
+    if let Some(indexed) = index::read_index(input)? {
+        return extract_indexed(input, indexed, path, output);
+    }
+    let wanted = normalize_entry_path(path);
+
+    let mut archive = Archive::new(plain::root_tar(split::open_archive(input)?)?);
+
+    let mut meta = None;
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+
+        match root_entry_kind(&mut entry)? {
+            RootEntry::Meta => meta = Some(ArchiveMeta::read(entry)?),
+            RootEntry::Member(codec) => {
+                let decoder = ArchiveMeta::member_decoder(meta.as_ref(), codec, None, entry)?;
+                let mut tar = Archive::new(decoder);
+                for inner in tar.entries()? {
+                    let mut inner = inner?;
+                    if normalize_entry_path(&inner.path()?) == wanted {
+                        io::copy(&mut inner, &mut output)?;
+                        return Ok(());
+                    }
+                }
+            }
+            RootEntry::Compressed(file) => {
+                if normalize_entry_path(&file.path) == wanted {
+                    io::copy(&mut file.codec.decoder(entry)?, &mut output)?;
+                    return Ok(());
+                }
+            }
+            RootEntry::Dedup => {
+                // The original may have been passed already, so look for it from the start
+                if let Some(original) = DedupManifest::read(entry)?.original_of(&wanted) {
+                    return extract(input, original, output);
+                }
+            }
+            RootEntry::HardLink => {
+                let link = normalize_entry_path(&entry.path()?);
+                if link == wanted {
+                    let target = hard_link_target(&entry, &link)?;
+                    return extract(input, &target, output);
+                }
+            }
+            RootEntry::Checksum
+            | RootEntry::Manifest
+            | RootEntry::Index
+            | RootEntry::Directory
+            | RootEntry::Symlink => {}
+            RootEntry::File => {
+                if normalize_entry_path(&entry.path()?) == wanted {
+                    io::copy(&mut entry, &mut output)?;
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    Err(TtareError::NotFound(path.to_path_buf()))
+}
+
+/// Writes the contents of the file at `path` in the archive at `input` to `output`, from where
+/// its index says the file is.
+fn extract_indexed<W: Write>(
+    input: &Path,
+    indexed: IndexedArchive,
+    path: &Path,
+    mut output: W,
+) -> Result<()> {
+    let IndexedArchive { index, meta, .. } = indexed;
+    let file = index
+        .find(&normalize_entry_path(path))?
+        .ok_or_else(|| TtareError::NotFound(path.to_path_buf()))?;
+
+    let mut archive = File::open(input).with_path("Could not open", input)?;
+    let copied = match file.location {
+        Location::Root { offset } => {
+            archive.seek(SeekFrom::Start(offset))?;
+            io::copy(&mut archive.take(file.size), &mut output)?
+        }
+        Location::Compressed { offset, len } => {
+            archive.seek(SeekFrom::Start(offset))?;
+            io::copy(&mut meta.codec.decoder(archive.take(len))?, &mut output)
+                .with_path("Could not decompress", &file.path)?
+        }
+        Location::Member { offset } => {
+            let member = index.member.ok_or_else(|| {
+                TtareError::CorruptArchive(format!(
+                    "{} is in a compressed member that isn't there",
+                    file.path.display()
+                ))
+            })?;
+            archive.seek(SeekFrom::Start(member))?;
+            let mut decoder = ArchiveMeta::member_decoder(Some(&meta), meta.codec, None, archive)?;
+            io::copy(&mut decoder.by_ref().take(offset), &mut io::sink())
+                .and_then(|_| io::copy(&mut decoder.take(file.size), &mut output))
+                .with_path("Could not decompress", &file.path)?
+        }
+        Location::Copy { .. } => unreachable!("the index finds the original of a copy"),
+    };
+
+    if copied == file.size {
+        Ok(())
+    } else {
+        Err(TtareError::CorruptArchive(format!(
+            "{} ends before its size in the index",
+            file.path.display()
+        )))
+    }
+}
+
+/// Writes the contents of the only file in the ttare archive at `input` to `output`.
+///
+/// Fails with `TtareError::NotSingleFile` if the archive holds no file or more than one, since
+/// there is no telling which one is wanted. The archive is listed first to find out, so the
+/// compressed member may be decompressed twice.
+pub fn extract_single<W: Write>(input: &Path, output: W) -> Result<()> {
+    let listing = list(input)?;
+    match listing.entries.as_slice() {
+        [entry] => extract(input, &entry.path, output),
+        entries => Err(TtareError::NotSingleFile(entries.len())),
+    }
+}
+
+/// Writes the contents of the files at `paths` in the ttare archive at `input` to `output`, one
+/// after the other with nothing between them, like `cat` does.
+///
+/// The archive is listed first, so that it fails with `TtareError::NotFound` before writing
+/// anything if any of the files isn't in it. Each file is then read like `extract` does, so the
+/// compressed member may be decompressed once per file, unless the archive has an index.
+pub fn cat<W: Write>(input: &Path, paths: &[PathBuf], mut output: W) -> Result<()> {
+    let listing = list(input)?;
+    if let Some(missing) = paths.iter().find(|path| {
+        let wanted = normalize_entry_path(path);
+        !listing
+            .entries
+            .iter()
+            .any(|entry| normalize_entry_path(&entry.path) == wanted)
+    }) {
+        return Err(TtareError::NotFound(missing.clone()));
+    }
+
+    for path in paths {
+        extract(input, path, &mut output)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,185 @@
+use std::{
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use tar::Archive;
+
+use crate::{
+    error::IoContext,
+    meta::ArchiveMeta,
+    normalize_entry_path, plain, root_entry_kind, split,
+    walk::{json_path, path_to_bytes},
+    Result, RootEntry, TtareError, TAR_BLOCK_BYTES,
+};
+
+/// Where each file of the archive is, so that it can be listed or extracted without reading the
+/// rest of the archive, nor decompressing the compressed member.
+///
+/// It is stored as JSON in the `.ttare.index` entry, the last one of the root tar, since where the
+/// other entries are is only known once they are written. The files are in the order they were
+/// added.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct ArchiveIndex {
+    /// Where the data of the compressed member starts in the root tar, if there is one.
+    pub(crate) member: Option<u64>,
+    pub(crate) files: Vec<IndexEntry>,
+}
+
+/// Where one file of the archive is.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct IndexEntry {
+    #[serde(with = "json_path")]
+    pub(crate) path: PathBuf,
+
+    /// The size of the file, before compression.
+    pub(crate) size: u64,
+    pub(crate) location: Location,
+}
+
+/// Where the contents of a file are, as offsets from the start of the root tar unless said
+/// otherwise.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "in", rename_all = "lowercase")]
+pub(crate) enum Location {
+    /// Stored as-is in the root tar, from `offset`.
+    Root { offset: u64 },
+
+    /// Compressed on its own with the archive's codec into the `len` bytes from `offset`.
+    Compressed { offset: u64, len: u64 },
+
+    /// In the compressed member, from `offset` in the tar it decompresses to.
+    Member { offset: u64 },
+
+    /// A copy of, or a hard link to, the file at `original`.
+    Copy {
+        #[serde(with = "json_path")]
+        original: PathBuf,
+    },
+}
+
+impl Location {
+    /// Whether the file was stored compressed, rather than as-is.
+    pub(crate) fn is_compressed(&self) -> bool {
+        matches!(self, Location::Compressed { .. } | Location::Member { .. })
+    }
+}
+
+impl ArchiveIndex {
+    /// Records that the file at `path`, of `size` bytes, is at `location`.
+    pub(crate) fn add(&mut self, path: &Path, size: u64, location: Location) {
+        self.files.push(IndexEntry {
+            path: normalize_entry_path(path),
+            size,
+            location,
+        });
+    }
+
+    pub(crate) fn to_bytes(&self) -> Result<Vec<u8>> {
+        // Only paths that aren't UTF-8 off Unix can't be stored
+        for file in &self.files {
+            path_to_bytes(&file.path)?;
+        }
+        Ok(serde_json::to_vec(self).expect("paths made of bytes can always be serialized"))
+    }
+
+    pub(crate) fn read(reader: impl Read) -> Result<Self> {
+        serde_json::from_reader(reader).map_err(|e| {
+            TtareError::CorruptArchive(format!("the index of the archive is unreadable: {e}"))
+        })
+    }
+
+    /// The file at `path`, or the one it is a copy of, if it's in the archive. The size of a
+    /// copy is that of its original.
+    pub(crate) fn find(&self, path: &Path) -> Result<Option<&IndexEntry>> {
+        let Some(mut entry) = self.files.iter().find(|file| file.path == path) else {
+            return Ok(None);
+        };
+
+        // A hard link can be to a copy, but copies can't go round in circles
+        for _ in 0..self.files.len() {
+            let Location::Copy { original } = &entry.location else {
+                return Ok(Some(entry));
+            };
+            entry = self
+                .files
+                .iter()
+                .find(|file| &file.path == original)
+                .ok_or_else(|| {
+                    TtareError::CorruptArchive(format!(
+                        "{} is a copy of a missing file",
+                        path.display()
+                    ))
+                })?;
+        }
+        Err(TtareError::CorruptArchive(format!(
+            "{} is a copy of itself",
+            path.display()
+        )))
+    }
+}
+
+/// The index of an archive, along with what else is read to use it.
+pub(crate) struct IndexedArchive {
+    pub(crate) index: ArchiveIndex,
+    pub(crate) meta: ArchiveMeta,
+
+    /// The modification time of the compressed member, or of the metadata for an archive without
+    /// a member.
+    pub(crate) created: u64,
+}
+
+/// Reads the index at the end of the archive at `input`, if it has one that is up to date.
+///
+/// Only the headers of the root tar are read, seeking over everything else. Split archives and
+/// archives compressed as a whole can't be seeked, and never have an index that can be used, so
+/// `None` is returned without reading them, for the caller to read the whole archive instead.
+pub(crate) fn read_index(input: &Path) -> Result<Option<IndexedArchive>> {
+    if split::is_split(input) {
+        return Ok(None);
+    }
+    let mut file = File::open(input).with_path("Could not open", input)?;
+    if plain::starts_like_gzip(&mut file).with_path("Could not read", input)? {
+        return Ok(None);
+    }
+
+    let mut archive = Archive::new(file);
+    let mut index = None;
+    let mut meta = None;
+    let mut created = None;
+    for entry in archive.entries_with_seek()? {
+        let mut entry = entry?;
+
+        // Whatever comes after the index was added after it, which it doesn't know about
+        if index.is_some() {
+            return Ok(None);
+        }
+
+        match root_entry_kind(&mut entry)? {
+            RootEntry::Meta => {
+                created = Some(entry.header().mtime()?);
+                meta = Some(ArchiveMeta::read(entry)?);
+            }
+            RootEntry::Member(_) => created = Some(entry.header().mtime()?),
+            RootEntry::Index => index = Some(ArchiveIndex::read(entry)?),
+            _ => {}
+        }
+    }
+
+    Ok(match (index, meta, created) {
+        (Some(index), Some(meta), Some(created)) => Some(IndexedArchive {
+            index,
+            meta,
+            created,
+        }),
+        _ => None,
+    })
+}
+
+/// Where the data of the entry that was just added to a tar starts, from the `end` of the tar and
+/// the `len` of the data, which is padded to a whole number of blocks.
+pub(crate) fn data_offset(end: u64, len: u64) -> u64 {
+    end - len.next_multiple_of(TAR_BLOCK_BYTES)
+}
@@ -0,0 +1,2915 @@
+//! ttare builds tar archives that only compress the files that are worth compressing.
+//!
+//! Each file's entropy is sampled. Files with low entropy are bundled into an internal compressed
+//! tar, while files with high entropy are stored as-is in the root tar, so no time is wasted
+//! trying to compress data that is already compressed or random.
+//!
+//! When the files are all compressible, or all incompressible, `plain_targz` writes a plain tar
+//! instead, gzipped as a whole in the first case, so that tar reads it without ttare.
+
+use std::{
+    env,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write},
+    num::{NonZeroU64, NonZeroUsize},
+    path::{self, Component, Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime},
+};
+
+use change::SizedReader;
+use checkpoint::Checkpoint;
+use checksum::{Crc32Reader, Crc32Writer};
+use codec::Encoder;
+use dedup::{DedupManifest, Deduplicator};
+use entropy::HistogramReader;
+use error::IoContext;
+use index::{ArchiveIndex, Location};
+use limit::OpenFileLimit;
+use log::{debug, info};
+use manifest::ChecksumManifest;
+use meta::ArchiveMeta;
+use owner::OwnerRestorer;
+use per_file::CompressedFile;
+use plain::RootWriter;
+use progress::Progress;
+use rayon::prelude::*;
+use rustc_hash::FxHashMap;
+use salvage::Salvage;
+use serde::Serialize;
+use split::SplitWriter;
+use spool::{Spool, SpoolLocation};
+use tar::{Archive, EntryType, Header};
+use throttle::{Throttle, Throttled};
+use tracing::{debug_span, info_span, Span};
+use xattrs::{XattrRestorer, Xattrs};
+
+mod append;
+mod change;
+mod checkpoint;
+mod checksum;
+mod codec;
+mod compare;
+mod dedup;
+mod dictionary;
+mod entropy;
+mod error;
+mod estimate;
+mod extract;
+mod index;
+mod limit;
+mod list;
+mod manifest;
+mod memory;
+mod meta;
+mod owner;
+mod per_file;
+mod plain;
+mod progress;
+mod recompress;
+mod rules;
+mod salvage;
+mod split;
+mod spool;
+mod stats;
+mod throttle;
+mod verify;
+mod walk;
+mod xattrs;
+
+pub use append::append;
+pub use change::{set_before_read_hook, OnChange, ON_CHANGE_RETRIES};
+pub use codec::Codec;
+pub use compare::{compare, Comparison};
+pub use dictionary::ZstdDictionary;
+pub use entropy::{
+    analyze_entropy, classify, decide, entropy, full_entropy, full_histogram,
+    has_incompressible_extension, sample_entropy, sample_histogram, sniffs_incompressible,
+    suggest_threshold, threshold_from_percentage, EntropyAnalysis, Estimator, Histogram,
+    COMPRESSIBLE_FRACTION, ENTROPY_SAMPLING, ENTROPY_THRESHOLD, INCOMPRESSIBLE_EXTENSIONS,
+    MAX_ENTROPY, MIN_SAMPLE_BYTES, SMALL_FILE_BYTES,
+};
+pub use error::{Result, TtareError};
+pub use estimate::{estimate, SizeEstimate};
+pub use extract::{cat, extract, extract_single};
+pub use limit::default_max_files_open;
+pub use list::{list, ListEntry, Listing};
+pub use memory::{compress_to_vec, decompress_from_slice};
+pub use meta::TTARE_FORMAT_VERSION;
+pub use recompress::{recompress, RecompressOptions};
+pub use rules::{DecisionRule, DecisionRules};
+pub use stats::{stats, DatasetStats, FileCount, HistogramBucket};
+pub use verify::verify;
+pub use walk::{gather_files, read_file_list, GatheredFiles, WalkOptions};
+
+/// The name of the entry at the front of the tar archive that describes how it was written, as
+/// JSON.
+const TTARE_META_FILE_NAME: &str = ".ttare.meta";
+
+/// The name of the entry in the tar archive that holds the CRC32 of the compressed member, as 8 hex
+/// digits.
+const TTARE_CHECKSUM_FILE_NAME: &str = ".ttare.crc32";
+
+/// The name of the entry in the tar archive that lists the files stored as copies of another file.
+const TTARE_DEDUP_FILE_NAME: &str = ".ttare.dedup";
+
+/// The name of the entry in the tar archive that holds the CRC32 of each file, as JSON.
+const TTARE_MANIFEST_FILE_NAME: &str = ".ttare.manifest";
+
+/// The name of the entry at the end of the tar archive that tells where each file is, as JSON.
+const TTARE_INDEX_FILE_NAME: &str = ".ttare.index";
+
+/// The size of a tar header, which is the first thing in a ttare archive.
+const TAR_BLOCK_BYTES: u64 = 512;
+
+/// How much of a stream is kept in memory to sample its entropy, since it can't be seeked.
+pub const STREAM_ANALYSIS_BYTES: u64 = 1024 * 1024;
+
+/// The largest file that is read into memory in full to analyze it, so that it's only read from
+/// disk once. Larger files are sampled, then read again to be added to the archive.
+pub const READ_ONCE_FILE_BYTES: u64 = 1024 * 1024;
+
+/// The default size of the buffers that the files are read through and the archive and the
+/// compressed data are written through when compressing.
+///
+/// Storing files as-is, which is bound by IO, is 40% faster with it than with 8 KiB buffers, as
+/// measured by `benches/io_buffer_size.rs`, while 1 MiB buffers only gain another 8%. Each thread
+/// that compresses a file on its own has buffers of its own, so they are kept that small.
+pub const IO_BUFFER_SIZE: usize = 256 * 1024;
+
+/// The extension added to the path of an archive while it is written.
+const PARTIAL_EXTENSION: &str = ".partial";
+
+/// How much of the files read into memory by the analysis is kept until they are added to the
+/// archive, across all of them. The files analyzed after that is used up are read again.
+const READ_ONCE_BUDGET_BYTES: u64 = 64 * 1024 * 1024;
+
+/// The settings that decide how files are classified and compressed.
+#[derive(Clone, Debug)]
+pub struct CompressOptions {
+    /// The percentage of the file to sample to compute the entropy.
+    pub sample_percentage: f32,
+
+    /// The smallest sample taken from a file, or the whole file if it is smaller.
+    pub min_sample_bytes: u64,
+
+    /// The largest sample taken from a file, if any. It takes precedence over `sample_percentage`.
+    pub max_sample_bytes: Option<u64>,
+
+    /// Computes the entropy over the whole file instead of a sample, ignoring `sample_percentage`.
+    pub full_entropy: bool,
+
+    /// How the entropy of each file is estimated, see `Estimator`.
+    pub estimator: Estimator,
+
+    /// The threshold of the entropy, in bits per byte, above which a file is not compressed. `None`
+    /// uses the codec's default, see `Codec::default_entropy_threshold`.
+    pub entropy_threshold: Option<f32>,
+
+    /// Computes the entropy over consecutive windows of this many bytes of each file, so that a
+    /// file is judged by how much of it is compressible, see `compressible_fraction`.
+    pub window_bytes: Option<NonZeroU64>,
+
+    /// With `window_bytes`, the share of a file that has to be in windows whose entropy is at most
+    /// the threshold for it to be compressed, from 0 to 1.
+    pub compressible_fraction: f32,
+
+    /// The size below which files are always compressed, without computing their entropy. Empty
+    /// files are still stored as-is, and 0 turns it off.
+    pub small_file_bytes: u64,
+
+    /// Stores the files with the extension of a format that is already compressed as-is, without
+    /// reading them to compute their entropy.
+    pub extension_shortcut: bool,
+
+    /// More extensions of incompressible formats, on top of `INCOMPRESSIBLE_EXTENSIONS`, with or
+    /// without their leading dot.
+    pub incompressible_extensions: Vec<String>,
+
+    /// Stores the files that start like a format that is already compressed, such as JPEG or gzip,
+    /// as-is without computing their entropy.
+    pub sniff: bool,
+
+    /// Still computes the entropy of the files recognized as a compressed format, and warns about
+    /// those it would have compressed.
+    pub check_formats: bool,
+
+    /// Globs that force the files they match to be compressed or stored, taking precedence over
+    /// both the extension shortcut and the entropy threshold.
+    pub rules: DecisionRules,
+
+    /// Stores every file as-is in the root tar, without computing any entropy. This takes
+    /// precedence over everything else that decides.
+    pub no_compress: bool,
+
+    /// The codec used to compress the compressible files.
+    pub codec: Codec,
+
+    /// The compression level, from 0 to the codec's `Codec::max_level`. `None` uses the codec's
+    /// default level.
+    pub compression_level: Option<u32>,
+
+    /// Skips the files that can't be opened with a warning, instead of failing.
+    pub skip_errors: bool,
+
+    /// What to do with a file whose size changes while it is read into the archive.
+    pub on_change: OnChange,
+
+    /// Archives the files that are ttare archives themselves, which otherwise fail with
+    /// `TtareError::NestedArchive`.
+    pub allow_nested: bool,
+
+    /// Stores each path of a file with more than one with its contents, instead of storing the
+    /// paths after the first as hard links to it.
+    pub hard_dereference: bool,
+
+    /// Stores files with the same contents as an earlier file as a reference to it.
+    pub dedup: bool,
+
+    /// Shows a progress bar on stderr.
+    pub progress: bool,
+
+    /// Prints each file's entropy and whether it is compressed to stderr as it is added, whatever
+    /// the log level.
+    pub show_entropy: bool,
+
+    /// The modification time of the entries that ttare adds to the archive, as seconds since the
+    /// Unix epoch. `None` uses the current time, or 0 when `reproducible` is set.
+    pub mtime: Option<u64>,
+
+    /// Writes the same archive for the same files, whatever order they are given in and whoever
+    /// writes it. When `mtime` is set, the files' modification times are also clamped to it.
+    pub reproducible: bool,
+
+    /// Stores the files that symlinks point to under the symlinks' names, instead of the symlinks.
+    pub dereference: bool,
+
+    /// Compresses each compressible file on its own, as an entry of the root tar, instead of
+    /// bundling them in the compressed member.
+    pub per_file_compression: bool,
+
+    /// Stores the compressible files as-is when compressing them made them bigger, instead of only
+    /// warning about it.
+    pub no_expand: bool,
+
+    /// Also compresses the files that are stored as-is, each on its own at the codec's fastest
+    /// level, and keeps whichever is smaller.
+    pub compress_all: bool,
+
+    /// Leaves out the two blocks of zeros that end the root tar and the tar in the compressed
+    /// member. A plain tar.gz always keeps them.
+    pub strip_trailer: bool,
+
+    /// Splits the archive written by `compress` into parts of at most this many bytes, such as
+    /// `archive.ttare.001`.
+    pub split_size: Option<NonZeroU64>,
+
+    /// Records the CRC32 of each file in the archive, so that `decompress` and `verify` can tell
+    /// which files are corrupt.
+    pub manifest: bool,
+
+    /// Adds an index of where each file is to the end of the archive, so that `list` and
+    /// `extract` don't have to decompress the whole compressed member.
+    pub index: bool,
+
+    /// Once `compress` wrote the archive, checks that every file in it has the same CRC32 as the
+    /// file it was read from.
+    pub verify_after: bool,
+
+    /// Records how far `compress` got in a checkpoint file at this path, so that an interrupted run
+    /// can go on with `resume`. It can't be used with `split_size` or `plain_targz`.
+    pub checkpoint: Option<PathBuf>,
+
+    /// Goes on with the run that wrote the `checkpoint`, which has to be given the same files and
+    /// options.
+    pub resume: bool,
+
+    /// Where the compressed data is spooled before it is added to the archive. Defaults to the
+    /// system's temporary directory.
+    pub temp_dir: Option<PathBuf>,
+
+    /// A zstd dictionary to compress the compressed member with. The archive can only be
+    /// decompressed with the same dictionary.
+    pub zstd_dictionary: Option<ZstdDictionary>,
+
+    /// How many of the files being compressed are open at once, at most. `None` uses
+    /// `default_max_files_open`.
+    pub max_files_open: Option<NonZeroUsize>,
+
+    /// Stores the files under their paths relative to this directory, which they all have to be
+    /// in, instead of under the paths they were given with.
+    pub base_dir: Option<PathBuf>,
+
+    /// Stores the files under each source path under the destination path paired with it instead,
+    /// as `(source, destination)`. It takes precedence over `base_dir`.
+    pub name_map: Vec<(PathBuf, PathBuf)>,
+
+    /// Caps how many bytes per second are read from the files and written to the archive, taken
+    /// together.
+    pub throttle_bytes_per_sec: Option<NonZeroU64>,
+
+    /// The size of the buffers that the files are read through, and that the archive is written
+    /// through, in bytes.
+    pub io_buffer_size: usize,
+
+    /// Replaces the archive at the output path, or its parts when it is split, if there is one
+    /// already.
+    pub overwrite: bool,
+
+    /// Writes a plain tar, gzipped as a whole when every file is compressible, unless some files
+    /// are compressible and others aren't. Only `compress` and `compress_to` write plain archives.
+    pub plain_targz: bool,
+
+    /// Stores the extended attributes of the files, directories and symlinks, as PAX extensions.
+    /// Only Unix has them.
+    pub xattrs: bool,
+}
+
+impl Default for CompressOptions {
+    fn default() -> Self {
+        CompressOptions {
+            sample_percentage: ENTROPY_SAMPLING,
+            min_sample_bytes: MIN_SAMPLE_BYTES,
+            max_sample_bytes: None,
+            full_entropy: false,
+            estimator: Estimator::default(),
+            entropy_threshold: None,
+            window_bytes: None,
+            compressible_fraction: COMPRESSIBLE_FRACTION,
+            small_file_bytes: SMALL_FILE_BYTES,
+            extension_shortcut: true,
+            incompressible_extensions: vec![],
+            sniff: false,
+            check_formats: false,
+            rules: DecisionRules::default(),
+            codec: Codec::default(),
+            compression_level: None,
+            skip_errors: false,
+            on_change: OnChange::default(),
+            allow_nested: false,
+            hard_dereference: false,
+            dedup: false,
+            progress: false,
+            show_entropy: false,
+            mtime: None,
+            reproducible: false,
+            dereference: false,
+            per_file_compression: false,
+            no_expand: false,
+            compress_all: false,
+            strip_trailer: false,
+            split_size: None,
+            manifest: false,
+            index: false,
+            verify_after: false,
+            checkpoint: None,
+            resume: false,
+            temp_dir: None,
+            zstd_dictionary: None,
+            max_files_open: None,
+            base_dir: None,
+            name_map: vec![],
+            throttle_bytes_per_sec: None,
+            io_buffer_size: IO_BUFFER_SIZE,
+            overwrite: false,
+            plain_targz: false,
+            no_compress: false,
+            xattrs: false,
+        }
+    }
+}
+
+impl CompressOptions {
+    /// The entropy threshold that files are classified with, which depends on the codec unless one
+    /// was given.
+    pub fn threshold(&self) -> f32 {
+        self.entropy_threshold
+            .unwrap_or_else(|| self.codec.default_entropy_threshold())
+    }
+
+    /// Fails unless a sample of a positive size can be taken. Percentages above 1 sample the whole
+    /// file.
+    fn check_sampling(&self) -> Result<()> {
+        if self.sample_percentage.is_nan() || self.sample_percentage <= 0.0 {
+            return Err(TtareError::InvalidSamplePercentage(self.sample_percentage));
+        }
+
+        match self.max_sample_bytes {
+            Some(max) if max == 0 || max < self.min_sample_bytes => {
+                Err(TtareError::InvalidSampleBounds {
+                    min: self.min_sample_bytes,
+                    max,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// How many of the files being compressed can be open at once.
+    fn max_files_open(&self) -> NonZeroUsize {
+        self.max_files_open.unwrap_or_else(default_max_files_open)
+    }
+
+    /// The modification time of the entries that ttare adds to the archive itself.
+    fn entry_mtime(&self) -> u64 {
+        match self.mtime {
+            Some(mtime) => mtime,
+            None if self.reproducible => 0,
+            None => now(),
+        }
+    }
+
+    /// Fails if `plain_targz` is set with an option that needs ttare's own layout.
+    fn check_plain_targz(&self) -> Result<()> {
+        let conflict = if self.codec != Codec::Gzip {
+            Some("another codec than gzip")
+        } else if self.dedup {
+            Some("dedup")
+        } else if self.manifest {
+            Some("a manifest")
+        } else if self.index {
+            Some("an index")
+        } else if self.per_file_compression {
+            Some("per-file compression")
+        } else if self.zstd_dictionary.is_some() {
+            Some("a zstd dictionary")
+        } else if self.compress_all {
+            Some("every file compressed")
+        } else if self.strip_trailer {
+            Some("the trailer stripped")
+        } else {
+            None
+        };
+
+        match conflict {
+            Some(option) => Err(TtareError::PlainTargzConflict(option)),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether the file that failed to be added with `error` is skipped, instead of failing.
+    fn skips(&self, error: &TtareError) -> bool {
+        self.skip_errors
+            || (self.on_change == OnChange::Skip && matches!(error, TtareError::FileChanged(_)))
+    }
+
+    /// Fails if these options can't be used to classify files.
+    fn check(&self) -> Result<()> {
+        if !(0.0..=1.0).contains(&self.compressible_fraction) {
+            return Err(TtareError::InvalidCompressibleFraction(
+                self.compressible_fraction,
+            ));
+        }
+
+        if self.plain_targz {
+            self.check_plain_targz()?;
+        }
+
+        if self.full_entropy {
+            Ok(())
+        } else {
+            self.check_sampling()
+        }
+    }
+}
+
+/// The outcome of analyzing one file's entropy.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FileAnalysis {
+    /// The analyzed file.
+    pub path: PathBuf,
+
+    /// The size of the file when it was analyzed.
+    pub size: u64,
+
+    /// The sampled entropy of the file, in bits per byte, or `None` if it wasn't computed because
+    /// of a rule, the file's size, its extension or its format.
+    pub entropy: Option<f32>,
+
+    /// Whether the file would be compressed.
+    pub decision: EntropyAnalysis,
+}
+
+/// What happened during a `compress` run.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct CompressSummary {
+    /// The total size of the files that were archived.
+    pub input_bytes: u64,
+
+    /// The number of files stored in the compressed member.
+    pub compressed_files: usize,
+
+    /// The number of files stored as-is in the root tar.
+    pub stored_files: usize,
+
+    /// The number of files stored as a reference to an earlier file with the same contents.
+    pub deduplicated_files: usize,
+
+    /// The number of files stored as a hard link to an earlier path of the same file.
+    pub hard_links: usize,
+
+    /// The size of the compressed member, or 0 if there is none.
+    pub compressed_member_bytes: u64,
+
+    /// The total size of the files that were compressed, before compression.
+    pub compressed_input_bytes: u64,
+
+    /// The total size of the files that were compressed, after compression: the compressed member
+    /// if it holds any file, and the files compressed on their own.
+    pub compressed_output_bytes: u64,
+
+    /// `compressed_output_bytes` divided by `compressed_input_bytes`, or `None` if no file was
+    /// compressed. It is above 1 when compressing made the files bigger.
+    pub compression_ratio: Option<f64>,
+
+    /// The size of the whole archive.
+    pub archive_bytes: u64,
+
+    /// The size of the archive divided by the size of the input, or `None` if there was no input.
+    pub ratio: Option<f64>,
+
+    /// The files that were left out of the archive because they couldn't be read, when skipping
+    /// errors.
+    pub skipped: Vec<PathBuf>,
+}
+
+/// Logs how `analysis` was decided, or prints it to stderr with `show`.
+fn report_decision(analysis: &FileAnalysis, show: bool, progress: &Progress) {
+    let report = match analysis.entropy {
+        Some(entropy) => format!(
+            "{}: entropy {:.3}, {:?}",
+            analysis.path.display(),
+            entropy,
+            analysis.decision
+        ),
+        None => format!(
+            "{}: {:?} because of a rule, its size, its extension or its format",
+            analysis.path.display(),
+            analysis.decision
+        ),
+    };
+    if show {
+        progress.note(format_args!("{}", report));
+    } else {
+        info!("{}", report);
+    }
+}
+
+/// Analyzes the entropy of each file as `compress` would, without writing anything.
+///
+/// Files are analyzed in parallel, but the results are always in the same order as `files`. When
+/// skipping errors, the files that can't be read are left out of the results.
+pub fn analyze_files(files: &[PathBuf], opts: &CompressOptions) -> Result<Vec<FileAnalysis>> {
+    let progress = Progress::new(opts.progress, files);
+    let throttle = Throttle::new(opts.throttle_bytes_per_sec);
+    let result = analyze_files_skipping(files, opts, &progress, &throttle, 0);
+    progress.finish();
+    Ok(result?
+        .0
+        .into_iter()
+        .map(|analyzed| analyzed.analysis)
+        .collect())
+}
+
+/// A file analyzed to be added to the archive, along with its contents if they were read in full.
+struct AnalyzedFile {
+    analysis: FileAnalysis,
+    contents: Option<ReadContents>,
+}
+
+/// The contents of a file that the analysis read in full, and its metadata when it was read.
+struct ReadContents {
+    data: Vec<u8>,
+    metadata: fs::Metadata,
+}
+
+/// Analyzes the entropy of each file, also returning the files that were skipped.
+///
+/// The files of up to `READ_ONCE_FILE_BYTES` are read in full and kept along with their analysis,
+/// as long as they fit in the `read_once_budget` bytes, so that they don't have to be read again.
+fn analyze_files_skipping(
+    files: &[PathBuf],
+    opts: &CompressOptions,
+    progress: &Progress,
+    throttle: &Throttle,
+    read_once_budget: u64,
+) -> Result<(Vec<AnalyzedFile>, Vec<PathBuf>)> {
+    opts.check()?;
+    progress.phase("analyzing");
+    let phase = info_span!("analyze", files = files.len()).entered();
+
+    let budget = AtomicU64::new(read_once_budget);
+    let limit = OpenFileLimit::new(opts.max_files_open());
+    let results: Vec<Result<AnalyzedFile>> = files
+        .par_iter()
+        .map(|path| {
+            let _span = debug_span!(parent: &*phase, "analyze", path = %path.display()).entered();
+            let len = fs::metadata(path).with_path("Could not read", path)?.len();
+            if let Some(decision) = forced_decision(path, len, opts) {
+                let check_nested = !opts.allow_nested && len >= TAR_BLOCK_BYTES;
+                let check_format = opts.check_formats
+                    && decision == EntropyAnalysis::DontCompress
+                    && forced_by_extension(path, opts);
+                if check_nested || check_format {
+                    let _permit = limit.acquire();
+                    let mut file = File::open(path).with_path("Could not open", path)?;
+                    if check_nested {
+                        check_not_nested(path, &mut file)?;
+                    }
+                    if check_format {
+                        check_recognized_format(path, &mut throttle.wrap(file), opts, progress)?;
+                    }
+                }
+                progress.file_done(len);
+                return Ok(AnalyzedFile {
+                    analysis: FileAnalysis {
+                        path: path.clone(),
+                        size: len,
+                        entropy: None,
+                        decision,
+                    },
+                    contents: None,
+                });
+            }
+
+            // The permit is dropped after the file, when the analysis is done
+            let _permit = limit.acquire();
+            let file = File::open(path).with_path("Could not open", path)?;
+            let metadata = file.metadata()?;
+            let mut file = throttle.wrap(file);
+            let len = metadata.len();
+
+            if !opts.allow_nested {
+                check_not_nested(path, &mut file)?;
+            }
+            if sniffs_incompressible(&mut file, opts).with_path("Could not read", path)? {
+                if opts.check_formats {
+                    check_recognized_format(path, &mut file, opts, progress)?;
+                }
+                progress.file_done(len);
+                return Ok(AnalyzedFile {
+                    analysis: FileAnalysis {
+                        path: path.clone(),
+                        size: len,
+                        entropy: None,
+                        decision: EntropyAnalysis::DontCompress,
+                    },
+                    contents: None,
+                });
+            }
+
+            let read_once = len <= READ_ONCE_FILE_BYTES
+                && budget
+                    .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| {
+                        left.checked_sub(len)
+                    })
+                    .is_ok();
+
+            // The sample is taken from memory the same way it would be from the file
+            let (entropy, decision, contents) = if read_once {
+                let contents = read_whole(path, file, metadata, opts, progress, throttle)?;
+                let (entropy, decision) = classify(&mut Cursor::new(&contents.data), opts)
+                    .with_path("Could not read", path)?;
+                (entropy, decision, Some(contents))
+            } else {
+                let (entropy, decision) =
+                    classify(&mut file, opts).with_path("Could not read", path)?;
+                (entropy, decision, None)
+            };
+            progress.file_done(len);
+
+            // It was read again if it changed while it was read
+            let size = contents
+                .as_ref()
+                .map_or(len, |contents| contents.data.len() as u64);
+            Ok(AnalyzedFile {
+                analysis: FileAnalysis {
+                    path: path.clone(),
+                    size,
+                    entropy: Some(entropy),
+                    decision,
+                },
+                contents,
+            })
+        })
+        .collect();
+
+    let mut analyses = vec![];
+    let mut skipped = vec![];
+
+    for (path, result) in files.iter().zip(results) {
+        match result {
+            Ok(analyzed) => analyses.push(analyzed),
+            Err(e) if opts.skips(&e) => {
+                progress.warn(format_args!("skipping {}: {:#}", path.display(), e));
+                skipped.push(path.clone());
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok((analyses, skipped))
+}
+
+/// Reads the whole of `file`, opened at `path`, which `metadata` says the size of. If it turns
+/// out to have another size, it's opened and read again as `on_change` says.
+fn read_whole(
+    path: &Path,
+    mut file: Throttled<File>,
+    mut metadata: fs::Metadata,
+    opts: &CompressOptions,
+    progress: &Progress,
+    throttle: &Throttle,
+) -> Result<ReadContents> {
+    for retry in 0.. {
+        change::before_read(path);
+        let mut data = Vec::with_capacity(metadata.len() as usize);
+        file.read_to_end(&mut data)
+            .with_path("Could not read", path)?;
+        if data.len() as u64 == metadata.len() {
+            return Ok(ReadContents { data, metadata });
+        }
+        if opts.on_change != OnChange::Retry || retry == ON_CHANGE_RETRIES {
+            break;
+        }
+
+        progress.warn(format_args!(
+            "{} changed while it was read, reading it again",
+            path.display()
+        ));
+        let reopened = File::open(path).with_path("Could not open", path)?;
+        metadata = reopened.metadata()?;
+        file = throttle.wrap(reopened);
+    }
+
+    Err(TtareError::FileChanged(path.to_path_buf()))
+}
+
+/// The settings that decide how archives are extracted.
+#[derive(Clone, Debug, Default)]
+pub struct DecompressOptions {
+    /// Gives the files the owner and group recorded in the archive, which only root can do, on
+    /// Unix. When it isn't allowed, a warning is logged and the files keep their default owner.
+    pub preserve_owner: bool,
+
+    /// The zstd dictionary that the compressed member was compressed with, which is needed to
+    /// read an archive that records one.
+    pub zstd_dictionary: Option<ZstdDictionary>,
+
+    /// Replaces the files in the output directory that are also in the archive. Otherwise
+    /// extracting fails on the first of them, leaving the entries before it extracted. The
+    /// directories that are already there are always extracted into.
+    pub overwrite: bool,
+
+    /// Gives the files, directories and symlinks the extended attributes recorded in the archive,
+    /// after their owner. Only Unix has them, so elsewhere this only warns.
+    pub xattrs: bool,
+
+    /// Drops this many leading components from the path of each entry before extracting it, like
+    /// `tar --strip-components`. The entries with no more components than that are left out.
+    pub strip_components: usize,
+
+    /// Goes on extracting past the entries that can't be extracted, such as those of a compressed
+    /// member that is cut short, leaving out the files that can't be extracted in full. Fails
+    /// with `TtareError::Unrecoverable` once everything else is extracted, naming what was lost,
+    /// which includes the files that don't match their checksum when the archive records them.
+    pub best_effort: bool,
+}
+
+/// Decompresses the ttare archive at `input` into `output_dir`, creating it if needed.
+///
+/// When `input` is the first part of a split archive, such as `archive.ttare.001`, the other parts
+/// are read after it, failing if any of them is missing.
+pub fn decompress(input: &Path, output_dir: &Path, opts: DecompressOptions) -> Result<()> {
+    decompress_from(split::open_archive(input)?, output_dir, opts)
+}
+
+/// Decompresses the ttare archive read from `reader` into `output_dir`, creating it if needed.
+///
+/// The archive can also be a plain tar, or a plain tar.gz, as `plain_targz` writes them when the
+/// files aren't mixed.
+///
+/// The archive is read in a single pass, so the reader doesn't have to be seekable, and each
+/// entry is extracted as it is read, the compressed member decompressed on the way, so that the
+/// memory used doesn't grow with the size of the archive. Fails on the first entry whose path is
+/// absolute or goes up with `..`, since the archive could come from anyone, but the entries before
+/// it are left extracted.
+pub fn decompress_from<R: Read>(
+    reader: R,
+    output_dir: &Path,
+    opts: DecompressOptions,
+) -> Result<()> {
+    let _span = info_span!("decompress").entered();
+    fs::create_dir_all(output_dir).with_path("Could not create output directory", output_dir)?;
+
+    let mut archive = extracting_archive(plain::root_tar(reader)?);
+    let mut owners = OwnerRestorer::new(opts.preserve_owner);
+    let xattrs = XattrRestorer::new(opts.xattrs);
+
+    let mut meta = None;
+    let mut dedup = DedupManifest::default();
+    let mut manifest = None;
+    let mut directories = vec![];
+    let mut hard_links = vec![];
+    let mut salvage = Salvage::new(opts.best_effort);
+
+    // Extract all of the files. An archive without a compressed member is valid, it just
+    // means that none of the files were worth compressing.
+    for entry in archive.entries()? {
+        // The entries after a header that can't be read can't be found
+        let Some(mut entry) =
+            salvage.recover("the rest of the archive", entry.map_err(Into::into))?
+        else {
+            break;
+        };
+        let name = String::from_utf8_lossy(&entry.path_bytes()).into_owned();
+
+        let result = match root_entry_kind(&mut entry) {
+            Err(e) => Err(e),
+            Ok(RootEntry::Meta) => ArchiveMeta::read(entry).map(|read| meta = Some(read)),
+            Ok(RootEntry::Member(codec)) => {
+                // Decompress the internal tar
+                let dictionary = opts.zstd_dictionary.as_ref();
+                let codec_name = ArchiveMeta::member_codec(meta.as_ref(), codec).name();
+                debug!("decompressing the {} member", codec_name);
+                let _span = info_span!("member", codec = codec_name).entered();
+                let decompress =
+                    ArchiveMeta::member_decoder(meta.as_ref(), codec, dictionary, entry)?;
+                let mut tar = extracting_archive(decompress);
+                for inner in tar.entries()? {
+                    // Nothing can be read past what can't be decompressed
+                    let Some(mut inner) = salvage.recover(
+                        "the rest of the compressed member",
+                        inner.map_err(Into::into),
+                    )?
+                    else {
+                        break;
+                    };
+                    let path = inner.path()?.into_owned();
+                    check_entry_path(&path)?;
+                    let Some(path) = strip_components(&path, opts.strip_components) else {
+                        continue;
+                    };
+                    check_overwrite(output_dir, &path, opts.overwrite)?;
+                    debug!("extracting {}", path.display());
+                    let _span = debug_span!("extract", path = %path.display()).entered();
+                    let target = output_dir.join(&path);
+                    let extracted = xattrs.read(&mut inner).and_then(|attrs| {
+                        unpack_entry(&mut inner, output_dir, &path, opts.strip_components)?;
+                        Ok(attrs)
+                    });
+                    // The next entry fails too if the member can't be decompressed past it
+                    if let Some(attrs) = salvage.recover_file(&path, &target, extracted)? {
+                        owners.restore(&target, inner.header())?;
+                        xattrs.restore(&target, &attrs)?;
+                    }
+                }
+                Ok(())
+            }
+            Ok(RootEntry::Compressed(file)) => {
+                check_entry_path(&file.path)?;
+                let Some(path) = strip_components(&file.path, opts.strip_components) else {
+                    continue;
+                };
+                debug!("extracting {}", path.display());
+                let _span = debug_span!("extract", path = %path.display()).entered();
+                let header = entry.header().clone();
+                let target = output_dir.join(&path);
+                let extracted = xattrs.read(&mut entry).and_then(|attrs| {
+                    per_file::unpack(entry, &file, &path, output_dir, opts.overwrite)?;
+                    Ok(attrs)
+                });
+                if let Some(attrs) = salvage.recover_file(&file.path, &target, extracted)? {
+                    owners.restore(&target, &header)?;
+                    xattrs.restore(&target, &attrs)?;
+                }
+                continue;
+            }
+            Ok(RootEntry::Dedup) => DedupManifest::read(entry).map(|read| dedup = read),
+            Ok(RootEntry::Manifest) => {
+                ChecksumManifest::read(entry).map(|read| manifest = Some(read))
+            }
+            Ok(RootEntry::Checksum | RootEntry::Index) => Ok(()),
+            Ok(RootEntry::Directory) => {
+                let path = entry.path()?.into_owned();
+                check_entry_path(&path)?;
+                if let Some(path) = strip_components(&path, opts.strip_components) {
+                    directories.push((path, entry));
+                }
+                Ok(())
+            }
+            Ok(RootEntry::HardLink) => {
+                let path = entry.path()?.into_owned();
+                hard_link_target(&entry, &path).map(|target| hard_links.push((path, target)))
+            }
+            Ok(RootEntry::Symlink | RootEntry::File) => {
+                let path = entry.path()?.into_owned();
+                check_entry_path(&path)?;
+                let Some(path) = strip_components(&path, opts.strip_components) else {
+                    continue;
+                };
+                check_overwrite(output_dir, &path, opts.overwrite)?;
+                debug!("extracting {}", path.display());
+                let _span = debug_span!("extract", path = %path.display()).entered();
+                let target = output_dir.join(&path);
+                let extracted = xattrs.read(&mut entry).and_then(|attrs| {
+                    unpack_entry(&mut entry, output_dir, &path, opts.strip_components)?;
+                    Ok(attrs)
+                });
+                if let Some(attrs) = salvage.recover_file(&path, &target, extracted)? {
+                    owners.restore(&target, entry.header())?;
+                    xattrs.restore(&target, &attrs)?;
+                }
+                continue;
+            }
+        };
+
+        salvage.recover(name, result)?;
+    }
+
+    // The copies can only be made once their originals have been extracted
+    let links = info_span!("copies").entered();
+    for (copy, original) in &dedup.copies {
+        check_entry_path(copy)?;
+        check_entry_path(original)?;
+        let Some(copy) = strip_components(copy, opts.strip_components) else {
+            continue;
+        };
+        let Some(original) = strip_components(original, opts.strip_components) else {
+            log::warn!(
+                "leaving out {}, a copy of {} whose path is too short to be stripped",
+                copy.display(),
+                original.display()
+            );
+            continue;
+        };
+        check_overwrite(output_dir, &copy, opts.overwrite)?;
+        let target = output_dir.join(&copy);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let original = output_dir.join(original);
+        let copied = fs::copy(&original, &target).with_path("Could not copy a file to", &target);
+        if salvage.recover_file(&copy, &target, copied)?.is_some() {
+            owners.restore_like(&target, &original)?;
+            xattrs.restore_like(&target, &original)?;
+        }
+    }
+
+    // So can the hard links, whose targets can be copies too
+    for (link, target) in &hard_links {
+        check_entry_path(link)?;
+        check_entry_path(target)?;
+        let Some(link) = strip_components(link, opts.strip_components) else {
+            continue;
+        };
+        let Some(target) = strip_components(target, opts.strip_components) else {
+            log::warn!(
+                "leaving out {}, a hard link to {} whose path is too short to be stripped",
+                link.display(),
+                target.display()
+            );
+            continue;
+        };
+        check_overwrite(output_dir, &link, opts.overwrite)?;
+        debug!("linking {} to {}", link.display(), target.display());
+        let link_path = output_dir.join(&link);
+        if let Some(parent) = link_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // A hard link can't replace a file
+        if opts.overwrite {
+            let _ = fs::remove_file(&link_path);
+        }
+        let linked = fs::hard_link(output_dir.join(target), &link_path)
+            .with_path("Could not create the hard link", &link_path);
+        salvage.recover(link.display(), linked)?;
+    }
+    drop(links);
+
+    // The files are checked before the directories are restored, since they may not be readable
+    // afterwards, but the directories are restored either way
+    let checked = manifest.map_or(Ok(()), |manifest| {
+        manifest.check_extracted(output_dir, opts.strip_components)
+    });
+
+    // Like `tar::Archive::unpack`, the directories are restored last and innermost first, so that
+    // a read-only directory doesn't stop its contents from being extracted, and extracting them
+    // doesn't change its modification time.
+    directories.sort_by(|(a, _), (b, _)| b.cmp(a));
+    for (path, mut directory) in directories {
+        let mtime = directory.header().mtime()?;
+
+        let attrs = xattrs.read(&mut directory)?;
+        let unpacked = unpack_entry(&mut directory, output_dir, &path, opts.strip_components)?;
+        let path = output_dir.join(path);
+        if unpacked {
+            owners.restore(&path, directory.header())?;
+            xattrs.restore(&path, &attrs)?;
+            // tar only restores the permissions of directories
+            File::open(&path)
+                .and_then(|dir| {
+                    dir.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(mtime))
+                })
+                .with_path("Could not set the modification time of", &path)?;
+        }
+    }
+
+    salvage.finish(checked)
+}
+
+/// What an entry of the root tar holds.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum RootEntry {
+    /// The metadata of the archive.
+    Meta,
+
+    /// The compressed member, holding the compressible files.
+    Member(Codec),
+
+    /// A file compressed on its own.
+    Compressed(CompressedFile),
+
+    /// The list of files stored as copies of another file.
+    Dedup,
+
+    /// The CRC32 of each file.
+    Manifest,
+
+    /// Where each file is.
+    Index,
+
+    /// The CRC32 of the compressed member.
+    Checksum,
+
+    /// A directory, which is only recreated once everything else has been extracted.
+    Directory,
+
+    /// A symlink.
+    Symlink,
+
+    /// A hard link to an earlier entry, which is only recreated once everything else has been
+    /// extracted, since that entry can be in the compressed member.
+    HardLink,
+
+    /// A file stored as-is.
+    File,
+}
+
+/// Tells what `entry` of the root tar holds, from its type for directories and symlinks, its
+/// name, or its PAX extensions for files compressed on their own.
+fn root_entry_kind<R: Read>(entry: &mut tar::Entry<R>) -> Result<RootEntry> {
+    let entry_type = entry.header().entry_type();
+    if entry_type.is_dir() {
+        return Ok(RootEntry::Directory);
+    } else if entry_type.is_symlink() {
+        return Ok(RootEntry::Symlink);
+    } else if entry_type.is_hard_link() {
+        return Ok(RootEntry::HardLink);
+    }
+
+    if let Some(file) = CompressedFile::from_entry(entry)? {
+        return Ok(RootEntry::Compressed(file));
+    }
+
+    Ok(root_entry_kind_of(&entry.path()?))
+}
+
+/// The earlier entry that the hard link `entry`, at `path`, links to.
+fn hard_link_target<R: Read>(entry: &tar::Entry<R>, path: &Path) -> Result<PathBuf> {
+    let target = entry.link_name()?.ok_or_else(|| {
+        TtareError::CorruptArchive(format!("{} links to nothing", path.display()))
+    })?;
+    Ok(normalize_entry_path(&target))
+}
+
+/// Tells what an entry of the root tar at `path` holds.
+///
+/// Files are never stored as-is under a name that means something else, see
+/// `ArchiveWriter::append`.
+fn root_entry_kind_of(path: &Path) -> RootEntry {
+    let path = normalize_entry_path(path);
+    let name = path.to_str();
+
+    if name == Some(TTARE_META_FILE_NAME) {
+        RootEntry::Meta
+    } else if name == Some(TTARE_CHECKSUM_FILE_NAME) {
+        RootEntry::Checksum
+    } else if name == Some(TTARE_DEDUP_FILE_NAME) {
+        RootEntry::Dedup
+    } else if name == Some(TTARE_MANIFEST_FILE_NAME) {
+        RootEntry::Manifest
+    } else if name == Some(TTARE_INDEX_FILE_NAME) {
+        RootEntry::Index
+    } else if let Some(codec) = name.and_then(Codec::from_member_name) {
+        RootEntry::Member(codec)
+    } else {
+        RootEntry::File
+    }
+}
+
+/// Fails if `file`, opened at `path`, is a ttare archive, or the first part of a split one: a tar
+/// whose first entry is one that ttare adds, such as its metadata or its compressed member. The
+/// file is read from the start, and left there.
+fn check_not_nested<R: Read + Seek>(path: &Path, file: &mut R) -> Result<()> {
+    let mut block = [0; TAR_BLOCK_BYTES as usize];
+    let read = file
+        .read_exact(&mut block)
+        .map(|()| true)
+        .or_else(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => Ok(false),
+            _ => Err(e),
+        });
+    let read = read.with_path("Could not read", path)?;
+    file.seek(SeekFrom::Start(0))
+        .with_path("Could not read", path)?;
+    if !read {
+        return Ok(());
+    }
+
+    // A file that happens to start with one of ttare's names is only a tar header if its checksum
+    // matches too
+    let header = Header::from_byte_slice(&block);
+    let checksum = block
+        .iter()
+        .enumerate()
+        .map(|(i, byte)| {
+            if (148..156).contains(&i) {
+                u32::from(b' ')
+            } else {
+                u32::from(*byte)
+            }
+        })
+        .sum::<u32>();
+    let nested = header.cksum().is_ok_and(|cksum| cksum == checksum)
+        && header
+            .path()
+            .is_ok_and(|name| root_entry_kind_of(&name) != RootEntry::File);
+
+    if nested {
+        Err(TtareError::NestedArchive(path.to_path_buf()))
+    } else {
+        Ok(())
+    }
+}
+
+/// Drops the `.` components of a path stored in an archive, so that `./a` and `a` are the same.
+fn normalize_entry_path(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|component| *component != Component::CurDir)
+        .collect()
+}
+
+/// Drops the first `count` components of `path`, read from an archive, besides its `.` components.
+/// `None` when there is nothing left of it.
+pub(crate) fn strip_components(path: &Path, count: usize) -> Option<PathBuf> {
+    let path: PathBuf = normalize_entry_path(path)
+        .components()
+        .skip(count)
+        .collect();
+    (!path.as_os_str().is_empty()).then_some(path)
+}
+
+/// Extracts `entry` into `output_dir` at `path`, which is its own path with `strip_components` of
+/// its leading components dropped. Returns false if it was left out, like `Entry::unpack_in`.
+fn unpack_entry<R: Read>(
+    entry: &mut tar::Entry<R>,
+    output_dir: &Path,
+    path: &Path,
+    strip_components: usize,
+) -> Result<bool> {
+    // tar makes sure that its own path doesn't go through a symlink out of the output directory
+    if strip_components == 0 {
+        return Ok(entry.unpack_in(output_dir)?);
+    }
+
+    let target = output_dir.join(path);
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent).with_path("Could not create", parent)?;
+    }
+    entry
+        .unpack(&target)
+        .with_path("Could not extract", &target)?;
+    Ok(true)
+}
+
+/// Fails if `path`, read from an archive, is absolute or goes up with `..`, so that extracting it
+/// can't write outside of the output directory.
+pub(crate) fn check_entry_path(path: &Path) -> Result<()> {
+    if path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+    {
+        Ok(())
+    } else {
+        Err(TtareError::UnsafePath(path.to_path_buf()))
+    }
+}
+
+/// The name that the file at `path` on disk is stored under in the archive: the one `name_map`
+/// gives it, if any, else its path relative to `base_dir` when there is one, and otherwise its
+/// path without the leading `/`, `..` and `.`. `None` for `base_dir` itself, which has no name.
+/// Fails if the file isn't in `base_dir`, or if its name still goes up with `..` or is absolute,
+/// since it couldn't be extracted.
+pub(crate) fn entry_name(
+    path: &Path,
+    base_dir: Option<&Path>,
+    name_map: &[(PathBuf, PathBuf)],
+) -> Result<Option<PathBuf>> {
+    let name = match (mapped_name(path, name_map), base_dir) {
+        (Some(name), _) => name,
+        (None, Some(base_dir)) => {
+            let absolute_path = path::absolute(path).with_path("Could not resolve", path)?;
+            let absolute_base =
+                path::absolute(base_dir).with_path("Could not resolve", base_dir)?;
+            normalize_entry_path(&absolute_path)
+                .strip_prefix(normalize_entry_path(&absolute_base))
+                .map_err(|_| TtareError::OutsideBaseDir {
+                    path: path.to_path_buf(),
+                    base_dir: base_dir.to_path_buf(),
+                })?
+                .to_path_buf()
+        }
+        (None, None) => path
+            .components()
+            .skip_while(|component| !matches!(component, Component::Normal(_)))
+            .collect(),
+    };
+
+    let name = normalize_entry_path(&name);
+    if name.as_os_str().is_empty() {
+        return Ok(None);
+    }
+    check_entry_path(&name)?;
+    Ok(Some(name))
+}
+
+/// The name that `name_map` gives to the file at `path`: the destination of the longest source
+/// that is `path` or one of its parent directories, followed by the rest of `path`.
+fn mapped_name(path: &Path, name_map: &[(PathBuf, PathBuf)]) -> Option<PathBuf> {
+    let path = normalize_entry_path(path);
+    name_map
+        .iter()
+        .filter_map(|(source, destination)| {
+            let source = normalize_entry_path(source);
+            let rest = path.strip_prefix(&source).ok()?;
+            Some((source.components().count(), destination.join(rest)))
+        })
+        .max_by_key(|(len, _)| *len)
+        .map(|(_, name)| name)
+}
+
+/// Fails if extracting `path` into `output_dir` would replace a file that is already there, unless
+/// it can `overwrite` it. The directories that are already there are extracted into.
+pub(crate) fn check_overwrite(output_dir: &Path, path: &Path, overwrite: bool) -> Result<()> {
+    let target = output_dir.join(path);
+    match fs::symlink_metadata(&target) {
+        Ok(metadata) if !overwrite && !metadata.is_dir() => Err(TtareError::OutputExists(target)),
+        _ => Ok(()),
+    }
+}
+
+/// Opens a tar archive that restores the permissions and modification times of its entries when
+/// they are extracted.
+fn extracting_archive<R: Read>(reader: R) -> Archive<R> {
+    let mut archive = Archive::new(reader);
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_mtime(true);
+    archive
+}
+
+/// Creates the header for an entry that doesn't come from a file on disk, a GNU header like
+/// `disk_header`'s.
+fn data_header(size: u64, mtime: u64) -> Header {
+    let mut header = Header::new_gnu();
+    header.set_size(size);
+    header.set_mode(0o644);
+    header.set_mtime(mtime);
+    header.set_cksum();
+    header
+}
+
+/// Creates a temporary file in `dir`, or in the system's temporary directory. It's deleted as soon
+/// as it's created, so it goes away with the last handle to it, whether ttare succeeds or not.
+pub(crate) fn temp_file(dir: Option<&Path>) -> Result<File> {
+    let dir = dir.map_or_else(env::temp_dir, Path::to_path_buf);
+    tempfile::tempfile_in(&dir).with_path("Could not create a temporary file in", &dir)
+}
+
+/// The current time, as a tar modification time.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+/// Compresses `files` into a new ttare archive at `output`.
+///
+/// Unless it is split, the archive is written next to `output`, at `partial_path(output)`, and
+/// only renamed to `output` once it is complete, so that an interrupted run never leaves a
+/// truncated archive that looks finished.
+///
+/// The directories in `files` are stored as directory entries, without their contents, so that
+/// they are recreated with their permissions even when they are empty. Symlinks are stored as
+/// symlinks, unless `dereference` is set. `gather_files` finds the directories, symlinks and files
+/// to pass here.
+pub fn compress(
+    files: &[PathBuf],
+    output: &Path,
+    opts: CompressOptions,
+) -> Result<CompressSummary> {
+    let verify_opts = opts.verify_after.then(|| opts.clone());
+    let summary = match &opts.checkpoint {
+        Some(checkpoint) => checkpoint::compress(files, output, checkpoint, &opts)?,
+        None => create_archive(output, opts.split_size, opts.overwrite, |output_file| {
+            compress_to(files, output_file, opts)
+        })?,
+    };
+    if let Some(opts) = verify_opts {
+        verify::verify_sources(output, files, &summary.skipped, &opts)?;
+    }
+    Ok(summary)
+}
+
+/// Compresses `files` into a ttare archive written to `output`, such as stdout.
+///
+/// The archive is written in a single pass, so the output doesn't have to be seekable. If this
+/// fails, whatever was already written to the output is left there.
+pub fn compress_to<W: Write>(
+    files: &[PathBuf],
+    output: W,
+    opts: CompressOptions,
+) -> Result<CompressSummary> {
+    write_archive(files, output, &opts)
+}
+
+/// Compresses everything read from `reader` into a new ttare archive at `output`, as a single
+/// file named `name`.
+///
+/// The reader doesn't have to be seekable: the entropy is sampled from the first
+/// `STREAM_ANALYSIS_BYTES` bytes, which are kept in memory and written to the archive as-is, while
+/// the rest is spooled to a temporary file since its size has to be known before it is added. With
+/// `full_entropy`, the entropy is computed over the whole stream once it has been spooled.
+pub fn compress_reader<R: Read>(
+    reader: R,
+    name: &Path,
+    output: &Path,
+    opts: CompressOptions,
+) -> Result<CompressSummary> {
+    opts.check()?;
+
+    create_archive(output, opts.split_size, opts.overwrite, |output_file| {
+        compress_reader_to(reader, name, output_file, opts)
+    })
+}
+
+/// Compresses everything read from `reader` into a ttare archive written to `output`, as a single
+/// file named `name`, like `compress_reader` does.
+pub fn compress_reader_to<R: Read, W: Write>(
+    reader: R,
+    name: &Path,
+    output: W,
+    opts: CompressOptions,
+) -> Result<CompressSummary> {
+    opts.check()?;
+
+    // The stream is read in full before the archive is written, so they don't need to share a
+    // throttle
+    let mut reader = Throttle::new(opts.throttle_bytes_per_sec).wrap(reader);
+
+    let mut prefix = vec![];
+    reader
+        .by_ref()
+        .take(STREAM_ANALYSIS_BYTES)
+        .read_to_end(&mut prefix)
+        .with_path("Could not read", name)?;
+
+    // The whole stream is only counted for its entropy while it is spooled, so that the spool
+    // isn't read twice
+    let mut rest = temp_file(opts.temp_dir.as_deref())?;
+    let (rest_len, full_entropy) = if opts.full_entropy && opts.estimator == Estimator::Shannon {
+        let mut histogram = HistogramReader::new(&mut reader, &opts);
+        histogram.count(&prefix);
+        let rest_len = io::copy(&mut histogram, &mut rest).with_path("Could not read", name)?;
+        (rest_len, Some(histogram.classify(&opts)))
+    } else {
+        let rest_len = io::copy(&mut reader, &mut rest).with_path("Could not read", name)?;
+        (rest_len, None)
+    };
+    rest.seek(SeekFrom::Start(0))?;
+
+    let len = prefix.len() as u64 + rest_len;
+    let progress = Progress::new(false, &[]);
+    let (entropy, decision) = if let Some(decision) = forced_decision(name, len, &opts) {
+        if opts.check_formats
+            && decision == EntropyAnalysis::DontCompress
+            && forced_by_extension(name, &opts)
+        {
+            check_recognized_format(name, &mut Cursor::new(&prefix), &opts, &progress)?;
+        }
+        (None, decision)
+    } else if sniffs_incompressible(&mut Cursor::new(&prefix), &opts)? {
+        if opts.check_formats {
+            check_recognized_format(name, &mut Cursor::new(&prefix), &opts, &progress)?;
+        }
+        (None, EntropyAnalysis::DontCompress)
+    } else if let Some((entropy, decision)) = full_entropy.filter(|_| rest_len > 0) {
+        (Some(entropy), decision)
+    } else {
+        let (entropy, decision) = classify(&mut Cursor::new(&prefix), &opts)?;
+        (Some(entropy), decision)
+    };
+
+    let analysis = FileAnalysis {
+        path: name.to_path_buf(),
+        size: len,
+        entropy,
+        decision,
+    };
+    report_decision(&analysis, opts.show_entropy, &progress);
+
+    let mut writer = ArchiveWriter::new(output, &opts, progress)?;
+    let mut header = data_header(len, opts.entry_mtime());
+    let data = prefix.as_slice().chain(rest);
+    writer.append(decision, &mut header, name, &Xattrs::default(), data)?;
+    writer.finish()
+}
+
+/// Where the archive at `output` is written until it is complete, such as
+/// `archive.ttare.partial`.
+pub fn partial_path(output: &Path) -> PathBuf {
+    let mut path = output.as_os_str().to_owned();
+    path.push(PARTIAL_EXTENSION);
+    PathBuf::from(path)
+}
+
+/// Removes what an interrupted `compress` to `output` leaves behind: the partial archive, or the
+/// parts written so far when the archive is `split`. Meant to be called when the process is
+/// interrupted, since it can't clean up after itself then.
+pub fn remove_partial(output: &Path, split: bool) {
+    if !split {
+        let _ = fs::remove_file(partial_path(output));
+        return;
+    }
+
+    let mut index = 1;
+    while fs::remove_file(split::part_path(output, index)).is_ok() {
+        index += 1;
+    }
+}
+
+/// Creates the archive at `output`, or its parts when it is split, and writes it with `write`,
+/// removing it if that fails. Fails if the archive is already there, unless it can `overwrite` it.
+fn create_archive(
+    output: &Path,
+    split_size: Option<NonZeroU64>,
+    overwrite: bool,
+    write: impl FnOnce(&mut dyn Write) -> Result<CompressSummary>,
+) -> Result<CompressSummary> {
+    let Some(split_size) = split_size else {
+        if !overwrite && fs::symlink_metadata(output).is_ok() {
+            return Err(TtareError::OutputExists(output.to_path_buf()));
+        }
+
+        // A partial archive left over from an interrupted run is replaced
+        let partial = partial_path(output);
+        let mut output_file = File::create(&partial).with_path("Could not create", &partial)?;
+
+        let result = write(&mut output_file).and_then(|summary| {
+            drop(output_file);
+            fs::rename(&partial, output).with_path("Could not rename the archive to", output)?;
+            Ok(summary)
+        });
+
+        // Don't leave a truncated archive behind
+        if result.is_err() {
+            let _ = fs::remove_file(&partial);
+        }
+
+        return result;
+    };
+
+    let first_part = split::part_path(output, 1);
+    if !overwrite && fs::symlink_metadata(&first_part).is_ok() {
+        return Err(TtareError::OutputExists(first_part));
+    }
+
+    let mut parts = SplitWriter::new(output, split_size);
+    match write(&mut parts) {
+        Ok(summary) => {
+            parts.finish()?;
+            Ok(summary)
+        }
+        Err(e) => {
+            parts.remove();
+            Err(e)
+        }
+    }
+}
+
+/// Fails with the names that `name_map` gives to more than one of the files and symlinks in
+/// `paths`, since only the last of them would be extracted.
+fn check_unique_names(paths: &DiskPaths, opts: &CompressOptions) -> Result<()> {
+    let mut names = FxHashMap::default();
+    let mut collisions = vec![];
+    for path in paths.symlinks.iter().chain(&paths.files) {
+        if let Some(name) = entry_name(path, opts.base_dir.as_deref(), &opts.name_map)? {
+            let count = names.entry(name.clone()).or_insert(0);
+            *count += 1;
+            if *count == 2 {
+                collisions.push(name);
+            }
+        }
+    }
+
+    if collisions.is_empty() {
+        Ok(())
+    } else {
+        Err(TtareError::NameCollision(collisions))
+    }
+}
+
+/// Writes the ttare archive of `paths` to `output`, which is what `compress_to` does.
+///
+/// A plain tar.gz is only possible when every file is classified the same way, so with
+/// `opts.plain_targz` the files are all analyzed before the layout is picked. Otherwise each file
+/// is analyzed as it is added.
+fn write_archive<W: Write>(
+    paths: &[PathBuf],
+    output: W,
+    opts: &CompressOptions,
+) -> Result<CompressSummary> {
+    let _span = info_span!("compress").entered();
+    let paths = DiskPaths::ordered(paths, opts)?;
+    let progress = Progress::new(opts.progress, &paths.files);
+    if !opts.plain_targz {
+        let mut writer = ArchiveWriter::new(output, opts, progress)?;
+        writer.append_paths(&paths, opts)?;
+        return writer.finish();
+    }
+
+    // Whether the archive can be plain depends on how every file is classified, so they are all
+    // analyzed before anything is written
+    let throttle = Throttle::new(opts.throttle_bytes_per_sec);
+    let analyzed = analyze_files_skipping(
+        &paths.files,
+        opts,
+        &progress,
+        &throttle,
+        READ_ONCE_BUDGET_BYTES,
+    )?;
+    let layout = plain_layout(&analyzed.0, opts);
+    let spools = SpoolLocation::TempDir(opts.temp_dir.clone());
+    let mut writer = ArchiveWriter::with_layout(output, opts, progress, spools, throttle, layout)?;
+    writer.append_analyzed(&paths, analyzed, opts)?;
+    writer.finish()
+}
+
+/// How `plain_targz` lays out the analyzed `files`: as a plain tar when they are all compressible
+/// or all incompressible, unless one of them is named like an entry that ttare adds to the root
+/// tar, which `decompress` would mistake it for.
+fn plain_layout(files: &[AnalyzedFile], opts: &CompressOptions) -> Layout {
+    let reserved = files.iter().find(|file| {
+        matches!(
+            entry_name(&file.analysis.path, opts.base_dir.as_deref(), &opts.name_map),
+            Ok(Some(name)) if root_entry_kind_of(&name) != RootEntry::File
+        )
+    });
+    if let Some(file) = reserved {
+        info!(
+            "writing a ttare archive, since {} is named like an entry that ttare adds",
+            file.analysis.path.display()
+        );
+        return Layout::Ttare;
+    }
+
+    let compressible = files
+        .iter()
+        .filter(|file| file.analysis.decision == EntropyAnalysis::Compress)
+        .count();
+    if compressible == files.len() {
+        Layout::Plain { compressed: true }
+    } else if compressible == 0 {
+        Layout::Plain { compressed: false }
+    } else {
+        info!(
+            "writing a ttare archive, since {} of the {} files are compressible",
+            compressible,
+            files.len()
+        );
+        Layout::Ttare
+    }
+}
+
+/// The paths to add to an archive, by what they are on disk.
+struct DiskPaths {
+    dirs: Vec<PathBuf>,
+
+    /// The symlinks, which are stored as links unless `dereference` is set.
+    symlinks: Vec<PathBuf>,
+
+    /// Everything else. The paths that can't be read are here too, so that they fail, or are
+    /// skipped, like the files that can't be read.
+    files: Vec<PathBuf>,
+}
+
+impl DiskPaths {
+    fn split(paths: &[PathBuf], opts: &CompressOptions) -> Self {
+        let mut split = DiskPaths {
+            dirs: vec![],
+            symlinks: vec![],
+            files: vec![],
+        };
+
+        for path in paths {
+            let metadata = if opts.dereference {
+                fs::metadata(path)
+            } else {
+                fs::symlink_metadata(path)
+            };
+
+            match metadata {
+                Ok(metadata) if metadata.is_dir() => split.dirs.push(path.clone()),
+                Ok(metadata) if metadata.is_symlink() => split.symlinks.push(path.clone()),
+                _ => split.files.push(path.clone()),
+            }
+        }
+
+        split
+    }
+
+    /// Splits `paths` like `split` does, in the order they are added to the archive, once it's
+    /// checked that no two files are stored under the same name.
+    fn ordered(paths: &[PathBuf], opts: &CompressOptions) -> Result<Self> {
+        let mut paths = DiskPaths::split(paths, opts);
+        if !opts.name_map.is_empty() {
+            check_unique_names(&paths, opts)?;
+        }
+        if opts.reproducible {
+            // Parents still come before their contents, since a path sorts before any path it
+            // prefixes
+            paths.dirs.sort();
+            paths.symlinks.sort();
+            paths.files.sort();
+        }
+        Ok(paths)
+    }
+}
+
+/// Creates the header of a file or directory on disk, leaving out what would make the archive
+/// differ between runs when `reproducible` is set.
+///
+/// Like every header ttare writes, it's a GNU header, which holds sizes of 8 GiB and more that a
+/// ustar header can't, while names and link targets longer than any header holds are written
+/// ahead of it as GNU long name entries.
+fn disk_header(metadata: &fs::Metadata, opts: &CompressOptions) -> Result<Header> {
+    let mut header = Header::new_gnu();
+    header.set_metadata(metadata);
+
+    if opts.reproducible {
+        header.set_uid(0);
+        header.set_gid(0);
+        if let Some(mtime) = opts.mtime {
+            header.set_mtime(header.mtime()?.min(mtime));
+        }
+    }
+
+    Ok(header)
+}
+
+/// The device and inode of the file that `metadata` is about, if it has other paths too.
+#[cfg(unix)]
+fn hard_link_id(metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+
+    (metadata.nlink() > 1).then(|| (metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn hard_link_id(_metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+/// The decision for `path`, of `size` bytes, that doesn't depend on its contents: `DontCompress`
+/// for every file with `no_compress`, the one forced by a rule, `Compress` for a small file that
+/// isn't empty, or `DontCompress` for the extension of a format that is already compressed.
+fn forced_decision(path: &Path, size: u64, opts: &CompressOptions) -> Option<EntropyAnalysis> {
+    if opts.no_compress {
+        return Some(EntropyAnalysis::DontCompress);
+    }
+
+    opts.rules
+        .decision_for(path)
+        .or_else(|| {
+            (1..opts.small_file_bytes)
+                .contains(&size)
+                .then_some(EntropyAnalysis::Compress)
+        })
+        .or_else(|| {
+            has_incompressible_extension(path, opts).then_some(EntropyAnalysis::DontCompress)
+        })
+}
+
+/// Whether `forced_decision` stores `path` as-is because of its extension, when it does, rather
+/// than because of `no_compress` or a rule.
+fn forced_by_extension(path: &Path, opts: &CompressOptions) -> bool {
+    !opts.no_compress
+        && opts.rules.decision_for(path).is_none()
+        && has_incompressible_extension(path, opts)
+}
+
+/// Warns if the entropy of `contents`, those of the file at `path`, says to compress them even
+/// though they were recognized as a format that is already compressed, see
+/// `CompressOptions::check_formats`.
+fn check_recognized_format<R: Read + Seek>(
+    path: &Path,
+    contents: &mut R,
+    opts: &CompressOptions,
+    progress: &Progress,
+) -> Result<()> {
+    let (entropy, decision) = classify(contents, opts).with_path("Could not read", path)?;
+    if decision == EntropyAnalysis::Compress {
+        progress.warn(format_args!(
+            "{} is stored as-is as an already compressed format, but its entropy of {:.3} would have it compressed",
+            path.display(),
+            entropy
+        ));
+    }
+    Ok(())
+}
+
+/// Where a file that was opened to be added to the archive is stored, unless its name says
+/// otherwise, see `ArchiveWriter::append`.
+fn stored_decision(path: &Path, decision: EntropyAnalysis) -> EntropyAnalysis {
+    match root_entry_kind_of(path) {
+        RootEntry::File => decision,
+        _ => EntropyAnalysis::Compress,
+    }
+}
+
+/// The level a file is compressed at on its own: the one asked for, or the fastest one for a file
+/// that would have been stored as-is, and is only compressed to see if that makes it smaller.
+fn per_file_level(codec: Codec, level: Option<u32>, decision: EntropyAnalysis) -> Option<u32> {
+    match decision {
+        EntropyAnalysis::Compress => level,
+        EntropyAnalysis::DontCompress => Some(codec.fastest_level()),
+    }
+}
+
+/// A file that was opened to be added to the archive.
+struct OpenedFile {
+    path: PathBuf,
+    header: Header,
+    xattrs: Xattrs,
+    decision: EntropyAnalysis,
+    contents: Contents,
+}
+
+/// The contents of a file being added to the archive: still on disk, already read into memory
+/// by the analysis, or copied to a spool to check that they didn't change while they were read.
+enum Contents {
+    Disk(Throttled<File>),
+    Memory(Cursor<Vec<u8>>),
+    Spooled(Spool),
+}
+
+impl Contents {
+    /// A reader of the contents from the start, which only borrows them, so that they can be
+    /// compressed on another thread. A file on disk has to be at its start already.
+    fn shared(&self) -> Box<dyn Read + '_> {
+        match self {
+            Contents::Disk(file) => Box::new(file.borrowed()),
+            Contents::Memory(data) => Box::new(data.get_ref().as_slice()),
+            Contents::Spooled(Spool::Disk(file)) => Box::new(file),
+            Contents::Spooled(Spool::Memory(data)) => Box::new(data.get_ref().as_slice()),
+        }
+    }
+}
+
+impl Read for Contents {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Contents::Disk(file) => file.read(buf),
+            Contents::Memory(data) => data.read(buf),
+            Contents::Spooled(spool) => spool.read(buf),
+        }
+    }
+}
+
+impl Seek for Contents {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            Contents::Disk(file) => file.seek(pos),
+            Contents::Memory(data) => data.seek(pos),
+            Contents::Spooled(spool) => spool.seek(pos),
+        }
+    }
+}
+
+/// The root tar, streamed to the output, which is compressed as a whole for a plain tar.gz.
+type RootTar<W> = tar::Builder<CutOff<BufWriter<RootWriter<CountingWriter<Throttled<W>>>>>>;
+
+/// The compressed tar, spooled to a temporary file while its CRC32 is computed.
+type CompressTar = tar::Builder<CutOff<CountingWriter<MemberWriter>>>;
+
+/// What compresses the compressed member into its spool.
+type MemberEncoder = Encoder<BufWriter<Crc32Writer<Spool>>>;
+
+/// How the files are laid out in an archive.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Layout {
+    /// The root tar, metadata first, with the compressible files in the compressed member.
+    Ttare,
+
+    /// A tar of all the files and nothing else, compressed as a whole with gzip or not at all, as
+    /// `plain_targz` writes when the files are all compressible or all incompressible.
+    Plain { compressed: bool },
+}
+
+/// An archive being written: the root tar, and the compressed tar that ends up inside it.
+struct ArchiveWriter<W: Write> {
+    root_tar: RootTar<W>,
+    compress_tar: CompressTar,
+    codec: Codec,
+    layout: Layout,
+    copies: DedupManifest,
+    summary: CompressSummary,
+    progress: Progress,
+
+    /// Where files compressed on their own are staged, when compressing each file on its own.
+    per_file_spool: Option<Spool>,
+
+    /// Where the files that would be stored as-is are staged, when they are compressed anyway to
+    /// see if that makes them smaller.
+    raw_spool: Option<Spool>,
+    compression_level: Option<u32>,
+
+    /// The modification time of the entries that ttare adds.
+    mtime: u64,
+
+    /// Stores the files of the compressed member, or files compressed on their own, as-is when
+    /// compressing them made them bigger.
+    no_expand: bool,
+
+    /// How many files are in the compressed member, and their size before compression.
+    member_files: usize,
+    member_input_bytes: u64,
+
+    /// Whether a file in the compressed member can't be moved to the root tar, because it's named
+    /// like one of the entries that ttare adds there.
+    member_has_reserved_names: bool,
+
+    /// The CRC32 of each file added so far, when they are recorded.
+    manifest: Option<ChecksumManifest>,
+
+    /// Where each file added so far is, when it is recorded.
+    index: Option<ArchiveIndex>,
+
+    /// Where the compressed member and the files compressed on their own are spooled.
+    spools: SpoolLocation,
+
+    /// The dictionary the compressed member is compressed with, to read it back if its files
+    /// have to be moved out of it.
+    zstd_dictionary: Option<ZstdDictionary>,
+    base_dir: Option<PathBuf>,
+    name_map: Vec<(PathBuf, PathBuf)>,
+    throttle: Throttle,
+    io_buffer_size: usize,
+
+    /// Whether the extended attributes of what is added from disk are stored.
+    xattrs: bool,
+
+    /// Whether the user was already told that a leading `/` or `..` was removed from a name.
+    stripped_names: bool,
+
+    /// Leaves out the blocks of zeros that end the root tar and the compressed tar.
+    strip_trailer: bool,
+
+    /// Finds the files added so far that a file is a copy of, when they are deduplicated.
+    deduplicator: Option<Deduplicator>,
+
+    /// The first path of each file with more than one that was added so far, by its device and
+    /// inode, under its name in the archive.
+    first_links: FxHashMap<(u64, u64), PathBuf>,
+
+    /// Where how far the archive got is recorded, when it can be resumed.
+    checkpoint: Option<Checkpoint>,
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    fn new(output: W, opts: &CompressOptions, progress: Progress) -> Result<Self> {
+        let spools = SpoolLocation::TempDir(opts.temp_dir.clone());
+        Self::with_spools(output, opts, progress, spools)
+    }
+
+    /// Creates a writer that stages the compressed data at `spools`.
+    fn with_spools(
+        output: W,
+        opts: &CompressOptions,
+        progress: Progress,
+        spools: SpoolLocation,
+    ) -> Result<Self> {
+        let throttle = Throttle::new(opts.throttle_bytes_per_sec);
+        Self::with_layout(output, opts, progress, spools, throttle, Layout::Ttare)
+    }
+
+    /// Creates a writer that lays out the archive as `layout` says, and whose IO counts against
+    /// `throttle`.
+    fn with_layout(
+        output: W,
+        opts: &CompressOptions,
+        progress: Progress,
+        spools: SpoolLocation,
+        throttle: Throttle,
+        layout: Layout,
+    ) -> Result<Self> {
+        // The root tar is streamed straight to the output, while the compressed tar is spooled,
+        // since its size has to be known before it can be added to the root tar
+        let member = MemberWriter::Compressed(member_encoder(&spools, opts)?);
+        let mut writer =
+            Self::with_member(output, opts, progress, spools, throttle, layout, member)?;
+
+        // The metadata comes first, so readers know how to read the rest of the archive
+        if layout == Layout::Ttare {
+            writer.append_meta(opts)?;
+        }
+        Ok(writer)
+    }
+
+    /// Creates a writer whose compressed tar is written to `member`, without adding anything to
+    /// the root tar yet.
+    fn with_member(
+        output: W,
+        opts: &CompressOptions,
+        progress: Progress,
+        spools: SpoolLocation,
+        throttle: Throttle,
+        layout: Layout,
+        member: MemberWriter,
+    ) -> Result<Self> {
+        let output = CountingWriter::new(throttle.wrap(output));
+        let output = match layout {
+            Layout::Plain { compressed: true } => {
+                RootWriter::Compressed(opts.codec.encoder(output, opts.compression_level, None)?)
+            }
+            Layout::Ttare | Layout::Plain { compressed: false } => RootWriter::Tar(output),
+        };
+        let output = BufWriter::with_capacity(opts.io_buffer_size, output);
+        let root_tar = tar::Builder::new(CutOff::new(output));
+
+        if opts.xattrs && !xattrs::SUPPORTED {
+            progress.warn(format_args!(
+                "extended attributes aren't supported on this platform, so they aren't stored"
+            ));
+        }
+
+        match opts.compression_level {
+            Some(level) => info!("compressing with {} at level {}", opts.codec.name(), level),
+            None => info!("compressing with {}", opts.codec.name()),
+        }
+
+        Ok(ArchiveWriter {
+            root_tar,
+            compress_tar: tar::Builder::new(CutOff::new(CountingWriter::new(member))),
+            codec: opts.codec,
+            layout,
+            copies: DedupManifest::default(),
+            summary: CompressSummary::default(),
+            progress,
+            per_file_spool: opts
+                .per_file_compression
+                .then(|| spools.spool())
+                .transpose()?,
+            raw_spool: (opts.compress_all && !opts.no_compress && layout == Layout::Ttare)
+                .then(|| spools.spool())
+                .transpose()?,
+            compression_level: opts.compression_level,
+            mtime: opts.entry_mtime(),
+            no_expand: opts.no_expand,
+            member_files: 0,
+            member_input_bytes: 0,
+            member_has_reserved_names: false,
+            manifest: opts.manifest.then(ChecksumManifest::default),
+            index: (opts.index && layout == Layout::Ttare).then(ArchiveIndex::default),
+            spools,
+            zstd_dictionary: opts.zstd_dictionary.clone(),
+            base_dir: opts.base_dir.clone(),
+            name_map: opts.name_map.clone(),
+            stripped_names: false,
+            throttle,
+            io_buffer_size: opts.io_buffer_size,
+            xattrs: opts.xattrs && xattrs::SUPPORTED,
+            strip_trailer: opts.strip_trailer,
+            deduplicator: opts.dedup.then(Deduplicator::default),
+            first_links: FxHashMap::default(),
+            checkpoint: None,
+        })
+    }
+
+    /// Adds the metadata that tells how the archive was written, which comes first in the root
+    /// tar.
+    fn append_meta(&mut self, opts: &CompressOptions) -> Result<()> {
+        let meta = ArchiveMeta::new(opts).to_bytes();
+        let mut header = data_header(meta.len() as u64, self.mtime);
+        self.root_tar.append_data(
+            &mut header,
+            Path::new(TTARE_META_FILE_NAME),
+            meta.as_slice(),
+        )?;
+        Ok(())
+    }
+
+    /// Adds an entry to the tar picked by `decision`.
+    ///
+    /// A file named like one of the entries that ttare adds to the root tar, such as
+    /// `.ttare.tar.gz`, is added to the compressed tar instead, since names don't mean anything
+    /// there and it would be mistaken for that entry in the root tar.
+    fn append(
+        &mut self,
+        decision: EntropyAnalysis,
+        header: &mut Header,
+        path: &Path,
+        xattrs: &Xattrs,
+        data: impl Read,
+    ) -> Result<()> {
+        let _span = debug_span!("write", path = %path.display()).entered();
+        let size = header.size()?;
+        let data = SizedReader::new(data, size);
+        let mut data = Crc32Reader::new(BufReader::with_capacity(self.io_buffer_size, data));
+
+        // A plain archive has nothing but the root tar, which is compressed as a whole if at all
+        let decision = match self.layout {
+            Layout::Ttare => stored_decision(path, decision),
+            Layout::Plain { .. } => EntropyAnalysis::DontCompress,
+        };
+
+        if decision == EntropyAnalysis::DontCompress {
+            if let Some(mut spool) = self.raw_spool.take() {
+                let result =
+                    self.compress_alone(&mut spool, decision, header, path, xattrs, &mut data);
+                self.raw_spool = Some(spool);
+                result?;
+                self.record_checksum(path, data.crc32());
+                return Ok(());
+            }
+        }
+
+        let result = match decision {
+            EntropyAnalysis::Compress => match self.per_file_spool.take() {
+                Some(mut spool) => {
+                    let result =
+                        self.compress_alone(&mut spool, decision, header, path, xattrs, &mut data);
+                    self.per_file_spool = Some(spool);
+                    result?;
+                    self.record_checksum(path, data.crc32());
+                    return Ok(());
+                }
+                None => {
+                    self.summary.compressed_files += 1;
+                    self.summary.compressed_input_bytes += size;
+                    self.member_files += 1;
+                    self.member_input_bytes += size;
+                    self.member_has_reserved_names |= root_entry_kind_of(path) != RootEntry::File;
+                    xattrs
+                        .append_to(&mut self.compress_tar)
+                        .and_then(|()| self.compress_tar.append_data(header, path, &mut data))
+                        .map(|()| Location::Member {
+                            offset: index::data_offset(
+                                self.compress_tar.get_ref().inner.written,
+                                size,
+                            ),
+                        })
+                }
+            },
+            EntropyAnalysis::DontCompress => {
+                if self.layout == (Layout::Plain { compressed: true }) {
+                    self.summary.compressed_files += 1;
+                    self.summary.compressed_input_bytes += size;
+                } else {
+                    self.summary.stored_files += 1;
+                }
+                xattrs
+                    .append_to(&mut self.root_tar)
+                    .and_then(|()| self.root_tar.append_data(header, path, &mut data))
+                    .map(|()| Location::Root {
+                        offset: index::data_offset(root_position(&self.root_tar), size),
+                    })
+            }
+        };
+
+        let location = result
+            .with_path("Could not add", path)
+            .map_err(|e| change::name_change(e, path))?;
+        self.record_location(path, size, location);
+        self.summary.input_bytes += size;
+        self.progress.file_done(size);
+        self.record_checksum(path, data.crc32());
+        Ok(())
+    }
+
+    /// Records that the file added at `path`, of `size` bytes, is at `location`, if where the
+    /// files are is recorded.
+    fn record_location(&mut self, path: &Path, size: u64, location: Location) {
+        if let Some(index) = &mut self.index {
+            index.add(path, size, location);
+        }
+    }
+
+    /// Records the CRC32 of the contents of the file added at `path`, if they are recorded.
+    fn record_checksum(&mut self, path: &Path, crc32: u32) {
+        if let Some(manifest) = &mut self.manifest {
+            manifest.add(path, crc32);
+        }
+    }
+
+    /// Compresses the file read from `data` on its own into `spool`, at the level that suits
+    /// `decision`, then adds it with `append_spooled`.
+    fn compress_alone(
+        &mut self,
+        spool: &mut Spool,
+        decision: EntropyAnalysis,
+        header: &mut Header,
+        path: &Path,
+        xattrs: &Xattrs,
+        data: impl Read,
+    ) -> Result<()> {
+        let level = per_file_level(self.codec, self.compression_level, decision);
+        let compressed_len =
+            per_file::compress(spool, self.codec, level, self.io_buffer_size, data)
+                .with_path("Could not compress", path)
+                .map_err(|e| change::name_change(e, path))?;
+        self.append_spooled(decision, header, path, xattrs, spool, compressed_len)
+    }
+
+    /// Adds a file that was already compressed on its own into `spool` by `per_file::compress`.
+    ///
+    /// With `no_expand`, the file is stored as-is instead if compressing it made it bigger. A file
+    /// whose `decision` was to store it as-is, and was only compressed for `compress_all`, is
+    /// stored as-is unless compressing it made it smaller.
+    fn append_spooled(
+        &mut self,
+        decision: EntropyAnalysis,
+        header: &mut Header,
+        path: &Path,
+        xattrs: &Xattrs,
+        spool: &mut Spool,
+        compressed_len: u64,
+    ) -> Result<()> {
+        let size = header.size()?;
+        let expanded = match decision {
+            EntropyAnalysis::DontCompress => compressed_len >= size,
+            EntropyAnalysis::Compress => self.no_expand && compressed_len > size,
+        };
+
+        let result = if expanded && root_entry_kind_of(path) == RootEntry::File {
+            debug!(
+                "{}: stored as-is, since compressing didn't make it smaller",
+                path.display()
+            );
+            self.summary.stored_files += 1;
+            let data = self.codec.decoder(spool.take(compressed_len))?;
+            xattrs.append_to(&mut self.root_tar)?;
+            self.root_tar.append_data(header, path, data)?;
+            Ok(Location::Root {
+                offset: index::data_offset(root_position(&self.root_tar), size),
+            })
+        } else {
+            self.summary.compressed_files += 1;
+            self.summary.compressed_input_bytes += size;
+            self.summary.compressed_output_bytes += compressed_len;
+            per_file::append_spooled(
+                &mut self.root_tar,
+                spool,
+                compressed_len,
+                self.codec,
+                header,
+                path,
+                xattrs,
+            )
+            .map(|()| Location::Compressed {
+                offset: index::data_offset(root_position(&self.root_tar), compressed_len),
+                len: compressed_len,
+            })
+        };
+
+        let location = result.with_path("Could not add", path)?;
+        self.record_location(path, size, location);
+        self.summary.input_bytes += size;
+        self.progress.file_done(size);
+        Ok(())
+    }
+
+    /// Adds the files that have been opened, in order.
+    ///
+    /// When compressing each file on its own, the files to compress are compressed in parallel
+    /// into their own temporary file first, then added in order, so that the archive doesn't
+    /// depend on thread scheduling.
+    fn append_batch(&mut self, batch: Vec<OpenedFile>) -> Result<()> {
+        if self.per_file_spool.is_none() {
+            for mut opened in batch {
+                self.append(
+                    opened.decision,
+                    &mut opened.header,
+                    &opened.path,
+                    &opened.xattrs,
+                    &mut opened.contents,
+                )?;
+            }
+            return Ok(());
+        }
+
+        let (codec, level, location) = (self.codec, self.compression_level, &self.spools);
+        let buffer_size = self.io_buffer_size;
+        let compress_all = self.raw_spool.is_some();
+        let phase = Span::current();
+        let spools: Vec<Option<Result<(Spool, u64, u32)>>> = batch
+            .par_iter()
+            .map(|opened| {
+                let decision = stored_decision(&opened.path, opened.decision);
+                if decision == EntropyAnalysis::DontCompress && !compress_all {
+                    return None;
+                }
+                let path = opened.path.display();
+                let _span = debug_span!(parent: &phase, "compress", path = %path).entered();
+                let level = per_file_level(codec, level, decision);
+
+                let size = match opened.header.size() {
+                    Ok(size) => size,
+                    Err(e) => return Some(Err(e.into())),
+                };
+                let data = SizedReader::new(opened.contents.shared(), size);
+                let mut data = Crc32Reader::new(data);
+                Some(
+                    per_file::spool(codec, level, location, buffer_size, &mut data)
+                        .map(|(spool, compressed_len)| (spool, compressed_len, data.crc32()))
+                        .with_path("Could not compress", &opened.path)
+                        .map_err(|e| change::name_change(e, &opened.path)),
+                )
+            })
+            .collect();
+
+        for (mut opened, spool) in batch.into_iter().zip(spools) {
+            match spool {
+                Some(spooled) => {
+                    let (mut spool, compressed_len, crc32) = spooled?;
+                    self.append_spooled(
+                        stored_decision(&opened.path, opened.decision),
+                        &mut opened.header,
+                        &opened.path,
+                        &opened.xattrs,
+                        &mut spool,
+                        compressed_len,
+                    )?;
+                    self.record_checksum(&opened.path, crc32);
+                }
+                None => self.append(
+                    opened.decision,
+                    &mut opened.header,
+                    &opened.path,
+                    &opened.xattrs,
+                    &mut opened.contents,
+                )?,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Adds the directories and files from disk, classifying the files as `opts` says.
+    fn append_paths(&mut self, paths: &DiskPaths, opts: &CompressOptions) -> Result<()> {
+        // Analysis is CPU bound so it runs in parallel, while the files are appended in input
+        // order so that the archive doesn't depend on thread scheduling.
+        let analyzed = analyze_files_skipping(
+            &paths.files,
+            opts,
+            &self.progress,
+            &self.throttle,
+            READ_ONCE_BUDGET_BYTES,
+        )?;
+        self.append_analyzed(paths, analyzed, opts)
+    }
+
+    /// Adds the directories and files from disk, given the analysis of the files and the files
+    /// that it skipped.
+    fn append_analyzed(
+        &mut self,
+        paths: &DiskPaths,
+        (analyses, skipped): (Vec<AnalyzedFile>, Vec<PathBuf>),
+        opts: &CompressOptions,
+    ) -> Result<()> {
+        self.append_dirs_and_symlinks(paths, opts)?;
+        self.append_files(&paths.files, (analyses, skipped), opts)
+    }
+
+    /// Adds the directories and symlinks from disk, which come first, so that the directories are
+    /// ahead of their contents in the archive.
+    fn append_dirs_and_symlinks(
+        &mut self,
+        paths: &DiskPaths,
+        opts: &CompressOptions,
+    ) -> Result<()> {
+        for dir in &paths.dirs {
+            let Some(name) = self.entry_name(dir)? else {
+                continue;
+            };
+            let mut header =
+                disk_header(&fs::metadata(dir).with_path("Could not read", dir)?, opts)?;
+            header.set_size(0);
+            let xattrs = self.disk_xattrs(dir, true)?;
+            self.append_dir(&mut header, &name, &xattrs)?;
+        }
+
+        for symlink in &paths.symlinks {
+            let name = self.file_entry_name(symlink)?;
+            let metadata = fs::symlink_metadata(symlink).with_path("Could not read", symlink)?;
+            let target = fs::read_link(symlink).with_path("Could not read the link", symlink)?;
+            let mut header = disk_header(&metadata, opts)?;
+            header.set_size(0);
+            let xattrs = self.disk_xattrs(symlink, false)?;
+            self.append_symlink(&mut header, &name, &target, &xattrs)?;
+        }
+        Ok(())
+    }
+
+    /// Adds the `files` from disk, given the analysis of the files and the files that it skipped,
+    /// recording how many of them are done in the checkpoint as they are added.
+    fn append_files(
+        &mut self,
+        files: &[PathBuf],
+        (analyses, skipped): (Vec<AnalyzedFile>, Vec<PathBuf>),
+        opts: &CompressOptions,
+    ) -> Result<()> {
+        self.summary.skipped.extend(skipped);
+        self.progress.phase("compressing");
+        let _span = info_span!("write").entered();
+
+        // How many of the files were added or left out, which the analyses skipped some of
+        let mut done = 0;
+
+        // Only so many files are kept open at once, while enough of them to keep every thread busy
+        // are compressed together when compressing each file on its own. Each of those also has
+        // a spool open.
+        let batch_size = match self.per_file_spool {
+            Some(_) => (rayon::current_num_threads() * 2).min(opts.max_files_open().get() / 2),
+            None => 1,
+        }
+        .max(1);
+        let mut batch = Vec::with_capacity(batch_size);
+
+        for AnalyzedFile { analysis, contents } in analyses {
+            if let Some(position) = files[done..].iter().position(|path| *path == analysis.path) {
+                done += position + 1;
+            }
+            report_decision(&analysis, opts.show_entropy, &self.progress);
+
+            // The file was analyzed through each of its paths, but its contents are only stored
+            // under the first
+            let mut link_id = None;
+            if !opts.hard_dereference {
+                // It can disappear after it has been analyzed
+                let metadata = match fs::metadata(&analysis.path)
+                    .with_path("Could not read", &analysis.path)
+                {
+                    Ok(metadata) => metadata,
+                    Err(e) if opts.skips(&e) => {
+                        self.skip_file(analysis.path, &e);
+                        continue;
+                    }
+                    Err(e) => return Err(e),
+                };
+                link_id = hard_link_id(&metadata);
+                let first = link_id.and_then(|id| self.first_links.get(&id).cloned());
+                if let Some(first) = first {
+                    info!(
+                        "{}: stored as a hard link to {}",
+                        analysis.path.display(),
+                        first.display()
+                    );
+                    let name = self.file_entry_name(&analysis.path)?;
+                    self.summary.hard_links += 1;
+                    self.summary.input_bytes += metadata.len();
+                    self.progress.file_done(metadata.len());
+                    let mut header = disk_header(&metadata, opts)?;
+                    self.append_hard_link(&mut header, &name, &first)?;
+                    continue;
+                }
+            }
+
+            let (mut contents, header) = match contents {
+                Some(ReadContents { data, metadata }) => (
+                    Contents::Memory(Cursor::new(data)),
+                    disk_header(&metadata, opts)?,
+                ),
+                // Open the file. It can still disappear after it has been analyzed.
+                None => match self.open_file(&analysis.path, opts) {
+                    Ok(opened) => opened,
+                    Err(e) if opts.skips(&e) => {
+                        self.skip_file(analysis.path, &e);
+                        continue;
+                    }
+                    Err(e) => return Err(e),
+                },
+            };
+
+            // Its contents are in the archive from here on, so its other paths can link to it
+            let name = self.file_entry_name(&analysis.path)?;
+            if let Some(id) = link_id {
+                if let Some(checkpoint) = &mut self.checkpoint {
+                    checkpoint.first_links.push((id, name.clone()));
+                }
+                self.first_links.insert(id, name.clone());
+            }
+
+            if let Some(deduplicator) = &mut self.deduplicator {
+                let size = header.size()?;
+                if let Some(original) =
+                    deduplicator.original_of(&analysis.path, size, &mut contents)?
+                {
+                    info!(
+                        "{}: stored as a copy of {}",
+                        analysis.path.display(),
+                        original.display()
+                    );
+                    self.summary.deduplicated_files += 1;
+                    self.summary.input_bytes += size;
+                    self.progress.file_done(size);
+                    let original = self.file_entry_name(&original)?;
+                    self.copies.copies.push((name, original));
+                    continue;
+                }
+            }
+
+            batch.push(OpenedFile {
+                header,
+                xattrs: self.disk_xattrs(&analysis.path, true)?,
+                path: name,
+                decision: analysis.decision,
+                contents,
+            });
+            if batch.len() == batch_size {
+                self.append_batch(std::mem::take(&mut batch))?;
+                self.record_checkpoint(done, false)?;
+            }
+        }
+
+        self.append_batch(batch)?;
+        self.record_checkpoint(files.len(), true)
+    }
+
+    /// Leaves out the file at `path`, which couldn't be read because of `error`.
+    fn skip_file(&mut self, path: PathBuf, error: &TtareError) {
+        self.progress
+            .warn(format_args!("skipping {}: {:#}", path.display(), error));
+        self.summary.skipped.push(path);
+    }
+
+    /// Records in the checkpoint, if there is one, that `done` of the files given to
+    /// `append_files` were added, once enough of them were added since the last record, or
+    /// whenever it's `forced`.
+    fn record_checkpoint(&mut self, done: usize, forced: bool) -> Result<()> {
+        let Some(mut checkpoint) = self.checkpoint.take() else {
+            return Ok(());
+        };
+        let result = if forced || checkpoint.is_due(done, self.summary.input_bytes) {
+            // What the record says was written has to be written before it
+            self.root_tar
+                .get_mut()
+                .flush()
+                .and_then(|()| self.compress_tar.get_mut().flush())
+                .with_action("Could not write the archive")
+                .and_then(|()| checkpoint.record(self, done))
+        } else {
+            Ok(())
+        };
+        self.checkpoint = Some(checkpoint);
+        result
+    }
+
+    /// Opens the file at `path` to add it to the archive, along with its header.
+    ///
+    /// Unless `on_change` fails on a change, which is caught as the entry is written, the contents
+    /// are copied to a spool first, so that the file can be read again up to `ON_CHANGE_RETRIES`
+    /// times or skipped before its entry is written if they don't have the size of the header.
+    fn open_file(&self, path: &Path, opts: &CompressOptions) -> Result<(Contents, Header)> {
+        let mut retries = 0;
+        loop {
+            let file = File::open(path).with_path("Could not open", path)?;
+            let header = disk_header(&file.metadata()?, opts)?;
+            change::before_read(path);
+            let mut file = self.throttle.wrap(file);
+            if opts.on_change == OnChange::Fail {
+                return Ok((Contents::Disk(file), header));
+            }
+
+            let mut spool = self.spools.spool()?;
+            let copied = io::copy(&mut SizedReader::new(&mut file, header.size()?), &mut spool)
+                .with_path("Could not read", path)
+                .map_err(|e| change::name_change(e, path));
+            match copied {
+                Ok(_) => {
+                    spool.seek(SeekFrom::Start(0))?;
+                    return Ok((Contents::Spooled(spool), header));
+                }
+                Err(TtareError::FileChanged(_))
+                    if opts.on_change == OnChange::Retry && retries < ON_CHANGE_RETRIES =>
+                {
+                    self.progress.warn(format_args!(
+                        "{} changed while it was read, reading it again",
+                        path.display()
+                    ));
+                    retries += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// The name that the file at `path` on disk is stored under, telling the user the first time
+    /// that a leading `/` or `..` is removed.
+    fn entry_name(&mut self, path: &Path) -> Result<Option<PathBuf>> {
+        let name = entry_name(path, self.base_dir.as_deref(), &self.name_map)?;
+        let stripped = matches!(
+            path.components().next(),
+            Some(Component::Prefix(_) | Component::RootDir | Component::ParentDir)
+        );
+        let named = self.base_dir.is_some() || mapped_name(path, &self.name_map).is_some();
+        if stripped && !named && !self.stripped_names {
+            self.progress.warn(format_args!(
+                "removing the leading `/` and `..` from the names of the entries"
+            ));
+            self.stripped_names = true;
+        }
+        Ok(name)
+    }
+
+    /// The name of a file or symlink, which can't be the base directory.
+    fn file_entry_name(&mut self, path: &Path) -> Result<PathBuf> {
+        self.entry_name(path)?
+            .ok_or_else(|| TtareError::OutsideBaseDir {
+                path: path.to_path_buf(),
+                base_dir: self.base_dir.clone().unwrap_or_default(),
+            })
+    }
+
+    /// The extended attributes of the file at `path` on disk, following it if it's a symlink and
+    /// `follow` is set, or none if they aren't stored.
+    fn disk_xattrs(&self, path: &Path, follow: bool) -> Result<Xattrs> {
+        if self.xattrs {
+            Xattrs::read(path, follow)
+        } else {
+            Ok(Xattrs::default())
+        }
+    }
+
+    /// Adds a directory entry to the root tar.
+    fn append_dir(&mut self, header: &mut Header, path: &Path, xattrs: &Xattrs) -> Result<()> {
+        debug!("adding directory {}", path.display());
+        xattrs
+            .append_to(&mut self.root_tar)
+            .and_then(|()| self.root_tar.append_data(header, path, io::empty()))
+            .with_path("Could not add", path)
+    }
+
+    /// Adds a symlink to `target` to the root tar.
+    fn append_symlink(
+        &mut self,
+        header: &mut Header,
+        path: &Path,
+        target: &Path,
+        xattrs: &Xattrs,
+    ) -> Result<()> {
+        debug!("adding symlink {} to {}", path.display(), target.display());
+        xattrs
+            .append_to(&mut self.root_tar)
+            .and_then(|()| self.root_tar.append_link(header, path, target))
+            .with_path("Could not add", path)
+    }
+
+    /// Adds a hard link to `target`, an earlier entry of the archive, to the root tar.
+    fn append_hard_link(&mut self, header: &mut Header, path: &Path, target: &Path) -> Result<()> {
+        debug!(
+            "adding hard link {} to {}",
+            path.display(),
+            target.display()
+        );
+        header.set_entry_type(EntryType::Link);
+        header.set_size(0);
+        self.root_tar
+            .append_link(header, path, target)
+            .with_path("Could not add", path)?;
+        let original = target.to_path_buf();
+        self.record_location(path, 0, Location::Copy { original });
+        Ok(())
+    }
+
+    /// Adds the compressed tar to the root tar and finishes writing the archive.
+    fn finish(self) -> Result<CompressSummary> {
+        let _span = info_span!("finish").entered();
+        let ArchiveWriter {
+            mut root_tar,
+            mut compress_tar,
+            codec,
+            layout,
+            copies,
+            mut summary,
+            progress,
+            mtime,
+            no_expand,
+            member_files,
+            member_input_bytes,
+            member_has_reserved_names,
+            manifest,
+            mut index,
+            zstd_dictionary,
+            strip_trailer,
+            ..
+        } = self;
+
+        // Finish compressing the compressed tar
+        compress_tar.get_mut().cut = strip_trailer;
+        let (mut spool, crc32) = compress_tar.into_inner()?.inner.inner.finish()?;
+        let mut compressed_len = spool.stream_position()?;
+        spool.seek(SeekFrom::Start(0))?;
+
+        // The files are only moved out of the compressed member if they can all be moved, since the
+        // checksum and the member are left out for an archive without compressed files. An empty
+        // member would only be the codec's overhead, so it is left out too.
+        let expanded = compressed_len > member_input_bytes;
+        let keep_member =
+            member_files > 0 && !(expanded && no_expand && !member_has_reserved_names);
+        if keep_member {
+            summary.compressed_output_bytes += compressed_len;
+        } else if member_files > 0 {
+            info!(
+                "storing the {} compressed files as-is, since compressing them made them bigger",
+                member_files
+            );
+            let member = codec.decoder_with_dictionary(&mut spool, zstd_dictionary.as_ref())?;
+            let mut member = Archive::new(member);
+            // The files are moved in the order they were added, and so is what the index knows
+            // of them
+            let mut moved = index.as_mut().map(|index| {
+                index
+                    .files
+                    .iter_mut()
+                    .filter(|file| matches!(file.location, Location::Member { .. }))
+            });
+            for entry in member.entries()? {
+                let mut entry = entry?;
+                let path = entry.path()?.into_owned();
+                let mut header = entry.header().clone();
+                let size = entry.size();
+                Xattrs::of_entry(&mut entry)?
+                    .append_to(&mut root_tar)
+                    .and_then(|()| root_tar.append_data(&mut header, &path, entry))
+                    .with_path("Could not add", &path)?;
+                if let Some(file) = moved.as_mut().and_then(Iterator::next) {
+                    let offset = index::data_offset(root_position(&root_tar), size);
+                    file.location = Location::Root { offset };
+                }
+            }
+
+            summary.compressed_files -= member_files;
+            summary.stored_files += member_files;
+            summary.compressed_input_bytes -= member_input_bytes;
+        }
+        if !keep_member {
+            compressed_len = 0;
+        }
+
+        if !copies.copies.is_empty() {
+            let manifest = copies.to_bytes()?;
+            let mut header = data_header(manifest.len() as u64, mtime);
+            root_tar.append_data(
+                &mut header,
+                Path::new(TTARE_DEDUP_FILE_NAME),
+                manifest.as_slice(),
+            )?;
+
+            // Ahead of the hard links, which are the only copies so far, as the archive lists them
+            if let Some(index) = &mut index {
+                let hard_links = index
+                    .files
+                    .iter()
+                    .position(|file| matches!(file.location, Location::Copy { .. }))
+                    .unwrap_or(index.files.len());
+                let mut copied = ArchiveIndex::default();
+                for (copy, original) in &copies.copies {
+                    let original = normalize_entry_path(original);
+                    copied.add(copy, 0, Location::Copy { original });
+                }
+                index.files.splice(hard_links..hard_links, copied.files);
+            }
+        }
+
+        if let Some(manifest) = manifest {
+            let manifest = manifest.to_bytes()?;
+            let mut header = data_header(manifest.len() as u64, mtime);
+            root_tar.append_data(
+                &mut header,
+                Path::new(TTARE_MANIFEST_FILE_NAME),
+                manifest.as_slice(),
+            )?;
+        }
+
+        if keep_member {
+            // Add the checksum ahead of the compressed tar, so it is known when the compressed tar
+            // is read
+            let checksum = format!("{:08x}\n", crc32);
+            let mut header = data_header(checksum.len() as u64, mtime);
+            root_tar.append_data(
+                &mut header,
+                Path::new(TTARE_CHECKSUM_FILE_NAME),
+                checksum.as_bytes(),
+            )?;
+
+            // Add the compressed tar to the root tar
+            let mut header = data_header(compressed_len, mtime);
+            root_tar.append_data(
+                &mut header,
+                Path::new(codec.member_name()),
+                progress.writing(compressed_len, spool),
+            )?;
+            if let Some(index) = &mut index {
+                index.member = Some(index::data_offset(root_position(&root_tar), compressed_len));
+            }
+        }
+
+        // The index comes last, once where everything else is is known
+        if let Some(index) = index {
+            let index = index.to_bytes()?;
+            let mut header = data_header(index.len() as u64, mtime);
+            root_tar.append_data(
+                &mut header,
+                Path::new(TTARE_INDEX_FILE_NAME),
+                index.as_slice(),
+            )?;
+        }
+
+        if summary.compressed_output_bytes > summary.compressed_input_bytes {
+            progress.warn(format_args!(
+                "compressing {} files made them bigger, from {} to {} bytes",
+                summary.compressed_files,
+                summary.compressed_input_bytes,
+                summary.compressed_output_bytes
+            ));
+        }
+
+        // Finish writing the root tar to the output file
+        root_tar.get_mut().cut = strip_trailer;
+        let output = root_tar
+            .into_inner()?
+            .inner
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .finish()?;
+        progress.finish();
+
+        summary.compressed_member_bytes = compressed_len;
+        summary.archive_bytes = output.written;
+        if layout == (Layout::Plain { compressed: true }) {
+            summary.compressed_output_bytes = summary.archive_bytes;
+        }
+        if summary.input_bytes > 0 {
+            summary.ratio = Some(summary.archive_bytes as f64 / summary.input_bytes as f64);
+        }
+        if summary.compressed_input_bytes > 0 {
+            summary.compression_ratio = Some(
+                summary.compressed_output_bytes as f64 / summary.compressed_input_bytes as f64,
+            );
+        }
+
+        info!(
+            "{} files compressed, {} stored, {} deduplicated, {} hard links",
+            summary.compressed_files,
+            summary.stored_files,
+            summary.deduplicated_files,
+            summary.hard_links
+        );
+        info!(
+            "{} bytes in, {} bytes out, with a compressed member of {} bytes",
+            summary.input_bytes, summary.archive_bytes, summary.compressed_member_bytes
+        );
+
+        Ok(summary)
+    }
+}
+
+/// Where the next entry of `root_tar` starts, counting what is still buffered. The root tar of an
+/// archive that is compressed as a whole has no offsets to tell.
+fn root_position<W: Write>(root_tar: &RootTar<W>) -> u64 {
+    let buffered = &root_tar.get_ref().inner;
+    let written = match buffered.get_ref() {
+        RootWriter::Tar(output) => output.written,
+        RootWriter::Compressed(_) => 0,
+    };
+    written + buffered.buffer().len() as u64
+}
+
+/// Counts the bytes written through it, since the output can't always be asked for its size.
+struct CountingWriter<W> {
+    inner: W,
+    written: u64,
+}
+
+impl<W> CountingWriter<W> {
+    fn new(inner: W) -> Self {
+        CountingWriter { inner, written: 0 }
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// What the tar of the compressed member is written to: its encoder, or a file that it's staged
+/// to uncompressed when the archive can be resumed, since an encoder can't be picked up where an
+/// interrupted run left it. What was staged is compressed once every file is added, which
+/// compresses it to the same bytes as the encoder would have.
+enum MemberWriter {
+    Compressed(MemberEncoder),
+    Staged {
+        stage: BufWriter<File>,
+        encoder: MemberEncoder,
+    },
+}
+
+impl MemberWriter {
+    /// Compresses what was staged, if anything was, then writes out the end of the compressed
+    /// stream, returning the spool it was compressed into and its CRC32.
+    fn finish(self) -> io::Result<(Spool, u32)> {
+        let encoder = match self {
+            MemberWriter::Compressed(encoder) => encoder,
+            MemberWriter::Staged { stage, mut encoder } => {
+                let mut stage = stage.into_inner().map_err(|e| e.into_error())?;
+                stage.seek(SeekFrom::Start(0))?;
+                io::copy(&mut stage, &mut encoder)?;
+                encoder
+            }
+        };
+        Ok(encoder
+            .finish()?
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .finish())
+    }
+}
+
+impl Write for MemberWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            MemberWriter::Compressed(encoder) => encoder.write(buf),
+            MemberWriter::Staged { stage, .. } => stage.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            MemberWriter::Compressed(encoder) => encoder.flush(),
+            MemberWriter::Staged { stage, .. } => stage.flush(),
+        }
+    }
+}
+
+/// Creates the encoder that the compressed member is compressed with, into a spool at `spools`.
+fn member_encoder(spools: &SpoolLocation, opts: &CompressOptions) -> Result<MemberEncoder> {
+    opts.codec.encoder(
+        BufWriter::with_capacity(opts.io_buffer_size, Crc32Writer::new(spools.spool()?)),
+        opts.compression_level,
+        opts.zstd_dictionary.as_ref(),
+    )
+}
+
+/// Drops what is written through it once it is cut off, since `tar::Builder` always ends a tar with
+/// two blocks of zeros, which `CompressOptions::strip_trailer` leaves out.
+struct CutOff<W> {
+    inner: W,
+    cut: bool,
+}
+
+impl<W> CutOff<W> {
+    fn new(inner: W) -> Self {
+        CutOff { inner, cut: false }
+    }
+}
+
+impl<W: Write> Write for CutOff<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.cut {
+            return Ok(buf.len());
+        }
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
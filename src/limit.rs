@@ -0,0 +1,73 @@
+use std::{
+    num::NonZeroUsize,
+    sync::{Condvar, Mutex},
+};
+
+/// How many files the process is taken to be allowed to open when the system doesn't say, which
+/// is the default of the C runtime on Windows.
+const FALLBACK_ALLOWED_FILES_OPEN: usize = 512;
+
+/// How many of the files being compressed are open at once when `max_files_open` isn't set: half
+/// of what the process is allowed to open, leaving the rest for the archive, the spools and
+/// whatever else has files open.
+pub fn default_max_files_open() -> NonZeroUsize {
+    let allowed = allowed_files_open().unwrap_or(FALLBACK_ALLOWED_FILES_OPEN);
+    NonZeroUsize::new(allowed / 2).unwrap_or(NonZeroUsize::MIN)
+}
+
+/// The soft limit on the file descriptors of the process.
+#[cfg(unix)]
+fn allowed_files_open() -> Option<usize> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: `limit` is a valid `rlimit` for `getrlimit` to write to
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        return None;
+    }
+    Some(usize::try_from(limit.rlim_cur).unwrap_or(usize::MAX))
+}
+
+#[cfg(not(unix))]
+fn allowed_files_open() -> Option<usize> {
+    None
+}
+
+/// Blocks the threads analyzing files once `max` of them have a file open, until one of them is
+/// done with its file.
+pub(crate) struct OpenFileLimit {
+    available: Mutex<usize>,
+    released: Condvar,
+}
+
+impl OpenFileLimit {
+    pub(crate) fn new(max: NonZeroUsize) -> Self {
+        OpenFileLimit {
+            available: Mutex::new(max.get()),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Waits until another file can be opened, which it can until the permit is dropped.
+    pub(crate) fn acquire(&self) -> OpenFilePermit<'_> {
+        let mut available = self.available.lock().unwrap();
+        while *available == 0 {
+            available = self.released.wait(available).unwrap();
+        }
+        *available -= 1;
+        OpenFilePermit { limit: self }
+    }
+}
+
+/// Allows a file to be open while it is held.
+pub(crate) struct OpenFilePermit<'a> {
+    limit: &'a OpenFileLimit,
+}
+
+impl Drop for OpenFilePermit<'_> {
+    fn drop(&mut self) {
+        *self.limit.available.lock().unwrap() += 1;
+        self.limit.released.notify_one();
+    }
+}
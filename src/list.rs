@@ -0,0 +1,168 @@
+use std::{path::Path, path::PathBuf};
+
+use tar::Archive;
+
+use crate::{
+    dedup::DedupManifest,
+    hard_link_target,
+    index::{self, IndexedArchive, Location},
+    meta::ArchiveMeta,
+    normalize_entry_path, plain, root_entry_kind, split, Result, RootEntry, TtareError,
+};
+
+/// A file stored in a ttare archive.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ListEntry {
+    /// The path of the file in the archive.
+    pub path: PathBuf,
+
+    /// The size of the file, before compression.
+    pub size: u64,
+
+    /// Whether the file was stored compressed, in the compressed member, on its own or in a plain
+    /// tar.gz, rather than as-is.
+    pub compressed: bool,
+}
+
+/// What a ttare archive holds, as read by `list`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Listing {
+    /// The files in the archive.
+    pub entries: Vec<ListEntry>,
+
+    /// When the archive was created, as seconds since the Unix epoch, or `None` if it doesn't say.
+    pub created: Option<u64>,
+
+    /// Whether the compressible files were bundled together in the compressed member, rather than
+    /// compressed each on its own, or `None` for a plain tar, which doesn't say.
+    pub solid: Option<bool>,
+}
+
+/// Lists the files in the ttare archive at `input`, without extracting them.
+///
+/// The files inside the compressed member are listed in its place, and the files stored as copies
+/// of another file are listed last, like their original. The creation time is the modification
+/// time of the compressed member, or of the metadata for an archive without one, which are both
+/// set when the archive is written.
+///
+/// An archive with an index is listed from it, in the same order, without decompressing the
+/// compressed member.
+pub fn list(input: &Path) -> Result<Listing> {
+    if let Some(indexed) = index::read_index(input)? {
+        return list_indexed(indexed);
+    }
+
+    // The files of a plain tar.gz are compressed along with the whole of it
+    let (root_tar, gzipped) = plain::open_root_tar(split::open_archive(input)?)?;
+    let mut archive = Archive::new(root_tar);
+    let mut entries = vec![];
+    let mut meta = None;
+    let mut dedup = DedupManifest::default();
+    let mut hard_links = vec![];
+    let mut created = None;
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+
+        match root_entry_kind(&mut entry)? {
+            RootEntry::Meta => {
+                created = Some(entry.header().mtime()?);
+                meta = Some(ArchiveMeta::read(entry)?);
+            }
+            RootEntry::Member(codec) => {
+                created = Some(entry.header().mtime()?);
+                let decoder = ArchiveMeta::member_decoder(meta.as_ref(), codec, None, entry)?;
+                let mut tar = Archive::new(decoder);
+                for inner in tar.entries()? {
+                    let inner = inner?;
+                    entries.push(ListEntry {
+                        path: inner.path()?.into_owned(),
+                        size: inner.size(),
+                        compressed: true,
+                    });
+                }
+            }
+            RootEntry::Compressed(file) => {
+                entries.push(ListEntry {
+                    path: file.path,
+                    size: file.size,
+                    compressed: true,
+                });
+            }
+            RootEntry::Dedup => dedup = DedupManifest::read(entry)?,
+            RootEntry::HardLink => {
+                hard_links.push((path.clone(), hard_link_target(&entry, &path)?))
+            }
+            RootEntry::Checksum
+            | RootEntry::Manifest
+            | RootEntry::Index
+            | RootEntry::Directory
+            | RootEntry::Symlink => {}
+            RootEntry::File => {
+                entries.push(ListEntry {
+                    path,
+                    size: entry.size(),
+                    compressed: gzipped,
+                });
+            }
+        }
+    }
+
+    // A hard link is listed like a copy of the file it links to, which can be a copy itself
+    dedup.copies.extend(hard_links);
+    for (copy, original) in dedup.copies {
+        let original = entries
+            .iter()
+            .find(|entry| normalize_entry_path(&entry.path) == original)
+            .ok_or_else(|| {
+                TtareError::CorruptArchive(format!(
+                    "{} is a copy of a missing file",
+                    copy.display()
+                ))
+            })?;
+        entries.push(ListEntry {
+            path: copy,
+            ..original.clone()
+        });
+    }
+
+    Ok(Listing {
+        entries,
+        created,
+        solid: meta.map(|meta| !meta.per_file_compression),
+    })
+}
+
+/// Lists the files that the index of an archive knows of, in the order a full read of the archive
+/// lists them: the files of the root tar, those of the compressed member, then the copies.
+fn list_indexed(indexed: IndexedArchive) -> Result<Listing> {
+    let IndexedArchive {
+        index,
+        meta,
+        created,
+    } = indexed;
+
+    let mut entries = vec![];
+    for (position, file) in index.files.iter().enumerate() {
+        let original = index.find(&file.path)?.expect("the file is in the index");
+        let order = match file.location {
+            Location::Root { offset } | Location::Compressed { offset, .. } => (0, offset),
+            Location::Member { offset } => (1, offset),
+            Location::Copy { .. } => (2, position as u64),
+        };
+        let entry = ListEntry {
+            path: file.path.clone(),
+            size: original.size,
+            compressed: original.location.is_compressed(),
+        };
+        entries.push((order, entry));
+    }
+    entries.sort_by_key(|(order, _)| *order);
+
+    Ok(Listing {
+        entries: entries.into_iter().map(|(_, entry)| entry).collect(),
+        created: Some(created),
+        solid: Some(!meta.per_file_compression),
+    })
+}
@@ -1,235 +1,1585 @@
 use std::{
-    fs::File,
-    io::{Read, Seek, SeekFrom, Write},
-    path::Path,
-    time::SystemTime,
+    env,
+    error::Error,
+    fmt,
+    fmt::Write as _,
+    fs::{self, File},
+    io::{self, BufWriter, IsTerminal, Write},
+    num::{NonZeroU64, NonZeroUsize},
+    path::{Path, PathBuf},
+    process::{self, ExitCode},
+    time::Instant,
 };
 
-use clap::{command, Parser, Subcommand};
-use color_eyre::{
-    eyre::{self, Context},
-    Result,
+use clap::{
+    error::ErrorKind, parser::ValueSource, ArgAction, ArgMatches, Args, CommandFactory,
+    FromArgMatches, Parser, Subcommand,
 };
-use eyre::eyre;
-use flate2::{
-    read::{GzDecoder, GzEncoder},
-    Compression,
+use clap_complete::Shell;
+use color_eyre::{eyre::Context, Report, Result};
+use indicatif::HumanBytes;
+use log::{Level, LevelFilter};
+use owo_colors::{OwoColorize, Stream};
+use tracing::{
+    field::{Field, Visit},
+    span::{Attributes, Id},
+    Subscriber,
+};
+use tracing_subscriber::{
+    filter::LevelFilter as SpanLevel,
+    layer::{Context as LayerContext, SubscriberExt},
+    registry::LookupSpan,
+    Layer,
+};
+use ttare::{
+    gather_files, read_file_list, suggest_threshold, threshold_from_percentage, Codec,
+    CompressOptions, DatasetStats, DecisionRules, DecompressOptions, EntropyAnalysis, Estimator,
+    FileAnalysis, OnChange, RecompressOptions, SizeEstimate, TtareError, WalkOptions,
+    ZstdDictionary, COMPRESSIBLE_FRACTION, ENTROPY_SAMPLING, IO_BUFFER_SIZE, MIN_SAMPLE_BYTES,
+    SMALL_FILE_BYTES,
 };
-use rayon::prelude::*;
-use rustc_hash::FxHashMap;
-use tar::{Archive, Header};
-
-/// For each file, analysis of the file's entropy is computed, and a decision to either compress or not compress the file is made.
-enum EntropyAnalysis {
-    Compress,
-    DontCompress,
-}
-
-/// The threshold of the entropy, at which any file with entropy above this threshold will not be compressed.
-const ENTROPY_THRESHOLD: f32 = 6.5f32;
-
-/// The percentage of the file to sample to compute the entropy.
-const ENTROPY_SAMPLING: f32 = 0.5f32;
-
-/// The name of the internal file in the tar archive that contains the files that were compressed.
-const TTARE_COMPRESS_FILE_NAME: &str = ".ttare.tar.gz";
 
 #[derive(Parser, Debug)]
 #[command(author, version, about)]
 #[command(propagate_version = true)]
+#[command(after_long_help = EXIT_CODES)]
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+
+    /// Prints each file's entropy with -v and every entry with -vv, and hides the progress bar
+    #[arg(short, long, action = ArgAction::Count, global = true)]
+    verbose: u8,
+
+    /// Only prints errors, without warnings or the progress bar
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// How many threads analyze and compress files in parallel. 0 uses one per core.
+    #[arg(short, long, value_name = "N", default_value_t = 0, global = true)]
+    jobs: usize,
+
+    /// Prints how long each phase took to stderr, and with -v how long each file took
+    #[arg(long, global = true)]
+    trace: bool,
+
+    /// Never colors the output, which is otherwise colored when stdout is a terminal and $NO_COLOR isn't set
+    #[arg(long, global = true)]
+    no_color: bool,
 }
 
 #[derive(Subcommand, Debug)]
 enum Commands {
     /// Compresses a list of files
-    Compress {
-        /// The files to compress
-        files: Vec<String>,
+    Compress(Box<CompressArgs>),
+
+    /// Decompresses a ttare file
+    Decompress {
+        /// The ttare file to decompress, or the first part of a split one. Use - for stdin.
+        input_file: PathBuf,
+
+        /// With --to-stdout, the path of the file to write, when the archive holds more than one
+        #[arg(requires = "to_stdout")]
+        path: Option<PathBuf>,
 
-        /// The destination ttare file
+        /// The destination directory. Defaults to the current directory.
         #[arg(short, long)]
-        output_file: String,
+        output_dir: Option<PathBuf>,
+
+        /// Writes the only file in the archive, or the given path, to stdout instead of extracting files
+        #[arg(long, conflicts_with = "output_dir")]
+        to_stdout: bool,
+
+        /// Gives the files the owner and group recorded in the archive, on Unix, which only root can
+        #[arg(long, conflicts_with = "to_stdout")]
+        preserve_owner: bool,
+
+        /// The zstd dictionary the archive was compressed with, if any
+        #[arg(long, value_name = "FILE", conflicts_with = "to_stdout")]
+        zstd_dict: Option<PathBuf>,
+
+        /// Replaces the files in the destination directory that are also in the archive
+        #[arg(long, conflicts_with = "to_stdout")]
+        overwrite: bool,
 
-        /// The percentage of the file to sample to compute the entropy.
+        /// Gives the files the extended attributes recorded in the archive, on Unix
+        #[arg(long, conflicts_with = "to_stdout")]
+        xattrs: bool,
+
+        /// Drops the first N components of each path before extracting it, like tar --strip-components
+        #[arg(
+            long,
+            value_name = "N",
+            default_value_t = 0,
+            conflicts_with = "to_stdout"
+        )]
+        strip_components: usize,
+
+        /// Extracts what can be extracted from a corrupt archive, then fails naming what couldn't be
+        #[arg(long, conflicts_with = "to_stdout")]
+        best_effort: bool,
+    },
+
+    /// Lists the files in a ttare file, marking those stored compressed (C) or raw (R)
+    List {
+        /// The ttare file to list
+        input_file: PathBuf,
+    },
+
+    /// Extracts a single file from a ttare file
+    Extract {
+        /// The ttare file to extract from
+        input_file: PathBuf,
+
+        /// The path of the file in the archive
+        path: PathBuf,
+
+        /// Where to write the file. Use - to write it to stdout.
         #[arg(short, long)]
-        sample_percentage: Option<f32>,
+        output: PathBuf,
+    },
+
+    /// Prints the contents of files in a ttare file to stdout, without extracting them
+    Cat {
+        /// The ttare file to read from
+        input_file: PathBuf,
+
+        /// The paths of the files in the archive
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+    },
+
+    /// Adds files to a ttare file, classifying them like the files already in it
+    Append {
+        /// The ttare file to add to
+        archive: PathBuf,
+
+        /// The files to add
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+
+        /// Adds the contents of directories, recursively
+        #[arg(short, long)]
+        recursive: bool,
+
+        /// Where to spool the compressed data before it is added to the archive. Defaults to $TMPDIR.
+        #[arg(long, value_name = "DIR")]
+        temp_dir: Option<PathBuf>,
+
+        /// The zstd dictionary the archive was compressed with, if any
+        #[arg(long, value_name = "FILE")]
+        zstd_dict: Option<PathBuf>,
+
+        /// Stores the files under their paths relative to this directory, which they all have to be in
+        #[arg(short = 'C', long, value_name = "DIR")]
+        base_dir: Option<PathBuf>,
 
-        /// The threshold of the entropy, at which any file with entropy above this threshold will not be compressed.
+        /// Stores the extended attributes of the files added, on Unix
+        #[arg(long)]
+        xattrs: bool,
+    },
+
+    /// Writes the files of a ttare file into a new one, such as with another codec
+    Recompress {
+        /// The ttare file to recompress
+        input_file: PathBuf,
+
+        /// The new ttare file
         #[arg(short, long)]
+        output_file: PathBuf,
+
+        /// Replaces the new ttare file if it already exists, instead of failing
+        #[arg(short, long)]
+        force: bool,
+
+        /// The codec of the new ttare file
+        #[arg(short, long, value_enum, default_value_t)]
+        codec: Codec,
+
+        /// The compression level, from 0 to 9, or to 19 for zstd and 11 for brotli
+        #[arg(short = 'l', long, value_parser = clap::value_parser!(u32).range(0..=19))]
+        compression_level: Option<u32>,
+
+        /// Classifies the files with this threshold instead of the one the ttare file was written with
+        #[arg(short, long, value_parser = parse_entropy_threshold)]
         entropy_threshold: Option<f32>,
+
+        /// Samples this percentage of each file instead of the one the ttare file was written with
+        #[arg(short, long, value_parser = parse_sample_percentage)]
+        sample_percentage: Option<f32>,
+
+        /// The zstd dictionary of the ttare file, which zstd compresses the new one with too
+        #[arg(long, value_name = "FILE")]
+        zstd_dict: Option<PathBuf>,
+
+        /// Where to extract the files before they are added to the new ttare file. Defaults to $TMPDIR.
+        #[arg(long, value_name = "DIR")]
+        temp_dir: Option<PathBuf>,
     },
 
-    /// Decompresses a ttare file
-    Decompress {
-        /// The ttare file to decompress
-        input_file: String,
+    /// Checks that a ttare file isn't corrupt, without extracting it
+    Verify {
+        /// The ttare file to check
+        input_file: PathBuf,
+    },
 
-        /// The destination directory. Defaults to the current directory.
+    /// Prints the paths added (A), removed (D) or changed (M) in the second of two ttare files
+    Compare {
+        /// The ttare file to compare against
+        first: PathBuf,
+
+        /// The ttare file to compare
+        second: PathBuf,
+    },
+
+    /// Analyzes files as compress would, and prints how many would be compressed and how much that could save
+    Stats {
+        /// The files and directories to analyze
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+
+        /// The codec whose default threshold the files are classified with
+        #[arg(short, long, value_enum, default_value_t)]
+        codec: Codec,
+
+        /// Classifies the files with this threshold, in bits per byte, not the codec's default
+        #[arg(short, long, env = "TTARE_ENTROPY_THRESHOLD", value_parser = parse_entropy_threshold)]
+        entropy_threshold: Option<f32>,
+
+        /// The percentage of each file to sample to compute its entropy. Defaults to 0.5.
+        #[arg(short, long, env = "TTARE_SAMPLE_PERCENTAGE", value_parser = parse_sample_percentage)]
+        sample_percentage: Option<f32>,
+
+        /// Counts the files that start like a compressed format as stored, like compress --sniff
+        #[arg(long)]
+        sniff: bool,
+
+        /// Also prints how many files fall in each half a bit per byte of entropy
+        #[arg(long)]
+        histogram: bool,
+
+        /// Prints the statistics as JSON to stdout instead, histogram included
+        #[arg(long, conflicts_with = "histogram")]
+        json: bool,
+    },
+
+    /// Prints the counts of each byte value in the sample of a file, as CSV
+    #[command(hide = true)]
+    Histogram {
+        /// The file to count the bytes of
+        file: PathBuf,
+
+        /// Counts every byte of the file, like compress --full-entropy, instead of a sample
+        #[arg(long)]
+        full_entropy: bool,
+
+        /// Where to write the CSV. Defaults to stdout.
         #[arg(short, long)]
-        output_dir: Option<String>,
+        output: Option<PathBuf>,
     },
+
+    /// Prints a completion script for the shell to stdout
+    Completions {
+        /// The shell to complete ttare's commands and flags in
+        shell: Shell,
+    },
+}
+
+#[derive(Args, Debug)]
+struct CompressArgs {
+    /// The files to compress
+    files: Vec<PathBuf>,
+
+    /// The destination ttare file. Use - to write it to stdout.
+    #[arg(short, long, required_unless_present_any = ["dry_run", "threshold_tune"])]
+    output_file: Option<PathBuf>,
+
+    /// Replaces the destination ttare file, or its parts, if it already exists
+    #[arg(short, long)]
+    force: bool,
+
+    /// The percentage of each file to sample to compute its entropy. Defaults to 0.5.
+    #[arg(short, long, env = "TTARE_SAMPLE_PERCENTAGE", value_parser = parse_sample_percentage)]
+    sample_percentage: Option<f32>,
+
+    /// The smallest sample taken from a file, in bytes. Defaults to 64 KiB.
+    #[arg(long, value_name = "BYTES")]
+    min_sample_bytes: Option<u64>,
+
+    /// The largest sample taken from a file, in bytes, however large the file is.
+    #[arg(long, value_name = "BYTES")]
+    max_sample_bytes: Option<u64>,
+
+    /// Computes the entropy over the whole of each file instead of sampling it, which is slower
+    #[arg(long, conflicts_with_all = ["min_sample_bytes", "max_sample_bytes"])]
+    full_entropy: bool,
+
+    /// How the entropy of each file is estimated
+    #[arg(long, value_enum, default_value_t = Estimator::Shannon)]
+    estimator: Estimator,
+
+    /// The entropy, in bits per byte, above which a file is stored as-is. Defaults to the codec's.
+    #[arg(short, long, env = "TTARE_ENTROPY_THRESHOLD", value_parser = parse_entropy_threshold)]
+    entropy_threshold: Option<f32>,
+
+    /// The threshold of the entropy as a percentage of 8 bits per byte, so that 81.25 is 6.5 bits per byte
+    #[arg(long, value_name = "PERCENT", value_parser = parse_entropy_threshold_pct)]
+    entropy_threshold_pct: Option<f32>,
+
+    /// Computes the entropy over consecutive windows of this many bytes instead of the whole sample
+    #[arg(long, value_name = "BYTES")]
+    window_bytes: Option<NonZeroU64>,
+
+    /// The share of windows, from 0 to 1, below the threshold for a file to be compressed
+    #[arg(long, value_name = "FRACTION", requires = "window_bytes")]
+    compressible_fraction: Option<f32>,
+
+    /// Always compresses the files smaller than this many bytes. Defaults to 512, and 0 turns it off.
+    #[arg(long, value_name = "BYTES")]
+    small_file_bytes: Option<u64>,
+
+    /// Analyzes the files with the extension of a compressed format, such as .jpg, like any other
+    #[arg(long)]
+    no_extension_shortcut: bool,
+
+    /// Also stores the files with this extension as-is. Can be repeated.
+    #[arg(long, value_name = "EXT", conflicts_with = "no_extension_shortcut")]
+    incompressible_ext: Vec<String>,
+
+    /// Also stores the files that start like an already compressed format, such as JPEG or gzip, as-is
+    #[arg(long)]
+    sniff: bool,
+
+    /// Warns about the files in a compressed format whose entropy would have them compressed
+    #[arg(long)]
+    check_formats: bool,
+
+    /// A file of rules, one per line as GLOB = compress or GLOB = store, that force a file's fate
+    #[arg(long, value_name = "FILE")]
+    rules: Option<PathBuf>,
+
+    /// The compression level, from 0 to 9, or to 19 for zstd and 11 for brotli
+    #[arg(short = 'l', long, value_parser = clap::value_parser!(u32).range(0..=19))]
+    compression_level: Option<u32>,
+
+    /// The codec used to compress the compressible files.
+    #[arg(short, long, value_enum, default_value_t)]
+    codec: Codec,
+
+    /// Adds the contents of directories, recursively
+    #[arg(short, long)]
+    recursive: bool,
+
+    /// Follows symlinks, storing what they point to instead of the symlinks
+    #[arg(long)]
+    dereference: bool,
+
+    /// Leaves out the files and directories matching this glob when adding directories. Can be repeated.
+    #[arg(long, value_name = "GLOB")]
+    exclude: Vec<String>,
+
+    /// Leaves out the files larger than this size when adding directories, such as 100M
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    exclude_larger_than: Option<u64>,
+
+    /// Leaves out the files smaller than this size when adding directories, such as 4K
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    exclude_smaller_than: Option<u64>,
+
+    /// Only keeps the files matching this glob when adding directories. Can be repeated.
+    #[arg(long, value_name = "GLOB")]
+    include: Vec<String>,
+
+    /// Prints the entropy of each file and whether it would be compressed, without writing an archive
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Prints the entropy of each file and whether it is compressed to stderr as the archive is written
+    #[arg(long, conflicts_with_all = ["dry_run", "threshold_tune"])]
+    show_entropy: bool,
+
+    /// Suggests an entropy threshold that separates the compressible files, without writing an archive
+    #[arg(long, conflicts_with = "dry_run")]
+    threshold_tune: bool,
+
+    /// Prints a rough estimate of the archive's size and how long compressing takes to stderr
+    #[arg(long, conflicts_with = "threshold_tune")]
+    estimate: bool,
+
+    /// Also compresses the files listed in this file, one per line. Use - to read the list from stdin.
+    #[arg(short = 'T', long)]
+    files_from: Option<PathBuf>,
+
+    /// Separates the paths in the --files-from list with NUL bytes instead of newlines
+    #[arg(long, requires = "files_from")]
+    null: bool,
+
+    /// Skips files that can't be read with a warning instead of failing, then exits with code 5
+    #[arg(long)]
+    skip_errors: bool,
+
+    /// Archives the files that are ttare archives themselves, instead of failing on them
+    #[arg(long)]
+    allow_nested: bool,
+
+    /// What to do with a file that changes size while it's being archived
+    #[arg(long, value_enum, default_value_t = OnChange::Fail)]
+    on_change: OnChange,
+
+    /// Stores each path of a hard-linked file with its contents, instead of as hard links to the first
+    #[arg(long)]
+    hard_dereference: bool,
+
+    /// Stores files with the same contents as an earlier file as a reference to it
+    #[arg(long)]
+    dedup: bool,
+
+    /// Stores every file as-is, without computing their entropy, like a plain tar with ttare's metadata
+    #[arg(
+        long,
+        conflicts_with_all = ["per_file_compression", "compression_level", "zstd_dict", "rules"]
+    )]
+    no_compress: bool,
+
+    /// Bundles the compressible files together in a single compressed member, which is the default
+    #[arg(long, overrides_with = "per_file_compression")]
+    solid: bool,
+
+    /// Compresses each compressible file on its own instead of bundling them together
+    #[arg(long, visible_alias = "no-solid", overrides_with = "solid")]
+    per_file_compression: bool,
+
+    /// Splits the archive into parts of at most this many bytes, named like ARCHIVE.001
+    #[arg(long, value_name = "BYTES")]
+    split_size: Option<NonZeroU64>,
+
+    /// Writes a plain .tar.gz when every file is compressible, or a plain .tar when none is. Needs gzip.
+    #[arg(long, conflicts_with_all = ["dedup", "manifest", "index", "per_file_compression", "zstd_dict", "stdin"])]
+    plain_targz: bool,
+
+    /// Stores the compressible files as-is when compressing made them bigger, instead of warning
+    #[arg(long)]
+    no_expand: bool,
+
+    /// Also compresses the files that would be stored as-is, and keeps whichever is smaller
+    #[arg(long, conflicts_with_all = ["no_compress", "plain_targz"])]
+    compress_all: bool,
+
+    /// Leaves out the two blocks of zeros that end the archive's tars
+    #[arg(long, conflicts_with = "plain_targz")]
+    strip_trailer: bool,
+
+    /// Records the CRC32 of each file in the archive, so that corrupt files can be told apart
+    #[arg(long)]
+    manifest: bool,
+
+    /// Adds an index of where each file is, so that list and extract don't decompress the compressed member
+    #[arg(long)]
+    index: bool,
+
+    /// Once the archive is written, checks that each file in it matches the one it was read from
+    #[arg(long, conflicts_with_all = ["stdin", "dry_run", "threshold_tune"])]
+    verify_after: bool,
+
+    /// Records how far the archive got in this file, so that --resume can go on from it
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["split_size", "plain_targz", "stdin"])]
+    checkpoint: Option<PathBuf>,
+
+    /// Goes on with the run that wrote this checkpoint, given the same files and options
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = ["checkpoint", "split_size", "plain_targz", "stdin"]
+    )]
+    resume: Option<PathBuf>,
+
+    /// Where to spool the compressed data before it is added to the archive. Defaults to $TMPDIR.
+    #[arg(long, value_name = "DIR")]
+    temp_dir: Option<PathBuf>,
+
+    /// Stores the files under their paths relative to this directory, which they all have to be in
+    #[arg(short = 'C', long, value_name = "DIR")]
+    base_dir: Option<PathBuf>,
+
+    /// Stores the files at SRC under DEST instead, such as --map /tmp/build=release. Can be repeated.
+    #[arg(long = "map", value_name = "SRC=DEST", value_parser = parse_name_mapping)]
+    name_map: Vec<(PathBuf, PathBuf)>,
+
+    /// Caps how many megabytes (10^6 bytes) per second are read and written, such as 0.5
+    #[arg(long, value_name = "MB", value_parser = parse_throttle)]
+    throttle_mbps: Option<NonZeroU64>,
+
+    /// The size of the buffers that files are read and written through, in bytes. Defaults to 256 KiB.
+    #[arg(long, value_name = "BYTES")]
+    io_buffer_size: Option<usize>,
+
+    /// How many files are open at once, at most. Defaults to half of the process's limit.
+    #[arg(long, value_name = "N")]
+    max_files_open: Option<NonZeroUsize>,
+
+    /// Compresses with this zstd dictionary, which decompressing needs too. Needs --codec zstd.
+    #[arg(long, value_name = "FILE")]
+    zstd_dict: Option<PathBuf>,
+
+    /// The modification time of the entries that ttare adds, as seconds since the Unix epoch
+    #[arg(long, value_name = "EPOCH")]
+    mtime: Option<u64>,
+
+    /// Stores the extended attributes of the files, directories and symlinks, on Unix
+    #[arg(long)]
+    xattrs: bool,
+
+    /// Writes the same archive for the same files, whatever order they are given in and whoever writes it
+    #[arg(long)]
+    reproducible: bool,
+
+    /// Prints a JSON summary of the run to stdout
+    #[arg(long, conflicts_with_all = ["dry_run", "threshold_tune"])]
+    json: bool,
+
+    /// Compresses what is read from stdin as a single file, named by --stdin-name
+    #[arg(
+        long,
+        requires = "stdin_name",
+        conflicts_with_all = ["files", "files_from", "dry_run", "threshold_tune"]
+    )]
+    stdin: bool,
+
+    /// The name of the file read from stdin in the archive
+    #[arg(long, requires = "stdin")]
+    stdin_name: Option<PathBuf>,
+}
+
+/// How many characters the longest bar of the `stats --histogram` output takes.
+const HISTOGRAM_WIDTH: usize = 40;
+
+/// The exit codes, as `--help` lists them.
+const EXIT_CODES: &str = "\
+Exit codes:
+  0    Success
+  1    Any other error, or the archives compared differ
+  2    Usage error: invalid arguments or options, or a missing zstd dictionary
+  3    I/O error, such as a missing file or one that can't be read or written
+  4    Corrupt archive, or files that don't match their checksum
+  5    Partial success: some files were skipped with --skip-errors or --on-change skip
+  130  Interrupted with Ctrl-C";
+
+/// What ttare exits with, so that scripts can tell why it failed, see `EXIT_CODES`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Exit {
+    Failure = 1,
+    Usage = 2,
+    Io = 3,
+    Corrupt = 4,
+    Partial = 5,
+}
+
+impl Exit {
+    /// The exit code that tells what `report` is about.
+    fn of(report: &Report) -> Self {
+        for cause in report.chain() {
+            if cause.is::<UsageError>() {
+                return Exit::Usage;
+            }
+            if cause.is::<Skipped>() {
+                return Exit::Partial;
+            }
+            if let Some(error) = cause.downcast_ref::<TtareError>() {
+                return Exit::of_ttare(error);
+            }
+            if cause.is::<io::Error>() {
+                return Exit::Io;
+            }
+        }
+        Exit::Failure
+    }
+
+    fn of_ttare(error: &TtareError) -> Self {
+        match error {
+            // Decoders fail like this on a stream that was cut short or altered
+            TtareError::Io { source, .. }
+                if matches!(
+                    source.kind(),
+                    io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof
+                ) =>
+            {
+                Exit::Corrupt
+            }
+            TtareError::Io { .. }
+            | TtareError::NotUtf8(_)
+            | TtareError::NotFound(_)
+            | TtareError::MissingPart(_)
+            | TtareError::OutputExists(_)
+            | TtareError::FileChanged(_) => Exit::Io,
+            TtareError::IsADirectory(_)
+            | TtareError::InvalidGlob { .. }
+            | TtareError::InvalidRule { .. }
+            | TtareError::InvalidSamplePercentage(_)
+            | TtareError::InvalidSampleBounds { .. }
+            | TtareError::InvalidCompressibleFraction(_)
+            | TtareError::InvalidCompressionLevel { .. }
+            | TtareError::DictionaryWithoutZstd(_)
+            | TtareError::MissingDictionary(_)
+            | TtareError::WrongDictionary { .. }
+            | TtareError::NotSingleFile(_)
+            | TtareError::DuplicatePaths(_)
+            | TtareError::OutsideBaseDir { .. }
+            | TtareError::NestedArchive(_)
+            | TtareError::NameCollision(_)
+            | TtareError::PlainTargzConflict(_)
+            | TtareError::CheckpointConflict(_)
+            | TtareError::CheckpointMismatch(_)
+            | TtareError::AppendConflict(_) => Exit::Usage,
+            TtareError::ChecksumMismatch(_)
+            | TtareError::SourceMismatch(_)
+            | TtareError::MissingInnerMember
+            | TtareError::UnsupportedVersion { .. }
+            | TtareError::InvalidMetadata(_)
+            | TtareError::UnsafePath(_)
+            | TtareError::Unrecoverable(_)
+            | TtareError::CorruptArchive(_) => Exit::Corrupt,
+            _ => Exit::Failure,
+        }
+    }
+}
+
+/// Options that can't be used together, which clap can't tell by itself.
+#[derive(Debug)]
+struct UsageError(&'static str);
+
+impl fmt::Display for UsageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+impl Error for UsageError {}
+
+/// How many files were left out of the archive, which was written without them.
+#[derive(Debug)]
+struct Skipped(usize);
+
+impl fmt::Display for Skipped {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} files were skipped because of errors", self.0)
+    }
 }
 
-fn main() -> Result<()> {
+impl Error for Skipped {}
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(report) => {
+            eprintln!("Error: {:?}", report);
+            ExitCode::from(Exit::of(&report) as u8)
+        }
+    }
+}
+
+fn run() -> Result<()> {
     color_eyre::install()?;
 
-    let args = Cli::parse();
+    let matches = Cli::command().get_matches();
+    let args = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    check_threshold_forms(&matches);
+    check_compression_level(&matches);
+    init_logging(args.verbose, args.quiet);
+    if args.trace {
+        init_tracing(args.verbose)?;
+    }
+    if args.no_color {
+        owo_colors::set_override(false);
+    }
+
+    // The progress bar would be garbled by the lines logged in verbose mode, or by the timings
+    let progress = !args.quiet && args.verbose == 0 && !args.trace && io::stderr().is_terminal();
 
-    match args.command {
-        Commands::Compress {
+    // Everything that rayon runs in parallel runs on this pool
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(args.jobs)
+        .build()
+        .context("Could not start the threads")?;
+    pool.install(|| run_command(args.command, args.quiet, progress))
+}
+
+/// Exits like clap does when the entropy threshold is given both as --entropy-threshold and
+/// --entropy-threshold-pct. clap can't tell them apart by itself, since it would also reject
+/// --entropy-threshold-pct when the threshold comes from $TTARE_ENTROPY_THRESHOLD, which the flag
+/// takes precedence over.
+fn check_threshold_forms(matches: &ArgMatches) {
+    let Some(("compress", compress)) = matches.subcommand() else {
+        return;
+    };
+    if compress.value_source("entropy_threshold") == Some(ValueSource::CommandLine)
+        && compress.contains_id("entropy_threshold_pct")
+    {
+        let mut command = Cli::command();
+        // Names the usage after ttare, as clap does
+        command.build();
+        command
+            .find_subcommand_mut("compress")
+            .expect("compress is a subcommand")
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--entropy-threshold can't be used with --entropy-threshold-pct",
+            )
+            .exit();
+    }
+}
+
+/// Exits like clap does when the compression level is higher than the codec's highest, which
+/// clap can't tell by itself since it depends on --codec.
+fn check_compression_level(matches: &ArgMatches) {
+    let Some((name @ ("compress" | "recompress"), args)) = matches.subcommand() else {
+        return;
+    };
+    let (Some(&codec), Some(&level)) = (
+        args.get_one::<Codec>("codec"),
+        args.get_one::<u32>("compression_level"),
+    ) else {
+        return;
+    };
+    if level > codec.max_level() {
+        let mut command = Cli::command();
+        command.build();
+        command
+            .find_subcommand_mut(name)
+            .expect("the subcommand was matched")
+            .error(
+                ErrorKind::ValueValidation,
+                format!(
+                    "the compression level of {} goes up to {}, not {level}",
+                    codec.name(),
+                    codec.max_level()
+                ),
+            )
+            .exit();
+    }
+}
+
+fn run_command(command: Commands, quiet: bool, progress: bool) -> Result<()> {
+    match command {
+        Commands::Compress(args) => compress(*args, progress)?,
+        Commands::Decompress {
+            input_file,
+            path,
+            to_stdout: true,
+            ..
+        } => {
+            if is_dash(&input_file) {
+                return Err(UsageError("--to-stdout can't read the archive from stdin").into());
+            }
+            // Stdout doesn't translate line endings, so binary files come out as they are stored
+            let stdout = io::stdout().lock();
+            match path {
+                Some(path) => ttare::extract(&input_file, &path, stdout)?,
+                None => ttare::extract_single(&input_file, stdout)?,
+            }
+        }
+        Commands::Decompress {
+            input_file,
+            output_dir,
+            preserve_owner,
+            zstd_dict,
+            overwrite,
+            xattrs,
+            strip_components,
+            best_effort,
+            ..
+        } => {
+            let output_dir = output_dir.as_deref().unwrap_or(Path::new("."));
+            let opts = DecompressOptions {
+                preserve_owner,
+                zstd_dictionary: zstd_dict.as_deref().map(ZstdDictionary::open).transpose()?,
+                overwrite,
+                xattrs,
+                strip_components,
+                best_effort,
+            };
+
+            if is_dash(&input_file) {
+                ttare::decompress_from(io::stdin().lock(), output_dir, opts)?;
+            } else {
+                ttare::decompress(&input_file, output_dir, opts)?;
+            }
+        }
+        Commands::List { input_file } => {
+            let listing = ttare::list(&input_file)?;
+            for entry in listing.entries {
+                println!(
+                    "{} {:>12} {}",
+                    if entry.compressed { 'C' } else { 'R' },
+                    entry.size,
+                    entry.path.display()
+                );
+            }
+
+            // On stderr, so that the listing can still be read by scripts
+            if !quiet {
+                if let Some(created) = listing.created {
+                    eprintln!("archive created at {}", format_time(created));
+                }
+                match listing.solid {
+                    Some(true) => {
+                        eprintln!("solid: the compressible files are compressed together")
+                    }
+                    Some(false) => {
+                        eprintln!("not solid: each compressible file is compressed on its own")
+                    }
+                    None => {}
+                }
+            }
+        }
+        Commands::Extract {
+            input_file,
+            path,
+            output,
+        } => {
+            if is_dash(&output) {
+                ttare::extract(&input_file, &path, io::stdout().lock())?;
+            } else {
+                let file = File::create(&output)
+                    .with_context(|| format!("Could not create {}", output.display()))?;
+
+                let mut writer = BufWriter::new(file);
+                let result = ttare::extract(&input_file, &path, &mut writer)
+                    .and_then(|()| Ok(writer.flush()?));
+
+                if let Err(e) = result {
+                    let _ = fs::remove_file(&output);
+                    return Err(e.into());
+                }
+            }
+        }
+        Commands::Cat { input_file, paths } => {
+            let mut stdout = BufWriter::new(io::stdout().lock());
+            // A reader that stops early, like `head`, closes the pipe, which ends the output
+            match ttare::cat(&input_file, &paths, &mut stdout).and_then(|()| Ok(stdout.flush()?)) {
+                Err(TtareError::Io { source, .. })
+                    if source.kind() == io::ErrorKind::BrokenPipe => {}
+                result => result?,
+            }
+        }
+        Commands::Append {
+            archive,
             files,
+            recursive,
+            temp_dir,
+            zstd_dict,
+            base_dir,
+            xattrs,
+        } => {
+            let walk_opts = WalkOptions {
+                recursive,
+                ..WalkOptions::default()
+            };
+            let paths = gather_files(&files, &walk_opts)?.into_paths();
+
+            let opts = CompressOptions {
+                progress,
+                temp_dir,
+                zstd_dictionary: zstd_dict.as_deref().map(ZstdDictionary::open).transpose()?,
+                base_dir,
+                xattrs,
+                ..CompressOptions::default()
+            };
+            ttare::append(&archive, &paths, opts)?;
+        }
+        Commands::Recompress {
+            input_file,
             output_file,
+            force,
+            codec,
+            compression_level,
+            entropy_threshold,
             sample_percentage,
+            zstd_dict,
+            temp_dir,
+        } => {
+            let opts = RecompressOptions {
+                codec,
+                compression_level,
+                entropy_threshold,
+                sample_percentage,
+                zstd_dictionary: zstd_dict.as_deref().map(ZstdDictionary::open).transpose()?,
+                temp_dir,
+                overwrite: force,
+                progress,
+            };
+            remove_partial_on_interrupt(&output_file, false)?;
+            ttare::recompress(&input_file, &output_file, opts)?;
+        }
+        Commands::Verify { input_file } => {
+            ttare::verify(&input_file)?;
+            println!("OK");
+        }
+        Commands::Compare { first, second } => {
+            let comparison = ttare::compare(&first, &second)?;
+
+            let mut changes: Vec<(&str, &PathBuf)> = comparison
+                .added
+                .iter()
+                .map(|path| ("A", path))
+                .chain(comparison.removed.iter().map(|path| ("D", path)))
+                .chain(comparison.changed.iter().map(|path| ("M", path)))
+                .collect();
+            changes.sort_by_key(|(_, path)| *path);
+            for (change, path) in changes {
+                println!("{} {}", change, path.display());
+            }
+
+            if !comparison.is_same() {
+                process::exit(1);
+            }
+        }
+        Commands::Stats {
+            paths,
+            codec,
             entropy_threshold,
+            sample_percentage,
+            sniff,
+            histogram,
+            json,
         } => {
-            compress(
-                files,
-                output_file,
-                sample_percentage.unwrap_or(ENTROPY_SAMPLING),
-                entropy_threshold.unwrap_or(ENTROPY_THRESHOLD),
-            )?;
+            let walk_opts = WalkOptions {
+                recursive: true,
+                ..WalkOptions::default()
+            };
+            let files = gather_files(&paths, &walk_opts)?.files;
+
+            let defaults = CompressOptions::default();
+            let opts = CompressOptions {
+                codec,
+                entropy_threshold,
+                sample_percentage: sample_percentage.unwrap_or(defaults.sample_percentage),
+                sniff,
+                progress,
+                ..defaults
+            };
+            let stats = ttare::stats(&files, &opts)?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&stats)?);
+            } else {
+                print_stats(&stats, histogram);
+            }
         }
-        Commands::Decompress {
-            input_file,
-            output_dir,
+        Commands::Histogram {
+            file,
+            full_entropy,
+            output,
         } => {
-            decompress(&input_file, output_dir)?;
+            let mut input =
+                File::open(&file).with_context(|| format!("Could not open {}", file.display()))?;
+            let histogram = if full_entropy {
+                ttare::full_histogram(&mut input)?
+            } else {
+                ttare::sample_histogram(&mut input, &CompressOptions::default())?
+            };
+
+            match output {
+                Some(output) => histogram
+                    .write_csv(BufWriter::new(File::create(&output).with_context(
+                        || format!("Could not create {}", output.display()),
+                    )?))?,
+                None => histogram.write_csv(io::stdout().lock())?,
+            }
+            eprintln!(
+                "entropy {:.3} bits per byte, over {} bytes",
+                histogram.entropy(),
+                histogram.total()
+            );
+        }
+        Commands::Completions { shell } => {
+            clap_complete::generate(shell, &mut Cli::command(), "ttare", &mut io::stdout());
         }
     }
 
     Ok(())
 }
 
-fn decompress(input_file: &str, output_dir: Option<String>) -> Result<()> {
-    let output_dir = output_dir.unwrap_or_else(|| ".".to_string());
+/// Prints what `--estimate` expects the archive to take, and how long compressing it takes.
+fn print_estimate(estimate: &SizeEstimate) {
+    let ratio = estimate
+        .ratio
+        .map_or_else(String::new, |ratio| format!(" ({:.0}%)", ratio * 100.0));
+    eprintln!(
+        "estimate: about {}{ratio} from {}, compressing {} files of {} in about {:.0}s",
+        HumanBytes(estimate.archive_bytes),
+        HumanBytes(estimate.input_bytes),
+        estimate.compressed_files,
+        HumanBytes(estimate.compressed_input_bytes),
+        estimate.compress_seconds.ceil(),
+    );
+}
+
+/// Prints what `--dry-run` found: the entropy, decision and size of each file in aligned columns,
+/// with the decisions colored when stdout supports it, then how many files would be compressed
+/// and stored.
+fn print_dry_run(analyses: &[FileAnalysis]) {
+    // Files stored because of a rule, their size or their extension weren't read
+    let rows: Vec<(String, String)> = analyses
+        .iter()
+        .map(|analysis| {
+            let entropy = match analysis.entropy {
+                Some(entropy) => format!("{:.3}", entropy),
+                None => "-".to_string(),
+            };
+            (entropy, HumanBytes(analysis.size).to_string())
+        })
+        .collect();
+
+    let entropy_width = rows
+        .iter()
+        .map(|(entropy, _)| entropy.len())
+        .chain(["ENTROPY".len()])
+        .max()
+        .unwrap_or_default();
+    let decision_width = format!("{:?}", EntropyAnalysis::DontCompress).len();
+    let size_width = rows
+        .iter()
+        .map(|(_, size)| size.len())
+        .chain(["SIZE".len()])
+        .max()
+        .unwrap_or_default();
 
-    let mut archive = Archive::new(
-        File::open(&input_file).with_context(|| format!("Could not open {}", &input_file))?,
+    println!(
+        "{:>entropy_width$}  {:<decision_width$}  {:>size_width$}  PATH",
+        "ENTROPY", "DECISION", "SIZE"
     );
+    for (analysis, (entropy, size)) in analyses.iter().zip(&rows) {
+        // Padded before it's colored, since the escape codes don't take up any room
+        let decision = format!("{:<decision_width$}", format!("{:?}", analysis.decision));
+        let decision = match analysis.decision {
+            EntropyAnalysis::Compress => decision
+                .if_supports_color(Stream::Stdout, |text| text.green())
+                .to_string(),
+            EntropyAnalysis::DontCompress => decision
+                .if_supports_color(Stream::Stdout, |text| text.yellow())
+                .to_string(),
+        };
+        println!(
+            "{entropy:>entropy_width$}  {decision}  {size:>size_width$}  {}",
+            analysis.path.display()
+        );
+    }
 
-    // Extract all of the files
-    for entry in archive.entries()? {
-        let mut entry = entry?;
+    println!();
+    for (decision, verb) in [
+        (EntropyAnalysis::Compress, "compressed"),
+        (EntropyAnalysis::DontCompress, "stored"),
+    ] {
+        let (count, bytes) = analyses
+            .iter()
+            .filter(|analysis| analysis.decision == decision)
+            .fold((0, 0), |(count, bytes), analysis| {
+                (count + 1, bytes + analysis.size)
+            });
+        println!("{count} files {verb}, {}", HumanBytes(bytes));
+    }
+}
 
-        let path = entry.path()?;
-        let path = path
-            .to_str()
-            .ok_or_else(|| eyre!("Could not get path in tar file"))?;
+/// Prints what `stats` found: how many files would be compressed and stored, the spread of their
+/// entropy and how much compressing could save, then how many files fall in each bucket of the
+/// histogram with `histogram`.
+fn print_stats(stats: &DatasetStats, histogram: bool) {
+    println!(
+        "{} files compressed, {}",
+        stats.compressed.files,
+        HumanBytes(stats.compressed.bytes)
+    );
+    println!(
+        "{} files stored, {}",
+        stats.stored.files,
+        HumanBytes(stats.stored.bytes)
+    );
+    if stats.unmeasured.files > 0 {
+        println!(
+            "{} files, {}, classified by a rule, their size, their extension or their format",
+            stats.unmeasured.files,
+            HumanBytes(stats.unmeasured.bytes)
+        );
+    }
+    if let (Some(min), Some(median), Some(max)) =
+        (stats.min_entropy, stats.median_entropy, stats.max_entropy)
+    {
+        println!(
+            "entropy from {min:.3} to {max:.3}, with a median of {median:.3} and a threshold of {:.3}",
+            stats.entropy_threshold
+        );
+    }
+    println!(
+        "compressing could save about {}, going by the entropy",
+        HumanBytes(stats.potential_savings_bytes)
+    );
 
-        if path == TTARE_COMPRESS_FILE_NAME {
-            // Decompress the .tar.gz
-            let mut decompress = GzDecoder::new(entry);
-            let mut tar = Archive::new(&mut decompress);
-            tar.unpack(&output_dir)?;
-        } else {
-            entry.unpack_in(&output_dir)?;
-        }
+    if !histogram {
+        return;
     }
+    let sizes: Vec<String> = stats
+        .histogram
+        .iter()
+        .map(|bucket| HumanBytes(bucket.count.bytes).to_string())
+        .collect();
+    let size_width = sizes
+        .iter()
+        .map(String::len)
+        .chain(["SIZE".len()])
+        .max()
+        .unwrap_or_default();
+    let most = stats
+        .histogram
+        .iter()
+        .map(|bucket| bucket.count.files)
+        .max()
+        .unwrap_or_default();
 
-    Ok(())
+    println!();
+    println!("ENTROPY   FILES  {:>size_width$}", "SIZE");
+    for (bucket, size) in stats.histogram.iter().zip(&sizes) {
+        let bar = "#".repeat((bucket.count.files * HISTOGRAM_WIDTH).div_ceil(most.max(1)));
+        let row = format!(
+            "{:.1}-{:.1}  {:>6}  {size:>size_width$}  {bar}",
+            bucket.from, bucket.to, bucket.count.files
+        );
+        println!("{}", row.trim_end());
+    }
 }
 
-fn compress(
-    files: Vec<String>,
-    output_file: String,
-    entropy_sampling: f32,
-    entropy_threshold: f32,
-) -> Result<()> {
-    let mut root_tar = tar::Builder::new(Vec::new());
-    let mut compress_tar = tar::Builder::new(Vec::new());
+/// Parses a throttle given in megabytes per second, which can be fractional, into bytes per
+/// second.
+fn parse_throttle(megabytes: &str) -> std::result::Result<NonZeroU64, String> {
+    let megabytes: f64 = megabytes.parse().map_err(|e| format!("{e}"))?;
+    // Negative numbers and NaN are cast to 0
+    NonZeroU64::new((megabytes * 1_000_000.0).round() as u64)
+        .ok_or_else(|| format!("must be more than 0, not {megabytes}"))
+}
 
-    for file_name in files {
-        // Open the file
-        let mut file = File::open(&file_name).with_context(|| "Failed to open file")?;
+/// Parses a `SRC=DEST` mapping of the files at SRC to their names in the archive, which have to
+/// stay in the output directory when they are extracted.
+fn parse_name_mapping(mapping: &str) -> std::result::Result<(PathBuf, PathBuf), String> {
+    let (source, destination) = mapping
+        .split_once('=')
+        .ok_or_else(|| format!("expected SRC=DEST, not {mapping}"))?;
+    if source.is_empty() {
+        return Err("the source path is empty".to_string());
+    }
+    let destination = Path::new(destination);
+    if destination.has_root()
+        || destination
+            .components()
+            .any(|component| component == std::path::Component::ParentDir)
+    {
+        return Err(format!(
+            "{} would be extracted outside of the output directory",
+            destination.display()
+        ));
+    }
+    Ok((PathBuf::from(source), destination.to_path_buf()))
+}
 
-        let analysis_result = analyze_entropy(&mut file, entropy_sampling, entropy_threshold)?;
+/// Parses a sample percentage, which has to be a positive number.
+fn parse_sample_percentage(value: &str) -> std::result::Result<f32, String> {
+    let percentage: f32 = value
+        .parse()
+        .map_err(|e| from_env("TTARE_SAMPLE_PERCENTAGE", value, format!("{e}")))?;
+    if percentage.is_finite() && percentage > 0.0 {
+        Ok(percentage)
+    } else {
+        Err(from_env(
+            "TTARE_SAMPLE_PERCENTAGE",
+            value,
+            format!("must be a positive number, not {percentage}"),
+        ))
+    }
+}
 
-        file.seek(SeekFrom::Start(0))?;
+/// Parses an entropy threshold, which can be any number of bits per byte but NaN, which no
+/// entropy would be above.
+fn parse_entropy_threshold(value: &str) -> std::result::Result<f32, String> {
+    let threshold: f32 = value
+        .parse()
+        .map_err(|e| from_env("TTARE_ENTROPY_THRESHOLD", value, format!("{e}")))?;
+    if threshold.is_nan() {
+        Err(from_env(
+            "TTARE_ENTROPY_THRESHOLD",
+            value,
+            "must be a number of bits per byte, not NaN".to_string(),
+        ))
+    } else {
+        Ok(threshold)
+    }
+}
 
-        // Add the file to the correct tar
-        match analysis_result {
-            EntropyAnalysis::Compress => {
-                compress_tar.append_file(Path::new(&file_name), &mut file)?;
-            }
-            EntropyAnalysis::DontCompress => {
-                root_tar.append_file(Path::new(&file_name), &mut file)?;
-            }
+/// Parses a size in bytes, which can end with K, M, G or T for units of 1024 bytes, optionally
+/// followed by B or iB, such as 100M or 4KiB.
+fn parse_size(value: &str) -> std::result::Result<u64, String> {
+    let upper = value.trim().to_ascii_uppercase();
+    let number = upper
+        .strip_suffix("IB")
+        .or_else(|| upper.strip_suffix('B'))
+        .unwrap_or(&upper);
+    let (digits, unit) = match number.char_indices().last() {
+        Some((at, 'K')) => (&number[..at], 1 << 10),
+        Some((at, 'M')) => (&number[..at], 1 << 20),
+        Some((at, 'G')) => (&number[..at], 1 << 30),
+        Some((at, 'T')) => (&number[..at], 1 << 40),
+        _ => (number, 1),
+    };
+    let count: u64 = digits
+        .trim()
+        .parse()
+        .map_err(|_| format!("must be a number of bytes, such as 512, 64K or 100M, not {value}"))?;
+    count
+        .checked_mul(unit)
+        .ok_or_else(|| format!("{value} is too large"))
+}
+
+/// Parses an entropy threshold given as a percentage of `MAX_ENTROPY`, from 0 to 100.
+fn parse_entropy_threshold_pct(value: &str) -> std::result::Result<f32, String> {
+    let percentage: f32 = value.parse().map_err(|e| format!("{e}"))?;
+    if (0.0..=100.0).contains(&percentage) {
+        Ok(percentage)
+    } else {
+        Err(format!("must be between 0 and 100, not {percentage}"))
+    }
+}
+
+/// Adds to the `error` of parsing `value` that it's the value of the environment variable `var`,
+/// when it is, since clap only names the flag.
+fn from_env(var: &str, value: &str, error: String) -> String {
+    if env::var_os(var).is_some_and(|set| set == value) {
+        format!("{error}, in ${var}")
+    } else {
+        error
+    }
+}
+
+/// Formats seconds since the Unix epoch as a UTC date and time, such as `2024-03-01 12:30:00 UTC`.
+fn format_time(secs: u64) -> String {
+    let (days, secs) = (secs / 86_400, secs % 86_400);
+
+    // From the days since the epoch to the civil date, counting years from March so that the leap
+    // day is last, see https://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = if month_from_march < 10 {
+        month_from_march + 3
+    } else {
+        month_from_march - 9
+    };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// Logs ttare's messages to stderr, at the level picked by `-v` and `-q`, unless `RUST_LOG` says
+/// otherwise.
+fn init_logging(verbose: u8, quiet: bool) {
+    let level = match (quiet, verbose) {
+        (true, _) => LevelFilter::Error,
+        (false, 0) => LevelFilter::Warn,
+        (false, 1) => LevelFilter::Info,
+        (false, 2) => LevelFilter::Debug,
+        (false, _) => LevelFilter::Trace,
+    };
+
+    env_logger::Builder::new()
+        .filter_level(level.min(LevelFilter::Warn))
+        .filter_module("ttare", level)
+        .parse_default_env()
+        .format(|buf, record| {
+            let level = match record.level() {
+                Level::Error => "error",
+                Level::Warn => "warning",
+                Level::Info => "info",
+                Level::Debug => "debug",
+                Level::Trace => "trace",
+            };
+            writeln!(buf, "{}: {}", level, record.args())
+        })
+        .init();
+}
+
+/// Prints how long each of ttare's phases took to stderr, as they end, and how long each file took
+/// with `-v`.
+fn init_tracing(verbose: u8) -> Result<()> {
+    let level = if verbose > 0 {
+        SpanLevel::DEBUG
+    } else {
+        SpanLevel::INFO
+    };
+    let subscriber = tracing_subscriber::registry().with(Timings.with_filter(level));
+    tracing::subscriber::set_global_default(subscriber).context("Could not start tracing")
+}
+
+/// Prints how long each span took to stderr when it closes, indented by how deep it is.
+struct Timings;
+
+/// When a span started, and its fields as they are printed.
+struct Timing {
+    started: Instant,
+    fields: String,
+}
+
+impl<S> Layer<S> for Timings
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: LayerContext<'_, S>) {
+        let mut fields = Fields::default();
+        attrs.record(&mut fields);
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(Timing {
+                started: Instant::now(),
+                fields: fields.0,
+            });
         }
     }
 
-    // Write the compressed tar to the root tar
-    let compress_tar = compress_tar.into_inner()?;
+    fn on_close(&self, id: Id, ctx: LayerContext<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let extensions = span.extensions();
+        let Some(timing) = extensions.get::<Timing>() else {
+            return;
+        };
+        let depth = span.scope().skip(1).count();
+        eprintln!(
+            "timing: {:indent$}{}{} took {:.3?}",
+            "",
+            span.name(),
+            timing.fields,
+            timing.started.elapsed(),
+            indent = depth * 2
+        );
+    }
+}
 
-    // compress it
-    let mut encoder = GzEncoder::new(compress_tar.as_slice(), Compression::default());
-    let mut compressed_buf = vec![];
-    encoder.read_to_end(&mut compressed_buf)?;
+/// The fields of a span, as ` name=value` for each of them.
+#[derive(Default)]
+struct Fields(String);
 
-    // Create the header for the compressed tar
-    let mut header = Header::new_gnu();
-    header.set_size(compressed_buf.len() as u64);
-    header.set_mode(0o666);
-    header.set_mtime(
-        SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)?
-            .as_secs() as u64,
-    );
-    header.set_cksum();
+impl Visit for Fields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        let _ = write!(self.0, " {}={}", field.name(), value);
+    }
 
-    // Add it to the root tar
-    root_tar.append_data(
-        &mut header,
-        Path::new(TTARE_COMPRESS_FILE_NAME),
-        compressed_buf.as_slice(),
-    )?;
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        let _ = write!(self.0, " {}={:?}", field.name(), value);
+    }
+}
 
-    // Write the root tar to the output file
-    let root_tar = root_tar.into_inner()?;
-    let mut output_file = File::create(output_file)?;
-    output_file.write_all(&root_tar)?;
+/// Whether `path` is `-`, which stands for stdin or stdout.
+fn is_dash(path: &Path) -> bool {
+    path.as_os_str() == "-"
+}
 
-    Ok(())
+/// Removes the partial archive at `output`, or the parts written so far when it is `split`, if
+/// the process is interrupted with Ctrl-C, then exits like the interrupt would have.
+fn remove_partial_on_interrupt(output: &Path, split: bool) -> Result<()> {
+    let output = output.to_path_buf();
+    ctrlc::set_handler(move || {
+        ttare::remove_partial(&output, split);
+        process::exit(130);
+    })
+    .context("Could not handle Ctrl-C")
 }
 
-fn analyze_entropy(
-    file: &mut File,
-    entropy_sampling: f32,
-    entropy_threshold: f32,
-) -> Result<EntropyAnalysis> {
-    let entropy_bytes_len = (file.metadata()?.len() as f32 * entropy_sampling) as usize;
+fn compress(args: CompressArgs, progress: bool) -> Result<()> {
+    let rules = match &args.rules {
+        Some(path) => DecisionRules::read(
+            File::open(path).with_context(|| format!("Could not open {}", path.display()))?,
+        )?,
+        None => DecisionRules::default(),
+    };
+
+    let opts = CompressOptions {
+        sample_percentage: args.sample_percentage.unwrap_or(ENTROPY_SAMPLING),
+        min_sample_bytes: args.min_sample_bytes.unwrap_or(MIN_SAMPLE_BYTES),
+        max_sample_bytes: args.max_sample_bytes,
+        full_entropy: args.full_entropy,
+        estimator: args.estimator,
+        entropy_threshold: args
+            .entropy_threshold_pct
+            .map(threshold_from_percentage)
+            .or(args.entropy_threshold),
+        window_bytes: args.window_bytes,
+        compressible_fraction: args.compressible_fraction.unwrap_or(COMPRESSIBLE_FRACTION),
+        small_file_bytes: args.small_file_bytes.unwrap_or(SMALL_FILE_BYTES),
+        extension_shortcut: !args.no_extension_shortcut,
+        incompressible_extensions: args.incompressible_ext,
+        sniff: args.sniff,
+        check_formats: args.check_formats,
+        rules,
+        codec: args.codec,
+        compression_level: args.compression_level,
+        skip_errors: args.skip_errors,
+        allow_nested: args.allow_nested,
+        hard_dereference: args.hard_dereference,
+        on_change: args.on_change,
+        dedup: args.dedup,
+        progress,
+        show_entropy: args.show_entropy,
+        mtime: args.mtime,
+        reproducible: args.reproducible,
+        dereference: args.dereference,
+        per_file_compression: args.per_file_compression,
+        no_expand: args.no_expand,
+        compress_all: args.compress_all,
+        strip_trailer: args.strip_trailer,
+        split_size: args.split_size,
+        manifest: args.manifest,
+        index: args.index,
+        verify_after: args.verify_after,
+        checkpoint: args.resume.clone().or(args.checkpoint),
+        resume: args.resume.is_some(),
+        temp_dir: args.temp_dir,
+        zstd_dictionary: args
+            .zstd_dict
+            .as_deref()
+            .map(ZstdDictionary::open)
+            .transpose()?,
+        max_files_open: args.max_files_open,
+        base_dir: args.base_dir,
+        name_map: args.name_map,
+        throttle_bytes_per_sec: args.throttle_mbps,
+        io_buffer_size: args.io_buffer_size.unwrap_or(IO_BUFFER_SIZE),
+        overwrite: args.force,
+        plain_targz: args.plain_targz,
+        no_compress: args.no_compress,
+        xattrs: args.xattrs,
+    };
+
+    let to_stdout = args.output_file.as_deref().is_some_and(is_dash);
+    if to_stdout && args.json {
+        return Err(
+            UsageError("--json can't be used when the archive is written to stdout").into(),
+        );
+    }
+    if to_stdout && args.split_size.is_some() {
+        return Err(
+            UsageError("--split-size can't be used when the archive is written to stdout").into(),
+        );
+    }
+    if to_stdout && args.verify_after {
+        return Err(UsageError(
+            "--verify-after can't be used when the archive is written to stdout",
+        )
+        .into());
+    }
+
+    if to_stdout && opts.checkpoint.is_some() {
+        return Err(UsageError(
+            "--checkpoint and --resume can't be used when the archive is written to stdout",
+        )
+        .into());
+    }
+
+    if args.stdin {
+        let output_file = args.output_file.expect("clap requires an output file");
+        let name = args
+            .stdin_name
+            .as_deref()
+            .expect("clap requires a name for stdin");
+        let summary = if to_stdout {
+            ttare::compress_reader_to(io::stdin().lock(), name, io::stdout().lock(), opts)?
+        } else {
+            remove_partial_on_interrupt(&output_file, args.split_size.is_some())?;
+            ttare::compress_reader(io::stdin().lock(), name, &output_file, opts)?
+        };
+
+        if args.json {
+            println!("{}", serde_json::to_string_pretty(&summary)?);
+        }
+
+        return Ok(());
+    }
+
+    let mut files = args.files;
+
+    match args.files_from.as_deref() {
+        Some(list) if is_dash(list) => files.extend(read_file_list(io::stdin().lock(), args.null)?),
+        Some(list) => files.extend(read_file_list(
+            File::open(list).with_context(|| format!("Could not open {}", list.display()))?,
+            args.null,
+        )?),
+        None => {}
+    }
+
+    let walk_opts = WalkOptions {
+        recursive: args.recursive,
+        skip_errors: args.skip_errors,
+        exclude: args.exclude,
+        include: args.include,
+        exclude_larger_than: args.exclude_larger_than,
+        exclude_smaller_than: args.exclude_smaller_than,
+        dereference: args.dereference,
+    };
+
+    let gathered = gather_files(&files, &walk_opts)?;
+    let mut skipped = gathered.skipped.len();
 
-    // TODO: ideally this would randomly sample the file, but for the sake of speed,
-    // read the first entropy_bytes_len bytes of the file
-    let mut entropy_bytes = vec![0u8; entropy_bytes_len];
-    file.read_exact(&mut entropy_bytes)?;
+    if args.estimate {
+        print_estimate(&ttare::estimate(&gathered.files, &opts)?);
+    }
 
-    let entropy = entropy(&entropy_bytes);
+    if args.threshold_tune {
+        let analyses = ttare::analyze_files(&gathered.files, &opts)?;
+        skipped += gathered.files.len() - analyses.len();
+        let entropies: Vec<f32> = analyses.iter().filter_map(|a| a.entropy).collect();
+
+        match suggest_threshold(&entropies) {
+            Some(threshold) => {
+                let compressed = entropies.iter().filter(|&&e| e <= threshold).count();
+                println!("suggested threshold: {:.3}", threshold);
+                println!("{} files compressed", compressed);
+                println!("{} files stored", analyses.len() - compressed);
+            }
+            None => println!("no threshold separates these files"),
+        }
+    } else if args.dry_run {
+        let analyses = ttare::analyze_files(&gathered.files, &opts)?;
+        skipped += gathered.files.len() - analyses.len();
 
-    if entropy > entropy_threshold {
-        Ok(EntropyAnalysis::DontCompress)
+        print_dry_run(&analyses);
     } else {
-        Ok(EntropyAnalysis::Compress)
+        let output_file = args.output_file.expect("clap requires an output file");
+        let paths = gathered.into_paths();
+        let summary = if to_stdout {
+            ttare::compress_to(&paths, io::stdout().lock(), opts)?
+        } else {
+            // The partial archive is what a checkpoint is resumed from
+            if opts.checkpoint.is_none() {
+                remove_partial_on_interrupt(&output_file, args.split_size.is_some())?;
+            }
+            ttare::compress(&paths, &output_file, opts)?
+        };
+        skipped += summary.skipped.len();
+
+        if args.json {
+            println!("{}", serde_json::to_string_pretty(&summary)?);
+        }
     }
-}
 
-fn entropy(entropy_bytes: &[u8]) -> f32 {
-    let total = entropy_bytes.len() as f32;
+    if skipped > 0 {
+        return Err(Skipped(skipped).into());
+    }
 
-    let counts = entropy_bytes
-        .iter()
-        .fold(FxHashMap::default(), |mut counts, byte| {
-            *counts.entry(byte).or_insert(0) += 1;
-            counts
-        });
-
-    counts
-        .into_par_iter()
-        .map(|(_, count)| {
-            let p = count as f32 / total;
-            -p * p.log2()
-        })
-        .sum()
+    Ok(())
 }
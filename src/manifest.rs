@@ -0,0 +1,112 @@
+use std::{
+    fs::File,
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+use rustc_hash::FxHashMap;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    checksum::Crc32Reader,
+    error::IoContext,
+    normalize_entry_path, strip_components as strip_components_of,
+    walk::{json_path, path_to_bytes},
+    Result, TtareError,
+};
+
+/// The CRC32 of the contents of every file in the archive, so that corruption can be pinned down
+/// to the files it hit, instead of only telling that the archive is corrupt.
+///
+/// It is stored as JSON in the `.ttare.manifest` entry, ahead of the compressed member. The files
+/// stored as copies of another file aren't listed, since they are checked through their original.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct ChecksumManifest {
+    pub(crate) files: Vec<FileChecksum>,
+}
+
+/// The CRC32 of one file in the archive.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct FileChecksum {
+    #[serde(with = "json_path")]
+    pub(crate) path: PathBuf,
+    pub(crate) crc32: u32,
+}
+
+impl ChecksumManifest {
+    /// Records the CRC32 of the contents of the file at `path`.
+    pub(crate) fn add(&mut self, path: &Path, crc32: u32) {
+        self.files.push(FileChecksum {
+            path: normalize_entry_path(path),
+            crc32,
+        });
+    }
+
+    pub(crate) fn to_bytes(&self) -> Result<Vec<u8>> {
+        // Only paths that aren't UTF-8 off Unix can't be stored
+        for file in &self.files {
+            path_to_bytes(&file.path)?;
+        }
+        Ok(serde_json::to_vec(self).expect("paths made of bytes can always be serialized"))
+    }
+
+    pub(crate) fn read(reader: impl Read) -> Result<Self> {
+        serde_json::from_reader(reader).map_err(|e| {
+            TtareError::CorruptArchive(format!("the checksums of the files are unreadable: {e}"))
+        })
+    }
+
+    /// Fails with the files whose CRC32 in `actual` doesn't match, or that are missing from it.
+    pub(crate) fn check(&self, actual: &FxHashMap<PathBuf, u32>) -> Result<()> {
+        let failed: Vec<PathBuf> = self
+            .files
+            .iter()
+            .filter(|file| actual.get(&file.path) != Some(&file.crc32))
+            .map(|file| file.path.clone())
+            .collect();
+
+        if failed.is_empty() {
+            Ok(())
+        } else {
+            Err(TtareError::ChecksumMismatch(failed))
+        }
+    }
+
+    /// Fails with the files extracted into `output_dir` whose contents don't match their CRC32,
+    /// once `strip_components` of their leading components were dropped from their paths.
+    pub(crate) fn check_extracted(&self, output_dir: &Path, strip_components: usize) -> Result<()> {
+        let mut extracted = ChecksumManifest::default();
+        let mut actual = FxHashMap::default();
+        for file in &self.files {
+            // The files whose paths are too short to be stripped weren't extracted
+            let Some(path) = strip_components_of(&file.path, strip_components) else {
+                continue;
+            };
+            extracted.files.push(file.clone());
+
+            // A file that can't be read doesn't match
+            if let Ok(crc32) = File::open(output_dir.join(path)).and_then(crc32_of) {
+                actual.insert(file.path.clone(), crc32);
+            }
+        }
+        extracted.check(&actual)
+    }
+}
+
+/// Computes the CRC32 of everything read from `reader`.
+pub(crate) fn crc32_of(reader: impl Read) -> io::Result<u32> {
+    let mut reader = Crc32Reader::new(reader);
+    io::copy(&mut reader, &mut io::sink())?;
+    Ok(reader.crc32())
+}
+
+/// Reads `reader` to the end, recording its CRC32 as that of the file at `path` in `actual`.
+pub(crate) fn record_crc32(
+    actual: &mut FxHashMap<PathBuf, u32>,
+    path: &Path,
+    reader: impl Read,
+) -> Result<()> {
+    let crc32 = crc32_of(reader).with_path("Could not read", path)?;
+    actual.insert(normalize_entry_path(path), crc32);
+    Ok(())
+}
@@ -0,0 +1,145 @@
+use std::{
+    io::{Cursor, Read},
+    path::Path,
+};
+
+use rustc_hash::FxHashMap;
+use tar::Archive;
+
+use crate::{
+    analyze_entropy, data_header, dedup::DedupManifest, error::IoContext, forced_decision,
+    hard_link_target, manifest::ChecksumManifest, meta::ArchiveMeta, normalize_entry_path, plain,
+    progress::Progress, root_entry_kind, spool::SpoolLocation, ArchiveWriter, CompressOptions,
+    Result, RootEntry, TtareError, Xattrs,
+};
+
+/// Compresses the named blobs in `inputs` into a ttare archive, returned as bytes.
+///
+/// Each blob is classified like a file of the same name would be, by the rules, its extension and
+/// its entropy, and the compressed data is staged in memory, so nothing touches the disk. The
+/// entries are plain files with the mode `0o644`, and the names have to be relative paths.
+pub fn compress_to_vec(inputs: &[(String, Vec<u8>)], opts: CompressOptions) -> Result<Vec<u8>> {
+    opts.check()?;
+
+    let mut output = vec![];
+    let mut writer = ArchiveWriter::with_spools(
+        &mut output,
+        &opts,
+        Progress::new(false, &[]),
+        SpoolLocation::Memory,
+    )?;
+
+    for (name, data) in inputs {
+        let path = Path::new(name);
+        let decision = match forced_decision(path, data.len() as u64, &opts) {
+            Some(decision) => decision,
+            None => analyze_entropy(&mut Cursor::new(data), &opts)?,
+        };
+
+        let mut header = data_header(data.len() as u64, opts.entry_mtime());
+        writer.append(
+            decision,
+            &mut header,
+            path,
+            &Xattrs::default(),
+            data.as_slice(),
+        )?;
+    }
+
+    writer.finish()?;
+    Ok(output)
+}
+
+/// Decompresses the ttare archive in `archive` into memory, returning the name and contents of
+/// each of its files.
+///
+/// The files come in the order they are stored in, which puts the stored files ahead of the
+/// compressed ones, and the copies left out by deduplication last. Directories and symlinks are
+/// skipped, and names that aren't valid UTF-8 are converted lossily. Fails if the archive's
+/// checksums don't match its files.
+pub fn decompress_from_slice(archive: &[u8]) -> Result<Vec<(String, Vec<u8>)>> {
+    let mut archive = Archive::new(plain::root_tar(archive)?);
+
+    let mut files = vec![];
+    let mut meta = None;
+    let mut dedup = DedupManifest::default();
+    let mut hard_links = vec![];
+    let mut manifest = None;
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+
+        match root_entry_kind(&mut entry)? {
+            RootEntry::Meta => meta = Some(ArchiveMeta::read(entry)?),
+            RootEntry::Member(codec) => {
+                let decoder = ArchiveMeta::member_decoder(meta.as_ref(), codec, None, entry)?;
+                let mut tar = Archive::new(decoder);
+                for inner in tar.entries()? {
+                    let inner = inner?;
+                    if inner.header().entry_type().is_file() {
+                        let path = inner.path()?.into_owned();
+                        files.push(read_file(&path, inner)?);
+                    }
+                }
+            }
+            RootEntry::Compressed(file) => {
+                files.push(read_file(&file.path, file.codec.decoder(entry)?)?);
+            }
+            RootEntry::Dedup => dedup = DedupManifest::read(entry)?,
+            RootEntry::HardLink => {
+                let path = entry.path()?.into_owned();
+                hard_links.push((path.clone(), hard_link_target(&entry, &path)?));
+            }
+            RootEntry::Manifest => manifest = Some(ChecksumManifest::read(entry)?),
+            RootEntry::Checksum | RootEntry::Index | RootEntry::Directory | RootEntry::Symlink => {}
+            RootEntry::File => {
+                let path = entry.path()?.into_owned();
+                files.push(read_file(&path, entry)?);
+            }
+        }
+    }
+
+    // A hard link holds what the file it links to holds, which can be a copy itself
+    dedup.copies.extend(hard_links);
+    for (copy, original) in &dedup.copies {
+        let original = normalize_entry_path(original)
+            .to_string_lossy()
+            .into_owned();
+        let data = files
+            .iter()
+            .find(|(name, _)| *name == original)
+            .map(|(_, data)| data.clone())
+            .ok_or_else(|| {
+                TtareError::CorruptArchive(format!(
+                    "{} is a copy of {}, which isn't in the archive",
+                    copy.display(),
+                    original
+                ))
+            })?;
+        files.push((
+            normalize_entry_path(copy).to_string_lossy().into_owned(),
+            data,
+        ));
+    }
+
+    if let Some(manifest) = manifest {
+        let actual: FxHashMap<_, _> = files
+            .iter()
+            .map(|(name, data)| (name.into(), crc32fast::hash(data)))
+            .collect();
+        manifest.check(&actual)?;
+    }
+
+    Ok(files)
+}
+
+/// Reads the contents of the file at `path` from `data`.
+fn read_file(path: &Path, mut data: impl Read) -> Result<(String, Vec<u8>)> {
+    let mut contents = vec![];
+    data.read_to_end(&mut contents)
+        .with_path("Could not read", path)?;
+    Ok((
+        normalize_entry_path(path).to_string_lossy().into_owned(),
+        contents,
+    ))
+}
@@ -0,0 +1,224 @@
+use std::{io::Read, num::NonZeroU64};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    Codec, CompressOptions, DecisionRule, DecisionRules, Estimator, Result, TtareError,
+    ZstdDictionary, COMPRESSIBLE_FRACTION,
+};
+
+/// The version of the archive format written by this version of ttare.
+///
+/// Archives written before the format had a version have no `.ttare.meta` entry, and always
+/// compress their member with the codec named by its name.
+pub const TTARE_FORMAT_VERSION: u32 = 1;
+
+/// What the `.ttare.meta` entry at the front of an archive records about how it was written.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct ArchiveMeta {
+    /// The version of the archive format.
+    pub(crate) version: u32,
+
+    /// The codec of the compressed member.
+    pub(crate) codec: Codec,
+
+    /// The entropy threshold the files were classified with.
+    pub(crate) entropy_threshold: f32,
+
+    /// The percentage of each file that was sampled to compute its entropy.
+    pub(crate) sample_percentage: f32,
+
+    /// The smallest sample taken from each file. Archives that don't record it had no floor.
+    #[serde(default)]
+    pub(crate) min_sample_bytes: u64,
+
+    /// The largest sample taken from each file, if any.
+    #[serde(default)]
+    pub(crate) max_sample_bytes: Option<u64>,
+
+    /// Whether the entropy was computed over the whole of each file instead of a sample.
+    #[serde(default)]
+    pub(crate) full_entropy: bool,
+
+    /// How the entropy of each file was estimated. Archives that don't record it used the Shannon
+    /// entropy.
+    #[serde(default)]
+    pub(crate) estimator: Estimator,
+
+    /// The size of the windows the entropy was computed over, if it was.
+    #[serde(default)]
+    pub(crate) window_bytes: Option<NonZeroU64>,
+
+    /// The share of a file that had to be in compressible windows for it to be compressed.
+    #[serde(default = "compressible_fraction")]
+    pub(crate) compressible_fraction: f32,
+
+    /// Whether the files with the extension of an already compressed format were stored without
+    /// reading them. Archives that don't record it read every file.
+    #[serde(default)]
+    pub(crate) extension_shortcut: bool,
+
+    /// The extensions that were stored without reading them, on top of the built-in ones.
+    #[serde(default)]
+    pub(crate) incompressible_extensions: Vec<String>,
+
+    /// Whether the files that start like an already compressed format were stored without
+    /// computing their entropy.
+    #[serde(default)]
+    pub(crate) sniff: bool,
+
+    /// The rules that forced the decision for the files they matched.
+    #[serde(default)]
+    pub(crate) rules: Vec<DecisionRule>,
+
+    /// Whether every file was stored as-is, without computing its entropy.
+    #[serde(default)]
+    pub(crate) no_compress: bool,
+
+    /// Whether the files that would be stored as-is were compressed on their own too, keeping
+    /// whichever was smaller.
+    #[serde(default)]
+    pub(crate) compress_all: bool,
+
+    /// Whether the compressible files were compressed on their own instead of in the member.
+    #[serde(default)]
+    pub(crate) per_file_compression: bool,
+
+    /// Whether the CRC32 of each file was recorded.
+    #[serde(default)]
+    pub(crate) manifest: bool,
+
+    /// Whether an index of where each file is was added to the end.
+    #[serde(default)]
+    pub(crate) index: bool,
+
+    /// Whether the blocks of zeros that end the tars were left out.
+    #[serde(default)]
+    pub(crate) strip_trailer: bool,
+
+    /// The digest of the zstd dictionary that the compressed member was compressed with, if any.
+    #[serde(default)]
+    pub(crate) zstd_dictionary: Option<String>,
+}
+
+impl ArchiveMeta {
+    pub(crate) fn new(opts: &CompressOptions) -> Self {
+        ArchiveMeta {
+            version: TTARE_FORMAT_VERSION,
+            codec: opts.codec,
+            entropy_threshold: opts.threshold(),
+            sample_percentage: opts.sample_percentage,
+            min_sample_bytes: opts.min_sample_bytes,
+            max_sample_bytes: opts.max_sample_bytes,
+            full_entropy: opts.full_entropy,
+            estimator: opts.estimator,
+            window_bytes: opts.window_bytes,
+            compressible_fraction: opts.compressible_fraction,
+            extension_shortcut: opts.extension_shortcut,
+            incompressible_extensions: opts.incompressible_extensions.clone(),
+            sniff: opts.sniff,
+            rules: opts.rules.rules().to_vec(),
+            no_compress: opts.no_compress,
+            compress_all: opts.compress_all,
+            per_file_compression: opts.per_file_compression,
+            manifest: opts.manifest,
+            index: opts.index,
+            strip_trailer: opts.strip_trailer,
+            zstd_dictionary: opts
+                .zstd_dictionary
+                .as_ref()
+                .map(|dictionary| dictionary.digest().to_string()),
+        }
+    }
+
+    /// Makes `opts` classify and compress files the way the archive was written.
+    pub(crate) fn apply_to(&self, opts: &mut CompressOptions) -> Result<()> {
+        opts.codec = self.codec;
+        opts.entropy_threshold = Some(self.entropy_threshold);
+        opts.sample_percentage = self.sample_percentage;
+        opts.min_sample_bytes = self.min_sample_bytes;
+        opts.max_sample_bytes = self.max_sample_bytes;
+        opts.full_entropy = self.full_entropy;
+        opts.estimator = self.estimator;
+        opts.window_bytes = self.window_bytes;
+        opts.compressible_fraction = self.compressible_fraction;
+        opts.extension_shortcut = self.extension_shortcut;
+        opts.incompressible_extensions = self.incompressible_extensions.clone();
+        opts.sniff = self.sniff;
+        opts.rules = DecisionRules::new(self.rules.clone())?;
+        opts.no_compress = self.no_compress;
+        opts.compress_all = self.compress_all;
+        opts.per_file_compression = self.per_file_compression;
+        opts.manifest = self.manifest;
+        opts.index = self.index;
+        opts.strip_trailer = self.strip_trailer;
+        opts.zstd_dictionary = self.dictionary(opts.zstd_dictionary.as_ref())?.cloned();
+        Ok(())
+    }
+
+    /// The dictionary that the compressed member was compressed with, out of the one `given`, if
+    /// any. Fails if the member needs a dictionary and `given` isn't it.
+    pub(crate) fn dictionary<'a>(
+        &self,
+        given: Option<&'a ZstdDictionary>,
+    ) -> Result<Option<&'a ZstdDictionary>> {
+        let Some(expected) = &self.zstd_dictionary else {
+            return Ok(None);
+        };
+
+        match given {
+            None => Err(TtareError::MissingDictionary(expected.clone())),
+            Some(given) if given.digest() != expected => Err(TtareError::WrongDictionary {
+                expected: expected.clone(),
+                given: given.digest().to_string(),
+            }),
+            Some(given) => Ok(Some(given)),
+        }
+    }
+
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(self).expect("the metadata can always be serialized")
+    }
+
+    /// Reads the metadata, failing if the archive was written in a newer format than this
+    /// version of ttare understands.
+    pub(crate) fn read(reader: impl Read) -> Result<Self> {
+        let meta: ArchiveMeta =
+            serde_json::from_reader(reader).map_err(TtareError::InvalidMetadata)?;
+
+        if meta.version > TTARE_FORMAT_VERSION {
+            return Err(TtareError::UnsupportedVersion {
+                version: meta.version,
+                supported: TTARE_FORMAT_VERSION,
+            });
+        }
+
+        Ok(meta)
+    }
+
+    /// The codec to decode the compressed member with, given the codec its name stands for.
+    pub(crate) fn member_codec(meta: Option<&ArchiveMeta>, named: Codec) -> Codec {
+        meta.map_or(named, |meta| meta.codec)
+    }
+
+    /// Wraps the compressed member read from `reader` in a decoder, given the codec its name
+    /// stands for and the zstd dictionary that was given, if any. Fails if the member needs
+    /// another dictionary.
+    pub(crate) fn member_decoder<'a>(
+        meta: Option<&ArchiveMeta>,
+        named: Codec,
+        dictionary: Option<&ZstdDictionary>,
+        reader: impl Read + 'a,
+    ) -> Result<Box<dyn Read + 'a>> {
+        let dictionary = match meta {
+            Some(meta) => meta.dictionary(dictionary)?,
+            None => None,
+        };
+        Self::member_codec(meta, named).decoder_with_dictionary(reader, dictionary)
+    }
+}
+
+/// The compressible fraction of archives that don't record one, which had no windows.
+fn compressible_fraction() -> f32 {
+    COMPRESSIBLE_FRACTION
+}
@@ -0,0 +1,108 @@
+use std::path::Path;
+
+use tar::Header;
+
+use crate::Result;
+
+/// Gives the extracted files the owner and group recorded in the archive, as long as that is
+/// allowed. The first time it isn't, because ttare isn't running as root, it warns and leaves the
+/// rest of the files to whoever is extracting them.
+pub(crate) struct OwnerRestorer {
+    enabled: bool,
+}
+
+impl OwnerRestorer {
+    pub(crate) fn new(enabled: bool) -> Self {
+        OwnerRestorer { enabled }
+    }
+
+    /// Gives the file extracted at `path` the owner and group in `header`.
+    ///
+    /// Changing the owner clears the setuid and setgid bits, so the mode in `header` is set again
+    /// when it has them.
+    #[cfg(unix)]
+    pub(crate) fn restore(&mut self, path: &Path, header: &Header) -> Result<()> {
+        use std::os::unix::fs::{chown, lchown};
+
+        if !self.enabled {
+            return Ok(());
+        }
+
+        let (uid, gid) = (owner_id(header.uid()?), owner_id(header.gid()?));
+        if header.entry_type().is_symlink() {
+            return self.check(lchown(path, uid, gid), path);
+        }
+
+        self.check(chown(path, uid, gid), path)?;
+        let mode = header.mode()?;
+        if self.enabled && mode & 0o6000 != 0 {
+            crate::per_file::set_mode(path, mode)?;
+        }
+        Ok(())
+    }
+
+    /// Gives the file at `copy` the owner and group of the file at `original`.
+    #[cfg(unix)]
+    pub(crate) fn restore_like(&mut self, copy: &Path, original: &Path) -> Result<()> {
+        use std::os::unix::fs::{chown, MetadataExt, PermissionsExt};
+
+        if !self.enabled {
+            return Ok(());
+        }
+
+        let metadata = std::fs::metadata(original)?;
+        self.check(
+            chown(copy, Some(metadata.uid()), Some(metadata.gid())),
+            copy,
+        )?;
+        if self.enabled && metadata.mode() & 0o6000 != 0 {
+            crate::per_file::set_mode(copy, metadata.permissions().mode())?;
+        }
+        Ok(())
+    }
+
+    /// Stops restoring the owners if `result` says it isn't allowed, or fails with its error.
+    #[cfg(unix)]
+    fn check(&mut self, result: std::io::Result<()>, path: &Path) -> Result<()> {
+        use crate::error::IoContext;
+
+        match result {
+            Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+                log::warn!(
+                    "can't restore the owners of the files, only root can: {}",
+                    e
+                );
+                self.enabled = false;
+                Ok(())
+            }
+            result => result.with_path("Could not restore the owner of", path),
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub(crate) fn restore(&mut self, _path: &Path, _header: &Header) -> Result<()> {
+        self.unsupported();
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    pub(crate) fn restore_like(&mut self, _copy: &Path, _original: &Path) -> Result<()> {
+        self.unsupported();
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn unsupported(&mut self) {
+        if self.enabled {
+            log::warn!("the owners of the files can only be restored on Unix");
+            self.enabled = false;
+        }
+    }
+}
+
+/// The owner or group ID in a header, which is as wide as an archive can hold, as one the system
+/// takes. IDs that don't fit are left unchanged.
+#[cfg(unix)]
+fn owner_id(id: u64) -> Option<u32> {
+    u32::try_from(id).ok()
+}
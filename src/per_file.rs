@@ -0,0 +1,220 @@
+use std::{
+    ffi::OsString,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use tar::{Builder, Entry, Header};
+
+use crate::{
+    check_entry_path, check_overwrite,
+    error::IoContext,
+    spool::{Spool, SpoolLocation},
+    walk::{path_from_bytes, path_to_bytes},
+    xattrs::Xattrs,
+    Codec, Result, TtareError,
+};
+
+/// The PAX extension that marks an entry of the root tar as a file compressed on its own, naming
+/// its codec.
+const PAX_CODEC_KEY: &str = "TTARE.codec";
+
+/// The PAX extension that holds the size of a file compressed on its own, before compression.
+const PAX_SIZE_KEY: &str = "TTARE.size";
+
+/// A file of the root tar that was compressed on its own, with `--per-file-compression`.
+///
+/// It is stored under its own name with the codec's suffix, such as `notes.txt.gz`, so that it can
+/// still be decompressed by hand after extracting it with any tar. What tells it apart from a file
+/// that is really named like that is the PAX extension naming its codec.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct CompressedFile {
+    /// The codec the file was compressed with.
+    pub(crate) codec: Codec,
+
+    /// The path of the file, without the codec's suffix.
+    pub(crate) path: PathBuf,
+
+    /// The size of the file, before compression.
+    pub(crate) size: u64,
+}
+
+impl CompressedFile {
+    /// Reads what `entry` holds if it is a file compressed on its own.
+    pub(crate) fn from_entry<R: Read>(entry: &mut Entry<R>) -> Result<Option<Self>> {
+        let mut codec = None;
+        let mut size = None;
+
+        if let Some(extensions) = entry.pax_extensions()? {
+            for extension in extensions {
+                let extension = extension?;
+                let value = || {
+                    extension.value().map_err(|_| {
+                        TtareError::CorruptArchive("a PAX extension isn't UTF-8".to_string())
+                    })
+                };
+
+                match extension.key() {
+                    Ok(PAX_CODEC_KEY) => {
+                        let name = value()?;
+                        codec = Some(Codec::from_name(name).ok_or_else(|| {
+                            TtareError::CorruptArchive(format!("unknown codec {}", name))
+                        })?);
+                    }
+                    Ok(PAX_SIZE_KEY) => {
+                        size = Some(value()?.parse().map_err(|_| {
+                            TtareError::CorruptArchive(format!(
+                                "invalid size {}",
+                                value().unwrap_or_default()
+                            ))
+                        })?);
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        let Some(codec) = codec else {
+            return Ok(None);
+        };
+
+        let stored = entry.path()?;
+        let stored_bytes = path_to_bytes(&stored)?;
+        let name = stored_bytes
+            .strip_suffix(codec.suffix().as_bytes())
+            .ok_or_else(|| {
+                TtareError::CorruptArchive(format!(
+                    "{} doesn't end with {}",
+                    stored.display(),
+                    codec.suffix()
+                ))
+            })?;
+        let path = path_from_bytes(name)?;
+
+        Ok(Some(CompressedFile {
+            codec,
+            path,
+            size: size.ok_or_else(|| {
+                TtareError::CorruptArchive(format!("{} has no size", stored.display()))
+            })?,
+        }))
+    }
+}
+
+/// Compresses `data` with `codec` into `spool`, replacing what it held, and rewinds it, reading and
+/// writing through buffers of `buffer_size` bytes. Returns the size of the compressed data.
+pub(crate) fn compress(
+    spool: &mut Spool,
+    codec: Codec,
+    level: Option<u32>,
+    buffer_size: usize,
+    data: impl Read,
+) -> Result<u64> {
+    spool.clear()?;
+
+    let mut encoder = codec.encoder(
+        BufWriter::with_capacity(buffer_size, &mut *spool),
+        level,
+        None,
+    )?;
+    io::copy(
+        &mut BufReader::with_capacity(buffer_size, data),
+        &mut encoder,
+    )?;
+    encoder.finish()?.flush()?;
+
+    let compressed_len = spool.stream_position()?;
+    spool.seek(SeekFrom::Start(0))?;
+    Ok(compressed_len)
+}
+
+/// Compresses `data` with `codec` into a new spool at `location`, returning it rewound, with the
+/// size of the compressed data.
+pub(crate) fn spool(
+    codec: Codec,
+    level: Option<u32>,
+    location: &SpoolLocation,
+    buffer_size: usize,
+    data: impl Read,
+) -> Result<(Spool, u64)> {
+    let mut spool = location.spool()?;
+    let compressed_len = compress(&mut spool, codec, level, buffer_size, data)?;
+    Ok((spool, compressed_len))
+}
+
+/// Adds the `compressed_len` bytes that `compress` staged in `spool` to `tar`, under `path` with
+/// the codec's suffix. `header` describes the file before compression, and `xattrs` are of the
+/// file, which share its PAX extensions.
+pub(crate) fn append_spooled<W: Write>(
+    tar: &mut Builder<W>,
+    spool: &mut Spool,
+    compressed_len: u64,
+    codec: Codec,
+    header: &mut Header,
+    path: &Path,
+    xattrs: &Xattrs,
+) -> Result<()> {
+    let size = header.size()?.to_string();
+    let xattrs: Vec<_> = xattrs.pax_extensions().collect();
+    tar.append_pax_extensions(
+        [
+            (PAX_CODEC_KEY, codec.name().as_bytes()),
+            (PAX_SIZE_KEY, size.as_bytes()),
+        ]
+        .into_iter()
+        .chain(xattrs.iter().map(|(key, value)| (key.as_str(), *value))),
+    )?;
+
+    let mut name = OsString::from(path);
+    name.push(codec.suffix());
+    header.set_size(compressed_len);
+    tar.append_data(header, name, spool.take(compressed_len))?;
+
+    Ok(())
+}
+
+/// Decompresses the file compressed on its own in `entry` into `output_dir` at `path`, which is
+/// the path of the file unless components were stripped from it, restoring its permissions and
+/// modification time like the other files. Fails if the file is already there, unless it can
+/// `overwrite` it.
+pub(crate) fn unpack<R: Read>(
+    entry: Entry<R>,
+    file: &CompressedFile,
+    path: &Path,
+    output_dir: &Path,
+    overwrite: bool,
+) -> Result<()> {
+    check_entry_path(path)?;
+    check_overwrite(output_dir, path, overwrite)?;
+
+    let mode = entry.header().mode()?;
+    let mtime = entry.header().mtime()?;
+
+    let path = output_dir.join(path);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut output = File::create(&path).with_path("Could not create", &path)?;
+    io::copy(&mut file.codec.decoder(entry)?, &mut output)
+        .with_path("Could not decompress", &file.path)?;
+
+    output.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(mtime))?;
+    set_mode(&path, mode)?;
+
+    Ok(())
+}
+
+#[cfg(unix)]
+pub(crate) fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn set_mode(_path: &Path, _mode: u32) -> io::Result<()> {
+    Ok(())
+}
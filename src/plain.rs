@@ -0,0 +1,76 @@
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+
+use flate2::read::MultiGzDecoder;
+
+use crate::codec::Encoder;
+
+/// The first bytes of a gzip stream.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// What the root tar is written to: the output, or an encoder in front of it when the archive is a
+/// plain tar compressed as a whole.
+pub(crate) enum RootWriter<W: Write> {
+    Tar(W),
+    Compressed(Encoder<W>),
+}
+
+impl<W: Write> RootWriter<W> {
+    /// Writes out the end of the compressed stream, if there is one, returning the output.
+    pub(crate) fn finish(self) -> io::Result<W> {
+        match self {
+            RootWriter::Tar(mut output) => {
+                output.flush()?;
+                Ok(output)
+            }
+            RootWriter::Compressed(encoder) => {
+                let mut output = encoder.finish()?;
+                output.flush()?;
+                Ok(output)
+            }
+        }
+    }
+}
+
+impl<W: Write> Write for RootWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            RootWriter::Tar(output) => output.write(buf),
+            RootWriter::Compressed(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            RootWriter::Tar(output) => output.flush(),
+            RootWriter::Compressed(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Reads the root tar of the archive read from `reader`, gunzipping it first when it's a plain
+/// tar.gz, as `plain_targz` writes. A root tar never starts like gzip, since it starts with the
+/// name of its first entry.
+pub(crate) fn root_tar<'a>(reader: impl Read + 'a) -> io::Result<Box<dyn Read + 'a>> {
+    Ok(open_root_tar(reader)?.0)
+}
+
+/// Reads the root tar like `root_tar` does, along with whether the archive is a plain tar.gz.
+pub(crate) fn open_root_tar<'a>(reader: impl Read + 'a) -> io::Result<(Box<dyn Read + 'a>, bool)> {
+    let mut reader = BufReader::new(reader);
+    if reader.fill_buf()?.starts_with(&GZIP_MAGIC) {
+        Ok((Box::new(MultiGzDecoder::new(reader)), true))
+    } else {
+        Ok((Box::new(reader), false))
+    }
+}
+
+/// Whether `reader` starts like gzip, as a plain tar.gz does, leaving it at the start.
+pub(crate) fn starts_like_gzip<R: Read + Seek>(reader: &mut R) -> io::Result<bool> {
+    let mut magic = vec![];
+    reader
+        .by_ref()
+        .take(GZIP_MAGIC.len() as u64)
+        .read_to_end(&mut magic)?;
+    reader.seek(SeekFrom::Start(0))?;
+    Ok(magic == GZIP_MAGIC)
+}
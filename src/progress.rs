@@ -0,0 +1,96 @@
+use std::{
+    fmt, fs,
+    io::Read,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use indicatif::{ProgressBar, ProgressState, ProgressStyle};
+
+/// Reports how far along a run is, on stderr.
+///
+/// Updating the progress doesn't take a lock, so the parallel analysis doesn't wait on it.
+pub(crate) struct Progress {
+    bar: ProgressBar,
+    files: Arc<AtomicUsize>,
+    total_files: usize,
+}
+
+impl Progress {
+    /// Creates the progress of a run over `files`, which is only shown if `enabled`.
+    pub(crate) fn new(enabled: bool, files: &[PathBuf]) -> Self {
+        let done = Arc::new(AtomicUsize::new(0));
+        let total_files = files.len();
+
+        if !enabled {
+            return Progress {
+                bar: ProgressBar::hidden(),
+                files: done,
+                total_files,
+            };
+        }
+
+        let total_bytes = files
+            .iter()
+            .filter_map(|path| fs::metadata(path).ok())
+            .map(|metadata| metadata.len())
+            .sum();
+
+        let counter = done.clone();
+        let style = ProgressStyle::with_template(
+            "{msg:>11} [{bar:30}] {files} files, {bytes}/{total_bytes}",
+        )
+        .expect("the progress template is valid")
+        .with_key("files", move |_: &ProgressState, w: &mut dyn fmt::Write| {
+            let _ = write!(w, "{}/{}", counter.load(Ordering::Relaxed), total_files);
+        })
+        .progress_chars("=> ");
+
+        Progress {
+            bar: ProgressBar::new(total_bytes).with_style(style),
+            files: done,
+            total_files,
+        }
+    }
+
+    /// Starts a new phase of the run, counting the files from zero again.
+    pub(crate) fn phase(&self, name: &'static str) {
+        self.files.store(0, Ordering::Relaxed);
+        self.bar.set_position(0);
+        self.bar.set_message(name);
+    }
+
+    /// Records that a file of `bytes` bytes went through the current phase.
+    pub(crate) fn file_done(&self, bytes: u64) {
+        self.files.fetch_add(1, Ordering::Relaxed);
+        self.bar.inc(bytes);
+    }
+
+    /// Starts the last phase, where the compressed member of `len` bytes is read back from
+    /// `reader` into the archive.
+    pub(crate) fn writing<R: Read>(&self, len: u64, reader: R) -> impl Read {
+        self.files.store(self.total_files, Ordering::Relaxed);
+        self.bar.set_position(0);
+        self.bar.set_length(len);
+        self.bar.set_message("writing");
+        self.bar.wrap_read(reader)
+    }
+
+    /// Logs a warning without garbling the progress bar.
+    pub(crate) fn warn(&self, warning: fmt::Arguments) {
+        self.bar.suspend(|| log::warn!("{}", warning));
+    }
+
+    /// Prints a line to stderr, whatever the log level, without garbling the progress bar.
+    pub(crate) fn note(&self, line: fmt::Arguments) {
+        self.bar.suspend(|| eprintln!("{}", line));
+    }
+
+    /// Removes the progress bar.
+    pub(crate) fn finish(&self) {
+        self.bar.finish_and_clear();
+    }
+}
@@ -0,0 +1,108 @@
+use std::{
+    env,
+    path::{Path, PathBuf},
+};
+
+use tar::Archive;
+
+use crate::{
+    compress, decompress, error::IoContext, gather_files, meta::ArchiveMeta, plain,
+    root_entry_kind, split, Codec, CompressOptions, CompressSummary, DecompressOptions, Result,
+    RootEntry, WalkOptions, ZstdDictionary,
+};
+
+/// How `recompress` writes the new archive, and what it classifies its files with instead of the
+/// settings recorded in the old one.
+#[derive(Clone, Debug, Default)]
+pub struct RecompressOptions {
+    /// The codec of the new archive.
+    pub codec: Codec,
+
+    /// The level of the codec, or its default level if `None`.
+    pub compression_level: Option<u32>,
+
+    /// Classifies the files with this threshold instead of the one the archive was written with.
+    pub entropy_threshold: Option<f32>,
+
+    /// Samples this percentage of each file instead of the one the archive was written with.
+    pub sample_percentage: Option<f32>,
+
+    /// The zstd dictionary the archive was compressed with, if it was. The new archive is
+    /// compressed with it too if it's compressed with zstd.
+    pub zstd_dictionary: Option<ZstdDictionary>,
+
+    /// Where the files are extracted to, and the compressed data spooled, before they are added
+    /// to the new archive. Defaults to the system's temporary directory.
+    pub temp_dir: Option<PathBuf>,
+
+    /// Replaces the archive at the output path, if there is one already.
+    pub overwrite: bool,
+
+    /// Shows a progress bar on stderr as the new archive is written.
+    pub progress: bool,
+}
+
+/// Writes the files of the ttare archive at `input` into a new ttare archive at `output`, such as
+/// to change its codec.
+///
+/// The files are classified with the settings recorded in the archive unless `opts` overrides
+/// them, and are extracted into a temporary directory first. Their owners and extended attributes
+/// aren't carried over, and the copies of identical files are stored as files of their own.
+pub fn recompress(input: &Path, output: &Path, opts: RecompressOptions) -> Result<CompressSummary> {
+    let mut compress_opts = CompressOptions {
+        zstd_dictionary: opts.zstd_dictionary.clone(),
+        ..CompressOptions::default()
+    };
+    if let Some(meta) = read_meta(input)? {
+        meta.apply_to(&mut compress_opts)?;
+    }
+
+    let dir = opts.temp_dir.clone().unwrap_or_else(env::temp_dir);
+    let extracted = tempfile::Builder::new()
+        .prefix(".ttare-recompress")
+        .tempdir_in(&dir)
+        .with_path("Could not create a temporary directory in", &dir)?;
+    let decompress_opts = DecompressOptions {
+        zstd_dictionary: compress_opts.zstd_dictionary.clone(),
+        ..DecompressOptions::default()
+    };
+    decompress(input, extracted.path(), decompress_opts)?;
+
+    compress_opts.codec = opts.codec;
+    compress_opts.compression_level = opts.compression_level;
+    if let Some(threshold) = opts.entropy_threshold {
+        compress_opts.entropy_threshold = Some(threshold);
+    }
+    if let Some(percentage) = opts.sample_percentage {
+        compress_opts.sample_percentage = percentage;
+    }
+    if opts.codec != Codec::Zstd {
+        compress_opts.zstd_dictionary = None;
+    }
+    compress_opts.temp_dir = opts.temp_dir;
+    compress_opts.overwrite = opts.overwrite;
+    compress_opts.progress = opts.progress;
+    compress_opts.base_dir = Some(extracted.path().to_path_buf());
+    // The archives among the files were already in the old archive
+    compress_opts.allow_nested = true;
+
+    let walk_opts = WalkOptions {
+        recursive: true,
+        ..WalkOptions::default()
+    };
+    let paths = gather_files(&[extracted.path().to_path_buf()], &walk_opts)?.into_paths();
+    compress(&paths, output, compress_opts)
+}
+
+/// Reads the metadata at the front of the archive at `input`, if it has any.
+fn read_meta(input: &Path) -> Result<Option<ArchiveMeta>> {
+    let mut archive = Archive::new(plain::root_tar(split::open_archive(input)?)?);
+    let Some(entry) = archive.entries()?.next() else {
+        return Ok(None);
+    };
+    let mut entry = entry?;
+    if root_entry_kind(&mut entry)? != RootEntry::Meta {
+        return Ok(None);
+    }
+    ArchiveMeta::read(entry).map(Some)
+}
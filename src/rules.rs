@@ -0,0 +1,96 @@
+use std::{io::Read, path::Path};
+
+use globset::GlobSet;
+use serde::{Deserialize, Serialize};
+
+use crate::{error::IoContext, walk::build_globs, EntropyAnalysis, Result, TtareError};
+
+/// A glob that forces the decision for the files it matches, whatever their entropy.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DecisionRule {
+    /// The glob, matched against the path of the file or its file name.
+    pub pattern: String,
+
+    /// Whether the files it matches are compressed or stored.
+    pub decision: EntropyAnalysis,
+}
+
+/// Rules that force the decision for the files they match, which take precedence over both the
+/// extension shortcut and the entropy threshold. The files they match aren't read to compute
+/// their entropy.
+///
+/// When several rules match a file, the first one wins.
+#[derive(Clone, Debug, Default)]
+pub struct DecisionRules {
+    rules: Vec<DecisionRule>,
+    globs: GlobSet,
+}
+
+impl DecisionRules {
+    /// Compiles `rules`, failing if any of their globs is invalid.
+    pub fn new(rules: Vec<DecisionRule>) -> Result<Self> {
+        let patterns: Vec<String> = rules.iter().map(|rule| rule.pattern.clone()).collect();
+        let globs = build_globs(&patterns)?;
+        Ok(DecisionRules { rules, globs })
+    }
+
+    /// Reads rules from `reader`, one per line, as `PATTERN = compress` or `PATTERN = store`.
+    ///
+    /// Blank lines and lines starting with `#` are ignored.
+    pub fn read<R: Read>(mut reader: R) -> Result<Self> {
+        let mut text = String::new();
+        reader
+            .read_to_string(&mut text)
+            .with_action("Could not read the rules")?;
+
+        let mut rules = vec![];
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let invalid = || TtareError::InvalidRule {
+                line: index + 1,
+                text: line.to_string(),
+            };
+            // Globs can hold an `=`, decisions can't
+            let (pattern, decision) = line.rsplit_once('=').ok_or_else(invalid)?;
+            let decision = match decision.trim() {
+                "compress" => EntropyAnalysis::Compress,
+                "store" => EntropyAnalysis::DontCompress,
+                _ => return Err(invalid()),
+            };
+            let pattern = pattern.trim();
+            if pattern.is_empty() {
+                return Err(invalid());
+            }
+
+            rules.push(DecisionRule {
+                pattern: pattern.to_string(),
+                decision,
+            });
+        }
+
+        Self::new(rules)
+    }
+
+    /// The rules, in the order they are tried.
+    pub fn rules(&self) -> &[DecisionRule] {
+        &self.rules
+    }
+
+    /// The decision forced for `path` by the first rule that matches it, if any.
+    pub fn decision_for(&self, path: &Path) -> Option<EntropyAnalysis> {
+        if self.rules.is_empty() {
+            return None;
+        }
+
+        let by_name = path
+            .file_name()
+            .map(|name| self.globs.matches(name))
+            .unwrap_or_default();
+        let first = self.globs.matches(path).into_iter().chain(by_name).min()?;
+        Some(self.rules[first].decision)
+    }
+}
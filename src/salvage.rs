@@ -0,0 +1,83 @@
+use std::{error::Error, fmt::Display, fs, path::Path};
+
+use crate::{Result, TtareError};
+
+/// Keeps extracting an archive past what can't be extracted from it, when it's enabled, and
+/// remembers what that was so that it can be reported once everything else was extracted.
+pub(crate) struct Salvage {
+    enabled: bool,
+    lost: Vec<String>,
+}
+
+impl Salvage {
+    pub(crate) fn new(enabled: bool) -> Self {
+        Salvage {
+            enabled,
+            lost: vec![],
+        }
+    }
+
+    /// Returns what `result`, of extracting `what`, holds. If it's an error, it is only returned
+    /// when salvaging is off, or when it's about a file that is already there, which says
+    /// nothing about the archive. Otherwise it's logged, `what` is remembered as lost and `None`
+    /// is returned.
+    pub(crate) fn recover<T>(
+        &mut self,
+        what: impl Display,
+        result: Result<T>,
+    ) -> Result<Option<T>> {
+        match result {
+            Ok(value) => Ok(Some(value)),
+            Err(e) if !self.enabled || matches!(e, TtareError::OutputExists(_)) => Err(e),
+            Err(e) => {
+                let mut message = e.to_string();
+                let mut source = e.source();
+                while let Some(cause) = source {
+                    message = format!("{}: {}", message, cause);
+                    source = cause.source();
+                }
+                log::warn!("could not recover {}: {}", what, message);
+                self.lost.push(what.to_string());
+                Ok(None)
+            }
+        }
+    }
+
+    /// Like `recover`, also removing what was extracted at `path` before it failed, so that only
+    /// complete files are left.
+    pub(crate) fn recover_file<T>(
+        &mut self,
+        path: &Path,
+        target: &Path,
+        result: Result<T>,
+    ) -> Result<Option<T>> {
+        let recovered = self.recover(path.display(), result)?;
+        if recovered.is_none() {
+            // It can be a directory that was already there, which isn't removed
+            let _ = fs::remove_file(target);
+        }
+        Ok(recovered)
+    }
+
+    /// Fails with everything that was lost, along with the files that didn't match their
+    /// checksum in `checked`, or returns `checked` if nothing was lost.
+    pub(crate) fn finish(mut self, checked: Result<()>) -> Result<()> {
+        match checked {
+            Err(TtareError::ChecksumMismatch(paths)) if self.enabled => {
+                for path in paths {
+                    let path = path.display().to_string();
+                    if !self.lost.contains(&path) {
+                        self.lost.push(path);
+                    }
+                }
+            }
+            checked => checked?,
+        }
+
+        if self.lost.is_empty() {
+            Ok(())
+        } else {
+            Err(TtareError::Unrecoverable(self.lost))
+        }
+    }
+}
@@ -0,0 +1,223 @@
+use std::{
+    fs::{self, File},
+    io::{self, Read, Write},
+    num::NonZeroU64,
+    path::{Path, PathBuf},
+};
+
+use crate::{error::IoContext, Result, TtareError};
+
+/// The extension of the first part of a split archive.
+const FIRST_PART_EXTENSION: &str = "001";
+
+/// The path of part `index` of the archive split from `base`, counting from 1, such as
+/// `archive.ttare.002`.
+pub(crate) fn part_path(base: &Path, index: usize) -> PathBuf {
+    let mut path = base.as_os_str().to_owned();
+    path.push(format!(".{:03}", index));
+    PathBuf::from(path)
+}
+
+/// Whether `input` is named like the first part of a split archive.
+pub(crate) fn is_split(input: &Path) -> bool {
+    input.extension().and_then(|ext| ext.to_str()) == Some(FIRST_PART_EXTENSION)
+}
+
+/// Opens the archive at `input` to read it, along with the parts that follow it if it is the
+/// first part of a split archive, named like `archive.ttare.001`.
+pub(crate) fn open_archive(input: &Path) -> Result<Box<dyn Read>> {
+    if is_split(input) {
+        Ok(Box::new(SplitReader::open(input)?))
+    } else {
+        Ok(Box::new(
+            File::open(input).with_path("Could not open", input)?,
+        ))
+    }
+}
+
+/// Writes an archive as parts of at most `part_size` bytes, named after the archive with the
+/// number of the part, such as `archive.ttare.001`.
+///
+/// Every part but the last is full, and the last one never is, even if that means it's empty, so
+/// that a reader can tell when parts are missing at the end.
+pub(crate) struct SplitWriter {
+    base: PathBuf,
+    part_size: u64,
+    part: Option<File>,
+    parts: usize,
+    written: u64,
+}
+
+impl SplitWriter {
+    pub(crate) fn new(base: &Path, part_size: NonZeroU64) -> Self {
+        SplitWriter {
+            base: base.to_path_buf(),
+            part_size: part_size.get(),
+            part: None,
+            parts: 0,
+            written: 0,
+        }
+    }
+
+    /// Starts writing the next part.
+    fn next_part(&mut self) -> io::Result<()> {
+        let path = part_path(&self.base, self.parts + 1);
+        let part = File::create(&path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Could not create {}: {}", path.display(), e),
+            )
+        })?;
+
+        self.part = Some(part);
+        self.parts += 1;
+        self.written = 0;
+        Ok(())
+    }
+
+    /// Writes the last part, and removes the parts left over from an archive that was split into
+    /// more parts at the same path.
+    pub(crate) fn finish(mut self) -> Result<()> {
+        if self.parts == 0 || self.parts > 1 && self.written == self.part_size {
+            self.next_part()?;
+        }
+        self.flush()?;
+
+        let mut index = self.parts + 1;
+        loop {
+            let stale = part_path(&self.base, index);
+            match fs::remove_file(&stale) {
+                Ok(()) => index += 1,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+                Err(e) => return Err(e).with_path("Could not remove", &stale),
+            }
+        }
+    }
+
+    /// Removes the parts written so far, so that a failed run doesn't leave a truncated archive.
+    pub(crate) fn remove(self) {
+        drop(self.part);
+        for index in 1..=self.parts {
+            let _ = fs::remove_file(part_path(&self.base, index));
+        }
+    }
+}
+
+impl Write for SplitWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.part.is_none() || self.written == self.part_size {
+            self.next_part()?;
+        }
+
+        let room = (self.part_size - self.written).min(buf.len() as u64) as usize;
+        let written = self
+            .part
+            .as_mut()
+            .expect("a part was just started")
+            .write(&buf[..room])?;
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.part {
+            Some(part) => part.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Reads the parts of a split archive one after the other.
+struct SplitReader {
+    parts: std::vec::IntoIter<PathBuf>,
+    part: Option<File>,
+}
+
+impl SplitReader {
+    /// Finds the parts of the archive whose first part is at `first`, failing if any is missing.
+    fn open(first: &Path) -> Result<Self> {
+        let base = first.with_extension("");
+
+        let mut parts = vec![];
+        let mut sizes = vec![];
+        loop {
+            let path = part_path(&base, parts.len() + 1);
+            match fs::metadata(&path) {
+                Ok(metadata) => {
+                    sizes.push(metadata.len());
+                    parts.push(path);
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound && !parts.is_empty() => break,
+                Err(e) => return Err(e).with_path("Could not open", &path),
+            }
+        }
+
+        // A part is missing in the middle if one comes after the gap, and at the end if the last
+        // part is full, since the last part never is
+        let next = part_path(&base, parts.len() + 1);
+        let count = parts.len();
+        if count > 1 && sizes[count - 1] == sizes[0] || has_later_part(&base, count)? {
+            return Err(TtareError::MissingPart(next));
+        }
+        if let Some(short) = (1..count - 1).find(|&i| sizes[i] != sizes[0]) {
+            return Err(TtareError::CorruptArchive(format!(
+                "{} isn't the same size as the first part",
+                parts[short].display()
+            )));
+        }
+
+        Ok(SplitReader {
+            parts: parts.into_iter(),
+            part: None,
+        })
+    }
+}
+
+/// Whether there is a part of the archive split from `base` after part `count`.
+fn has_later_part(base: &Path, count: usize) -> Result<bool> {
+    let dir = match base.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let Some(name) = base.file_name().and_then(|name| name.to_str()) else {
+        return Ok(false);
+    };
+
+    for entry in fs::read_dir(dir).with_path("Could not read directory", dir)? {
+        let entry = entry.with_path("Could not read directory", dir)?;
+        let later = entry
+            .file_name()
+            .to_str()
+            .and_then(|file_name| file_name.strip_prefix(name)?.strip_prefix('.'))
+            .and_then(|index| index.parse::<usize>().ok())
+            .is_some_and(|index| index > count);
+        if later {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+impl Read for SplitReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if let Some(part) = &mut self.part {
+                match part.read(buf)? {
+                    0 if !buf.is_empty() => self.part = None,
+                    read => return Ok(read),
+                }
+            }
+
+            let Some(path) = self.parts.next() else {
+                return Ok(0);
+            };
+            self.part = Some(File::open(&path).map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!("Could not open {}: {}", path.display(), e),
+                )
+            })?);
+        }
+    }
+}
@@ -0,0 +1,79 @@
+use std::{
+    fs::File,
+    io::{self, Cursor, Read, Seek, SeekFrom, Write},
+    path::PathBuf,
+};
+
+use crate::{temp_file, Result};
+
+/// Where the compressed data is staged until its size is known and it can be added to the root
+/// tar.
+#[derive(Clone, Debug)]
+pub(crate) enum SpoolLocation {
+    /// In temporary files in this directory, or in the system's temporary directory.
+    TempDir(Option<PathBuf>),
+
+    /// In memory, for archives that are built without touching the disk.
+    Memory,
+}
+
+impl SpoolLocation {
+    /// Creates an empty spool here.
+    pub(crate) fn spool(&self) -> Result<Spool> {
+        Ok(match self {
+            SpoolLocation::TempDir(dir) => Spool::Disk(temp_file(dir.as_deref())?),
+            SpoolLocation::Memory => Spool::Memory(Cursor::default()),
+        })
+    }
+}
+
+/// Compressed data staged in a temporary file or in memory.
+pub(crate) enum Spool {
+    Disk(File),
+    Memory(Cursor<Vec<u8>>),
+}
+
+impl Spool {
+    /// Drops what the spool holds and rewinds it.
+    pub(crate) fn clear(&mut self) -> io::Result<()> {
+        match self {
+            Spool::Disk(file) => file.set_len(0)?,
+            Spool::Memory(cursor) => cursor.get_mut().clear(),
+        }
+        self.seek(SeekFrom::Start(0)).map(drop)
+    }
+}
+
+impl Read for Spool {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Spool::Disk(file) => file.read(buf),
+            Spool::Memory(cursor) => cursor.read(buf),
+        }
+    }
+}
+
+impl Write for Spool {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Spool::Disk(file) => file.write(buf),
+            Spool::Memory(cursor) => cursor.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Spool::Disk(file) => file.flush(),
+            Spool::Memory(cursor) => cursor.flush(),
+        }
+    }
+}
+
+impl Seek for Spool {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            Spool::Disk(file) => file.seek(pos),
+            Spool::Memory(cursor) => cursor.seek(pos),
+        }
+    }
+}
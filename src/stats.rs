@@ -0,0 +1,136 @@
+use std::path::PathBuf;
+
+use serde::Serialize;
+
+use crate::{analyze_files, CompressOptions, EntropyAnalysis, FileAnalysis, Result};
+
+/// How many bits per byte each bucket of `DatasetStats::histogram` spans.
+const HISTOGRAM_BUCKET_BITS: f32 = 0.5;
+
+/// What `stats` found out about a set of files, to tell how ttare would store them.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct DatasetStats {
+    /// The threshold the files were classified with.
+    pub entropy_threshold: f32,
+
+    /// The files that would be compressed, and their total size.
+    pub compressed: FileCount,
+
+    /// The files that would be stored as-is, and their total size.
+    pub stored: FileCount,
+
+    /// The files that were classified without computing their entropy, because of a rule, their
+    /// size, their extension or their format. They are counted in `compressed` or `stored` too.
+    pub unmeasured: FileCount,
+
+    /// The lowest entropy of the files whose entropy was computed, in bits per byte, or `None` if
+    /// there are none.
+    pub min_entropy: Option<f32>,
+
+    /// The median entropy of the files whose entropy was computed.
+    pub median_entropy: Option<f32>,
+
+    /// The highest entropy of the files whose entropy was computed.
+    pub max_entropy: Option<f32>,
+
+    /// How many bytes compressing the files that would be compressed could save, going by their
+    /// entropy: a file with an entropy of 2 bits per byte could shrink to a quarter of its size.
+    /// Codecs usually do better on text, from repeated sequences that the entropy doesn't see, and
+    /// the files whose entropy wasn't computed aren't counted.
+    pub potential_savings_bytes: u64,
+
+    /// How many of the files whose entropy was computed fall in each half a bit per byte, from 0 to
+    /// 8 bits per byte.
+    pub histogram: Vec<HistogramBucket>,
+}
+
+/// A number of files, and their total size.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct FileCount {
+    /// How many files there are.
+    pub files: usize,
+
+    /// Their total size, in bytes.
+    pub bytes: u64,
+}
+
+impl FileCount {
+    fn add(&mut self, bytes: u64) {
+        self.files += 1;
+        self.bytes += bytes;
+    }
+}
+
+/// The files whose entropy falls in a range of bits per byte.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct HistogramBucket {
+    /// Where the range starts, included.
+    pub from: f32,
+
+    /// Where the range ends, which is only included for the last bucket.
+    pub to: f32,
+
+    #[serde(flatten)]
+    pub count: FileCount,
+}
+
+/// Analyzes `files` as `compress` would, and sums up how they would be stored, without writing
+/// anything.
+///
+/// This is `analyze_files` summed up, for deciding whether a set of files is worth archiving with
+/// ttare and with which threshold, see `DatasetStats`.
+pub fn stats(files: &[PathBuf], opts: &CompressOptions) -> Result<DatasetStats> {
+    Ok(summarize(&analyze_files(files, opts)?, opts.threshold()))
+}
+
+/// Sums up the `analyses` of files classified with `threshold`.
+fn summarize(analyses: &[FileAnalysis], threshold: f32) -> DatasetStats {
+    let buckets = (8.0 / HISTOGRAM_BUCKET_BITS) as usize;
+    let mut stats = DatasetStats {
+        entropy_threshold: threshold,
+        histogram: (0..buckets)
+            .map(|i| HistogramBucket {
+                from: i as f32 * HISTOGRAM_BUCKET_BITS,
+                to: (i + 1) as f32 * HISTOGRAM_BUCKET_BITS,
+                count: FileCount::default(),
+            })
+            .collect(),
+        ..DatasetStats::default()
+    };
+
+    let mut entropies = vec![];
+    for analysis in analyses {
+        match analysis.decision {
+            EntropyAnalysis::Compress => stats.compressed.add(analysis.size),
+            EntropyAnalysis::DontCompress => stats.stored.add(analysis.size),
+        }
+
+        let Some(entropy) = analysis.entropy else {
+            stats.unmeasured.add(analysis.size);
+            continue;
+        };
+        entropies.push(entropy);
+        // The probe can go a little over 8 bits per byte when compressing makes the sample bigger
+        let bucket = ((entropy / HISTOGRAM_BUCKET_BITS) as usize).min(buckets - 1);
+        stats.histogram[bucket].count.add(analysis.size);
+        if analysis.decision == EntropyAnalysis::Compress {
+            let kept = f64::from(entropy.min(8.0)) / 8.0;
+            stats.potential_savings_bytes += (analysis.size as f64 * (1.0 - kept)) as u64;
+        }
+    }
+
+    entropies.sort_by(f32::total_cmp);
+    if let (Some(&min), Some(&max)) = (entropies.first(), entropies.last()) {
+        let middle = entropies.len() / 2;
+        let median = if entropies.len() % 2 == 0 {
+            (entropies[middle - 1] + entropies[middle]) / 2.0
+        } else {
+            entropies[middle]
+        };
+        stats.min_entropy = Some(min);
+        stats.median_entropy = Some(median);
+        stats.max_entropy = Some(max);
+    }
+
+    stats
+}
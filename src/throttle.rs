@@ -0,0 +1,115 @@
+use std::{
+    io::{self, Read, Seek, SeekFrom, Write},
+    num::NonZeroU64,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How long the bucket can be left unused and still have its bytes read or written at once, which
+/// keeps throttled IO from coming in bursts.
+const BURST: Duration = Duration::from_millis(100);
+
+/// Caps how many bytes per second are read and written through the readers and writers that share
+/// it, by sleeping the thread that reads or writes past the cap. Doesn't limit anything when it
+/// has no rate.
+#[derive(Clone, Default)]
+pub(crate) struct Throttle {
+    bucket: Option<Arc<Mutex<Bucket>>>,
+}
+
+/// A token bucket: the bytes that can be read or written without waiting, which fill up at the
+/// rate of the throttle, up to the bytes of a burst.
+struct Bucket {
+    bytes_per_sec: f64,
+    available: f64,
+    filled: Instant,
+}
+
+impl Throttle {
+    pub(crate) fn new(bytes_per_sec: Option<NonZeroU64>) -> Self {
+        Throttle {
+            bucket: bytes_per_sec.map(|bytes_per_sec| {
+                Arc::new(Mutex::new(Bucket {
+                    bytes_per_sec: bytes_per_sec.get() as f64,
+                    available: 0.0,
+                    filled: Instant::now(),
+                }))
+            }),
+        }
+    }
+
+    /// Wraps `inner`, so that what is read from or written to it counts against this throttle.
+    pub(crate) fn wrap<T>(&self, inner: T) -> Throttled<T> {
+        Throttled {
+            inner,
+            throttle: self.clone(),
+        }
+    }
+
+    /// Takes `bytes` out of the bucket, then sleeps until the bucket isn't in debt anymore. The
+    /// bytes have already been read or written, since the caller can't know how many there are
+    /// beforehand.
+    fn consume(&self, bytes: usize) {
+        let Some(bucket) = &self.bucket else {
+            return;
+        };
+
+        let wait = {
+            let mut bucket = bucket.lock().unwrap();
+            let now = Instant::now();
+            let elapsed = now.duration_since(bucket.filled).as_secs_f64();
+            bucket.available = (bucket.available + elapsed * bucket.bytes_per_sec)
+                .min(bucket.bytes_per_sec * BURST.as_secs_f64());
+            bucket.filled = now;
+            bucket.available -= bytes as f64;
+            (bucket.available < 0.0).then(|| -bucket.available / bucket.bytes_per_sec)
+        };
+
+        if let Some(wait) = wait {
+            thread::sleep(Duration::from_secs_f64(wait));
+        }
+    }
+}
+
+/// A reader or writer whose IO is capped by a `Throttle`.
+pub(crate) struct Throttled<T> {
+    inner: T,
+    throttle: Throttle,
+}
+
+impl<T> Throttled<T> {
+    /// The same throttled reader or writer, only borrowed.
+    pub(crate) fn borrowed(&self) -> Throttled<&T> {
+        Throttled {
+            inner: &self.inner,
+            throttle: self.throttle.clone(),
+        }
+    }
+}
+
+impl<T: Read> Read for Throttled<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.throttle.consume(read);
+        Ok(read)
+    }
+}
+
+impl<T: Write> Write for Throttled<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.throttle.consume(written);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T: Seek> Seek for Throttled<T> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
@@ -0,0 +1,194 @@
+use std::{
+    env,
+    fs::{self, File},
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+use rayon::prelude::*;
+use rustc_hash::FxHashMap;
+use tar::Archive;
+
+use crate::{
+    checksum::Crc32Reader,
+    decompress,
+    dedup::DedupManifest,
+    entry_name,
+    error::IoContext,
+    index::ArchiveIndex,
+    manifest::{crc32_of, record_crc32, ChecksumManifest},
+    meta::ArchiveMeta,
+    plain, root_entry_kind, split, Codec, CompressOptions, DecompressOptions, Result, RootEntry,
+    TtareError,
+};
+
+/// Checks that the ttare archive at `input` can be read in full, without extracting anything.
+///
+/// Every header of the root tar and of the compressed member is read, which checks their
+/// checksums, and every file is read to the end. The compressed member is checked against the
+/// CRC32 stored next to it, when the archive has one, and it has to be there if its checksum is.
+///
+/// When the archive records the CRC32 of each file, every file is checked against it, and the
+/// files that don't match or can't be read are all reported instead of only the first error.
+pub fn verify(input: &Path) -> Result<()> {
+    let mut archive = Archive::new(plain::root_tar(split::open_archive(input)?)?);
+
+    let mut meta: Option<ArchiveMeta> = None;
+    let mut expected_crc32 = None;
+    let mut has_member = false;
+    let mut manifest = None;
+    let mut actual = FxHashMap::default();
+    let mut first_error = None;
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+
+        let result = match root_entry_kind(&mut entry)? {
+            RootEntry::Meta => {
+                meta = Some(ArchiveMeta::read(entry)?);
+                Ok(())
+            }
+            RootEntry::Member(codec) => {
+                has_member = true;
+                // A member compressed with a dictionary can't be read at all without it, which
+                // says nothing about its files
+                if let Some(meta) = &meta {
+                    meta.dictionary(None)?;
+                }
+                verify_member(entry, meta.as_ref(), codec, expected_crc32, &mut actual)
+            }
+            RootEntry::Compressed(file) => file.codec.decoder(entry).and_then(|decoder| {
+                record_crc32(&mut actual, &file.path, decoder)
+                    .with_path("Could not decompress", &file.path)
+            }),
+            RootEntry::Dedup => DedupManifest::read(entry).map(drop),
+            RootEntry::Index => ArchiveIndex::read(entry).map(drop),
+            RootEntry::Manifest => {
+                manifest = Some(ChecksumManifest::read(entry)?);
+                Ok(())
+            }
+            RootEntry::Checksum => {
+                let mut checksum = String::new();
+                entry.read_to_string(&mut checksum)?;
+                expected_crc32 = Some(u32::from_str_radix(checksum.trim(), 16).map_err(|_| {
+                    TtareError::CorruptArchive(format!("invalid checksum in {}", path.display()))
+                })?);
+                Ok(())
+            }
+            RootEntry::Directory | RootEntry::Symlink | RootEntry::HardLink => Ok(()),
+            RootEntry::File => record_crc32(&mut actual, &path, &mut entry),
+        };
+
+        // The files are only checked one by one when their checksums are recorded, otherwise the
+        // first error is all there is to tell
+        match result {
+            Err(e) if meta.as_ref().is_some_and(|meta| meta.manifest) => {
+                first_error.get_or_insert(e);
+            }
+            result => result?,
+        }
+    }
+
+    if expected_crc32.is_some() && !has_member {
+        return Err(TtareError::MissingInnerMember);
+    }
+
+    if meta.as_ref().is_some_and(|meta| meta.manifest) {
+        manifest
+            .ok_or_else(|| {
+                TtareError::CorruptArchive("the checksums of the files are missing".to_string())
+            })?
+            .check(&actual)?;
+    }
+
+    first_error.map_or(Ok(()), Err)
+}
+
+/// Reads every file in the compressed member, whose name stands for `codec`, recording their CRC32
+/// in `actual`, and checks the member against `expected_crc32`.
+fn verify_member(
+    entry: impl Read,
+    meta: Option<&ArchiveMeta>,
+    codec: Codec,
+    expected_crc32: Option<u32>,
+    actual: &mut FxHashMap<PathBuf, u32>,
+) -> Result<()> {
+    let mut reader = Crc32Reader::new(entry);
+    let mut tar = Archive::new(ArchiveMeta::member_decoder(meta, codec, None, &mut reader)?);
+    for inner in tar.entries()? {
+        let mut inner = inner?;
+        let inner_path = inner.path()?.into_owned();
+        record_crc32(actual, &inner_path, &mut inner)?;
+    }
+    drop(tar);
+
+    // The decoder can stop before the end of the member, so the rest still has to be read to be
+    // checked
+    io::copy(&mut reader, &mut io::sink())?;
+
+    if let Some(expected) = expected_crc32 {
+        let actual = reader.crc32();
+        if actual != expected {
+            return Err(TtareError::CorruptArchive(format!(
+                "expected CRC32 {:08x} for the compressed member, found {:08x}",
+                expected, actual
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Extracts the archive that was just written at `archive` into a temporary directory, and checks
+/// that every file in `files`, apart from those that were `skipped`, has the same CRC32 as what it
+/// was extracted to. The directories and symlinks aren't checked.
+pub(crate) fn verify_sources(
+    archive: &Path,
+    files: &[PathBuf],
+    skipped: &[PathBuf],
+    opts: &CompressOptions,
+) -> Result<()> {
+    let dir = opts.temp_dir.clone().unwrap_or_else(env::temp_dir);
+    let extracted = tempfile::Builder::new()
+        .prefix(".ttare-verify")
+        .tempdir_in(&dir)
+        .with_path("Could not create a temporary directory in", &dir)?;
+    let decompress_opts = DecompressOptions {
+        zstd_dictionary: opts.zstd_dictionary.clone(),
+        ..DecompressOptions::default()
+    };
+    decompress(archive, extracted.path(), decompress_opts)?;
+
+    let mut mismatched = files
+        .par_iter()
+        .filter(|path| !skipped.contains(path))
+        .map(|path| {
+            let metadata = if opts.dereference {
+                fs::metadata(path)
+            } else {
+                fs::symlink_metadata(path)
+            };
+            if !metadata.with_path("Could not read", path)?.is_file() {
+                return Ok(None);
+            }
+            let Some(name) = entry_name(path, opts.base_dir.as_deref(), &opts.name_map)? else {
+                return Ok(None);
+            };
+
+            let source = File::open(path)
+                .and_then(crc32_of)
+                .with_path("Could not read", path)?;
+            // A file that is missing from the archive doesn't match either
+            let copy = File::open(extracted.path().join(&name)).and_then(crc32_of);
+            Ok((copy.ok() != Some(source)).then(|| path.clone()))
+        })
+        .filter_map(Result::transpose)
+        .collect::<Result<Vec<_>>>()?;
+
+    if !mismatched.is_empty() {
+        mismatched.sort();
+        return Err(TtareError::SourceMismatch(mismatched));
+    }
+    Ok(())
+}
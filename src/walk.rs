@@ -0,0 +1,314 @@
+use std::{
+    fs,
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use rustc_hash::FxHashSet;
+use tracing::info_span;
+
+use crate::{error::IoContext, Result, TtareError};
+
+/// How the paths given to `gather_files` are resolved.
+#[derive(Clone, Debug, Default)]
+pub struct WalkOptions {
+    /// Adds the contents of directories, recursively.
+    pub recursive: bool,
+
+    /// Skips the paths that can't be read with a warning, instead of failing.
+    pub skip_errors: bool,
+
+    /// Globs of the paths to leave out when walking directories. They take precedence over
+    /// `include`.
+    pub exclude: Vec<String>,
+
+    /// Globs of the files to keep when walking directories. All of them are kept if it is empty.
+    pub include: Vec<String>,
+
+    /// Leaves out the files found when walking directories that are larger than this many bytes.
+    pub exclude_larger_than: Option<u64>,
+
+    /// Leaves out the files found when walking directories that are smaller than this many bytes.
+    pub exclude_smaller_than: Option<u64>,
+
+    /// Follows symlinks, walking the directories they point to, instead of gathering them as
+    /// symlinks.
+    pub dereference: bool,
+}
+
+/// The files found by `gather_files`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct GatheredFiles {
+    /// The regular files to compress.
+    pub files: Vec<PathBuf>,
+
+    /// The directories that were walked, each one before the directories inside it, so that they
+    /// can be recreated even when they are empty.
+    pub dirs: Vec<PathBuf>,
+
+    /// The symlinks, unless they are followed.
+    pub symlinks: Vec<PathBuf>,
+
+    /// The paths that couldn't be read, when skipping errors.
+    pub skipped: Vec<PathBuf>,
+}
+
+impl GatheredFiles {
+    /// The directories, symlinks and files to pass to `compress`.
+    pub fn into_paths(self) -> Vec<PathBuf> {
+        self.dirs
+            .into_iter()
+            .chain(self.symlinks)
+            .chain(self.files)
+            .collect()
+    }
+}
+
+/// Resolves the paths given on the command line to the regular files to compress.
+///
+/// Directories are walked when `recursive` is set, and special files such as sockets and fifos
+/// are skipped with a warning. Symlinks are gathered as they are, even when they point to a
+/// directory, unless `dereference` is set.
+///
+/// The files found in directories are filtered with the `exclude` and `include` globs, which are
+/// matched against the path relative to the directory given on the command line, and against the
+/// file name, so that `.git` and `*.tmp` match at any depth. Excluded directories aren't walked
+/// at all. The files that are kept are then filtered by their size with `exclude_larger_than`
+/// and `exclude_smaller_than`. The paths given on the command line are never filtered.
+pub fn gather_files(paths: &[PathBuf], opts: &WalkOptions) -> Result<GatheredFiles> {
+    let _span = info_span!("gather", paths = paths.len()).entered();
+    let mut walker = Walker {
+        opts,
+        exclude: build_globs(&opts.exclude)?,
+        include: if opts.include.is_empty() {
+            None
+        } else {
+            Some(build_globs(&opts.include)?)
+        },
+        visited_dirs: FxHashSet::default(),
+        gathered: GatheredFiles::default(),
+    };
+
+    for path in paths {
+        if !opts.recursive
+            && walker
+                .metadata(path)
+                .is_ok_and(|metadata| metadata.is_dir())
+        {
+            return Err(TtareError::IsADirectory(path.clone()));
+        }
+
+        let result = walker.add_path(path, None);
+        walker.skip_or_fail(result, path)?;
+    }
+
+    Ok(walker.gathered)
+}
+
+pub(crate) fn build_globs(globs: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for glob in globs {
+        builder.add(Glob::new(glob).map_err(|source| TtareError::InvalidGlob {
+            glob: glob.clone(),
+            source,
+        })?);
+    }
+    builder.build().map_err(|source| TtareError::InvalidGlob {
+        glob: globs.join(" "),
+        source,
+    })
+}
+
+/// The state of `gather_files` while it walks the paths.
+struct Walker<'a> {
+    opts: &'a WalkOptions,
+    exclude: GlobSet,
+    include: Option<GlobSet>,
+    visited_dirs: FxHashSet<PathBuf>,
+    gathered: GatheredFiles,
+}
+
+impl Walker<'_> {
+    /// Adds `path` to the gathered files, walking it if it is a directory. `root` is the directory
+    /// given on the command line that `path` was found in, if any.
+    fn add_path(&mut self, path: &Path, root: Option<&Path>) -> Result<()> {
+        let metadata = self.metadata(path).with_path("Could not read", path)?;
+
+        if metadata.is_dir() {
+            self.walk_dir(path, root.unwrap_or(path))?;
+        } else if metadata.is_symlink() {
+            if root.is_none_or(|root| self.is_included(path, root)) {
+                self.gathered.symlinks.push(path.to_path_buf());
+            }
+        } else if metadata.is_file() {
+            if root.is_none_or(|root| self.is_included(path, root) && self.fits(metadata.len())) {
+                self.gathered.files.push(path.to_path_buf());
+            }
+        } else {
+            log::warn!("skipping special file {}", path.display());
+        }
+
+        Ok(())
+    }
+
+    /// The metadata of `path`, or of what it points to if it is a symlink that is followed.
+    fn metadata(&self, path: &Path) -> io::Result<fs::Metadata> {
+        if self.opts.dereference {
+            fs::metadata(path)
+        } else {
+            fs::symlink_metadata(path)
+        }
+    }
+
+    /// Adds every regular file under `dir` to the gathered files, in a stable order.
+    ///
+    /// When symlinks are followed, each directory is only walked once so that symlink cycles can't
+    /// loop forever.
+    fn walk_dir(&mut self, dir: &Path, root: &Path) -> Result<()> {
+        let canonical = fs::canonicalize(dir).with_path("Could not resolve directory", dir)?;
+        if !self.visited_dirs.insert(canonical) {
+            log::warn!("skipping already visited directory {}", dir.display());
+            return Ok(());
+        }
+        self.gathered.dirs.push(dir.to_path_buf());
+
+        let mut entries = fs::read_dir(dir)
+            .and_then(|entries| {
+                entries
+                    .map(|entry| entry.map(|entry| entry.path()))
+                    .collect::<io::Result<Vec<_>>>()
+            })
+            .with_path("Could not read directory", dir)?;
+        entries.sort();
+
+        for path in entries {
+            if self.is_excluded(&path, root) {
+                continue;
+            }
+
+            let result = self.add_path(&path, Some(root));
+            self.skip_or_fail(result, &path)?;
+        }
+
+        Ok(())
+    }
+
+    fn is_excluded(&self, path: &Path, root: &Path) -> bool {
+        matches(&self.exclude, path, root)
+    }
+
+    fn is_included(&self, path: &Path, root: &Path) -> bool {
+        self.include
+            .as_ref()
+            .is_none_or(|include| matches(include, path, root))
+    }
+
+    /// Whether a file of `len` bytes is within the sizes that are kept.
+    fn fits(&self, len: u64) -> bool {
+        self.opts.exclude_larger_than.is_none_or(|max| len <= max)
+            && self.opts.exclude_smaller_than.is_none_or(|min| len >= min)
+    }
+
+    /// Records `path` as skipped if `result` failed and errors are being skipped.
+    fn skip_or_fail(&mut self, result: Result<()>, path: &Path) -> Result<()> {
+        match result {
+            Err(e) if self.opts.skip_errors => {
+                log::warn!("skipping {}: {:#}", path.display(), e);
+                self.gathered.skipped.push(path.to_path_buf());
+                Ok(())
+            }
+            result => result,
+        }
+    }
+}
+
+/// Whether `globs` match `path`, relative to `root`, or its file name.
+fn matches(globs: &GlobSet, path: &Path, root: &Path) -> bool {
+    let relative = path.strip_prefix(root).unwrap_or(path);
+    globs.is_match(relative) || path.file_name().is_some_and(|name| globs.is_match(name))
+}
+
+/// Reads a list of paths from `reader`, one per line, or separated by NUL bytes when `null` is
+/// set, like GNU tar's `--files-from` and `--null`.
+///
+/// Paths are taken literally, without any shell splitting, and empty entries are ignored.
+pub fn read_file_list<R: Read>(mut reader: R, null: bool) -> Result<Vec<PathBuf>> {
+    let mut list = vec![];
+    reader
+        .read_to_end(&mut list)
+        .with_action("Could not read the list of files")?;
+
+    let separator = if null { b'\0' } else { b'\n' };
+
+    list.split(|&byte| byte == separator)
+        .filter(|path| !path.is_empty())
+        .map(path_from_bytes)
+        .collect()
+}
+
+#[cfg(unix)]
+pub(crate) fn path_from_bytes(bytes: &[u8]) -> Result<PathBuf> {
+    use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+    Ok(PathBuf::from(OsStr::from_bytes(bytes)))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn path_from_bytes(bytes: &[u8]) -> Result<PathBuf> {
+    let path = std::str::from_utf8(bytes).map_err(|_| {
+        TtareError::NotUtf8(PathBuf::from(String::from_utf8_lossy(bytes).into_owned()))
+    })?;
+    Ok(PathBuf::from(path))
+}
+
+#[cfg(unix)]
+pub(crate) fn path_to_bytes(path: &Path) -> Result<Vec<u8>> {
+    use std::os::unix::ffi::OsStrExt;
+
+    Ok(path.as_os_str().as_bytes().to_vec())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn path_to_bytes(path: &Path) -> Result<Vec<u8>> {
+    let path = path
+        .to_str()
+        .ok_or_else(|| TtareError::NotUtf8(path.to_path_buf()))?;
+    Ok(path.as_bytes().to_vec())
+}
+
+/// Stores paths in JSON as strings when they are UTF-8, and as arrays of their bytes otherwise,
+/// since JSON strings can only hold UTF-8.
+pub(crate) mod json_path {
+    use std::path::{Path, PathBuf};
+
+    use serde::{de, ser, Deserialize, Deserializer, Serializer};
+
+    use super::{path_from_bytes, path_to_bytes};
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StoredPath {
+        Utf8(String),
+        Bytes(Vec<u8>),
+    }
+
+    pub(crate) fn serialize<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+        match path.to_str() {
+            Some(path) => serializer.serialize_str(path),
+            None => {
+                let bytes = path_to_bytes(path).map_err(ser::Error::custom)?;
+                serializer.serialize_bytes(&bytes)
+            }
+        }
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<PathBuf, D::Error> {
+        match StoredPath::deserialize(deserializer)? {
+            StoredPath::Utf8(path) => Ok(PathBuf::from(path)),
+            StoredPath::Bytes(bytes) => path_from_bytes(&bytes).map_err(de::Error::custom),
+        }
+    }
+}
@@ -0,0 +1,178 @@
+use std::{
+    io::{self, Read, Write},
+    path::Path,
+};
+
+use tar::{Builder, Entry};
+
+use crate::{error::IoContext, Result};
+
+/// The prefix of the PAX extensions that hold extended attributes, as GNU tar and bsdtar write
+/// them, so that they read ttare's too.
+const PAX_XATTR_PREFIX: &str = "SCHILY.xattr.";
+
+/// Whether extended attributes can be read and written on this platform.
+#[cfg(unix)]
+pub(crate) const SUPPORTED: bool = xattr::SUPPORTED_PLATFORM;
+
+#[cfg(not(unix))]
+pub(crate) const SUPPORTED: bool = false;
+
+/// The extended attributes of a file, by name. Most files have none, which costs nothing to
+/// store.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Xattrs {
+    attrs: Vec<(String, Vec<u8>)>,
+}
+
+impl Xattrs {
+    /// Reads the extended attributes of the file at `path`, or of the file it points to if it's a
+    /// symlink and `follow` is set. The attributes whose names aren't UTF-8 are left out, with a
+    /// warning, since PAX extensions can't name them.
+    #[cfg(unix)]
+    pub(crate) fn read(path: &Path, follow: bool) -> Result<Self> {
+        let names = if follow {
+            xattr::list_deref(path)
+        } else {
+            xattr::list(path)
+        };
+        let names = match names {
+            // The file system doesn't have any
+            Err(e) if e.kind() == io::ErrorKind::Unsupported => return Ok(Xattrs::default()),
+            names => names.with_path("Could not list the extended attributes of", path)?,
+        };
+
+        let mut attrs = vec![];
+        for name in names {
+            let Some(key) = name.to_str() else {
+                log::warn!(
+                    "leaving out the extended attribute {:?} of {}, whose name isn't UTF-8",
+                    name,
+                    path.display()
+                );
+                continue;
+            };
+
+            let value = if follow {
+                xattr::get_deref(path, &name)
+            } else {
+                xattr::get(path, &name)
+            };
+            // It can be removed between listing and reading it
+            if let Some(value) =
+                value.with_path("Could not read the extended attributes of", path)?
+            {
+                attrs.push((key.to_string(), value));
+            }
+        }
+        Ok(Xattrs { attrs })
+    }
+
+    #[cfg(not(unix))]
+    pub(crate) fn read(_path: &Path, _follow: bool) -> Result<Self> {
+        Ok(Xattrs::default())
+    }
+
+    /// Reads the extended attributes stored in the PAX extensions of `entry`.
+    pub(crate) fn of_entry<R: Read>(entry: &mut Entry<R>) -> Result<Self> {
+        let mut attrs = vec![];
+        if let Some(extensions) = entry.pax_extensions()? {
+            for extension in extensions {
+                let extension = extension?;
+                if let Some(name) = extension
+                    .key()
+                    .ok()
+                    .and_then(|key| key.strip_prefix(PAX_XATTR_PREFIX))
+                {
+                    attrs.push((name.to_string(), extension.value_bytes().to_vec()));
+                }
+            }
+        }
+        Ok(Xattrs { attrs })
+    }
+
+    /// The PAX extensions that store these attributes.
+    pub(crate) fn pax_extensions(&self) -> impl Iterator<Item = (String, &[u8])> {
+        self.attrs
+            .iter()
+            .map(|(name, value)| (format!("{}{}", PAX_XATTR_PREFIX, name), value.as_slice()))
+    }
+
+    /// Adds these attributes to `tar` as the PAX extensions of the next entry, if there are any.
+    pub(crate) fn append_to<W: Write>(&self, tar: &mut Builder<W>) -> io::Result<()> {
+        if self.attrs.is_empty() {
+            return Ok(());
+        }
+
+        let extensions: Vec<_> = self.pax_extensions().collect();
+        tar.append_pax_extensions(extensions.iter().map(|(key, value)| (key.as_str(), *value)))
+    }
+
+    /// Gives these attributes to the file extracted at `path`, or to the symlink itself.
+    #[cfg(unix)]
+    fn apply(&self, path: &Path) -> io::Result<()> {
+        for (name, value) in &self.attrs {
+            xattr::set(path, name, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// Gives the extracted files the extended attributes recorded in the archive, when it's enabled,
+/// warning once if this platform doesn't have any.
+pub(crate) struct XattrRestorer {
+    enabled: bool,
+}
+
+impl XattrRestorer {
+    pub(crate) fn new(enabled: bool) -> Self {
+        if enabled && !SUPPORTED {
+            log::warn!(
+                "extended attributes aren't supported on this platform, so they aren't restored"
+            );
+        }
+        XattrRestorer {
+            enabled: enabled && SUPPORTED,
+        }
+    }
+
+    /// The extended attributes stored with `entry`, which are only read if they are restored.
+    pub(crate) fn read<R: Read>(&self, entry: &mut Entry<R>) -> Result<Xattrs> {
+        if self.enabled {
+            Xattrs::of_entry(entry)
+        } else {
+            Ok(Xattrs::default())
+        }
+    }
+
+    /// Gives `xattrs` to the file extracted at `path`. This comes after restoring its owner,
+    /// which clears some of them, such as its capabilities.
+    #[cfg(unix)]
+    pub(crate) fn restore(&self, path: &Path, xattrs: &Xattrs) -> Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        xattrs
+            .apply(path)
+            .with_path("Could not restore the extended attributes of", path)
+    }
+
+    /// Gives the file at `copy` the extended attributes of the file at `original`.
+    #[cfg(unix)]
+    pub(crate) fn restore_like(&self, copy: &Path, original: &Path) -> Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        self.restore(copy, &Xattrs::read(original, false)?)
+    }
+
+    #[cfg(not(unix))]
+    pub(crate) fn restore(&self, _path: &Path, _xattrs: &Xattrs) -> Result<()> {
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    pub(crate) fn restore_like(&self, _copy: &Path, _original: &Path) -> Result<()> {
+        Ok(())
+    }
+}
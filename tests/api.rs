@@ -0,0 +1,1960 @@
+use std::{
+    fs,
+    io::{self, Cursor, Read, Write},
+    num::NonZeroU64,
+    path::{Path, PathBuf},
+};
+
+use tempfile::TempDir;
+use ttare::{
+    analyze_entropy, classify, entropy, gather_files, suggest_threshold, threshold_from_percentage,
+    Codec, CompressOptions, DecisionRules, DecompressOptions, EntropyAnalysis, Estimator,
+    Histogram, OnChange, RecompressOptions, TtareError, WalkOptions, ZstdDictionary,
+};
+
+mod common;
+
+use common::noise;
+
+#[test]
+fn analysis_decides_on_entropy() {
+    let opts = CompressOptions::default();
+
+    let text = b"the quick brown fox jumps over the lazy dog ".repeat(1000);
+    assert_eq!(
+        analyze_entropy(&mut Cursor::new(text), &opts).unwrap(),
+        EntropyAnalysis::Compress
+    );
+
+    assert_eq!(
+        analyze_entropy(&mut Cursor::new(noise(64 * 1024)), &opts).unwrap(),
+        EntropyAnalysis::DontCompress
+    );
+
+    let lenient = CompressOptions {
+        entropy_threshold: Some(8.0),
+        ..CompressOptions::default()
+    };
+    assert_eq!(
+        analyze_entropy(&mut Cursor::new(noise(64 * 1024)), &lenient).unwrap(),
+        EntropyAnalysis::Compress
+    );
+}
+
+#[test]
+fn entropy_is_in_bits_per_byte() {
+    assert_eq!(entropy(&[]), 0.0);
+    assert_eq!(entropy(&[7; 4096]), 0.0);
+    assert_eq!(entropy(&[0, 1]), 1.0);
+
+    let every_byte: Vec<u8> = (0..=255).cycle().take(256 * 16).collect();
+    assert_eq!(entropy(&every_byte), 8.0);
+
+    let random = entropy(&noise(1024 * 1024));
+    assert!(random > 7.99 && random <= 8.0, "{random}");
+}
+
+#[test]
+fn entropy_is_the_same_down_to_the_bit() {
+    let mut bytes = noise(64 * 1024);
+    bytes.extend(b"skewed towards text ".repeat(4000));
+
+    let first = entropy(&bytes);
+    for _ in 0..1000 {
+        assert_eq!(entropy(&bytes).to_bits(), first.to_bits());
+    }
+    assert_eq!(
+        ttare::full_entropy(&mut Cursor::new(&bytes))
+            .unwrap()
+            .to_bits(),
+        first.to_bits()
+    );
+
+    // Not -0.0
+    assert_eq!(entropy(&[7; 4096]).to_bits(), 0.0f32.to_bits());
+}
+
+#[test]
+fn histograms_count_what_the_entropy_is_computed_from() {
+    let histogram = Histogram::of(b"abracadabra");
+    assert_eq!(histogram.total(), 11);
+    assert_eq!(histogram.counts()[b'a' as usize], 5);
+    assert_eq!(histogram.counts()[b'b' as usize], 2);
+    assert_eq!(histogram.counts()[b'z' as usize], 0);
+    assert_eq!(histogram.counts().iter().sum::<u64>(), 11);
+    assert_eq!(
+        histogram.entropy().to_bits(),
+        entropy(b"abracadabra").to_bits()
+    );
+
+    let mut csv = vec![];
+    histogram.write_csv(&mut csv).unwrap();
+    let csv = String::from_utf8(csv).unwrap();
+    let lines: Vec<&str> = csv.lines().collect();
+    assert_eq!(lines.len(), 257);
+    assert_eq!(lines[0], "byte,count");
+    assert_eq!(lines[1], "0,0");
+    assert_eq!(lines[1 + b'a' as usize], "97,5");
+
+    // The sample and the whole contents are counted like their entropy is computed
+    let bytes = noise(1024 * 1024);
+    let opts = CompressOptions::default();
+    let sample = ttare::sample_histogram(&mut Cursor::new(&bytes), &opts).unwrap();
+    assert_eq!(
+        sample.entropy().to_bits(),
+        ttare::sample_entropy(&mut Cursor::new(&bytes), &opts)
+            .unwrap()
+            .to_bits()
+    );
+    assert_eq!(sample.total(), bytes.len() as u64 / 2);
+    let full = ttare::full_histogram(&mut Cursor::new(&bytes)).unwrap();
+    assert_eq!(full, Histogram::of(&bytes));
+}
+
+#[test]
+fn parallel_analysis_keeps_input_order() {
+    let src = tempfile::TempDir::new().unwrap();
+
+    let files: Vec<_> = (0..200)
+        .map(|i| {
+            let path = src.path().join(format!("{i}.bin"));
+            let contents = if i % 3 == 0 {
+                noise(8 * 1024 + i)
+            } else {
+                b"ordered ".repeat(1000 + i)
+            };
+            std::fs::write(&path, contents).unwrap();
+            path
+        })
+        .collect();
+
+    let opts = CompressOptions::default();
+    let parallel = ttare::analyze_files(&files, &opts).unwrap();
+    let sequential = rayon::ThreadPoolBuilder::new()
+        .num_threads(1)
+        .build()
+        .unwrap()
+        .install(|| ttare::analyze_files(&files, &opts).unwrap());
+
+    assert_eq!(parallel, sequential);
+    for (i, (analysis, path)) in parallel.iter().zip(&files).enumerate() {
+        assert_eq!(&analysis.path, path);
+        let expected = if i % 3 == 0 {
+            EntropyAnalysis::DontCompress
+        } else {
+            EntropyAnalysis::Compress
+        };
+        assert_eq!(analysis.decision, expected, "{}", path.display());
+    }
+}
+
+#[test]
+fn suggested_threshold_splits_the_largest_gap() {
+    assert_eq!(suggest_threshold(&[]), None);
+    assert_eq!(suggest_threshold(&[4.0, 4.0]), None);
+    assert_eq!(
+        suggest_threshold(&[7.9, 1.0, 2.0, 7.5, 3.0, 8.0]),
+        Some(5.25)
+    );
+}
+
+#[test]
+fn empty_input_is_not_compressed() {
+    let opts = CompressOptions::default();
+    assert_eq!(
+        classify(&mut Cursor::new(vec![]), &opts).unwrap(),
+        (0.0, EntropyAnalysis::DontCompress)
+    );
+}
+
+#[test]
+fn walking_filters_with_globs() {
+    let dir = TempDir::new().unwrap();
+    let tree = dir.path().join("tree");
+    for path in [
+        ".git/config",
+        "sub/.git/HEAD",
+        "node_modules/dep/index.js",
+        "a.tmp",
+        "sub/deep/b.tmp",
+        "src/main.rs",
+        "src/lib.rs",
+        "notes.txt",
+    ] {
+        let path = tree.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, "").unwrap();
+    }
+    // Given on the command line, so it isn't filtered
+    fs::write(dir.path().join("explicit.tmp"), "").unwrap();
+
+    let gather = |exclude: &[&str], include: &[&str]| -> Vec<PathBuf> {
+        let opts = WalkOptions {
+            recursive: true,
+            exclude: exclude.iter().map(|glob| glob.to_string()).collect(),
+            include: include.iter().map(|glob| glob.to_string()).collect(),
+            ..WalkOptions::default()
+        };
+        gather_files(&[tree.clone(), dir.path().join("explicit.tmp")], &opts)
+            .unwrap()
+            .files
+            .into_iter()
+            .map(|path| path.strip_prefix(dir.path()).unwrap().to_path_buf())
+            .collect()
+    };
+    let paths = |paths: &[&str]| -> Vec<PathBuf> { paths.iter().map(PathBuf::from).collect() };
+
+    assert_eq!(
+        gather(&[".git", "node_modules", "*.tmp"], &[]),
+        paths(&[
+            "tree/notes.txt",
+            "tree/src/lib.rs",
+            "tree/src/main.rs",
+            "explicit.tmp"
+        ])
+    );
+
+    // Relative paths can be matched too
+    assert_eq!(
+        gather(&["src/*", "sub/**"], &[]),
+        paths(&[
+            "tree/.git/config",
+            "tree/a.tmp",
+            "tree/node_modules/dep/index.js",
+            "tree/notes.txt",
+            "explicit.tmp"
+        ])
+    );
+
+    // Excludes win over includes
+    assert_eq!(
+        gather(&["main.rs"], &["*.rs", "*.tmp"]),
+        paths(&[
+            "tree/a.tmp",
+            "tree/src/lib.rs",
+            "tree/sub/deep/b.tmp",
+            "explicit.tmp"
+        ])
+    );
+
+    assert!(gather_files(
+        &[tree],
+        &WalkOptions {
+            recursive: true,
+            exclude: vec!["[".to_string()],
+            ..WalkOptions::default()
+        }
+    )
+    .is_err());
+}
+
+#[test]
+fn compressing_everything_keeps_the_smaller_of_each_file() {
+    let src = TempDir::new().unwrap();
+    let text = src.path().join("text.txt");
+    let noisy = src.path().join("noise.bin");
+    fs::write(
+        &text,
+        b"the quick brown fox jumps over the lazy dog ".repeat(2000),
+    )
+    .unwrap();
+    fs::write(&noisy, noise(64 * 1024)).unwrap();
+    let files = [text.clone(), noisy.clone()];
+
+    for per_file_compression in [false, true] {
+        // Both files are over this threshold, so only the text is compressed, since compressing
+        // the noise makes it bigger
+        let opts = CompressOptions {
+            entropy_threshold: Some(0.5),
+            compress_all: true,
+            per_file_compression,
+            ..CompressOptions::default()
+        };
+        let archive = src.path().join(format!("{per_file_compression}.ttare"));
+        let summary = ttare::compress(&files, &archive, opts).unwrap();
+        assert_eq!(summary.compressed_files, 1);
+        assert_eq!(summary.stored_files, 1);
+        assert!(summary.compressed_output_bytes < summary.compressed_input_bytes / 10);
+
+        let listing = ttare::list(&archive).unwrap();
+        let compressed: Vec<_> = listing.entries.iter().map(|e| e.compressed).collect();
+        assert_eq!(compressed, [true, false], "{per_file_compression}");
+
+        let out = TempDir::new().unwrap();
+        ttare::decompress(&archive, out.path(), DecompressOptions::default()).unwrap();
+        for path in &files {
+            let extracted = out.path().join(path.strip_prefix("/").unwrap());
+            assert_eq!(fs::read(extracted).unwrap(), fs::read(path).unwrap());
+        }
+    }
+
+    // Without it, the threshold has both stored as-is
+    let opts = CompressOptions {
+        entropy_threshold: Some(0.5),
+        ..CompressOptions::default()
+    };
+    let summary = ttare::compress(&files, &src.path().join("raw.ttare"), opts).unwrap();
+    assert_eq!(summary.stored_files, 2);
+}
+
+#[test]
+fn cat_writes_the_files_one_after_the_other() {
+    let src = TempDir::new().unwrap();
+    let text = b"text in the member ".repeat(1000);
+    let raw = noise(16 * 1024);
+    fs::write(src.path().join("text.txt"), &text).unwrap();
+    fs::write(src.path().join("noise.bin"), &raw).unwrap();
+    fs::write(src.path().join("copy.txt"), &text).unwrap();
+    let files: Vec<PathBuf> = ["text.txt", "noise.bin", "copy.txt"]
+        .iter()
+        .map(|name| src.path().join(name))
+        .collect();
+
+    for index in [false, true] {
+        let archive = src.path().join(format!("{index}.ttare"));
+        let opts = CompressOptions {
+            base_dir: Some(src.path().to_path_buf()),
+            dedup: true,
+            index,
+            ..CompressOptions::default()
+        };
+        ttare::compress(&files, &archive, opts).unwrap();
+
+        let mut output = vec![];
+        let paths = ["noise.bin", "text.txt", "./copy.txt", "noise.bin"].map(PathBuf::from);
+        ttare::cat(&archive, &paths, &mut output).unwrap();
+        assert_eq!(output, [&raw[..], &text, &text, &raw].concat());
+
+        // Nothing is written when any of them is missing
+        let mut output = vec![];
+        let paths = ["text.txt", "missing.txt"].map(PathBuf::from);
+        assert!(matches!(
+            ttare::cat(&archive, &paths, &mut output),
+            Err(TtareError::NotFound(path)) if path == Path::new("missing.txt")
+        ));
+        assert!(output.is_empty());
+    }
+}
+
+#[test]
+fn recompressing_keeps_each_file_in_its_bucket() {
+    let src = TempDir::new().unwrap();
+    let tree = src.path().join("tree");
+    fs::create_dir_all(tree.join("nested")).unwrap();
+    fs::write(tree.join("text.txt"), b"the quick brown fox ".repeat(4000)).unwrap();
+    fs::write(tree.join("nested/noise.bin"), noise(64 * 1024)).unwrap();
+    // Six bits per byte, which only the strict threshold stores as-is
+    let mixed: Vec<u8> = noise(64 * 1024).iter().map(|byte| byte % 64).collect();
+    fs::write(tree.join("nested/mixed.bin"), &mixed).unwrap();
+
+    let opts = CompressOptions {
+        entropy_threshold: Some(5.0),
+        base_dir: Some(tree.clone()),
+        ..CompressOptions::default()
+    };
+    let files = gather_files(
+        std::slice::from_ref(&tree),
+        &WalkOptions {
+            recursive: true,
+            ..WalkOptions::default()
+        },
+    )
+    .unwrap()
+    .into_paths();
+    let original = src.path().join("original.ttare");
+    ttare::compress(&files, &original, opts).unwrap();
+
+    let buckets = |archive: &Path| {
+        let mut entries: Vec<(PathBuf, bool)> = ttare::list(archive)
+            .unwrap()
+            .entries
+            .into_iter()
+            .map(|entry| (entry.path, entry.compressed))
+            .collect();
+        entries.sort();
+        entries
+    };
+    let expected = vec![
+        (PathBuf::from("nested/mixed.bin"), false),
+        (PathBuf::from("nested/noise.bin"), false),
+        (PathBuf::from("text.txt"), true),
+    ];
+    assert_eq!(buckets(&original), expected);
+
+    // The strict threshold is kept through other codecs and back
+    let mut input = original.clone();
+    for codec in [Codec::Zstd, Codec::Xz, Codec::Gzip] {
+        let output = src.path().join(format!("{}.ttare", codec.name()));
+        let opts = RecompressOptions {
+            codec,
+            ..RecompressOptions::default()
+        };
+        ttare::recompress(&input, &output, opts).unwrap();
+        assert_eq!(buckets(&output), expected, "{}", codec.name());
+
+        let out = TempDir::new().unwrap();
+        ttare::decompress(&output, out.path(), DecompressOptions::default()).unwrap();
+        assert_eq!(
+            fs::read(out.path().join("nested/mixed.bin")).unwrap(),
+            mixed
+        );
+        input = output;
+    }
+
+    // Unless it's overridden
+    let output = src.path().join("overridden.ttare");
+    let opts = RecompressOptions {
+        entropy_threshold: Some(7.0),
+        ..RecompressOptions::default()
+    };
+    ttare::recompress(&original, &output, opts).unwrap();
+    assert_eq!(
+        buckets(&output)[0],
+        (PathBuf::from("nested/mixed.bin"), true)
+    );
+}
+
+#[test]
+fn estimates_are_in_the_ballpark_of_the_archive() {
+    let dir = TempDir::new().unwrap();
+    let text = dir.path().join("text.txt");
+    let noisy = dir.path().join("noise.bin");
+    fs::write(
+        &text,
+        b"the quick brown fox jumps over the lazy dog ".repeat(4000),
+    )
+    .unwrap();
+    fs::write(&noisy, noise(64 * 1024)).unwrap();
+    let files = [text.clone(), noisy.clone()];
+
+    let estimate = ttare::estimate(&files, &CompressOptions::default()).unwrap();
+    let text_len = fs::metadata(&text).unwrap().len();
+    assert_eq!(estimate.input_bytes, text_len + 64 * 1024);
+    assert_eq!(estimate.compressed_files, 1);
+    assert_eq!(estimate.compressed_input_bytes, text_len);
+    assert!(estimate.compressed_output_bytes < text_len / 4);
+
+    // The noise is stored as-is, so it is most of both the estimate and the archive
+    let archive = dir.path().join("out.ttare");
+    ttare::compress(&files, &archive, CompressOptions::default()).unwrap();
+    let actual = fs::metadata(&archive).unwrap().len() as f64;
+    let estimated = estimate.archive_bytes as f64;
+    assert!(
+        (0.8..1.25).contains(&(actual / estimated)),
+        "estimated {estimated} for an archive of {actual}"
+    );
+    assert_eq!(
+        estimate.ratio,
+        Some(estimated / estimate.input_bytes as f64)
+    );
+
+    let empty = ttare::estimate(&[], &CompressOptions::default()).unwrap();
+    assert_eq!(empty.ratio, None);
+    assert_eq!(empty.compressed_files, 0);
+}
+
+#[test]
+fn size_filters_keep_the_files_at_their_bounds() {
+    let dir = TempDir::new().unwrap();
+    let tree = dir.path().join("tree");
+    fs::create_dir(&tree).unwrap();
+    for len in [0, 99, 100, 101, 200] {
+        fs::write(tree.join(format!("{len}.txt")), vec![b'a'; len]).unwrap();
+        fs::write(tree.join(format!("{len}.log")), vec![b'a'; len]).unwrap();
+    }
+    fs::write(dir.path().join("explicit.txt"), vec![b'a'; 1000]).unwrap();
+
+    let gather = |larger_than: Option<u64>, smaller_than: Option<u64>, include: &[&str]| {
+        let opts = WalkOptions {
+            recursive: true,
+            include: include.iter().map(|glob| glob.to_string()).collect(),
+            exclude_larger_than: larger_than,
+            exclude_smaller_than: smaller_than,
+            ..WalkOptions::default()
+        };
+        gather_files(&[tree.clone(), dir.path().join("explicit.txt")], &opts)
+            .unwrap()
+            .files
+            .into_iter()
+            .map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(
+        gather(Some(100), None, &["*.txt"]),
+        ["0.txt", "100.txt", "99.txt", "explicit.txt"]
+    );
+    assert_eq!(
+        gather(None, Some(100), &["*.txt"]),
+        ["100.txt", "101.txt", "200.txt", "explicit.txt"]
+    );
+    // They stack with each other and with the globs, and the paths given are kept whatever size
+    assert_eq!(
+        gather(Some(100), Some(100), &[]),
+        ["100.log", "100.txt", "explicit.txt"]
+    );
+    assert_eq!(gather(Some(0), None, &["*.log"]), ["0.log", "explicit.txt"]);
+}
+
+#[test]
+fn full_entropy_sees_past_a_compressible_first_half() {
+    let mut contents = b"compressible ".repeat(32 * 1024 / 13);
+    contents.extend(noise(contents.len()));
+
+    // A sample that fits in a single chunk is read from the start of the file
+    let sampled = CompressOptions {
+        sample_percentage: 0.05,
+        min_sample_bytes: 0,
+        ..CompressOptions::default()
+    };
+    let full = CompressOptions {
+        full_entropy: true,
+        ..sampled.clone()
+    };
+
+    assert_eq!(
+        classify(&mut Cursor::new(&contents), &sampled).unwrap().1,
+        EntropyAnalysis::Compress
+    );
+
+    let (entropy, decision) = classify(&mut Cursor::new(&contents), &full).unwrap();
+    assert_eq!(decision, EntropyAnalysis::DontCompress);
+    assert_eq!(entropy, ttare::entropy(&contents));
+    assert_eq!(
+        ttare::full_entropy(&mut Cursor::new(&contents)).unwrap(),
+        entropy
+    );
+}
+
+#[test]
+fn errors_say_what_went_wrong() {
+    let dir = TempDir::new().unwrap();
+    fs::write(dir.path().join("a.txt"), "a").unwrap();
+    let output = dir.path().join("out.ttare");
+
+    let error = gather_files(&[dir.path().to_path_buf()], &WalkOptions::default()).unwrap_err();
+    assert!(matches!(error, TtareError::IsADirectory(path) if path == dir.path()));
+
+    let missing = dir.path().join("missing.txt");
+    let error = ttare::compress(
+        std::slice::from_ref(&missing),
+        &output,
+        CompressOptions::default(),
+    )
+    .unwrap_err();
+    assert!(matches!(error, TtareError::Io { path: Some(path), .. } if path == missing));
+    assert!(!output.exists());
+
+    let opts = CompressOptions {
+        sample_percentage: 0.0,
+        ..CompressOptions::default()
+    };
+    let error = ttare::compress(&[dir.path().join("a.txt")], &output, opts).unwrap_err();
+    assert!(matches!(error, TtareError::InvalidSamplePercentage(percentage) if percentage == 0.0));
+
+    ttare::compress_reader(
+        &b"a"[..],
+        Path::new("a.txt"),
+        &output,
+        CompressOptions::default(),
+    )
+    .unwrap();
+    let missing = Path::new("missing.txt");
+    let error = ttare::extract(&output, missing, Vec::new()).unwrap_err();
+    assert!(matches!(error, TtareError::NotFound(path) if path == missing));
+}
+
+#[test]
+fn samples_are_kept_within_bounds() {
+    // Only the first 16 KiB is compressible, and small samples are read from the start
+    let mut contents = b"compressible ".repeat(16 * 1024 / 13);
+    contents.extend(noise(48 * 1024));
+
+    let tiny = CompressOptions {
+        sample_percentage: 0.01,
+        min_sample_bytes: 0,
+        ..CompressOptions::default()
+    };
+    assert_eq!(
+        classify(&mut Cursor::new(&contents), &tiny).unwrap().1,
+        EntropyAnalysis::Compress
+    );
+
+    // The default floor is more than the whole file, so all of it is sampled
+    let floored = CompressOptions {
+        sample_percentage: 0.01,
+        ..CompressOptions::default()
+    };
+    let (entropy, decision) = classify(&mut Cursor::new(&contents), &floored).unwrap();
+    assert_eq!(decision, EntropyAnalysis::DontCompress);
+    assert_eq!(entropy, ttare::entropy(&contents));
+
+    // The cap wins over the percentage
+    let capped = CompressOptions {
+        sample_percentage: 1.0,
+        min_sample_bytes: 0,
+        max_sample_bytes: Some(4096),
+        ..CompressOptions::default()
+    };
+    assert_eq!(
+        classify(&mut Cursor::new(&contents), &capped).unwrap().1,
+        EntropyAnalysis::Compress
+    );
+
+    for max in [0, 1024] {
+        let invalid = CompressOptions {
+            min_sample_bytes: 4096,
+            max_sample_bytes: Some(max),
+            ..CompressOptions::default()
+        };
+        assert!(matches!(
+            classify(&mut Cursor::new(&contents), &invalid),
+            Err(TtareError::InvalidSampleBounds { min: 4096, max: m }) if m == max
+        ));
+    }
+}
+
+#[test]
+fn known_extensions_are_stored_without_reading_them() {
+    let dir = TempDir::new().unwrap();
+    let text = b"compressible, whatever the name says ".repeat(1000);
+    let files: Vec<PathBuf> = ["movie.MP4", "photo.jpg", "custom.dat", "notes.txt"]
+        .iter()
+        .map(|name| dir.path().join(name))
+        .collect();
+    for path in &files {
+        fs::write(path, &text).unwrap();
+    }
+
+    let decisions = |opts: &CompressOptions| -> Vec<(Option<f32>, EntropyAnalysis)> {
+        ttare::analyze_files(&files, opts)
+            .unwrap()
+            .into_iter()
+            .map(|analysis| (analysis.entropy.map(|_| 0.0), analysis.decision))
+            .collect()
+    };
+
+    let opts = CompressOptions {
+        incompressible_extensions: vec![".dat".to_string()],
+        ..CompressOptions::default()
+    };
+    assert_eq!(
+        decisions(&opts),
+        [
+            (None, EntropyAnalysis::DontCompress),
+            (None, EntropyAnalysis::DontCompress),
+            (None, EntropyAnalysis::DontCompress),
+            (Some(0.0), EntropyAnalysis::Compress),
+        ]
+    );
+
+    let analyzed = CompressOptions {
+        extension_shortcut: false,
+        ..opts
+    };
+    assert_eq!(
+        decisions(&analyzed),
+        [(Some(0.0), EntropyAnalysis::Compress); 4]
+    );
+}
+
+#[test]
+fn rules_override_the_extension_and_the_entropy() {
+    let dir = TempDir::new().unwrap();
+    let files: Vec<PathBuf> = ["random.log", "photo.jpg", "notes.txt", "logs/other.bin"]
+        .iter()
+        .map(|name| dir.path().join(name))
+        .collect();
+    fs::create_dir(dir.path().join("logs")).unwrap();
+    fs::write(&files[0], noise(64 * 1024)).unwrap();
+    fs::write(&files[1], noise(64 * 1024)).unwrap();
+    fs::write(
+        &files[2],
+        b"stored, whatever the entropy says ".repeat(1000),
+    )
+    .unwrap();
+    fs::write(&files[3], noise(64 * 1024)).unwrap();
+
+    let rules = DecisionRules::read(
+        &b"# forced decisions
+*.log = compress
+photo.jpg=compress
+
+*.txt = store
+*.bin = store
+**/logs/* = compress
+"[..],
+    )
+    .unwrap();
+    let opts = CompressOptions {
+        rules,
+        ..CompressOptions::default()
+    };
+    let decisions: Vec<(Option<f32>, EntropyAnalysis)> = ttare::analyze_files(&files, &opts)
+        .unwrap()
+        .into_iter()
+        .map(|analysis| (analysis.entropy, analysis.decision))
+        .collect();
+
+    // The first rule that matches wins, and the files aren't read
+    assert_eq!(
+        decisions,
+        [
+            (None, EntropyAnalysis::Compress),
+            (None, EntropyAnalysis::Compress),
+            (None, EntropyAnalysis::DontCompress),
+            (None, EntropyAnalysis::DontCompress),
+        ]
+    );
+
+    for (rules, line) in [
+        ("*.log = compress\n*.txt = squash\n", 2),
+        ("\n\n*.txt\n", 3),
+        ("= store\n", 1),
+    ] {
+        let error = DecisionRules::read(rules.as_bytes()).unwrap_err();
+        assert!(
+            matches!(&error, TtareError::InvalidRule { line: l, .. } if *l == line),
+            "{error:?}"
+        );
+    }
+    assert!(matches!(
+        DecisionRules::read(&b"[ = store"[..]),
+        Err(TtareError::InvalidGlob { .. })
+    ));
+}
+
+/// Appends a file named `name` to `builder`, without the checks that `tar` makes on paths.
+fn append_raw(builder: &mut tar::Builder<Vec<u8>>, name: &str, contents: &[u8]) {
+    let mut header = tar::Header::new_old();
+    header.as_old_mut().name[..name.len()].copy_from_slice(name.as_bytes());
+    header.set_size(contents.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    builder.append(&header, contents).unwrap();
+}
+
+#[test]
+fn entries_cannot_escape_the_output_directory() {
+    let dir = TempDir::new().unwrap();
+    let output_dir = dir.path().join("a/b/out");
+
+    let mut member = tar::Builder::new(Vec::new());
+    append_raw(&mut member, "../../evil", b"evil");
+    let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    gz.write_all(&member.into_inner().unwrap()).unwrap();
+    let member = gz.finish().unwrap();
+
+    let archives: [&[(&str, &[u8])]; 3] = [
+        &[("../../evil", b"evil")],
+        &[("/tmp/evil", b"evil")],
+        &[("safe.txt", b"safe"), (".ttare.tar.gz", &member)],
+    ];
+    for entries in archives {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (name, contents) in entries {
+            append_raw(&mut builder, name, contents);
+        }
+        let archive = builder.into_inner().unwrap();
+
+        let error = ttare::decompress_from(&archive[..], &output_dir, DecompressOptions::default())
+            .unwrap_err();
+        assert!(
+            matches!(&error, TtareError::UnsafePath(path) if path.ends_with("evil")),
+            "{error}"
+        );
+    }
+
+    assert!(!dir.path().join("a/evil").exists());
+    assert!(!dir.path().join("evil").exists());
+    assert!(!output_dir.join("evil").exists());
+}
+
+#[test]
+fn the_default_threshold_depends_on_the_codec() {
+    // Bytes spread over 108 values, so the entropy is between the gzip and zstd thresholds
+    let contents: Vec<u8> = noise(64 * 1024)
+        .into_iter()
+        .map(|byte| byte % 108)
+        .collect();
+    let decision = |codec: Codec, entropy_threshold: Option<f32>| {
+        let opts = CompressOptions {
+            codec,
+            entropy_threshold,
+            ..CompressOptions::default()
+        };
+        let (entropy, decision) = classify(&mut Cursor::new(&contents), &opts).unwrap();
+        assert!(entropy > 6.5 && entropy < 7.0, "{entropy}");
+        decision
+    };
+
+    assert_eq!(decision(Codec::Gzip, None), EntropyAnalysis::DontCompress);
+    assert_eq!(decision(Codec::Zstd, None), EntropyAnalysis::Compress);
+    assert_eq!(decision(Codec::Xz, None), EntropyAnalysis::Compress);
+
+    // A threshold that is given wins over the codec's
+    assert_eq!(decision(Codec::Gzip, Some(7.0)), EntropyAnalysis::Compress);
+    assert_eq!(
+        decision(Codec::Zstd, Some(6.5)),
+        EntropyAnalysis::DontCompress
+    );
+    assert_eq!(
+        Codec::Gzip.default_entropy_threshold(),
+        ttare::ENTROPY_THRESHOLD
+    );
+}
+
+#[test]
+fn percentages_are_shares_of_the_highest_entropy() {
+    assert_eq!(threshold_from_percentage(81.25), ttare::ENTROPY_THRESHOLD);
+    assert_eq!(threshold_from_percentage(87.5), 7.0);
+    assert_eq!(threshold_from_percentage(50.0), 4.0);
+    assert_eq!(threshold_from_percentage(0.0), 0.0);
+    assert_eq!(threshold_from_percentage(100.0), ttare::MAX_ENTROPY);
+
+    // Noise is above 81% of the highest entropy, and text below it
+    let decide_at = |percentage: f32, contents: &[u8]| {
+        let opts = CompressOptions {
+            entropy_threshold: Some(threshold_from_percentage(percentage)),
+            ..CompressOptions::default()
+        };
+        classify(&mut Cursor::new(contents), &opts).unwrap().1
+    };
+    let text = b"some plain text ".repeat(1000);
+    assert_eq!(
+        decide_at(81.0, &noise(64 * 1024)),
+        EntropyAnalysis::DontCompress
+    );
+    assert_eq!(decide_at(81.0, &text), EntropyAnalysis::Compress);
+    assert_eq!(
+        decide_at(100.0, &noise(64 * 1024)),
+        EntropyAnalysis::Compress
+    );
+}
+
+#[test]
+fn compressed_data_is_spooled_to_the_temp_dir() {
+    let src = TempDir::new().unwrap();
+    let temp_dir = TempDir::new().unwrap();
+    let text = b"spooled ".repeat(1000);
+    let output = src.path().join("archive.ttare");
+
+    for per_file_compression in [false, true] {
+        let opts = CompressOptions {
+            per_file_compression,
+            temp_dir: Some(temp_dir.path().to_path_buf()),
+            overwrite: true,
+            ..CompressOptions::default()
+        };
+        ttare::compress_reader(text.as_slice(), Path::new("text.txt"), &output, opts).unwrap();
+
+        // Nothing is left behind
+        assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 0);
+    }
+
+    let previous = fs::read(&output).unwrap();
+    let missing = temp_dir.path().join("missing");
+    let opts = CompressOptions {
+        temp_dir: Some(missing.clone()),
+        overwrite: true,
+        ..CompressOptions::default()
+    };
+    let error =
+        ttare::compress_reader(text.as_slice(), Path::new("text.txt"), &output, opts).unwrap_err();
+    assert!(
+        matches!(&error, TtareError::Io { path: Some(path), .. } if *path == missing),
+        "{error:?}"
+    );
+
+    // The archive is only replaced once the new one is complete
+    assert_eq!(fs::read(&output).unwrap(), previous);
+    assert!(!ttare::partial_path(&output).exists());
+}
+
+#[test]
+fn archives_round_trip_in_memory() {
+    let inputs = vec![
+        ("text.txt".to_string(), b"in memory ".repeat(1000)),
+        ("noise.bin".to_string(), noise(64 * 1024)),
+        ("empty.txt".to_string(), vec![]),
+        ("dir/nested.txt".to_string(), b"nested ".repeat(100)),
+    ];
+    let sorted = |mut files: Vec<(String, Vec<u8>)>| {
+        files.sort();
+        files
+    };
+
+    for codec in [Codec::Gzip, Codec::Zstd, Codec::Xz, Codec::Brotli] {
+        for per_file_compression in [false, true] {
+            // Nothing is spooled to the disk, so a temporary directory that doesn't exist is fine
+            let opts = CompressOptions {
+                codec,
+                per_file_compression,
+                manifest: true,
+                temp_dir: Some(PathBuf::from("/nonexistent/ttare")),
+                ..CompressOptions::default()
+            };
+            let archive = ttare::compress_to_vec(&inputs, opts).unwrap();
+
+            let files = ttare::decompress_from_slice(&archive).unwrap();
+            assert_eq!(sorted(files), sorted(inputs.clone()));
+
+            let dst = TempDir::new().unwrap();
+            ttare::decompress_from(archive.as_slice(), dst.path(), DecompressOptions::default())
+                .unwrap();
+            for (name, contents) in &inputs {
+                assert_eq!(&fs::read(dst.path().join(name)).unwrap(), contents);
+            }
+        }
+    }
+}
+
+#[test]
+fn in_memory_archives_are_classified_like_files() {
+    let inputs = vec![
+        ("text.txt".to_string(), b"classified ".repeat(1000)),
+        ("noise.bin".to_string(), noise(64 * 1024)),
+        ("text.zip".to_string(), b"classified ".repeat(1000)),
+    ];
+    let archive = ttare::compress_to_vec(&inputs, CompressOptions::default()).unwrap();
+
+    // The stored files come ahead of the compressed member
+    let names: Vec<String> = ttare::decompress_from_slice(&archive)
+        .unwrap()
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect();
+    assert_eq!(names, ["noise.bin", "text.zip", "text.txt"]);
+}
+
+#[test]
+fn corrupt_in_memory_archives_are_rejected() {
+    let inputs = vec![("text.txt".to_string(), noise(4096))];
+    let opts = CompressOptions {
+        manifest: true,
+        ..CompressOptions::default()
+    };
+    let mut archive = ttare::compress_to_vec(&inputs, opts).unwrap();
+
+    // Flip a byte of the stored file, which comes right after the metadata
+    let offset = archive
+        .windows(4096)
+        .position(|window| window == inputs[0].1.as_slice())
+        .unwrap();
+    archive[offset + 20] ^= 0xff;
+
+    let error = ttare::decompress_from_slice(&archive).unwrap_err();
+    assert!(
+        matches!(&error, TtareError::ChecksumMismatch(paths) if paths == &[PathBuf::from("text.txt")]),
+        "{error:?}"
+    );
+}
+
+#[test]
+fn archives_record_their_zstd_dictionary() {
+    let dictionary =
+        ZstdDictionary::new(b"a dictionary of words, shared words and more ".repeat(20));
+    let inputs = vec![("words.txt".to_string(), b"shared words ".repeat(100))];
+    let opts = CompressOptions {
+        codec: Codec::Zstd,
+        zstd_dictionary: Some(dictionary.clone()),
+        ..CompressOptions::default()
+    };
+    let archive = ttare::compress_to_vec(&inputs, opts).unwrap();
+
+    let dst = TempDir::new().unwrap();
+    let decompress = |zstd_dictionary| {
+        let opts = DecompressOptions {
+            zstd_dictionary,
+            ..DecompressOptions::default()
+        };
+        ttare::decompress_from(archive.as_slice(), dst.path(), opts)
+    };
+
+    let error = decompress(None).unwrap_err();
+    assert!(
+        matches!(&error, TtareError::MissingDictionary(digest) if digest == dictionary.digest()),
+        "{error:?}"
+    );
+
+    let other = ZstdDictionary::new(b"another dictionary".to_vec());
+    let error = decompress(Some(other.clone())).unwrap_err();
+    assert!(
+        matches!(&error, TtareError::WrongDictionary { expected, given }
+            if expected == dictionary.digest() && given == other.digest()),
+        "{error:?}"
+    );
+
+    decompress(Some(dictionary)).unwrap();
+    assert_eq!(fs::read(dst.path().join("words.txt")).unwrap(), inputs[0].1);
+
+    let opts = CompressOptions {
+        zstd_dictionary: Some(other),
+        ..CompressOptions::default()
+    };
+    let error = ttare::compress_to_vec(&inputs, opts).unwrap_err();
+    assert!(
+        matches!(error, TtareError::DictionaryWithoutZstd(Codec::Gzip)),
+        "{error:?}"
+    );
+}
+
+#[test]
+fn windows_judge_files_by_how_much_of_them_is_compressible() {
+    // A header of letters in front of a compressed blob, which is incompressible as a whole
+    let mut contents: Vec<u8> = noise(60 * 1024).iter().map(|b| b'a' + b % 26).collect();
+    contents.extend(noise(40 * 1024));
+    let whole = CompressOptions {
+        full_entropy: true,
+        ..CompressOptions::default()
+    };
+    let (entropy, decision) = classify(&mut Cursor::new(&contents), &whole).unwrap();
+    assert!(entropy > whole.threshold(), "{entropy}");
+    assert_eq!(decision, EntropyAnalysis::DontCompress);
+
+    for full_entropy in [true, false] {
+        let windows = CompressOptions {
+            full_entropy,
+            sample_percentage: 1.0,
+            window_bytes: NonZeroU64::new(8 * 1024),
+            ..CompressOptions::default()
+        };
+        let (windowed_entropy, decision) = classify(&mut Cursor::new(&contents), &windows).unwrap();
+        assert_eq!(windowed_entropy, entropy);
+        assert_eq!(decision, EntropyAnalysis::Compress, "{full_entropy}");
+
+        // Only 60% of it is compressible
+        let strict = CompressOptions {
+            compressible_fraction: 0.7,
+            ..windows
+        };
+        assert_eq!(
+            classify(&mut Cursor::new(&contents), &strict).unwrap().1,
+            EntropyAnalysis::DontCompress
+        );
+    }
+
+    let invalid = CompressOptions {
+        window_bytes: NonZeroU64::new(8 * 1024),
+        compressible_fraction: 1.5,
+        ..CompressOptions::default()
+    };
+    let error =
+        ttare::compress_to_vec(&[("mixed.bin".to_string(), contents)], invalid).unwrap_err();
+    assert!(
+        matches!(error, TtareError::InvalidCompressibleFraction(fraction) if fraction == 1.5),
+        "{error:?}"
+    );
+}
+
+#[test]
+fn a_last_window_that_isnt_full_doesnt_count() {
+    // The few random bytes past the last full window have a low entropy on their own
+    let contents = noise(4 * 8 * 1024 + 64);
+    let windows = CompressOptions {
+        full_entropy: true,
+        window_bytes: NonZeroU64::new(8 * 1024),
+        compressible_fraction: 0.001,
+        ..CompressOptions::default()
+    };
+    assert_eq!(
+        classify(&mut Cursor::new(&contents), &windows).unwrap().1,
+        EntropyAnalysis::DontCompress
+    );
+}
+
+#[test]
+fn the_probe_estimator_sees_repeated_sequences() {
+    // Every byte value is as likely as any other, but the same KiB comes back over and over
+    let contents = noise(1024).repeat(256);
+    let shannon = CompressOptions::default();
+    let (entropy, decision) = classify(&mut Cursor::new(&contents), &shannon).unwrap();
+    assert!(entropy > 7.5, "{entropy}");
+    assert_eq!(decision, EntropyAnalysis::DontCompress);
+
+    for codec in [Codec::Gzip, Codec::Zstd, Codec::Xz, Codec::Brotli] {
+        let probe = CompressOptions {
+            codec,
+            estimator: Estimator::Probe,
+            ..CompressOptions::default()
+        };
+        let (entropy, decision) = classify(&mut Cursor::new(&contents), &probe).unwrap();
+        assert!(entropy < 2.0, "{codec:?}: {entropy}");
+        assert_eq!(decision, EntropyAnalysis::Compress, "{codec:?}");
+
+        // Actual noise is still left alone
+        assert_eq!(
+            classify(&mut Cursor::new(noise(256 * 1024)), &probe)
+                .unwrap()
+                .1,
+            EntropyAnalysis::DontCompress,
+            "{codec:?}"
+        );
+    }
+}
+
+#[test]
+fn small_files_are_bundled_whatever_their_entropy() {
+    let src = TempDir::new().unwrap();
+
+    // Each of them is random enough to be stored as-is on its entropy alone
+    let noise = noise(2000 * 400);
+    let files: Vec<PathBuf> = noise
+        .chunks(400)
+        .enumerate()
+        .map(|(index, contents)| {
+            let path = src.path().join(format!("{index}.bin"));
+            fs::write(&path, contents).unwrap();
+            path
+        })
+        .collect();
+
+    let bundled = ttare::compress(
+        &files,
+        &src.path().join("bundled.ttare"),
+        CompressOptions::default(),
+    )
+    .unwrap();
+    assert_eq!(bundled.compressed_files, 2000);
+
+    let opts = CompressOptions {
+        small_file_bytes: 0,
+        ..CompressOptions::default()
+    };
+    let stored = ttare::compress(&files, &src.path().join("stored.ttare"), opts).unwrap();
+    assert_eq!(stored.stored_files, 2000);
+
+    // A stored file takes a header and padding of 624 bytes on top of its 400, which mostly
+    // compress away in the member
+    assert!(
+        bundled.archive_bytes * 3 < stored.archive_bytes * 2,
+        "{} bytes bundled, {} stored",
+        bundled.archive_bytes,
+        stored.archive_bytes
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn xattrs_are_restored_when_asked_to() {
+    let src = TempDir::new().unwrap();
+    let out = TempDir::new().unwrap();
+
+    let dir = src.path().join("dir");
+    let text = dir.join("text.txt");
+    let raw = dir.join("noise.bin");
+    fs::create_dir(&dir).unwrap();
+    fs::write(&text, b"with attributes ".repeat(1000)).unwrap();
+    fs::write(&raw, noise(64 * 1024)).unwrap();
+
+    // Not every file system has user attributes
+    if xattr::set(&dir, "user.ttare", b"dir").is_err() {
+        return;
+    }
+    xattr::set(&text, "user.ttare", b"text").unwrap();
+    xattr::set(&raw, "user.ttare", b"noise").unwrap();
+
+    for per_file_compression in [false, true] {
+        let archive = src.path().join(format!("{per_file_compression}.ttare"));
+        let opts = CompressOptions {
+            per_file_compression,
+            base_dir: Some(src.path().to_path_buf()),
+            xattrs: true,
+            ..CompressOptions::default()
+        };
+        ttare::compress(&[dir.clone(), text.clone(), raw.clone()], &archive, opts).unwrap();
+
+        let restored = out.path().join(format!("{per_file_compression}"));
+        let opts = DecompressOptions {
+            xattrs: true,
+            ..DecompressOptions::default()
+        };
+        ttare::decompress(&archive, &restored, opts).unwrap();
+
+        let restored = restored.join("dir");
+        for (path, value) in [
+            (restored.clone(), b"dir".as_slice()),
+            (restored.join("text.txt"), b"text"),
+            (restored.join("noise.bin"), b"noise"),
+        ] {
+            assert_eq!(
+                xattr::get(&path, "user.ttare").unwrap().as_deref(),
+                Some(value),
+                "{}",
+                path.display()
+            );
+        }
+
+        // They are only restored when asked to
+        let plain = out.path().join(format!("plain-{per_file_compression}"));
+        ttare::decompress(&archive, &plain, DecompressOptions::default()).unwrap();
+        assert_eq!(
+            xattr::get(plain.join("dir/text.txt"), "user.ttare").unwrap(),
+            None
+        );
+    }
+}
+
+#[test]
+fn the_index_lists_and_extracts_like_a_full_read() {
+    let src = TempDir::new().unwrap();
+    let files: Vec<(PathBuf, Vec<u8>)> = vec![
+        ("text.txt".into(), b"indexed text ".repeat(2000)),
+        ("noise.bin".into(), noise(40 * 1024)),
+        ("dir/more.txt".into(), b"more text ".repeat(300)),
+        ("dir/copy.txt".into(), b"indexed text ".repeat(2000)),
+        ("empty.txt".into(), vec![]),
+        (
+            "dir/a-name-that-is-long-enough-to-need-an-extension-of-its-own-in-the-tar-headers.txt"
+                .into(),
+            b"long name ".repeat(500),
+        ),
+    ];
+    let paths: Vec<PathBuf> = files
+        .iter()
+        .map(|(path, contents)| {
+            let path = src.path().join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, contents).unwrap();
+            path
+        })
+        .collect();
+
+    let cases = [
+        ("solid", CompressOptions::default()),
+        (
+            "per-file",
+            CompressOptions {
+                per_file_compression: true,
+                ..CompressOptions::default()
+            },
+        ),
+        // The noise is moved out of the member, since compressing it made it bigger
+        (
+            "expanded",
+            CompressOptions {
+                entropy_threshold: Some(8.0),
+                no_expand: true,
+                small_file_bytes: 0,
+                ..CompressOptions::default()
+            },
+        ),
+    ];
+    for (name, opts) in cases {
+        let opts = CompressOptions {
+            base_dir: Some(src.path().to_path_buf()),
+            dedup: true,
+            mtime: Some(1_000_000),
+            ..opts
+        };
+        let scanned = src.path().join(format!("{name}.ttare"));
+        ttare::compress(&paths, &scanned, opts.clone()).unwrap();
+        let indexed = src.path().join(format!("{name}-indexed.ttare"));
+        let opts = CompressOptions {
+            index: true,
+            ..opts
+        };
+        ttare::compress(&paths, &indexed, opts).unwrap();
+
+        let listing = ttare::list(&indexed).unwrap();
+        assert_eq!(listing, ttare::list(&scanned).unwrap(), "{name}");
+        assert_eq!(listing.entries.len(), files.len(), "{name}");
+
+        for (path, contents) in &files {
+            let mut extracted = vec![];
+            ttare::extract(&indexed, path, &mut extracted).unwrap();
+            assert_eq!(&extracted, contents, "{name}: {}", path.display());
+        }
+        assert!(matches!(
+            ttare::extract(&indexed, Path::new("missing.txt"), Vec::new()),
+            Err(TtareError::NotFound(_))
+        ));
+    }
+}
+
+/// The contents of the files that `change_once` changes, before it does.
+fn changing_contents(path: &Path) -> Vec<u8> {
+    if path.ends_with("changing-large.txt") {
+        b"a line of a log that keeps growing\n".repeat(64 * 1024)
+    } else {
+        b"a line of a log that gets truncated\n".repeat(100)
+    }
+}
+
+/// Truncates or grows the files named `changing-*` as they are about to be read, the first time
+/// only, which is known from their size.
+fn change_once(path: &Path) {
+    let name = path.file_name().unwrap().to_string_lossy();
+    if !name.starts_with("changing-") {
+        return;
+    }
+
+    let contents = changing_contents(path);
+    if fs::metadata(path).unwrap().len() != contents.len() as u64 {
+        return;
+    }
+    if name == "changing-large.txt" {
+        let mut file = fs::OpenOptions::new().append(true).open(path).unwrap();
+        file.write_all(b"and one more line\n").unwrap();
+    } else {
+        fs::write(path, &contents[..100]).unwrap();
+    }
+}
+
+/// Rewrites `drifting.txt` as either of them is about to be read, so that it is read as it was
+/// before `after-drift.txt` is read, and is left as it is after.
+fn drift(path: &Path) {
+    let drifting = path.with_file_name("drifting.txt");
+    if path.ends_with("drifting.txt") {
+        fs::write(drifting, b"before").unwrap();
+    } else if path.ends_with("after-drift.txt") {
+        fs::write(drifting, b"after!").unwrap();
+    }
+}
+
+/// The hook of every test that changes files as they are read, since there is only one.
+fn before_read(path: &Path) {
+    change_once(path);
+    drift(path);
+}
+
+#[test]
+fn verifying_after_compressing_finds_the_files_that_differ() {
+    ttare::set_before_read_hook(Some(before_read));
+
+    let src = TempDir::new().unwrap();
+    let tree = src.path().join("tree");
+    fs::create_dir(&tree).unwrap();
+    fs::write(tree.join("text.txt"), b"plain text ".repeat(1000)).unwrap();
+    fs::write(tree.join("noise.bin"), noise(8192)).unwrap();
+    fs::write(tree.join("copy.txt"), b"plain text ".repeat(1000)).unwrap();
+    let files: Vec<PathBuf> = ["text.txt", "noise.bin", "copy.txt"]
+        .iter()
+        .map(|name| tree.join(name))
+        .collect();
+    let archive = src.path().join("archive.ttare");
+    let scratch = TempDir::new().unwrap();
+
+    // The files are checked under the names they are stored with, and the files extracted to
+    // check them are removed
+    for opts in [
+        CompressOptions::default(),
+        CompressOptions {
+            base_dir: Some(tree.clone()),
+            dedup: true,
+            ..CompressOptions::default()
+        },
+        CompressOptions {
+            name_map: vec![(tree.clone(), PathBuf::from("renamed"))],
+            per_file_compression: true,
+            ..CompressOptions::default()
+        },
+    ] {
+        let opts = CompressOptions {
+            verify_after: true,
+            overwrite: true,
+            temp_dir: Some(scratch.path().to_path_buf()),
+            ..opts
+        };
+        ttare::compress(&files, &archive, opts).unwrap();
+        assert_eq!(fs::read_dir(scratch.path()).unwrap().count(), 0);
+    }
+
+    // Compressed on one thread, the drifting file is read before the file that changes it
+    fs::write(tree.join("drifting.txt"), b"before").unwrap();
+    fs::write(tree.join("after-drift.txt"), b"who knew").unwrap();
+    let files = [tree.join("drifting.txt"), tree.join("after-drift.txt")];
+    let opts = CompressOptions {
+        verify_after: true,
+        overwrite: true,
+        ..CompressOptions::default()
+    };
+    let error = rayon::ThreadPoolBuilder::new()
+        .num_threads(1)
+        .build()
+        .unwrap()
+        .install(|| ttare::compress(&files, &archive, opts))
+        .unwrap_err();
+    assert!(
+        matches!(&error, TtareError::SourceMismatch(paths) if paths == &files[..1]),
+        "{error:?}"
+    );
+}
+
+#[test]
+fn files_that_change_while_they_are_read_are_caught() {
+    ttare::set_before_read_hook(Some(before_read));
+
+    let src = TempDir::new().unwrap();
+    let small = src.path().join("changing-small.txt");
+    let large = src.path().join("changing-large.txt");
+    let steady = src.path().join("steady.txt");
+    let files = vec![small.clone(), large.clone(), steady.clone()];
+    let opts = |on_change| CompressOptions {
+        on_change,
+        base_dir: Some(src.path().to_path_buf()),
+        overwrite: true,
+        ..CompressOptions::default()
+    };
+    let reset = || {
+        for path in [&small, &large] {
+            fs::write(path, changing_contents(path)).unwrap();
+        }
+        fs::write(&steady, b"this one doesn't change").unwrap();
+    };
+    let archive = src.path().join("archive.ttare");
+
+    // Either of them fails the archive, which isn't left behind. The large one is caught as its
+    // entry is written, under its name in the archive.
+    for changing in [&small, &large] {
+        reset();
+        let files = [changing.clone(), steady.clone()];
+        let error = ttare::compress(&files, &archive, opts(OnChange::Fail)).unwrap_err();
+        assert!(
+            matches!(&error, TtareError::FileChanged(path) if changing.ends_with(path)),
+            "{error:?}"
+        );
+        assert!(!archive.exists());
+    }
+
+    reset();
+    let summary = ttare::compress(&files, &archive, opts(OnChange::Skip)).unwrap();
+    assert_eq!(summary.skipped, [small.clone(), large.clone()]);
+    let out = TempDir::new().unwrap();
+    ttare::decompress(&archive, out.path(), DecompressOptions::default()).unwrap();
+    assert!(!out.path().join("changing-small.txt").exists());
+    assert!(!out.path().join("changing-large.txt").exists());
+    assert_eq!(
+        fs::read(out.path().join("steady.txt")).unwrap(),
+        b"this one doesn't change"
+    );
+
+    // Reading them again gets them as they are once they changed
+    reset();
+    let summary = ttare::compress(&files, &archive, opts(OnChange::Retry)).unwrap();
+    assert!(summary.skipped.is_empty());
+    let out = TempDir::new().unwrap();
+    ttare::decompress(&archive, out.path(), DecompressOptions::default()).unwrap();
+    for path in &files {
+        assert_eq!(
+            fs::read(out.path().join(path.file_name().unwrap())).unwrap(),
+            fs::read(path).unwrap(),
+            "{}",
+            path.display()
+        );
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn skipping_the_first_link_of_a_file_stores_the_next_one() {
+    ttare::set_before_read_hook(Some(before_read));
+
+    let src = TempDir::new().unwrap();
+    fs::create_dir_all(src.path().join("sub")).unwrap();
+    let first = src.path().join("changing-large.txt");
+    let second = src.path().join("sub/changing-large.txt");
+    let archive = src.path().join("archive.ttare");
+
+    // The first link changes as it's read and is skipped, so the second one can't link to it
+    for per_file_compression in [false, true] {
+        let _ = fs::remove_file(&second);
+        fs::write(&first, changing_contents(&first)).unwrap();
+        fs::hard_link(&first, &second).unwrap();
+        let files = [first.clone(), second.clone()];
+        let opts = CompressOptions {
+            on_change: OnChange::Skip,
+            base_dir: Some(src.path().to_path_buf()),
+            per_file_compression,
+            overwrite: true,
+            ..CompressOptions::default()
+        };
+        let summary = ttare::compress(&files, &archive, opts).unwrap();
+        assert_eq!(summary.skipped, files[..1]);
+        assert_eq!(summary.hard_links, 0);
+
+        let out = TempDir::new().unwrap();
+        ttare::decompress(&archive, out.path(), DecompressOptions::default()).unwrap();
+        assert!(!out.path().join("changing-large.txt").exists());
+        assert_eq!(
+            fs::read(out.path().join("sub/changing-large.txt")).unwrap(),
+            fs::read(&second).unwrap()
+        );
+    }
+}
+
+#[test]
+fn resuming_from_a_checkpoint_writes_the_same_archive() {
+    ttare::set_before_read_hook(Some(before_read));
+
+    let src = TempDir::new().unwrap();
+    let tree = src.path().join("tree");
+    fs::create_dir_all(tree.join("sub")).unwrap();
+    let changing = tree.join("changing-large.txt");
+
+    // Enough files for a record ahead of the one that fails the run, with copies on either side
+    let mut files = vec![tree.join("sub")];
+    for i in 0..90 {
+        let path = tree.join(format!("{i:02}.txt"));
+        let contents = match i % 10 {
+            0 => noise(4096),
+            1 => b"the same contents ".repeat(50),
+            _ => format!("file {i}\n").repeat(100).into_bytes(),
+        };
+        fs::write(&path, contents).unwrap();
+        files.push(path);
+        if i == 80 {
+            files.push(changing.clone());
+        }
+    }
+
+    let archive = src.path().join("archive.ttare");
+    let checkpoint = src.path().join("archive.checkpoint");
+    let opts = |per_file_compression, checkpoint: Option<&Path>, resume| CompressOptions {
+        base_dir: Some(tree.clone()),
+        mtime: Some(1_700_000_000),
+        dedup: true,
+        manifest: true,
+        index: true,
+        per_file_compression,
+        checkpoint: checkpoint.map(Path::to_path_buf),
+        resume,
+        overwrite: true,
+        ..CompressOptions::default()
+    };
+
+    for per_file_compression in [false, true] {
+        // The file that grows as it's read fails the run, which leaves what it added behind
+        fs::write(&changing, changing_contents(&changing)).unwrap();
+        let interrupted = opts(per_file_compression, Some(&checkpoint), false);
+        let error = ttare::compress(&files, &archive, interrupted).unwrap_err();
+        assert!(matches!(error, TtareError::FileChanged(_)), "{error:?}");
+        assert!(src.path().join("archive.ttare.partial").exists());
+        assert!(fs::read_to_string(&checkpoint).unwrap().lines().count() > 1);
+
+        let resumed = opts(per_file_compression, Some(&checkpoint), true);
+        let summary = ttare::compress(&files, &archive, resumed).unwrap();
+        assert_eq!(summary.deduplicated_files, 16);
+        for leftover in [
+            "archive.checkpoint",
+            "archive.ttare.partial",
+            "archive.ttare.member.partial",
+        ] {
+            assert!(!src.path().join(leftover).exists(), "{leftover}");
+        }
+
+        let resumed = fs::read(&archive).unwrap();
+        let uninterrupted = opts(per_file_compression, None, false);
+        assert_eq!(
+            ttare::compress(&files, &archive, uninterrupted).unwrap(),
+            summary
+        );
+        assert!(fs::read(&archive).unwrap() == resumed);
+    }
+
+    // A checkpoint only resumes the run that wrote it
+    fs::write(&changing, changing_contents(&changing)).unwrap();
+    ttare::compress(&files, &archive, opts(false, Some(&checkpoint), false)).unwrap_err();
+    let error =
+        ttare::compress(&files[1..], &archive, opts(false, Some(&checkpoint), true)).unwrap_err();
+    assert!(
+        matches!(&error, TtareError::CheckpointMismatch(path) if *path == checkpoint),
+        "{error:?}"
+    );
+    let error = ttare::compress(
+        &files,
+        &archive,
+        CompressOptions {
+            split_size: NonZeroU64::new(1024 * 1024),
+            ..opts(false, Some(&checkpoint), false)
+        },
+    )
+    .unwrap_err();
+    assert!(
+        matches!(error, TtareError::CheckpointConflict(_)),
+        "{error:?}"
+    );
+}
+
+/// Fails unless the files at `a` and `b` have the same contents, without reading either in full.
+fn assert_same_contents(a: &Path, b: &Path) {
+    assert_eq!(
+        fs::metadata(a).unwrap().len(),
+        fs::metadata(b).unwrap().len()
+    );
+
+    let (mut a, mut b) = (fs::File::open(a).unwrap(), fs::File::open(b).unwrap());
+    let (mut chunk_a, mut chunk_b) = (vec![0; 1024 * 1024], vec![0; 1024 * 1024]);
+    loop {
+        let read = a.read(&mut chunk_a).unwrap();
+        if read == 0 {
+            break;
+        }
+        b.read_exact(&mut chunk_b[..read]).unwrap();
+        assert!(chunk_a[..read] == chunk_b[..read]);
+    }
+}
+
+/// The most memory this process had resident since it started, or since it was last reset.
+#[cfg(target_os = "linux")]
+fn peak_resident_bytes() -> u64 {
+    let status = fs::read_to_string("/proc/self/status").unwrap();
+    let kib = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmHWM:"))
+        .and_then(|line| line.split_whitespace().next())
+        .unwrap();
+    kib.parse::<u64>().unwrap() * 1024
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+#[ignore = "writes 3 GiB of files and reads them back, run it with --release -- --ignored"]
+fn multi_gigabyte_archives_are_extracted_in_bounded_memory() {
+    const GIB: u64 = 1024 * 1024 * 1024;
+
+    let src = TempDir::new().unwrap();
+
+    // Compressible, so it ends up in the compressed member
+    let text = src.path().join("big.txt");
+    let mut writer = io::BufWriter::new(fs::File::create(&text).unwrap());
+    let mut written = 0;
+    for line in 0.. {
+        if written >= 2 * GIB {
+            break;
+        }
+        let line = format!("this is line {line} of a very large log\n");
+        writer.write_all(line.as_bytes()).unwrap();
+        written += line.len() as u64;
+    }
+    writer.flush().unwrap();
+
+    // Incompressible, so it is stored as-is in the root tar
+    let blob = src.path().join("big.bin");
+    let mut writer = io::BufWriter::new(fs::File::create(&blob).unwrap());
+    let block = noise(1024 * 1024);
+    for _ in 0..1024 {
+        writer.write_all(&block).unwrap();
+    }
+    writer.flush().unwrap();
+
+    let archive = src.path().join("big.ttare");
+    let summary = ttare::compress(
+        &[text.clone(), blob.clone()],
+        &archive,
+        CompressOptions {
+            base_dir: Some(src.path().to_path_buf()),
+            ..CompressOptions::default()
+        },
+    )
+    .unwrap();
+    assert_eq!((summary.compressed_files, summary.stored_files), (1, 1));
+
+    // Only what extracting holds counts, not what compressing did
+    fs::write("/proc/self/clear_refs", "5").unwrap();
+    let out = TempDir::new().unwrap();
+    ttare::decompress(&archive, out.path(), DecompressOptions::default()).unwrap();
+    let peak = peak_resident_bytes();
+    assert!(peak < 256 * 1024 * 1024, "{peak} bytes");
+
+    assert_same_contents(&text, &out.path().join("big.txt"));
+    assert_same_contents(&blob, &out.path().join("big.bin"));
+}
+
+#[test]
+#[ignore = "compresses a sparse file of 9 GiB, run it with --release -- --ignored"]
+fn files_beyond_the_ustar_size_limit_are_archived() {
+    const SIZE: u64 = 9 * 1024 * 1024 * 1024;
+
+    // ustar headers hold sizes below 8 GiB, so this one needs GNU's
+    let src = TempDir::new().unwrap();
+    let sparse = src.path().join("sparse.bin");
+    let file = fs::File::create(&sparse).unwrap();
+    file.set_len(SIZE).unwrap();
+    drop(file);
+
+    for (name, opts) in [
+        ("member", CompressOptions::default()),
+        (
+            "per-file",
+            CompressOptions {
+                per_file_compression: true,
+                ..CompressOptions::default()
+            },
+        ),
+        (
+            "stored",
+            CompressOptions {
+                no_compress: true,
+                ..CompressOptions::default()
+            },
+        ),
+    ] {
+        let archive = src.path().join(format!("{name}.ttare"));
+        let opts = CompressOptions {
+            base_dir: Some(src.path().to_path_buf()),
+            index: true,
+            ..opts
+        };
+        ttare::compress(std::slice::from_ref(&sparse), &archive, opts).unwrap();
+
+        let listing = ttare::list(&archive).unwrap();
+        assert_eq!(listing.entries.len(), 1, "{name}");
+        assert_eq!(listing.entries[0].size, SIZE, "{name}");
+
+        let mut zeros = ZeroCounter::default();
+        ttare::extract(&archive, Path::new("sparse.bin"), &mut zeros).unwrap();
+        assert_eq!((zeros.zeros, zeros.others), (SIZE, 0), "{name}");
+        fs::remove_file(&archive).unwrap();
+    }
+}
+
+/// Counts the zeros written to it, and the other bytes, without keeping any of them.
+#[derive(Default)]
+struct ZeroCounter {
+    zeros: u64,
+    others: u64,
+}
+
+impl Write for ZeroCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let zeros = buf.iter().filter(|&&byte| byte == 0).count() as u64;
+        self.zeros += zeros;
+        self.others += buf.len() as u64 - zeros;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn sniffing_sees_past_misleading_extensions() {
+    let src = TempDir::new().unwrap();
+    let text = b"plain text, whatever the extension says ".repeat(200);
+
+    // A PNG signature in front of text, and text gzipped without compressing it
+    let png = [&b"\x89PNG\r\n\x1a\n"[..], &text].concat();
+    let mut gzip = flate2::write::GzEncoder::new(vec![], flate2::Compression::none());
+    gzip.write_all(&text).unwrap();
+    let gzip = gzip.finish().unwrap();
+
+    let files = [
+        ("image.txt", png),
+        ("archive.bin", gzip),
+        ("notes.jpg", text.clone()),
+    ];
+    let paths: Vec<PathBuf> = files
+        .iter()
+        .map(|(name, contents)| {
+            let path = src.path().join(name);
+            fs::write(&path, contents).unwrap();
+            path
+        })
+        .collect();
+
+    let decisions = |opts: &CompressOptions| -> Vec<(bool, EntropyAnalysis)> {
+        ttare::analyze_files(&paths, opts)
+            .unwrap()
+            .into_iter()
+            .map(|analysis| (analysis.entropy.is_some(), analysis.decision))
+            .collect()
+    };
+    // Their entropy is that of text, so only the extension tells, and whether the entropy was read
+    let guessed = CompressOptions::default();
+    assert_eq!(
+        decisions(&guessed),
+        [
+            (true, EntropyAnalysis::Compress),
+            (true, EntropyAnalysis::Compress),
+            (false, EntropyAnalysis::DontCompress),
+        ]
+    );
+
+    let sniffed = CompressOptions {
+        sniff: true,
+        extension_shortcut: false,
+        ..CompressOptions::default()
+    };
+    assert_eq!(
+        decisions(&sniffed),
+        [
+            (false, EntropyAnalysis::DontCompress),
+            (false, EntropyAnalysis::DontCompress),
+            (true, EntropyAnalysis::Compress),
+        ]
+    );
+
+    for (_, contents) in &files[..2] {
+        assert_eq!(
+            analyze_entropy(&mut Cursor::new(contents), &sniffed).unwrap(),
+            EntropyAnalysis::DontCompress
+        );
+    }
+
+    // Checking the formats only warns, so the files are stored the same way
+    for opts in [guessed, sniffed] {
+        let checked = CompressOptions {
+            check_formats: true,
+            ..opts.clone()
+        };
+        assert_eq!(decisions(&checked), decisions(&opts));
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn paths_that_arent_utf8_are_stored_as_they_are() {
+    use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+    let src = TempDir::new().unwrap();
+    let text_name = Path::new(OsStr::from_bytes(b"caf\xe9.txt"));
+    let copy_name = Path::new(OsStr::from_bytes(b"c\xf6py.txt"));
+    let text = b"not quite utf-8 ".repeat(500);
+    fs::write(src.path().join(text_name), &text).unwrap();
+    fs::write(src.path().join(copy_name), &text).unwrap();
+
+    for per_file_compression in [false, true] {
+        let archive = src.path().join(format!("{per_file_compression}.ttare"));
+        let opts = CompressOptions {
+            per_file_compression,
+            dedup: true,
+            manifest: true,
+            index: true,
+            base_dir: Some(src.path().to_path_buf()),
+            ..CompressOptions::default()
+        };
+        let files = [src.path().join(text_name), src.path().join(copy_name)];
+        ttare::compress(&files, &archive, opts).unwrap();
+
+        // The index and the manifest hold the names too
+        let listing = ttare::list(&archive).unwrap();
+        let paths: Vec<&Path> = listing.entries.iter().map(|e| e.path.as_path()).collect();
+        assert_eq!(paths, [text_name, copy_name]);
+        ttare::verify(&archive).unwrap();
+
+        let mut copy = vec![];
+        ttare::extract(&archive, copy_name, &mut copy).unwrap();
+        assert_eq!(copy, text);
+    }
+}
+
+#[test]
+fn stripping_the_trailer_leaves_a_readable_archive() {
+    let src = TempDir::new().unwrap();
+    let text = src.path().join("text.txt");
+    let noisy = src.path().join("noise.bin");
+    fs::write(&text, b"a small text file\n".repeat(20)).unwrap();
+    fs::write(&noisy, noise(4 * 1024)).unwrap();
+    let files = [text.clone(), noisy.clone()];
+
+    for per_file_compression in [false, true] {
+        let archive = |strip_trailer| {
+            let archive = src
+                .path()
+                .join(format!("{per_file_compression}-{strip_trailer}.ttare"));
+            let opts = CompressOptions {
+                per_file_compression,
+                strip_trailer,
+                index: true,
+                ..CompressOptions::default()
+            };
+            ttare::compress(&files, &archive, opts).unwrap();
+            (fs::read(&archive).unwrap(), archive)
+        };
+        let (kept, _) = archive(false);
+        let (stripped, archive) = archive(true);
+        assert!(kept.ends_with(&[0; 1024]));
+        assert!(!stripped.ends_with(&[0; 1024]));
+        assert!(
+            kept.len() - stripped.len() >= 1024,
+            "{per_file_compression}"
+        );
+
+        ttare::verify(&archive).unwrap();
+        assert_eq!(ttare::list(&archive).unwrap().entries.len(), 2);
+        let mut extracted = vec![];
+        ttare::extract(&archive, text.strip_prefix("/").unwrap(), &mut extracted).unwrap();
+        assert_eq!(extracted, fs::read(&text).unwrap());
+
+        let out = TempDir::new().unwrap();
+        ttare::decompress(&archive, out.path(), DecompressOptions::default()).unwrap();
+        for path in &files {
+            let extracted = out.path().join(path.strip_prefix("/").unwrap());
+            assert_eq!(fs::read(extracted).unwrap(), fs::read(path).unwrap());
+        }
+
+        // Appending keeps it stripped, like the rest of how the archive was written
+        let more = src.path().join("more.txt");
+        fs::write(&more, b"appended").unwrap();
+        ttare::append(&archive, &[more], CompressOptions::default()).unwrap();
+        assert!(!fs::read(&archive).unwrap().ends_with(&[0; 1024]));
+        assert_eq!(ttare::list(&archive).unwrap().entries.len(), 3);
+    }
+
+    // A plain tar.gz is meant to be read by anything
+    let opts = CompressOptions {
+        plain_targz: true,
+        strip_trailer: true,
+        ..CompressOptions::default()
+    };
+    let result = ttare::compress(&files, &src.path().join("plain.tar.gz"), opts);
+    assert!(matches!(result, Err(TtareError::PlainTargzConflict(_))));
+}
+
+#[test]
+fn stats_sum_up_how_the_files_would_be_stored() {
+    let src = TempDir::new().unwrap();
+    let write = |name: &str, contents: &[u8]| {
+        let path = src.path().join(name);
+        fs::write(&path, contents).unwrap();
+        path
+    };
+    let files = [
+        write("a.txt", &b"aaaaaaab".repeat(1024)),
+        write(
+            "text.txt",
+            &b"the quick brown fox jumps over the lazy dog ".repeat(200),
+        ),
+        write("noise.bin", &noise(16 * 1024)),
+        write("photo.jpg", &noise(4 * 1024)),
+    ];
+
+    let stats = ttare::stats(&files, &CompressOptions::default()).unwrap();
+    assert_eq!(stats.compressed.files, 2);
+    assert_eq!(stats.compressed.bytes, 8192 + 44 * 200);
+    assert_eq!(stats.stored.files, 2);
+    assert_eq!(stats.stored.bytes, 20 * 1024);
+    // The extension says it's already compressed
+    assert_eq!(stats.unmeasured.files, 1);
+    assert_eq!(stats.unmeasured.bytes, 4 * 1024);
+
+    let (min, median, max) = (
+        stats.min_entropy.unwrap(),
+        stats.median_entropy.unwrap(),
+        stats.max_entropy.unwrap(),
+    );
+    assert!(min < 1.0 && max > 7.9, "{min} {max}");
+    assert!(min < median && median < max, "{median}");
+    assert_eq!(
+        stats.entropy_threshold,
+        Codec::Gzip.default_entropy_threshold()
+    );
+
+    // The files at most 1 bit per byte can lose most of their size
+    assert!(stats.potential_savings_bytes > 8192 * 7 / 8);
+    assert!(stats.potential_savings_bytes < stats.compressed.bytes);
+
+    assert_eq!(stats.histogram.len(), 16);
+    assert_eq!(stats.histogram[0].from, 0.0);
+    assert_eq!(stats.histogram[15].to, 8.0);
+    let measured: usize = stats.histogram.iter().map(|b| b.count.files).sum();
+    assert_eq!(measured, 3);
+    assert_eq!(stats.histogram[15].count.bytes, 16 * 1024);
+
+    // Nothing is measured without files
+    let empty = ttare::stats(&[], &CompressOptions::default()).unwrap();
+    assert_eq!(empty.compressed.files + empty.stored.files, 0);
+    assert_eq!(empty.median_entropy, None);
+}
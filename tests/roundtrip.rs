@@ -0,0 +1,4767 @@
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, ExitStatus, Output, Stdio},
+};
+
+use tempfile::TempDir;
+
+mod common;
+
+use common::noise;
+
+fn run(dir: &Path, args: &[&str]) -> ExitStatus {
+    Command::new(env!("CARGO_BIN_EXE_ttare"))
+        .current_dir(dir)
+        .args(args)
+        .stderr(Stdio::null())
+        .status()
+        .expect("failed to run ttare")
+}
+
+fn ttare(dir: &Path, args: &[&str]) {
+    assert!(run(dir, args).success(), "ttare {:?} failed", args);
+}
+
+/// Runs ttare, returning what it printed to stdout.
+fn ttare_stdout(dir: &Path, args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_ttare"))
+        .current_dir(dir)
+        .args(args)
+        .output()
+        .expect("failed to run ttare");
+    assert!(output.status.success(), "ttare {:?} failed", args);
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn round_trip_mixed_files() {
+    let src = TempDir::new().unwrap();
+    let out = TempDir::new().unwrap();
+
+    let files: Vec<(&str, Vec<u8>)> = vec![
+        ("text.txt", b"hello ttare ".repeat(1000)),
+        ("zeros.bin", vec![0u8; 64 * 1024]),
+        ("noise.bin", noise(64 * 1024)),
+    ];
+    for (name, contents) in &files {
+        fs::write(src.path().join(name), contents).unwrap();
+    }
+
+    ttare(
+        src.path(),
+        &[
+            "compress",
+            "-o",
+            "archive.ttare",
+            "text.txt",
+            "zeros.bin",
+            "noise.bin",
+        ],
+    );
+
+    let restored = out.path().join("restored");
+    ttare(
+        src.path(),
+        &[
+            "decompress",
+            "archive.ttare",
+            "-o",
+            restored.to_str().unwrap(),
+        ],
+    );
+
+    for (name, contents) in &files {
+        assert_eq!(&fs::read(restored.join(name)).unwrap(), contents, "{name}");
+    }
+}
+
+#[test]
+fn round_trip_only_incompressible_files() {
+    let src = TempDir::new().unwrap();
+    let out = TempDir::new().unwrap();
+
+    let contents = noise(16 * 1024);
+    fs::write(src.path().join("noise.bin"), &contents).unwrap();
+
+    ttare(
+        src.path(),
+        &["compress", "-o", "archive.ttare", "noise.bin"],
+    );
+
+    // Without compressible files, there is no compressed member to add
+    assert_eq!(
+        root_entries(&src.path().join("archive.ttare")),
+        vec![".ttare.meta", "noise.bin"]
+    );
+    ttare(src.path(), &["verify", "archive.ttare"]);
+
+    ttare(
+        src.path(),
+        &[
+            "decompress",
+            "archive.ttare",
+            "-o",
+            out.path().to_str().unwrap(),
+        ],
+    );
+
+    assert_eq!(fs::read(out.path().join("noise.bin")).unwrap(), contents);
+}
+
+#[test]
+fn round_trip_only_compressible_files() {
+    let src = TempDir::new().unwrap();
+    let out = TempDir::new().unwrap();
+
+    let first = b"compressible ".repeat(1000);
+    let second = b"also compressible ".repeat(1000);
+    fs::write(src.path().join("first.txt"), &first).unwrap();
+    fs::write(src.path().join("second.txt"), &second).unwrap();
+
+    ttare(
+        src.path(),
+        &["compress", "-o", "archive.ttare", "first.txt", "second.txt"],
+    );
+
+    // The root tar only holds what is needed to read the compressed member
+    assert_eq!(
+        root_entries(&src.path().join("archive.ttare")),
+        vec![".ttare.meta", ".ttare.crc32", ".ttare.tar.gz"]
+    );
+    ttare(src.path(), &["verify", "archive.ttare"]);
+
+    ttare(
+        src.path(),
+        &[
+            "decompress",
+            "archive.ttare",
+            "-o",
+            out.path().to_str().unwrap(),
+        ],
+    );
+
+    assert_eq!(fs::read(out.path().join("first.txt")).unwrap(), first);
+    assert_eq!(fs::read(out.path().join("second.txt")).unwrap(), second);
+}
+
+#[test]
+fn round_trip_stored_gzip_level() {
+    let src = TempDir::new().unwrap();
+    let out = TempDir::new().unwrap();
+
+    let contents = b"level zero ".repeat(1000);
+    fs::write(src.path().join("text.txt"), &contents).unwrap();
+
+    ttare(
+        src.path(),
+        &["compress", "-o", "archive.ttare", "-l", "0", "text.txt"],
+    );
+    ttare(
+        src.path(),
+        &[
+            "decompress",
+            "archive.ttare",
+            "-o",
+            out.path().to_str().unwrap(),
+        ],
+    );
+
+    assert_eq!(fs::read(out.path().join("text.txt")).unwrap(), contents);
+}
+
+#[test]
+fn rejects_out_of_range_compression_level() {
+    let src = TempDir::new().unwrap();
+    fs::write(src.path().join("text.txt"), b"text").unwrap();
+
+    // They are usage errors, caught before any file is read, even a missing one
+    for (codec, level) in [("gzip", "10"), ("zstd", "20"), ("brotli", "12")] {
+        for files in [&["text.txt"][..], &["missing.txt"]] {
+            let args = [
+                &["compress", "-o", "archive.ttare", "-c", codec, "-l", level],
+                files,
+            ]
+            .concat();
+            assert_eq!(run(src.path(), &args).code(), Some(2), "{args:?}");
+            assert!(!src.path().join("archive.ttare").exists());
+        }
+    }
+    let status = run(
+        src.path(),
+        &[
+            "recompress",
+            "in.ttare",
+            "out.ttare",
+            "-c",
+            "gzip",
+            "-l",
+            "10",
+        ],
+    );
+    assert_eq!(status.code(), Some(2));
+}
+
+/// The names of the top level entries in the archive at `path`.
+fn root_entries(path: &Path) -> Vec<String> {
+    let mut archive = tar::Archive::new(fs::File::open(path).unwrap());
+    archive
+        .entries()
+        .unwrap()
+        .map(|entry| entry.unwrap().path().unwrap().display().to_string())
+        .collect()
+}
+
+#[cfg(unix)]
+#[test]
+fn reproducible_archives_match_known_good_ones() {
+    use std::{io::Read, os::unix::fs::PermissionsExt};
+
+    let src = TempDir::new().unwrap();
+    let text = src.path().join("text.txt");
+    let raw = src.path().join("noise.bin");
+    fs::write(&text, b"a line of text that compresses well\n".repeat(500)).unwrap();
+    fs::write(&raw, noise(16 * 1024)).unwrap();
+    for path in [&text, &raw] {
+        fs::set_permissions(path, fs::Permissions::from_mode(0o644)).unwrap();
+    }
+
+    for (codec, known_good) in [
+        (
+            "gzip",
+            &include_bytes!("fixtures/reproducible-gzip.ttare")[..],
+        ),
+        (
+            "zstd",
+            &include_bytes!("fixtures/reproducible-zstd.ttare")[..],
+        ),
+    ] {
+        let name = format!("{codec}.ttare");
+        ttare(
+            src.path(),
+            &[
+                "compress",
+                "--reproducible",
+                "--mtime",
+                "1700000000",
+                "--codec",
+                codec,
+                "-o",
+                &name,
+                "text.txt",
+                "noise.bin",
+            ],
+        );
+        let archive = fs::read(src.path().join(&name)).unwrap();
+        assert!(archive == known_good, "{codec}");
+
+        // The member is streamed through the encoder, which compresses it like encoding the
+        // whole tar at once does
+        let mut root = tar::Archive::new(archive.as_slice());
+        let mut member = vec![];
+        for entry in root.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            if entry
+                .path()
+                .unwrap()
+                .to_string_lossy()
+                .starts_with(".ttare.tar.")
+            {
+                entry.read_to_end(&mut member).unwrap();
+            }
+        }
+        let mut inner = vec![];
+        let whole = match codec {
+            "gzip" => {
+                flate2::read::GzDecoder::new(member.as_slice())
+                    .read_to_end(&mut inner)
+                    .unwrap();
+                let mut whole = vec![];
+                flate2::read::GzEncoder::new(inner.as_slice(), flate2::Compression::default())
+                    .read_to_end(&mut whole)
+                    .unwrap();
+                whole
+            }
+            _ => {
+                inner = zstd::decode_all(member.as_slice()).unwrap();
+                zstd::encode_all(inner.as_slice(), 0).unwrap()
+            }
+        };
+        assert!(!inner.is_empty(), "{codec}");
+        assert!(member == whole, "{codec}");
+    }
+}
+
+#[test]
+fn round_trip_zstd() {
+    let src = TempDir::new().unwrap();
+    let out = TempDir::new().unwrap();
+
+    let text = b"zstd round trip ".repeat(1000);
+    let raw = noise(16 * 1024);
+    fs::write(src.path().join("text.txt"), &text).unwrap();
+    fs::write(src.path().join("noise.bin"), &raw).unwrap();
+
+    ttare(
+        src.path(),
+        &[
+            "compress",
+            "-o",
+            "archive.ttare",
+            "--codec",
+            "zstd",
+            "text.txt",
+            "noise.bin",
+        ],
+    );
+
+    let entries = root_entries(&src.path().join("archive.ttare"));
+    assert!(entries.iter().any(|name| name == ".ttare.tar.zst"));
+    assert!(!entries.iter().any(|name| name == ".ttare.tar.gz"));
+
+    ttare(
+        src.path(),
+        &[
+            "decompress",
+            "archive.ttare",
+            "-o",
+            out.path().to_str().unwrap(),
+        ],
+    );
+
+    assert_eq!(fs::read(out.path().join("text.txt")).unwrap(), text);
+    assert_eq!(fs::read(out.path().join("noise.bin")).unwrap(), raw);
+
+    // Its top level is past the 9 that the other codecs stop at
+    ttare(
+        src.path(),
+        &[
+            "compress",
+            "-o",
+            "best.ttare",
+            "--codec",
+            "zstd",
+            "-l",
+            "19",
+            "text.txt",
+        ],
+    );
+    let best = out.path().join("best");
+    ttare(
+        src.path(),
+        &["decompress", "best.ttare", "-o", best.to_str().unwrap()],
+    );
+    assert_eq!(fs::read(best.join("text.txt")).unwrap(), text);
+}
+
+#[test]
+fn round_trip_xz() {
+    let src = TempDir::new().unwrap();
+
+    let text = b"xz round trip ".repeat(1000);
+    let raw = noise(16 * 1024);
+    fs::write(src.path().join("text.txt"), &text).unwrap();
+    fs::write(src.path().join("noise.bin"), &raw).unwrap();
+
+    for level in ["0", "9"] {
+        let out = TempDir::new().unwrap();
+        ttare(
+            src.path(),
+            &[
+                "compress",
+                "--force",
+                "-o",
+                "archive.ttare",
+                "-c",
+                "xz",
+                "-l",
+                level,
+                "text.txt",
+                "noise.bin",
+            ],
+        );
+
+        let entries = root_entries(&src.path().join("archive.ttare"));
+        assert!(entries.iter().any(|name| name == ".ttare.tar.xz"));
+
+        ttare(
+            src.path(),
+            &[
+                "decompress",
+                "archive.ttare",
+                "-o",
+                out.path().to_str().unwrap(),
+            ],
+        );
+
+        assert_eq!(fs::read(out.path().join("text.txt")).unwrap(), text);
+        assert_eq!(fs::read(out.path().join("noise.bin")).unwrap(), raw);
+    }
+}
+
+#[test]
+fn round_trip_brotli() {
+    let src = TempDir::new().unwrap();
+
+    let text = b"brotli round trip ".repeat(1000);
+    let raw = noise(16 * 1024);
+    fs::write(src.path().join("text.txt"), &text).unwrap();
+    fs::write(src.path().join("noise.bin"), &raw).unwrap();
+
+    for level in ["0", "11"] {
+        let out = TempDir::new().unwrap();
+        ttare(
+            src.path(),
+            &[
+                "compress",
+                "--force",
+                "-o",
+                "archive.ttare",
+                "-c",
+                "brotli",
+                "-l",
+                level,
+                "text.txt",
+                "noise.bin",
+            ],
+        );
+
+        let entries = root_entries(&src.path().join("archive.ttare"));
+        assert!(entries.iter().any(|name| name == ".ttare.tar.br"));
+
+        ttare(
+            src.path(),
+            &[
+                "decompress",
+                "archive.ttare",
+                "-o",
+                out.path().to_str().unwrap(),
+            ],
+        );
+
+        assert_eq!(fs::read(out.path().join("text.txt")).unwrap(), text);
+        assert_eq!(fs::read(out.path().join("noise.bin")).unwrap(), raw);
+    }
+}
+
+#[test]
+fn sampling_looks_past_a_low_entropy_header() {
+    let src = TempDir::new().unwrap();
+    let out = TempDir::new().unwrap();
+
+    // Reading only the first 10% of this file would see nothing but zeros
+    let mut contents = vec![0u8; 200 * 1024];
+    contents.extend(noise(800 * 1024));
+    fs::write(src.path().join("header.bin"), &contents).unwrap();
+
+    ttare(
+        src.path(),
+        &["compress", "-o", "archive.ttare", "-s", "0.1", "header.bin"],
+    );
+
+    let entries = root_entries(&src.path().join("archive.ttare"));
+    assert!(entries.iter().any(|name| name == "header.bin"));
+
+    ttare(
+        src.path(),
+        &[
+            "decompress",
+            "archive.ttare",
+            "-o",
+            out.path().to_str().unwrap(),
+        ],
+    );
+    assert_eq!(fs::read(out.path().join("header.bin")).unwrap(), contents);
+}
+
+#[test]
+fn round_trip_any_sample_percentage() {
+    let files: Vec<(&str, Vec<u8>)> = vec![
+        ("empty.bin", vec![]),
+        ("one.bin", vec![42]),
+        ("text.txt", b"sample everything ".repeat(5000)),
+        ("noise.bin", noise(10_007)),
+    ];
+
+    for sample in ["1.0", "1.5", "0.0001"] {
+        let src = TempDir::new().unwrap();
+        let out = TempDir::new().unwrap();
+
+        for (name, contents) in &files {
+            fs::write(src.path().join(name), contents).unwrap();
+        }
+
+        let mut args = vec!["compress", "-o", "archive.ttare", "-s", sample];
+        args.extend(files.iter().map(|(name, _)| *name));
+        ttare(src.path(), &args);
+
+        ttare(
+            src.path(),
+            &[
+                "decompress",
+                "archive.ttare",
+                "-o",
+                out.path().to_str().unwrap(),
+            ],
+        );
+
+        for (name, contents) in &files {
+            assert_eq!(
+                &fs::read(out.path().join(name)).unwrap(),
+                contents,
+                "{name} with sample {sample}"
+            );
+        }
+    }
+}
+
+#[test]
+fn round_trip_nested_directories() {
+    let src = TempDir::new().unwrap();
+    let out = TempDir::new().unwrap();
+
+    let files: Vec<(&str, Vec<u8>)> = vec![
+        ("tree/top.txt", b"top level ".repeat(500)),
+        ("tree/a/middle.txt", b"middle ".repeat(500)),
+        ("tree/a/b/c/deep.bin", noise(8 * 1024)),
+        ("tree/d/other.txt", b"other ".repeat(500)),
+    ];
+    for (name, contents) in &files {
+        let path = src.path().join(name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    // A symlink cycle must not make the walk loop forever
+    #[cfg(unix)]
+    std::os::unix::fs::symlink("..", src.path().join("tree/a/up")).unwrap();
+
+    assert!(!run(src.path(), &["compress", "-o", "archive.ttare", "tree"]).success());
+
+    ttare(
+        src.path(),
+        &["compress", "-o", "archive.ttare", "--recursive", "tree"],
+    );
+    ttare(
+        src.path(),
+        &[
+            "decompress",
+            "archive.ttare",
+            "-o",
+            out.path().to_str().unwrap(),
+        ],
+    );
+
+    for (name, contents) in &files {
+        assert_eq!(
+            &fs::read(out.path().join(name)).unwrap(),
+            contents,
+            "{name}"
+        );
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn round_trip_preserves_mode_and_mtime() {
+    use std::{
+        os::unix::fs::PermissionsExt,
+        time::{Duration, SystemTime},
+    };
+
+    let src = TempDir::new().unwrap();
+    let out = TempDir::new().unwrap();
+
+    let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_234_567_890);
+    let files: Vec<(&str, Vec<u8>, u32)> = vec![
+        ("script.sh", b"#!/bin/sh\necho ttare\n".repeat(100), 0o751),
+        ("secret.bin", noise(8 * 1024), 0o600),
+    ];
+    for (name, contents, mode) in &files {
+        let path = src.path().join(name);
+        fs::write(&path, contents).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(*mode)).unwrap();
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+    }
+
+    ttare(
+        src.path(),
+        &["compress", "-o", "archive.ttare", "script.sh", "secret.bin"],
+    );
+    ttare(
+        src.path(),
+        &[
+            "decompress",
+            "archive.ttare",
+            "-o",
+            out.path().to_str().unwrap(),
+        ],
+    );
+
+    for (name, _, mode) in &files {
+        let metadata = fs::metadata(out.path().join(name)).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o7777, *mode, "{name}");
+        assert_eq!(metadata.modified().unwrap(), mtime, "{name}");
+    }
+}
+
+#[test]
+fn list_shows_files_inside_the_compressed_member() {
+    for codec in ["gzip", "zstd"] {
+        let src = TempDir::new().unwrap();
+
+        fs::write(src.path().join("text.txt"), b"list me ".repeat(1000)).unwrap();
+        fs::write(src.path().join("noise.bin"), noise(4096)).unwrap();
+
+        ttare(
+            src.path(),
+            &[
+                "compress",
+                "-o",
+                "archive.ttare",
+                "-c",
+                codec,
+                "text.txt",
+                "noise.bin",
+            ],
+        );
+
+        let listing = ttare_stdout(src.path(), &["list", "archive.ttare"]);
+        let mut lines: Vec<Vec<&str>> = listing
+            .lines()
+            .map(|line| line.split_whitespace().collect())
+            .collect();
+        lines.sort();
+
+        assert_eq!(
+            lines,
+            vec![
+                vec!["C", "8000", "text.txt"],
+                vec!["R", "4096", "noise.bin"]
+            ],
+            "{codec}"
+        );
+        assert_eq!(fs::read_dir(src.path()).unwrap().count(), 3);
+    }
+}
+
+#[test]
+fn dry_run_reports_decisions_without_writing() {
+    let src = TempDir::new().unwrap();
+
+    fs::write(src.path().join("text.txt"), b"dry run ".repeat(1000)).unwrap();
+    fs::write(src.path().join("noise.bin"), noise(16 * 1024)).unwrap();
+
+    let report = ttare_stdout(
+        src.path(),
+        &[
+            "compress",
+            "--dry-run",
+            "-s",
+            "1.0",
+            "text.txt",
+            "noise.bin",
+        ],
+    );
+    let rows: Vec<Vec<&str>> = report
+        .lines()
+        .map(|line| line.split_whitespace().collect())
+        .collect();
+
+    assert_eq!(rows.len(), 6);
+    assert_eq!(rows[0], ["ENTROPY", "DECISION", "SIZE", "PATH"]);
+    assert_eq!(rows[1][1..], ["Compress", "7.81", "KiB", "text.txt"]);
+    assert_eq!(rows[2][1..], ["DontCompress", "16.00", "KiB", "noise.bin"]);
+    assert_eq!(rows[1][0], "2.500");
+    assert!(rows[3].is_empty());
+    assert_eq!(
+        report.lines().skip(4).collect::<Vec<_>>(),
+        ["1 files compressed, 7.81 KiB", "1 files stored, 16.00 KiB"]
+    );
+
+    // The columns are aligned, with the sizes right-justified
+    let lines: Vec<&str> = report.lines().collect();
+    assert_eq!(lines[1].find("KiB"), lines[2].find("KiB"));
+    assert_eq!(lines[0].find("PATH"), lines[1].find("text.txt"));
+
+    assert_eq!(fs::read_dir(src.path()).unwrap().count(), 2);
+
+    // Already compressed formats aren't read, unless asked to
+    fs::write(src.path().join("text.zip"), b"dry run ".repeat(1000)).unwrap();
+    let report = ttare_stdout(src.path(), &["compress", "--dry-run", "text.zip"]);
+    assert_eq!(
+        report
+            .lines()
+            .nth(1)
+            .unwrap()
+            .split_whitespace()
+            .collect::<Vec<_>>(),
+        ["-", "DontCompress", "7.81", "KiB", "text.zip"]
+    );
+
+    let report = ttare_stdout(
+        src.path(),
+        &[
+            "compress",
+            "--dry-run",
+            "--no-extension-shortcut",
+            "text.zip",
+        ],
+    );
+    assert_eq!(
+        report.lines().nth(1).unwrap().split_whitespace().nth(1),
+        Some("Compress")
+    );
+}
+
+/// Runs `ttare compress --dry-run` on `text.txt` in `dir` as if stdout were a terminal, with the
+/// environment variables `envs` on top, returning what it printed to stdout.
+fn dry_run_in_terminal(dir: &Path, envs: &[(&str, &str)], extra: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_ttare"))
+        .current_dir(dir)
+        .env("IGNORE_IS_TERMINAL", "1")
+        .env("TERM", "xterm")
+        .env_remove("NO_COLOR")
+        .envs(envs.iter().copied())
+        .args(["compress", "--dry-run", "text.txt"])
+        .args(extra)
+        .output()
+        .expect("failed to run ttare");
+    assert!(output.status.success());
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn dry_run_colors_decisions_unless_asked_not_to() {
+    let src = TempDir::new().unwrap();
+    fs::write(src.path().join("text.txt"), b"dry run ".repeat(1000)).unwrap();
+
+    let piped = ttare_stdout(src.path(), &["compress", "--dry-run", "text.txt"]);
+    assert!(!piped.contains('\x1b'));
+    let colored = dry_run_in_terminal(src.path(), &[], &[]);
+    assert!(colored.contains("\x1b[32mCompress"), "{colored:?}");
+
+    // Colors don't change the alignment
+    let strip = |report: &str| report.replace("\x1b[32m", "").replace("\x1b[39m", "");
+    assert_eq!(strip(&colored), piped);
+
+    assert_eq!(dry_run_in_terminal(src.path(), &[], &["--no-color"]), piped);
+    assert_eq!(
+        dry_run_in_terminal(src.path(), &[("NO_COLOR", "1")], &[]),
+        piped
+    );
+}
+
+#[test]
+fn compress_files_listed_in_a_file_or_stdin() {
+    let src = TempDir::new().unwrap();
+
+    let names = ["with space.txt", "plain.txt", "new\nline.txt"];
+    for name in names {
+        fs::write(src.path().join(name), name.repeat(100)).unwrap();
+    }
+    fs::write(src.path().join("list"), "with space.txt\n\nplain.txt\n").unwrap();
+
+    ttare(
+        src.path(),
+        &[
+            "compress",
+            "-o",
+            "listed.ttare",
+            "--files-from",
+            "list",
+            "list",
+        ],
+    );
+    let listing = ttare_stdout(src.path(), &["list", "listed.ttare"]);
+    let mut listed: Vec<&str> = listing
+        .lines()
+        .map(|line| line[1..].trim_start().split_once(' ').unwrap().1)
+        .collect();
+    listed.sort();
+    assert_eq!(listed, ["list", "plain.txt", "with space.txt"]);
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_ttare"))
+        .current_dir(src.path())
+        .args(["compress", "-o", "null.ttare", "-T", "-", "--null"])
+        .stdin(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(names.join("\0").as_bytes())
+        .unwrap();
+    assert!(child.wait().unwrap().success());
+
+    let out = TempDir::new().unwrap();
+    ttare(
+        src.path(),
+        &[
+            "decompress",
+            "null.ttare",
+            "-o",
+            out.path().to_str().unwrap(),
+        ],
+    );
+    for name in names {
+        assert_eq!(
+            fs::read(out.path().join(name)).unwrap(),
+            name.repeat(100).as_bytes()
+        );
+    }
+}
+
+#[test]
+fn threshold_tune_suggests_a_threshold() {
+    let src = TempDir::new().unwrap();
+
+    fs::write(src.path().join("text.txt"), b"tune ".repeat(1000)).unwrap();
+    fs::write(src.path().join("noise.bin"), noise(16 * 1024)).unwrap();
+
+    let report = ttare_stdout(
+        src.path(),
+        &["compress", "--threshold-tune", "text.txt", "noise.bin"],
+    );
+    let lines: Vec<&str> = report.lines().collect();
+
+    assert!(lines[0].starts_with("suggested threshold: "));
+    assert_eq!(lines[1..], ["1 files compressed", "1 files stored"]);
+    assert_eq!(fs::read_dir(src.path()).unwrap().count(), 2);
+}
+
+#[test]
+fn missing_files_fail_or_are_skipped() {
+    let src = TempDir::new().unwrap();
+    fs::write(src.path().join("text.txt"), b"still here ".repeat(100)).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_ttare"))
+        .current_dir(src.path())
+        .args(["compress", "-o", "archive.ttare", "text.txt", "missing.txt"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("missing.txt"));
+    assert!(!src.path().join("archive.ttare").exists());
+
+    let output = Command::new(env!("CARGO_BIN_EXE_ttare"))
+        .current_dir(src.path())
+        .args([
+            "compress",
+            "-o",
+            "archive.ttare",
+            "--skip-errors",
+            "text.txt",
+            "missing.txt",
+        ])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("skipping missing.txt"));
+
+    let listing = ttare_stdout(src.path(), &["list", "archive.ttare"]);
+    assert_eq!(listing.lines().count(), 1);
+    assert!(listing.contains("text.txt"));
+}
+
+#[test]
+fn extract_single_files() {
+    let src = TempDir::new().unwrap();
+
+    let text = b"extract me ".repeat(1000);
+    let raw = noise(16 * 1024);
+    fs::create_dir(src.path().join("dir")).unwrap();
+    fs::write(src.path().join("dir/text.txt"), &text).unwrap();
+    fs::write(src.path().join("noise.bin"), &raw).unwrap();
+
+    ttare(
+        src.path(),
+        &["compress", "-o", "archive.ttare", "-r", "dir", "noise.bin"],
+    );
+
+    ttare(
+        src.path(),
+        &["extract", "archive.ttare", "dir/text.txt", "-o", "text.out"],
+    );
+    assert_eq!(fs::read(src.path().join("text.out")).unwrap(), text);
+
+    let output = Command::new(env!("CARGO_BIN_EXE_ttare"))
+        .current_dir(src.path())
+        .args(["extract", "archive.ttare", "./noise.bin", "-o", "-"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.stdout, raw);
+
+    let output = Command::new(env!("CARGO_BIN_EXE_ttare"))
+        .current_dir(src.path())
+        .args([
+            "extract",
+            "archive.ttare",
+            "missing.txt",
+            "-o",
+            "missing.out",
+        ])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("missing.txt not found in archive"));
+    assert!(!src.path().join("missing.out").exists());
+}
+
+#[test]
+fn json_summary_describes_the_run() {
+    let src = TempDir::new().unwrap();
+
+    fs::write(src.path().join("text.txt"), b"summarize ".repeat(1000)).unwrap();
+    fs::write(src.path().join("noise.bin"), noise(16 * 1024)).unwrap();
+
+    let report = ttare_stdout(
+        src.path(),
+        &[
+            "compress",
+            "-o",
+            "archive.ttare",
+            "--json",
+            "text.txt",
+            "noise.bin",
+        ],
+    );
+    let summary: serde_json::Value = serde_json::from_str(&report).unwrap();
+
+    let archive_bytes = fs::metadata(src.path().join("archive.ttare"))
+        .unwrap()
+        .len();
+    assert_eq!(summary["input_bytes"], 10_000 + 16 * 1024);
+    assert_eq!(summary["compressed_files"], 1);
+    assert_eq!(summary["stored_files"], 1);
+    assert_eq!(summary["archive_bytes"], archive_bytes);
+    assert!(summary["compressed_member_bytes"].as_u64().unwrap() < 10_000);
+    assert_eq!(
+        summary["ratio"].as_f64().unwrap(),
+        archive_bytes as f64 / (10_000 + 16 * 1024) as f64
+    );
+    assert_eq!(summary["compressed_input_bytes"], 10_000);
+    assert_eq!(
+        summary["compressed_output_bytes"],
+        summary["compressed_member_bytes"]
+    );
+    assert!(summary["compression_ratio"].as_f64().unwrap() < 0.1);
+}
+
+#[test]
+fn tiny_random_files_are_not_expanded_with_no_expand() {
+    let src = TempDir::new().unwrap();
+
+    // Too small for their entropy to look random, so they are classified as compressible
+    let names: Vec<String> = (0..20).map(|i| format!("{i}.bin")).collect();
+    for (name, contents) in names.iter().zip(noise(2000).chunks(100)) {
+        fs::write(src.path().join(name), contents).unwrap();
+    }
+
+    let compress = |extra: &[&str]| -> (serde_json::Value, String) {
+        let mut args = vec!["compress", "--force", "-o", "archive.ttare", "--json"];
+        args.extend(extra);
+        args.extend(names.iter().map(String::as_str));
+        let output = Command::new(env!("CARGO_BIN_EXE_ttare"))
+            .current_dir(src.path())
+            .args(&args)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "ttare {:?} failed", args);
+        (
+            serde_json::from_slice(&output.stdout).unwrap(),
+            String::from_utf8(output.stderr).unwrap(),
+        )
+    };
+
+    for extra in [&[][..], &["--per-file-compression"]] {
+        let (summary, stderr) = compress(extra);
+        assert_eq!(summary["compressed_files"], 20);
+        assert_eq!(summary["compressed_input_bytes"], 2000);
+        assert!(summary["compression_ratio"].as_f64().unwrap() > 1.0);
+        assert!(
+            stderr.contains("warning: compressing 20 files made them bigger, from 2000 to "),
+            "{stderr}"
+        );
+    }
+
+    for extra in [
+        &["--no-expand"][..],
+        &["--no-expand", "--per-file-compression"],
+    ] {
+        let (summary, stderr) = compress(extra);
+        assert_eq!(summary["compressed_files"], 0, "{extra:?}");
+        assert_eq!(summary["stored_files"], 20);
+        assert_eq!(summary["compressed_output_bytes"], 0);
+        assert_eq!(summary["compression_ratio"], serde_json::Value::Null);
+        assert!(!stderr.contains("warning"), "{stderr}");
+
+        let entries = root_entries(&src.path().join("archive.ttare"));
+        let stored: Vec<&String> = entries
+            .iter()
+            .filter(|name| !name.starts_with('.'))
+            .collect();
+        assert_eq!(stored, names.iter().collect::<Vec<_>>());
+        if extra.len() == 1 {
+            // Without compressed files, there is no member to check either
+            assert_eq!(summary["compressed_member_bytes"], 0);
+            assert_eq!(entries.len(), 21, "{entries:?}");
+        }
+        assert_eq!(
+            ttare_stdout(src.path(), &["verify", "archive.ttare"]),
+            "OK\n"
+        );
+
+        let out = TempDir::new().unwrap();
+        ttare(
+            src.path(),
+            &[
+                "decompress",
+                "archive.ttare",
+                "-o",
+                out.path().to_str().unwrap(),
+            ],
+        );
+        for name in &names {
+            assert_eq!(
+                fs::read(out.path().join(name)).unwrap(),
+                fs::read(src.path().join(name)).unwrap()
+            );
+        }
+    }
+}
+
+#[test]
+fn empty_files_are_stored_raw() {
+    let src = TempDir::new().unwrap();
+    let out = TempDir::new().unwrap();
+
+    fs::write(src.path().join("empty.txt"), b"").unwrap();
+
+    ttare(
+        src.path(),
+        &["compress", "-o", "archive.ttare", "empty.txt"],
+    );
+
+    let listing = ttare_stdout(src.path(), &["list", "archive.ttare"]);
+    assert_eq!(
+        listing.split_whitespace().collect::<Vec<_>>(),
+        ["R", "0", "empty.txt"]
+    );
+
+    ttare(
+        src.path(),
+        &[
+            "decompress",
+            "archive.ttare",
+            "-o",
+            out.path().to_str().unwrap(),
+        ],
+    );
+    assert_eq!(fs::read(out.path().join("empty.txt")).unwrap(), b"");
+}
+
+#[test]
+fn round_trip_no_files() {
+    let src = TempDir::new().unwrap();
+    let out = TempDir::new().unwrap();
+
+    ttare(src.path(), &["compress", "-o", "archive.ttare"]);
+    assert_eq!(ttare_stdout(src.path(), &["list", "archive.ttare"]), "");
+
+    ttare(
+        src.path(),
+        &[
+            "decompress",
+            "archive.ttare",
+            "-o",
+            out.path().to_str().unwrap(),
+        ],
+    );
+    assert_eq!(fs::read_dir(out.path()).unwrap().count(), 0);
+}
+
+#[test]
+fn verify_detects_corruption() {
+    let src = TempDir::new().unwrap();
+
+    fs::write(src.path().join("text.txt"), "verify me ".repeat(1000)).unwrap();
+    fs::write(src.path().join("noise.bin"), noise(16 * 1024)).unwrap();
+    ttare(
+        src.path(),
+        &["compress", "-o", "archive.ttare", "text.txt", "noise.bin"],
+    );
+
+    assert_eq!(
+        ttare_stdout(src.path(), &["verify", "archive.ttare"]),
+        "OK\n"
+    );
+
+    let archive = fs::read(src.path().join("archive.ttare")).unwrap();
+    let member_offset = tar::Archive::new(archive.as_slice())
+        .entries()
+        .unwrap()
+        .map(|entry| entry.unwrap())
+        .find(|entry| entry.path().unwrap().to_str() == Some(".ttare.tar.gz"))
+        .unwrap()
+        .raw_file_position() as usize;
+
+    // Flip a byte in the middle of the compressed member
+    let mut corrupt = archive.clone();
+    corrupt[member_offset + 20] ^= 0xff;
+    fs::write(src.path().join("member.ttare"), corrupt).unwrap();
+    assert!(!run(src.path(), &["verify", "member.ttare"]).success());
+
+    // Flip a byte in the header of the first entry
+    let mut corrupt = archive;
+    corrupt[0] ^= 0xff;
+    fs::write(src.path().join("header.ttare"), corrupt).unwrap();
+    assert!(!run(src.path(), &["verify", "header.ttare"]).success());
+}
+
+#[test]
+fn compress_from_stdin() {
+    let src = TempDir::new().unwrap();
+    let out = TempDir::new().unwrap();
+
+    // Larger than what is buffered for the analysis, so the rest has to be spooled
+    let text = "piped through stdin ".repeat(100_000);
+    let streams = [
+        ("text.ttare", "logs/text.txt", text.into_bytes(), 'C'),
+        ("noise.ttare", "noise.bin", noise(64 * 1024), 'R'),
+    ];
+
+    for (archive, name, data, marker) in &streams {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_ttare"))
+            .current_dir(src.path())
+            .args(["compress", "--stdin", "--stdin-name", name, "-o", archive])
+            .stdin(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(data).unwrap();
+        assert!(child.wait().unwrap().success());
+
+        let listing = ttare_stdout(src.path(), &["list", archive]);
+        assert_eq!(listing, format!("{} {:>12} {}\n", marker, data.len(), name));
+
+        ttare(
+            src.path(),
+            &["decompress", archive, "-o", out.path().to_str().unwrap()],
+        );
+        assert_eq!(&fs::read(out.path().join(name)).unwrap(), data);
+    }
+
+    // stdin can't be mixed with files, and needs a name
+    fs::write(src.path().join("text.txt"), "text").unwrap();
+    assert!(!run(
+        src.path(),
+        &[
+            "compress",
+            "--stdin",
+            "--stdin-name",
+            "a",
+            "-o",
+            "a.ttare",
+            "text.txt"
+        ]
+    )
+    .success());
+    assert!(!run(src.path(), &["compress", "--stdin", "-o", "a.ttare"]).success());
+    assert!(!src.path().join("a.ttare").exists());
+}
+
+#[test]
+fn dedup_stores_identical_files_once() {
+    let src = TempDir::new().unwrap();
+    let out = TempDir::new().unwrap();
+
+    let text = "backed up twice ".repeat(1000);
+    let random = noise(64 * 1024);
+    let mut near = random.clone();
+    *near.last_mut().unwrap() ^= 1;
+
+    fs::create_dir_all(src.path().join("tree/copy")).unwrap();
+    fs::write(src.path().join("tree/text.txt"), &text).unwrap();
+    fs::write(src.path().join("tree/copy/text.txt"), &text).unwrap();
+    fs::write(src.path().join("tree/noise.bin"), &random).unwrap();
+    fs::write(src.path().join("tree/copy/noise.bin"), &random).unwrap();
+    fs::write(src.path().join("tree/near.bin"), &near).unwrap();
+
+    ttare(src.path(), &["compress", "-o", "plain.ttare", "-r", "tree"]);
+    let summary: serde_json::Value = serde_json::from_str(&ttare_stdout(
+        src.path(),
+        &[
+            "compress",
+            "-o",
+            "dedup.ttare",
+            "-r",
+            "--dedup",
+            "--json",
+            "tree",
+        ],
+    ))
+    .unwrap();
+    assert_eq!(summary["deduplicated_files"], 2);
+    assert_eq!(summary["stored_files"], 2);
+    assert_eq!(summary["compressed_files"], 1);
+
+    let plain_len = fs::metadata(src.path().join("plain.ttare")).unwrap().len();
+    let dedup_len = fs::metadata(src.path().join("dedup.ttare")).unwrap().len();
+    assert!(dedup_len + 60 * 1024 < plain_len);
+
+    let mut listing: Vec<String> = ttare_stdout(src.path(), &["list", "dedup.ttare"])
+        .lines()
+        .map(String::from)
+        .collect();
+    listing.sort();
+    assert_eq!(
+        listing,
+        [
+            format!("C {:>12} tree/copy/text.txt", text.len()),
+            format!("C {:>12} tree/text.txt", text.len()),
+            format!("R {:>12} tree/copy/noise.bin", random.len()),
+            format!("R {:>12} tree/near.bin", near.len()),
+            format!("R {:>12} tree/noise.bin", random.len()),
+        ]
+    );
+
+    assert_eq!(ttare_stdout(src.path(), &["verify", "dedup.ttare"]), "OK\n");
+
+    ttare(
+        src.path(),
+        &[
+            "extract",
+            "dedup.ttare",
+            "tree/copy/noise.bin",
+            "-o",
+            "noise.out",
+        ],
+    );
+    assert_eq!(fs::read(src.path().join("noise.out")).unwrap(), random);
+
+    ttare(
+        src.path(),
+        &[
+            "decompress",
+            "dedup.ttare",
+            "-o",
+            out.path().to_str().unwrap(),
+        ],
+    );
+    for (path, data) in [
+        ("tree/text.txt", text.as_bytes()),
+        ("tree/copy/text.txt", text.as_bytes()),
+        ("tree/noise.bin", &random),
+        ("tree/copy/noise.bin", &random),
+        ("tree/near.bin", &near),
+    ] {
+        assert_eq!(fs::read(out.path().join(path)).unwrap(), data, "{}", path);
+    }
+}
+
+#[test]
+fn no_progress_when_stderr_is_not_a_terminal() {
+    let src = TempDir::new().unwrap();
+    fs::write(src.path().join("text.txt"), "quiet please ".repeat(1000)).unwrap();
+
+    for args in [
+        &["compress", "-o", "archive.ttare", "text.txt"][..],
+        &[
+            "compress",
+            "--force",
+            "-q",
+            "-o",
+            "archive.ttare",
+            "text.txt",
+        ][..],
+    ] {
+        let output = Command::new(env!("CARGO_BIN_EXE_ttare"))
+            .current_dir(src.path())
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        assert!(output.stderr.is_empty(), "{:?}", output.stderr);
+    }
+}
+
+#[test]
+fn round_trip_files_named_like_internal_entries() {
+    let src = TempDir::new().unwrap();
+
+    let names = [
+        ".ttare.tar.gz",
+        ".ttare.tar.zst",
+        ".ttare.crc32",
+        ".ttare.dedup",
+    ];
+    for name in names {
+        // Incompressible, so they would otherwise be stored as-is next to the internal entries
+        fs::write(src.path().join(name), noise(16 * 1024)).unwrap();
+    }
+
+    for codec in ["gzip", "zstd", "xz", "brotli"] {
+        let out = TempDir::new().unwrap();
+        let mut args = vec!["compress", "--force", "-o", "archive.ttare", "-c", codec];
+        args.extend(names);
+        ttare(src.path(), &args);
+
+        assert_eq!(
+            ttare_stdout(src.path(), &["verify", "archive.ttare"]),
+            "OK\n"
+        );
+
+        let listing = ttare_stdout(src.path(), &["list", "archive.ttare"]);
+        assert_eq!(listing.lines().count(), names.len());
+        assert!(listing.lines().all(|line| line.starts_with('C')));
+
+        ttare(
+            src.path(),
+            &[
+                "decompress",
+                "archive.ttare",
+                "-o",
+                out.path().to_str().unwrap(),
+            ],
+        );
+        for name in names {
+            assert_eq!(
+                fs::read(out.path().join(name)).unwrap(),
+                fs::read(src.path().join(name)).unwrap(),
+                "{}",
+                name
+            );
+        }
+    }
+}
+
+/// Builds an archive at `path` from `(name, contents)` entries, as an older or newer ttare would.
+fn write_raw_archive(path: &Path, entries: &[(&str, Vec<u8>)]) {
+    let mut builder = tar::Builder::new(fs::File::create(path).unwrap());
+    for (name, contents) in entries {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, name, contents.as_slice())
+            .unwrap();
+    }
+    builder.finish().unwrap();
+}
+
+#[test]
+fn archives_record_their_format() {
+    let src = TempDir::new().unwrap();
+    fs::write(src.path().join("text.txt"), "metadata ".repeat(1000)).unwrap();
+    ttare(
+        src.path(),
+        &[
+            "compress",
+            "-o",
+            "archive.ttare",
+            "-c",
+            "zstd",
+            "-e",
+            "7",
+            "text.txt",
+        ],
+    );
+
+    let mut archive = tar::Archive::new(fs::File::open(src.path().join("archive.ttare")).unwrap());
+    let first = archive.entries().unwrap().next().unwrap().unwrap();
+    assert_eq!(first.path().unwrap().to_str(), Some(".ttare.meta"));
+    let meta: serde_json::Value = serde_json::from_reader(first).unwrap();
+    assert_eq!(meta["version"], ttare::TTARE_FORMAT_VERSION);
+    assert_eq!(meta["codec"], "zstd");
+    assert_eq!(meta["entropy_threshold"], 7.0);
+    assert_eq!(meta["sample_percentage"], 0.5);
+}
+
+#[test]
+fn newer_formats_are_rejected() {
+    let src = TempDir::new().unwrap();
+    let meta = format!(
+        r#"{{"version":{},"codec":"gzip","entropy_threshold":6.5,"sample_percentage":0.5}}"#,
+        ttare::TTARE_FORMAT_VERSION + 1
+    );
+    write_raw_archive(
+        &src.path().join("future.ttare"),
+        &[(".ttare.meta", meta.into_bytes()), ("a.bin", b"a".to_vec())],
+    );
+
+    for command in ["list", "verify", "decompress"] {
+        assert!(!run(src.path(), &[command, "future.ttare"]).success());
+    }
+    assert!(!src.path().join("a.bin").exists());
+}
+
+#[test]
+fn legacy_archives_without_metadata_are_read() {
+    let src = TempDir::new().unwrap();
+    let out = TempDir::new().unwrap();
+
+    let mut inner = tar::Builder::new(flate2::write::GzEncoder::new(
+        vec![],
+        flate2::Compression::default(),
+    ));
+    let text = b"legacy ".repeat(1000);
+    let mut header = tar::Header::new_gnu();
+    header.set_size(text.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    inner
+        .append_data(&mut header, "text.txt", text.as_slice())
+        .unwrap();
+    let member = inner.into_inner().unwrap().finish().unwrap();
+
+    let random = noise(1024);
+    write_raw_archive(
+        &src.path().join("legacy.ttare"),
+        &[("noise.bin", random.clone()), (".ttare.tar.gz", member)],
+    );
+
+    assert_eq!(
+        ttare_stdout(src.path(), &["list", "legacy.ttare"]),
+        format!("R {:>12} noise.bin\nC {:>12} text.txt\n", 1024, text.len())
+    );
+    assert_eq!(
+        ttare_stdout(src.path(), &["verify", "legacy.ttare"]),
+        "OK\n"
+    );
+    ttare(
+        src.path(),
+        &[
+            "decompress",
+            "legacy.ttare",
+            "-o",
+            out.path().to_str().unwrap(),
+        ],
+    );
+    assert_eq!(fs::read(out.path().join("noise.bin")).unwrap(), random);
+    assert_eq!(fs::read(out.path().join("text.txt")).unwrap(), text);
+}
+
+#[test]
+fn round_trip_per_file_compression() {
+    let src = TempDir::new().unwrap();
+
+    let text = b"compressed on its own ".repeat(1000);
+    let other = b"another one ".repeat(1000);
+    let random = noise(16 * 1024);
+    fs::create_dir_all(src.path().join("dir")).unwrap();
+    fs::write(src.path().join("dir/text.txt"), &text).unwrap();
+    fs::write(src.path().join("other.txt"), &other).unwrap();
+    // Incompressible and already named like a file compressed on its own
+    fs::write(src.path().join("real.gz"), &random).unwrap();
+
+    for (codec, suffix) in [
+        ("gzip", ".gz"),
+        ("zstd", ".zst"),
+        ("xz", ".xz"),
+        ("brotli", ".br"),
+    ] {
+        let out = TempDir::new().unwrap();
+        ttare(
+            src.path(),
+            &[
+                "compress",
+                "--force",
+                "-o",
+                "archive.ttare",
+                "-c",
+                codec,
+                "--per-file-compression",
+                "-r",
+                "dir",
+                "other.txt",
+                "real.gz",
+            ],
+        );
+
+        let entries = root_entries(&src.path().join("archive.ttare"));
+        assert!(entries.contains(&format!("dir/text.txt{}", suffix)));
+        assert!(entries.contains(&format!("other.txt{}", suffix)));
+        assert!(entries.contains(&"real.gz".to_string()));
+
+        assert_eq!(
+            ttare_stdout(src.path(), &["list", "archive.ttare"]),
+            format!(
+                "C {:>12} dir/text.txt\nC {:>12} other.txt\nR {:>12} real.gz\n",
+                text.len(),
+                other.len(),
+                random.len()
+            )
+        );
+        assert_eq!(
+            ttare_stdout(src.path(), &["verify", "archive.ttare"]),
+            "OK\n"
+        );
+
+        ttare(
+            src.path(),
+            &["extract", "archive.ttare", "other.txt", "-o", "other.out"],
+        );
+        assert_eq!(fs::read(src.path().join("other.out")).unwrap(), other);
+
+        ttare(
+            src.path(),
+            &[
+                "decompress",
+                "archive.ttare",
+                "-o",
+                out.path().to_str().unwrap(),
+            ],
+        );
+        assert_eq!(fs::read(out.path().join("dir/text.txt")).unwrap(), text);
+        assert_eq!(fs::read(out.path().join("other.txt")).unwrap(), other);
+        assert_eq!(fs::read(out.path().join("real.gz")).unwrap(), random);
+        // tar only keeps whole seconds
+        let mtime = |path: &Path| {
+            fs::metadata(path)
+                .unwrap()
+                .modified()
+                .unwrap()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs()
+        };
+        assert_eq!(
+            mtime(&out.path().join("other.txt")),
+            mtime(&src.path().join("other.txt"))
+        );
+    }
+}
+
+#[test]
+fn compress_to_stdout_and_decompress_from_stdin() {
+    let src = TempDir::new().unwrap();
+    let out = TempDir::new().unwrap();
+
+    let files: Vec<(&str, Vec<u8>)> = vec![
+        ("text.txt", b"piped to stdout ".repeat(1000)),
+        ("noise.bin", noise(64 * 1024)),
+    ];
+    for (name, contents) in &files {
+        fs::write(src.path().join(name), contents).unwrap();
+    }
+
+    let archive = Command::new(env!("CARGO_BIN_EXE_ttare"))
+        .current_dir(src.path())
+        .args(["compress", "-o", "-", "text.txt", "noise.bin"])
+        .output()
+        .unwrap();
+    assert!(archive.status.success());
+    assert!(!src.path().join("-").exists());
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_ttare"))
+        .args(["decompress", "-", "-o", out.path().to_str().unwrap()])
+        .stdin(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(&archive.stdout)
+        .unwrap();
+    assert!(child.wait().unwrap().success());
+
+    for (name, contents) in &files {
+        assert_eq!(
+            &fs::read(out.path().join(name)).unwrap(),
+            contents,
+            "{name}"
+        );
+    }
+
+    // The summary would be mixed into the archive
+    assert!(!run(src.path(), &["compress", "-o", "-", "--json", "text.txt"]).success());
+}
+
+#[cfg(unix)]
+#[test]
+fn round_trip_empty_and_read_only_directories() {
+    use std::{
+        os::unix::fs::PermissionsExt,
+        time::{Duration, SystemTime},
+    };
+
+    let src = TempDir::new().unwrap();
+    let out = TempDir::new().unwrap();
+
+    fs::create_dir_all(src.path().join("tree/empty/nested")).unwrap();
+    fs::create_dir_all(src.path().join("tree/locked")).unwrap();
+    fs::write(
+        src.path().join("tree/locked/text.txt"),
+        "locked ".repeat(500),
+    )
+    .unwrap();
+    fs::write(src.path().join("tree/locked/noise.bin"), noise(8 * 1024)).unwrap();
+
+    let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_234_567_890);
+    let dirs = [
+        ("tree/empty/nested", 0o700),
+        ("tree/empty", 0o750),
+        ("tree/locked", 0o555),
+    ];
+    for (dir, mode) in dirs {
+        let path = src.path().join(dir);
+        fs::File::open(&path).unwrap().set_modified(mtime).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+    }
+
+    ttare(
+        src.path(),
+        &["compress", "-o", "archive.ttare", "--recursive", "tree"],
+    );
+    ttare(
+        src.path(),
+        &[
+            "decompress",
+            "archive.ttare",
+            "-o",
+            out.path().to_str().unwrap(),
+        ],
+    );
+
+    for (dir, mode) in dirs {
+        let metadata = fs::metadata(out.path().join(dir)).unwrap();
+        assert!(metadata.is_dir(), "{dir}");
+        assert_eq!(metadata.permissions().mode() & 0o7777, mode, "{dir}");
+        assert_eq!(metadata.modified().unwrap(), mtime, "{dir}");
+    }
+    assert_eq!(
+        fs::read_to_string(out.path().join("tree/locked/text.txt")).unwrap(),
+        "locked ".repeat(500)
+    );
+
+    // Directories aren't files, so they aren't listed
+    let listing = ttare_stdout(src.path(), &["list", "archive.ttare"]);
+    assert_eq!(listing.lines().count(), 2, "{listing}");
+
+    for root in [src.path(), out.path()] {
+        fs::set_permissions(root.join("tree/locked"), fs::Permissions::from_mode(0o755)).unwrap();
+    }
+}
+
+#[test]
+fn append_adds_files_like_the_archive_was_written() {
+    let src = TempDir::new().unwrap();
+    let out = TempDir::new().unwrap();
+
+    let files: Vec<(&str, Vec<u8>)> = vec![
+        ("text.txt", b"written first ".repeat(1000)),
+        ("noise.bin", noise(16 * 1024)),
+        ("tree/later.txt", b"appended later ".repeat(1000)),
+        ("tree/later.bin", noise(32 * 1024)),
+    ];
+    for (name, contents) in &files {
+        let path = src.path().join(name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    for per_file in [false, true] {
+        let archive = src.path().join("archive.ttare");
+        let mut args = vec![
+            "compress",
+            "--force",
+            "-o",
+            "archive.ttare",
+            "--codec",
+            "zstd",
+            "text.txt",
+            "noise.bin",
+        ];
+        if per_file {
+            args.push("--per-file-compression");
+        }
+        ttare(src.path(), &args);
+
+        // The codec comes from the archive, not the defaults
+        ttare(src.path(), &["append", "archive.ttare", "-r", "tree"]);
+
+        let listing = ttare_stdout(src.path(), &["list", "archive.ttare"]);
+        for (marker, name) in [
+            ('C', "text.txt"),
+            ('R', "noise.bin"),
+            ('C', "tree/later.txt"),
+            ('R', "tree/later.bin"),
+        ] {
+            assert!(
+                listing
+                    .lines()
+                    .any(|line| line.starts_with(marker) && line.ends_with(name)),
+                "{name}: {listing}"
+            );
+        }
+
+        let entries = root_entries(&archive);
+        if per_file {
+            assert!(entries.iter().any(|name| name == "tree/later.txt.zst"));
+        } else {
+            assert!(entries.iter().any(|name| name == ".ttare.tar.zst"));
+        }
+        assert!(!entries.iter().any(|name| name.ends_with(".gz")));
+
+        ttare(src.path(), &["verify", "archive.ttare"]);
+        ttare(
+            src.path(),
+            &[
+                "decompress",
+                "archive.ttare",
+                "--overwrite",
+                "-o",
+                out.path().to_str().unwrap(),
+            ],
+        );
+        for (name, contents) in &files {
+            assert_eq!(
+                &fs::read(out.path().join(name)).unwrap(),
+                contents,
+                "{name}"
+            );
+        }
+
+        // Files that are already there are reported, and the archive is left as it is
+        let before = fs::read(&archive).unwrap();
+        let output = Command::new(env!("CARGO_BIN_EXE_ttare"))
+            .current_dir(src.path())
+            .args(["append", "archive.ttare", "text.txt", "tree/later.bin"])
+            .output()
+            .unwrap();
+        assert!(!output.status.success());
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("text.txt, tree/later.bin"), "{stderr}");
+        assert_eq!(fs::read(&archive).unwrap(), before);
+        assert_eq!(fs::read_dir(src.path()).unwrap().count(), 4);
+    }
+}
+
+#[test]
+fn reproducible_archives_are_byte_identical() {
+    let src = TempDir::new().unwrap();
+
+    fs::create_dir_all(src.path().join("tree/empty")).unwrap();
+    fs::write(src.path().join("tree/a.txt"), b"reproducible ".repeat(1000)).unwrap();
+    fs::write(src.path().join("tree/b.txt"), b"reproducible ".repeat(1000)).unwrap();
+    fs::write(src.path().join("noise.bin"), noise(16 * 1024)).unwrap();
+
+    for extra in [
+        &[][..],
+        &["--per-file-compression"],
+        &["--codec", "zstd", "--dedup"],
+    ] {
+        let mut first = vec![
+            "compress",
+            "--force",
+            "-o",
+            "first.ttare",
+            "--reproducible",
+            "-r",
+            "tree",
+            "noise.bin",
+        ];
+        first.extend(extra);
+        ttare(src.path(), &first);
+
+        // Given in another order, and written at another time
+        let mut second = vec![
+            "compress",
+            "--force",
+            "-o",
+            "second.ttare",
+            "--reproducible",
+            "-r",
+            "noise.bin",
+            "tree",
+        ];
+        second.extend(extra);
+        ttare(src.path(), &second);
+
+        let first = fs::read(src.path().join("first.ttare")).unwrap();
+        assert!(
+            first == fs::read(src.path().join("second.ttare")).unwrap(),
+            "{extra:?}"
+        );
+
+        // The entries that ttare adds don't carry the time they were written at
+        let mut archive = tar::Archive::new(first.as_slice());
+        for entry in archive.entries().unwrap() {
+            let entry = entry.unwrap();
+            if entry.path().unwrap().starts_with(".ttare.") {
+                assert_eq!(entry.header().mtime().unwrap(), 0);
+            }
+        }
+    }
+
+    // A fixed time is used for the entries that ttare adds, and clamps the files'
+    ttare(
+        src.path(),
+        &[
+            "compress",
+            "-o",
+            "clamped.ttare",
+            "--reproducible",
+            "--mtime",
+            "1000",
+            "noise.bin",
+        ],
+    );
+    let mut archive = tar::Archive::new(fs::File::open(src.path().join("clamped.ttare")).unwrap());
+    for entry in archive.entries().unwrap() {
+        assert_eq!(entry.unwrap().header().mtime().unwrap(), 1000);
+    }
+}
+
+#[test]
+fn parallel_per_file_compression_keeps_input_order() {
+    let src = TempDir::new().unwrap();
+
+    let mut names = vec![];
+    for i in 0..100 {
+        let name = format!("{i:03}.{}", if i % 4 == 0 { "bin" } else { "txt" });
+        let contents = if i % 4 == 0 {
+            noise(4096 + i)
+        } else {
+            format!("file number {i} ")
+                .repeat(100 * (i % 7 + 1))
+                .into_bytes()
+        };
+        fs::write(src.path().join(&name), contents).unwrap();
+        names.push(name);
+    }
+    // Given out of order, so the archive follows the input and not the file names
+    names.reverse();
+
+    let archive = |threads: &str| {
+        let mut args = vec![
+            "compress",
+            "-o",
+            "-",
+            "--per-file-compression",
+            "--mtime",
+            "0",
+        ];
+        args.extend(names.iter().map(String::as_str));
+        let output = Command::new(env!("CARGO_BIN_EXE_ttare"))
+            .current_dir(src.path())
+            .env("RAYON_NUM_THREADS", threads)
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        output.stdout
+    };
+
+    let sequential = archive("1");
+    assert!(sequential == archive("8"));
+
+    fs::write(src.path().join("archive.ttare"), &sequential).unwrap();
+    let listed: Vec<String> = ttare_stdout(src.path(), &["list", "archive.ttare"])
+        .lines()
+        .map(|line| line.rsplit(' ').next().unwrap().to_string())
+        .collect();
+    assert_eq!(listed, names);
+}
+
+#[cfg(unix)]
+#[test]
+fn round_trip_symlinks() {
+    use std::os::unix::fs::symlink;
+
+    let src = TempDir::new().unwrap();
+    let elsewhere = TempDir::new().unwrap();
+
+    let text = b"pointed to ".repeat(1000);
+    fs::create_dir_all(src.path().join("tree/sub")).unwrap();
+    fs::write(src.path().join("tree/a.txt"), &text).unwrap();
+    fs::write(src.path().join("tree/sub/b.txt"), &text).unwrap();
+    let outside = elsewhere.path().join("outside.txt");
+    fs::write(&outside, "outside").unwrap();
+
+    let links: Vec<(&str, PathBuf)> = vec![
+        ("tree/inside", PathBuf::from("a.txt")),
+        ("tree/sub/up", PathBuf::from("../a.txt")),
+        ("tree/dir", PathBuf::from("sub")),
+        ("tree/outside", outside.clone()),
+        ("tree/dangling", PathBuf::from("missing")),
+    ];
+    for (link, target) in &links {
+        symlink(target, src.path().join(link)).unwrap();
+    }
+
+    let out = TempDir::new().unwrap();
+    ttare(
+        src.path(),
+        &["compress", "-o", "archive.ttare", "-r", "tree"],
+    );
+    ttare(
+        src.path(),
+        &[
+            "decompress",
+            "archive.ttare",
+            "-o",
+            out.path().to_str().unwrap(),
+        ],
+    );
+
+    for (link, target) in &links {
+        assert_eq!(
+            &fs::read_link(out.path().join(link)).unwrap(),
+            target,
+            "{link}"
+        );
+    }
+    assert_eq!(fs::read(out.path().join("tree/sub/up")).unwrap(), text);
+    // The directory it points to wasn't walked twice
+    let listing = ttare_stdout(src.path(), &["list", "archive.ttare"]);
+    assert!(!listing.contains("tree/dir/"), "{listing}");
+
+    // Following them stores what they point to instead
+    let out = TempDir::new().unwrap();
+    ttare(
+        src.path(),
+        &[
+            "compress",
+            "--force",
+            "-o",
+            "archive.ttare",
+            "-r",
+            "--dereference",
+            "--exclude",
+            "dangling",
+            "tree",
+        ],
+    );
+    ttare(
+        src.path(),
+        &[
+            "decompress",
+            "archive.ttare",
+            "-o",
+            out.path().to_str().unwrap(),
+        ],
+    );
+
+    // The directory is only walked once, through the first path that leads to it
+    for path in ["tree/inside", "tree/dir/up", "tree/dir/b.txt"] {
+        let path = out.path().join(path);
+        assert!(!fs::symlink_metadata(&path).unwrap().is_symlink());
+        assert_eq!(fs::read(path).unwrap(), text);
+    }
+    assert_eq!(
+        fs::read_to_string(out.path().join("tree/outside")).unwrap(),
+        "outside"
+    );
+}
+
+#[test]
+fn verbosity_picks_what_is_logged() {
+    let src = TempDir::new().unwrap();
+    fs::write(src.path().join("text.txt"), "log me ".repeat(1000)).unwrap();
+    fs::write(src.path().join("noise.bin"), noise(16 * 1024)).unwrap();
+
+    let stderr = |args: &[&str]| -> String {
+        let output = Command::new(env!("CARGO_BIN_EXE_ttare"))
+            .current_dir(src.path())
+            .env_remove("RUST_LOG")
+            .args(args)
+            .output()
+            .unwrap();
+        String::from_utf8(output.stderr).unwrap()
+    };
+
+    let compress = ["compress", "-o", "archive.ttare", "text.txt", "noise.bin"];
+    let verbose = stderr(&[&["-v"][..], &compress].concat());
+    assert!(verbose.contains("info: compressing with gzip"), "{verbose}");
+    assert!(verbose.contains("info: text.txt: entropy "), "{verbose}");
+    assert!(verbose.contains(", Compress\n"), "{verbose}");
+    assert!(verbose.contains(", DontCompress\n"), "{verbose}");
+    assert!(
+        verbose.contains("1 files compressed, 1 stored"),
+        "{verbose}"
+    );
+    assert!(!verbose.contains("debug: "), "{verbose}");
+
+    let very_verbose = stderr(&["decompress", "archive.ttare", "-o", "out", "-vv"]);
+    assert!(
+        very_verbose.contains("debug: extracting text.txt\n"),
+        "{very_verbose}"
+    );
+    assert!(
+        very_verbose.contains("debug: extracting noise.bin\n"),
+        "{very_verbose}"
+    );
+
+    // Warnings are shown unless -q is given, while errors always are
+    let skipping = [
+        "compress",
+        "--force",
+        "--skip-errors",
+        "-o",
+        "skip.ttare",
+        "missing",
+    ];
+    let default = stderr(&skipping);
+    assert!(default.contains("warning: skipping missing"), "{default}");
+    let quiet = stderr(&[&skipping[..], &["-q"]].concat());
+    assert!(!quiet.contains("warning"), "{quiet}");
+    assert!(quiet.contains("1 files were skipped"), "{quiet}");
+
+    assert!(!run(
+        src.path(),
+        &["compress", "-q", "-v", "-o", "x.ttare", "text.txt"]
+    )
+    .success());
+}
+
+#[test]
+fn round_trip_split_archives() {
+    let src = TempDir::new().unwrap();
+    fs::write(src.path().join("text.txt"), b"split me ".repeat(4000)).unwrap();
+    fs::write(src.path().join("noise.bin"), noise(40 * 1024)).unwrap();
+    let files = ["text.txt", "noise.bin"];
+
+    let mut args = vec!["compress", "-o", "whole.ttare"];
+    args.extend(files);
+    ttare(src.path(), &args);
+
+    let mut args = vec!["compress", "-o", "split.ttare", "--split-size", "4096"];
+    args.extend(files);
+    ttare(src.path(), &args);
+
+    let part = |index: usize| src.path().join(format!("split.ttare.{index:03}"));
+    let parts = (1..).take_while(|&index| part(index).exists()).count();
+    let sizes: Vec<u64> = (1..=parts)
+        .map(|index| fs::metadata(part(index)).unwrap().len())
+        .collect();
+    assert!(parts > 10, "{sizes:?}");
+    assert!(sizes[..parts - 1].iter().all(|&size| size == 4096));
+    assert!(sizes[parts - 1] < 4096);
+    assert_eq!(
+        sizes.iter().sum::<u64>(),
+        fs::metadata(src.path().join("whole.ttare")).unwrap().len()
+    );
+    assert!(!src.path().join("split.ttare").exists());
+
+    assert_eq!(
+        ttare_stdout(src.path(), &["list", "split.ttare.001"]),
+        ttare_stdout(src.path(), &["list", "whole.ttare"])
+    );
+    assert_eq!(
+        ttare_stdout(src.path(), &["verify", "split.ttare.001"]),
+        "OK\n"
+    );
+
+    let out = TempDir::new().unwrap();
+    ttare(
+        src.path(),
+        &[
+            "decompress",
+            "split.ttare.001",
+            "-o",
+            out.path().to_str().unwrap(),
+        ],
+    );
+    for file in files {
+        assert_eq!(
+            fs::read(out.path().join(file)).unwrap(),
+            fs::read(src.path().join(file)).unwrap()
+        );
+    }
+
+    // Splitting into fewer parts again doesn't leave the old ones behind
+    let mut args = vec![
+        "compress",
+        "--force",
+        "-o",
+        "split.ttare",
+        "--split-size",
+        "1000000",
+    ];
+    args.extend(files);
+    ttare(src.path(), &args);
+    assert!(part(1).exists());
+    assert!(!part(2).exists());
+
+    // Parts missing in the middle or at the end are both noticed
+    let mut args = vec![
+        "compress",
+        "--force",
+        "-o",
+        "split.ttare",
+        "--split-size",
+        "4096",
+    ];
+    args.extend(files);
+    ttare(src.path(), &args);
+    for missing in [2, parts] {
+        let hidden = src.path().join("hidden");
+        fs::rename(part(missing), &hidden).unwrap();
+
+        let output = Command::new(env!("CARGO_BIN_EXE_ttare"))
+            .current_dir(src.path())
+            .args(["decompress", "split.ttare.001", "-o", "out"])
+            .output()
+            .unwrap();
+        assert!(!output.status.success());
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        let expected = format!("split.ttare.{missing:03} is missing");
+        assert!(stderr.contains(&expected), "{stderr}");
+
+        fs::rename(&hidden, part(missing)).unwrap();
+    }
+}
+
+#[test]
+fn list_shows_when_the_archive_was_created() {
+    let src = TempDir::new().unwrap();
+    fs::write(src.path().join("text.txt"), b"created ".repeat(1000)).unwrap();
+    fs::write(src.path().join("noise.bin"), noise(16 * 1024)).unwrap();
+
+    let list = |archive: &str| -> (String, String) {
+        let output = Command::new(env!("CARGO_BIN_EXE_ttare"))
+            .current_dir(src.path())
+            .args(["list", archive])
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        (
+            String::from_utf8(output.stdout).unwrap(),
+            String::from_utf8(output.stderr).unwrap(),
+        )
+    };
+
+    for (mtime, created, file) in [
+        ("1700000000", "2023-11-14 22:13:20 UTC", "text.txt"),
+        ("951782400", "2000-02-29 00:00:00 UTC", "noise.bin"),
+    ] {
+        ttare(
+            src.path(),
+            &[
+                "compress",
+                "--force",
+                "-o",
+                "archive.ttare",
+                "--mtime",
+                mtime,
+                file,
+            ],
+        );
+        let (stdout, stderr) = list("archive.ttare");
+        assert_eq!(stdout.lines().count(), 1);
+        assert_eq!(
+            stderr.lines().next(),
+            Some(format!("archive created at {created}").as_str())
+        );
+    }
+
+    // Archives written by other tools don't say when they were created
+    write_raw_archive(&src.path().join("plain.tar"), &[("a.bin", b"a".to_vec())]);
+    assert_eq!(list("plain.tar").1, "");
+}
+
+#[test]
+fn manifest_tells_which_files_are_corrupt() {
+    let src = TempDir::new().unwrap();
+    fs::write(src.path().join("a.txt"), "first text ".repeat(1000)).unwrap();
+    fs::write(src.path().join("b.txt"), "second text ".repeat(1000)).unwrap();
+    fs::write(src.path().join("x.bin"), noise(16 * 1024)).unwrap();
+    fs::write(src.path().join("y.bin"), noise(16 * 1024)).unwrap();
+
+    let stderr_of = |args: &[&str]| -> String {
+        let output = Command::new(env!("CARGO_BIN_EXE_ttare"))
+            .current_dir(src.path())
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(!output.status.success(), "ttare {:?} succeeded", args);
+        String::from_utf8(output.stderr).unwrap()
+    };
+
+    for (extra, corrupted) in [(None, "x.bin"), (Some("--per-file-compression"), "a.txt")] {
+        let mut args = vec!["compress", "--force", "--manifest", "-o", "archive.ttare"];
+        args.extend(extra);
+        args.extend(["a.txt", "b.txt", "x.bin", "y.bin"]);
+        ttare(src.path(), &args);
+        assert_eq!(
+            ttare_stdout(src.path(), &["verify", "archive.ttare"]),
+            "OK\n"
+        );
+
+        // Flip a byte in the middle of the stored data of one file
+        let mut archive = fs::read(src.path().join("archive.ttare")).unwrap();
+        let offset = tar::Archive::new(archive.as_slice())
+            .entries()
+            .unwrap()
+            .map(|entry| entry.unwrap())
+            .find(|entry| {
+                let path = entry.path().unwrap();
+                path.to_str().unwrap().starts_with(corrupted)
+            })
+            .unwrap()
+            .raw_file_position() as usize;
+        archive[offset + 20] ^= 0xff;
+        fs::write(src.path().join("corrupt.ttare"), archive).unwrap();
+
+        for args in [
+            &["verify", "corrupt.ttare"][..],
+            &["decompress", "corrupt.ttare", "-o", "out"],
+        ] {
+            let stderr = stderr_of(args);
+            assert!(stderr.contains(corrupted), "{args:?}: {stderr}");
+            for intact in ["a.txt", "b.txt", "x.bin", "y.bin"] {
+                assert_eq!(
+                    stderr.contains(intact),
+                    intact == corrupted,
+                    "{args:?}: {stderr}"
+                );
+            }
+        }
+        fs::remove_dir_all(src.path().join("out")).unwrap();
+    }
+}
+
+#[test]
+fn decompress_a_single_file_to_stdout() {
+    let src = TempDir::new().unwrap();
+
+    // Line endings and bytes that aren't UTF-8 have to come out as they went in
+    let mut binary = noise(16 * 1024);
+    binary.extend_from_slice(b"\r\n\n\r\x00\xff");
+    let text = b"to stdout\r\n".repeat(1000);
+    fs::write(src.path().join("binary.bin"), &binary).unwrap();
+    fs::write(src.path().join("text.txt"), &text).unwrap();
+
+    let to_stdout = |args: &[&str]| -> Option<Vec<u8>> {
+        let output = Command::new(env!("CARGO_BIN_EXE_ttare"))
+            .current_dir(src.path())
+            .args(args)
+            .output()
+            .unwrap();
+        output.status.success().then_some(output.stdout)
+    };
+
+    for (file, contents) in [("binary.bin", &binary), ("text.txt", &text)] {
+        ttare(
+            src.path(),
+            &["compress", "--force", "-o", "single.ttare", file],
+        );
+        assert_eq!(
+            to_stdout(&["decompress", "--to-stdout", "single.ttare"]).as_ref(),
+            Some(contents)
+        );
+    }
+
+    // With more than one file, the one to write has to be given
+    ttare(
+        src.path(),
+        &["compress", "-o", "both.ttare", "binary.bin", "text.txt"],
+    );
+    assert_eq!(
+        to_stdout(&["decompress", "--to-stdout", "both.ttare"]),
+        None
+    );
+    for (file, contents) in [("binary.bin", &binary), ("text.txt", &text)] {
+        assert_eq!(
+            to_stdout(&["decompress", "--to-stdout", "both.ttare", file]).as_ref(),
+            Some(contents)
+        );
+    }
+
+    // Nothing is extracted
+    assert_eq!(fs::read_dir(src.path()).unwrap().count(), 4);
+}
+
+#[test]
+fn rules_force_high_entropy_files_to_be_compressed() {
+    let src = TempDir::new().unwrap();
+    fs::write(src.path().join("random.log"), noise(16 * 1024)).unwrap();
+    fs::write(src.path().join("added.log"), noise(8 * 1024)).unwrap();
+    fs::write(src.path().join("noise.bin"), noise(4 * 1024)).unwrap();
+    fs::write(src.path().join("rules"), "*.log = compress\n").unwrap();
+
+    ttare(
+        src.path(),
+        &[
+            "compress",
+            "--rules",
+            "rules",
+            "-o",
+            "archive.ttare",
+            "random.log",
+            "noise.bin",
+        ],
+    );
+
+    // Files appended later follow the rules recorded in the archive
+    ttare(src.path(), &["append", "archive.ttare", "added.log"]);
+    let listing = ttare_stdout(src.path(), &["list", "archive.ttare"]);
+    let mut kinds: Vec<(&str, &str)> = listing
+        .lines()
+        .map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            (fields[2], fields[0])
+        })
+        .collect();
+    kinds.sort();
+    assert_eq!(
+        kinds,
+        [("added.log", "C"), ("noise.bin", "R"), ("random.log", "C")]
+    );
+
+    let out = TempDir::new().unwrap();
+    ttare(
+        src.path(),
+        &[
+            "decompress",
+            "archive.ttare",
+            "-o",
+            out.path().to_str().unwrap(),
+        ],
+    );
+    for name in ["random.log", "added.log", "noise.bin"] {
+        assert_eq!(
+            fs::read(out.path().join(name)).unwrap(),
+            fs::read(src.path().join(name)).unwrap()
+        );
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn preserve_owner_restores_uid_and_gid() {
+    use std::os::unix::fs::{chown, lchown, symlink, MetadataExt, PermissionsExt};
+
+    let src = TempDir::new().unwrap();
+    fs::write(src.path().join("text.txt"), b"owned ".repeat(1000)).unwrap();
+    fs::write(src.path().join("noise.bin"), noise(16 * 1024)).unwrap();
+    symlink("text.txt", src.path().join("link")).unwrap();
+
+    // Only root can give files away, and get them back with their owner
+    let root = fs::metadata(src.path().join("text.txt")).unwrap().uid() == 0;
+    if root {
+        for name in ["text.txt", "noise.bin"] {
+            chown(src.path().join(name), Some(1234), Some(5678)).unwrap();
+        }
+        lchown(src.path().join("link"), Some(1234), Some(5678)).unwrap();
+    }
+    // After giving it away, since that clears the setuid bit
+    fs::set_permissions(
+        src.path().join("noise.bin"),
+        fs::Permissions::from_mode(0o4755),
+    )
+    .unwrap();
+    let owner = |path: &Path| {
+        let metadata = fs::symlink_metadata(path).unwrap();
+        (metadata.uid(), metadata.gid())
+    };
+    let original = owner(&src.path().join("text.txt"));
+
+    for extra in [None, Some("--per-file-compression")] {
+        let mut args = vec!["compress", "--force", "-o", "archive.ttare"];
+        args.extend(extra);
+        args.extend(["text.txt", "noise.bin", "link"]);
+        ttare(src.path(), &args);
+
+        let out = TempDir::new().unwrap();
+        let output = Command::new(env!("CARGO_BIN_EXE_ttare"))
+            .current_dir(src.path())
+            .args(["decompress", "--preserve-owner", "archive.ttare", "-o"])
+            .arg(out.path())
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        let stderr = String::from_utf8(output.stderr).unwrap();
+
+        if root {
+            assert_eq!(stderr, "");
+            for name in ["text.txt", "noise.bin", "link"] {
+                assert_eq!(owner(&out.path().join(name)), original, "{name}");
+            }
+            let mode = fs::metadata(out.path().join("noise.bin")).unwrap().mode();
+            assert_eq!(mode & 0o7777, 0o4755);
+        } else {
+            assert!(stderr.contains("can't restore the owners"), "{stderr}");
+        }
+
+        // The owners are left alone by default
+        let out = TempDir::new().unwrap();
+        ttare(
+            src.path(),
+            &[
+                "decompress",
+                "archive.ttare",
+                "-o",
+                out.path().to_str().unwrap(),
+            ],
+        );
+        assert_eq!(
+            owner(&out.path().join("text.txt")),
+            owner(out.path()),
+            "{extra:?}"
+        );
+    }
+}
+
+#[test]
+fn files_read_once_are_stored_like_the_others() {
+    let src = TempDir::new().unwrap();
+
+    // Around the size up to which files are read into memory by the analysis
+    let limit = ttare::READ_ONCE_FILE_BYTES as usize;
+    let mut names = vec![];
+    for len in [limit - 1, limit, limit + 1] {
+        let text = format!("text{len}.txt");
+        fs::write(src.path().join(&text), b"read once ".repeat(len / 10)).unwrap();
+        let raw = format!("noise{len}.bin");
+        fs::write(src.path().join(&raw), noise(len)).unwrap();
+        names.extend([text, raw]);
+    }
+    fs::copy(src.path().join(&names[0]), src.path().join("copy.txt")).unwrap();
+    names.push("copy.txt".to_string());
+
+    for extra in [None, Some("--per-file-compression")] {
+        let mut args = vec!["compress", "--force", "--dedup", "-o", "archive.ttare"];
+        args.extend(extra);
+        args.extend(names.iter().map(String::as_str));
+        ttare(src.path(), &args);
+
+        let listing = ttare_stdout(src.path(), &["list", "archive.ttare"]);
+        for line in listing.lines() {
+            let expected = if line.ends_with(".txt") { "C " } else { "R " };
+            assert!(line.starts_with(expected), "{line}");
+        }
+
+        let out = TempDir::new().unwrap();
+        ttare(
+            src.path(),
+            &[
+                "decompress",
+                "archive.ttare",
+                "-o",
+                out.path().to_str().unwrap(),
+            ],
+        );
+        for name in &names {
+            assert_eq!(
+                fs::read(out.path().join(name)).unwrap(),
+                fs::read(src.path().join(name)).unwrap(),
+                "{name}"
+            );
+        }
+    }
+}
+
+#[test]
+fn completions_cover_every_command_and_flag() {
+    let dir = TempDir::new().unwrap();
+
+    for shell in ["bash", "zsh", "fish", "powershell"] {
+        let script = ttare_stdout(dir.path(), &["completions", shell]);
+        for word in [
+            "compress",
+            "decompress",
+            "list",
+            "extract",
+            "append",
+            "verify",
+            "compare",
+            "completions",
+            "per-file-compression",
+            "preserve-owner",
+            "verbose",
+        ] {
+            assert!(script.contains(word), "{shell} is missing {word}");
+        }
+    }
+
+    assert!(!run(dir.path(), &["completions", "tcsh"]).success());
+}
+
+#[test]
+fn zstd_dictionary_is_needed_to_decompress() {
+    let src = TempDir::new().unwrap();
+    let out = TempDir::new().unwrap();
+
+    let record = |i: usize| {
+        format!(r#"{{"id": {i}, "name": "record {i}", "kind": "sample", "tags": ["a", "b"]}}"#)
+    };
+    let dictionary: String = (1000..1100).map(record).collect();
+    fs::write(src.path().join("records.dict"), &dictionary).unwrap();
+    fs::write(src.path().join("other.dict"), "another dictionary").unwrap();
+    let names: Vec<String> = (0..20).map(|i| format!("record{i}.json")).collect();
+    for (i, name) in names.iter().enumerate() {
+        fs::write(src.path().join(name), record(i)).unwrap();
+    }
+
+    // The whole archive is padded to tar's records, so the member is what shrinks
+    let compress = |output: &str, dict: &[&str]| -> u64 {
+        let args = [&["compress", "-c", "zstd", "--json", "-o", output], dict].concat();
+        let files: Vec<&str> = names.iter().map(String::as_str).collect();
+        let report = ttare_stdout(src.path(), &[args, files].concat());
+        let summary: serde_json::Value = serde_json::from_str(&report).unwrap();
+        summary["compressed_member_bytes"].as_u64().unwrap()
+    };
+    let plain = compress("plain.ttare", &[]);
+    let primed = compress("primed.ttare", &["--zstd-dict", "records.dict"]);
+    assert!(
+        primed < plain,
+        "{primed} bytes with the dictionary, {plain} without"
+    );
+
+    let decompress = |dict: &[&str]| {
+        let restored = out.path().join("restored");
+        let args = [
+            &[
+                "decompress",
+                "primed.ttare",
+                "-o",
+                restored.to_str().unwrap(),
+            ],
+            dict,
+        ]
+        .concat();
+        Command::new(env!("CARGO_BIN_EXE_ttare"))
+            .current_dir(src.path())
+            .args(args)
+            .output()
+            .unwrap()
+    };
+
+    let missing = decompress(&[]);
+    assert!(!missing.status.success());
+    let stderr = String::from_utf8(missing.stderr).unwrap();
+    assert!(stderr.contains("which is needed to read it"), "{stderr}");
+
+    let wrong = decompress(&["--zstd-dict", "other.dict"]);
+    assert!(!wrong.status.success());
+
+    assert!(decompress(&["--zstd-dict", "records.dict"])
+        .status
+        .success());
+    for (i, name) in names.iter().enumerate() {
+        assert_eq!(
+            fs::read_to_string(out.path().join("restored").join(name)).unwrap(),
+            record(i)
+        );
+    }
+
+    // Only zstd takes a dictionary
+    assert!(!run(
+        src.path(),
+        &[
+            "compress",
+            "-o",
+            "gzip.ttare",
+            "--zstd-dict",
+            "records.dict",
+            "record0.json"
+        ]
+    )
+    .success());
+    assert!(!src.path().join("gzip.ttare").exists());
+}
+
+/// Runs ttare with at most `max_fds` file descriptors, and more threads than that, returning what
+/// it printed to stderr if it failed.
+#[cfg(unix)]
+fn ttare_with_few_fds(dir: &Path, max_fds: u32, args: &[&str]) -> std::result::Result<(), String> {
+    let script = format!("ulimit -n {max_fds} && exec \"$0\" \"$@\"");
+    let output = Command::new("sh")
+        .current_dir(dir)
+        .env("RAYON_NUM_THREADS", "128")
+        .args(["-c", &script, env!("CARGO_BIN_EXE_ttare")])
+        .args(args)
+        .output()
+        .expect("failed to run ttare");
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(String::from_utf8(output.stderr).unwrap())
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn many_files_dont_run_out_of_file_descriptors() {
+    let src = TempDir::new().unwrap();
+    let out = TempDir::new().unwrap();
+
+    let tree = src.path().join("tree");
+    fs::create_dir(&tree).unwrap();
+    for i in 0..2000 {
+        let contents = if i % 2 == 0 {
+            format!("file {i} ").repeat(100).into_bytes()
+        } else {
+            noise(512)
+        };
+        fs::write(tree.join(format!("{i}.bin")), contents).unwrap();
+    }
+
+    for extra in [
+        &[][..],
+        &["--per-file-compression"],
+        &["--max-files-open", "4"],
+    ] {
+        let args = [
+            &["compress", "--force", "-r", "-o", "archive.ttare", "tree"],
+            extra,
+        ]
+        .concat();
+        ttare_with_few_fds(src.path(), 64, &args).unwrap();
+
+        let restored = out.path().join("restored");
+        ttare(
+            src.path(),
+            &[
+                "decompress",
+                "archive.ttare",
+                "-o",
+                restored.to_str().unwrap(),
+            ],
+        );
+        assert_eq!(fs::read_dir(restored.join("tree")).unwrap().count(), 2000);
+        fs::remove_dir_all(&restored).unwrap();
+    }
+
+    // Without the limit, the files compressed together would have too many spools open at once
+    let error = ttare_with_few_fds(
+        src.path(),
+        64,
+        &[
+            "compress",
+            "--force",
+            "-r",
+            "-o",
+            "archive.ttare",
+            "--per-file-compression",
+            "--max-files-open",
+            "1000",
+            "tree",
+        ],
+    )
+    .unwrap_err();
+    assert!(error.contains("Too many open files"), "{error}");
+}
+
+/// The names of the entries listed by `ttare list`, sorted.
+fn listed_names(dir: &Path, archive: &str) -> Vec<String> {
+    let mut names: Vec<String> = ttare_stdout(dir, &["list", archive])
+        .lines()
+        .map(|line| line.split_whitespace().last().unwrap().to_owned())
+        .collect();
+    names.sort();
+    names
+}
+
+#[test]
+fn entries_are_stored_relative_to_the_base_dir() {
+    let src = TempDir::new().unwrap();
+    let out = TempDir::new().unwrap();
+
+    fs::create_dir_all(src.path().join("project/src")).unwrap();
+    fs::write(
+        src.path().join("project/src/main.txt"),
+        b"main ".repeat(500),
+    )
+    .unwrap();
+    fs::write(src.path().join("project/noise.bin"), noise(4096)).unwrap();
+    fs::write(src.path().join("outside.txt"), b"outside ".repeat(500)).unwrap();
+
+    let project = src.path().join("project");
+    let text = project.join("src/main.txt");
+    let text = text.to_str().unwrap();
+    let noise_bin = project.join("noise.bin");
+    let noise_bin = noise_bin.to_str().unwrap();
+
+    // Absolute paths lose their leading `/`, so they are extracted in the output directory
+    ttare(
+        src.path(),
+        &["compress", "-o", "absolute.ttare", text, noise_bin],
+    );
+    let stripped = |path: &str| path.trim_start_matches('/').to_owned();
+    assert_eq!(
+        listed_names(src.path(), "absolute.ttare"),
+        vec![stripped(noise_bin), stripped(text)]
+    );
+    ttare(
+        src.path(),
+        &[
+            "decompress",
+            "absolute.ttare",
+            "-o",
+            out.path().to_str().unwrap(),
+        ],
+    );
+    assert_eq!(
+        fs::read(out.path().join(stripped(text))).unwrap(),
+        b"main ".repeat(500)
+    );
+
+    // Relative and absolute paths are both named relative to the base directory
+    for base_dir in ["project", project.to_str().unwrap()] {
+        ttare(
+            src.path(),
+            &[
+                "compress",
+                "--force",
+                "-o",
+                "based.ttare",
+                "-C",
+                base_dir,
+                "project/src/main.txt",
+                noise_bin,
+            ],
+        );
+        assert_eq!(
+            listed_names(src.path(), "based.ttare"),
+            vec!["noise.bin", "src/main.txt"],
+            "{base_dir}"
+        );
+    }
+
+    let based = TempDir::new().unwrap();
+    ttare(
+        src.path(),
+        &[
+            "decompress",
+            "based.ttare",
+            "-o",
+            based.path().to_str().unwrap(),
+        ],
+    );
+    assert_eq!(
+        fs::read(based.path().join("src/main.txt")).unwrap(),
+        b"main ".repeat(500)
+    );
+    assert_eq!(
+        fs::read(based.path().join("noise.bin")).unwrap(),
+        noise(4096)
+    );
+
+    // The base directory itself isn't stored, only what is in it
+    ttare(
+        src.path(),
+        &[
+            "compress",
+            "-o",
+            "tree.ttare",
+            "--base-dir",
+            "project",
+            "--recursive",
+            "project",
+        ],
+    );
+    assert_eq!(
+        listed_names(src.path(), "tree.ttare"),
+        vec!["noise.bin", "src/main.txt"]
+    );
+
+    // Files outside of the base directory can't be named relative to it
+    assert!(!run(
+        src.path(),
+        &[
+            "compress",
+            "-o",
+            "outside.ttare",
+            "-C",
+            "project",
+            "outside.txt",
+        ],
+    )
+    .success());
+}
+
+#[test]
+fn throttle_caps_the_bytes_read_and_written() {
+    use std::time::{Duration, Instant};
+
+    let src = TempDir::new().unwrap();
+    let out = TempDir::new().unwrap();
+
+    // Stored raw, so about as many bytes are written as are read
+    let contents = noise(1_000_000);
+    fs::write(src.path().join("noise.bin"), &contents).unwrap();
+
+    let start = Instant::now();
+    ttare(
+        src.path(),
+        &[
+            "compress",
+            "-o",
+            "archive.ttare",
+            "--throttle-mbps",
+            "1",
+            "noise.bin",
+        ],
+    );
+    let elapsed = start.elapsed();
+
+    // 2 MB at 1 MB per second, leaving room for what the bucket lets through at once
+    assert!(elapsed >= Duration::from_millis(1800), "{elapsed:?}");
+
+    ttare(
+        src.path(),
+        &[
+            "decompress",
+            "archive.ttare",
+            "-o",
+            out.path().to_str().unwrap(),
+        ],
+    );
+    assert_eq!(fs::read(out.path().join("noise.bin")).unwrap(), contents);
+
+    assert!(!run(
+        src.path(),
+        &[
+            "compress",
+            "-o",
+            "other.ttare",
+            "--throttle-mbps",
+            "0",
+            "noise.bin",
+        ],
+    )
+    .success());
+}
+
+#[test]
+fn compare_reports_the_files_that_differ() {
+    let src = TempDir::new().unwrap();
+
+    fs::create_dir(src.path().join("tree")).unwrap();
+    fs::write(src.path().join("tree/kept.txt"), b"kept ".repeat(500)).unwrap();
+    fs::write(src.path().join("tree/changed.txt"), b"before ".repeat(500)).unwrap();
+    fs::write(src.path().join("tree/removed.bin"), noise(4096)).unwrap();
+    fs::write(src.path().join("tree/copy.txt"), b"kept ".repeat(500)).unwrap();
+
+    ttare(
+        src.path(),
+        &["compress", "-o", "first.ttare", "--recursive", "tree"],
+    );
+
+    // Another codec, deduplication and another time don't change the files
+    ttare(
+        src.path(),
+        &[
+            "compress",
+            "-o",
+            "same.ttare",
+            "--codec",
+            "zstd",
+            "--dedup",
+            "--mtime",
+            "1234567890",
+            "--per-file-compression",
+            "--recursive",
+            "tree",
+        ],
+    );
+    let compare = |second: &str| {
+        let output = Command::new(env!("CARGO_BIN_EXE_ttare"))
+            .current_dir(src.path())
+            .args(["compare", "first.ttare", second])
+            .output()
+            .expect("failed to run ttare");
+        (
+            output.status.code(),
+            String::from_utf8(output.stdout).unwrap(),
+        )
+    };
+    assert_eq!(compare("same.ttare"), (Some(0), String::new()));
+
+    fs::write(src.path().join("tree/changed.txt"), b"after ".repeat(500)).unwrap();
+    fs::remove_file(src.path().join("tree/removed.bin")).unwrap();
+    fs::write(src.path().join("tree/added.bin"), noise(2048)).unwrap();
+    ttare(
+        src.path(),
+        &["compress", "-o", "second.ttare", "--recursive", "tree"],
+    );
+
+    assert_eq!(
+        compare("second.ttare"),
+        (
+            Some(1),
+            "A tree/added.bin\nM tree/changed.txt\nD tree/removed.bin\n".to_string()
+        )
+    );
+}
+
+#[test]
+fn any_io_buffer_size_round_trips() {
+    let src = TempDir::new().unwrap();
+
+    let text = b"buffered ".repeat(50_000);
+    let raw = noise(3 * 1024 * 1024);
+    fs::write(src.path().join("text.txt"), &text).unwrap();
+    fs::write(src.path().join("noise.bin"), &raw).unwrap();
+
+    for size in ["0", "1", "4096", "1048576"] {
+        for per_file in [false, true] {
+            let out = TempDir::new().unwrap();
+            let mut args = vec![
+                "compress",
+                "--force",
+                "-o",
+                "archive.ttare",
+                "--io-buffer-size",
+                size,
+                "text.txt",
+                "noise.bin",
+            ];
+            if per_file {
+                args.push("--per-file-compression");
+            }
+            ttare(src.path(), &args);
+            ttare(
+                src.path(),
+                &[
+                    "decompress",
+                    "archive.ttare",
+                    "-o",
+                    out.path().to_str().unwrap(),
+                ],
+            );
+
+            assert_eq!(
+                fs::read(out.path().join("text.txt")).unwrap(),
+                text,
+                "{size}"
+            );
+            assert_eq!(
+                fs::read(out.path().join("noise.bin")).unwrap(),
+                raw,
+                "{size}"
+            );
+        }
+    }
+}
+
+#[test]
+fn existing_files_are_only_replaced_when_asked_to() {
+    let src = TempDir::new().unwrap();
+    let out = TempDir::new().unwrap();
+    let out_dir = out.path().to_str().unwrap();
+
+    fs::create_dir(src.path().join("dir")).unwrap();
+    fs::write(src.path().join("dir/text.txt"), b"archived ".repeat(500)).unwrap();
+    fs::write(src.path().join("noise.bin"), noise(4096)).unwrap();
+    fs::write(src.path().join("archive.ttare"), b"precious").unwrap();
+
+    let compress = ["compress", "-o", "archive.ttare", "-r", "dir", "noise.bin"];
+    assert!(!run(src.path(), &compress).success());
+    assert_eq!(
+        fs::read(src.path().join("archive.ttare")).unwrap(),
+        b"precious"
+    );
+    ttare(src.path(), &[&compress[..], &["-f"]].concat());
+    ttare(src.path(), &["verify", "archive.ttare"]);
+
+    // The first part of a split archive is checked the same way
+    fs::write(src.path().join("split.ttare.001"), b"precious").unwrap();
+    let split = [
+        "compress",
+        "-o",
+        "split.ttare",
+        "--split-size",
+        "4096",
+        "noise.bin",
+    ];
+    assert!(!run(src.path(), &split).success());
+    assert_eq!(
+        fs::read(src.path().join("split.ttare.001")).unwrap(),
+        b"precious"
+    );
+    ttare(src.path(), &[&split[..], &["--force"]].concat());
+    ttare(src.path(), &["verify", "split.ttare.001"]);
+
+    // Directories that are already there are extracted into, but files aren't replaced
+    let decompress = ["decompress", "archive.ttare", "-o", out_dir];
+    fs::create_dir(out.path().join("dir")).unwrap();
+    ttare(src.path(), &decompress);
+    assert_eq!(
+        fs::read(out.path().join("dir/text.txt")).unwrap(),
+        b"archived ".repeat(500)
+    );
+
+    for name in ["dir/text.txt", "noise.bin"] {
+        fs::write(out.path().join(name), b"edited").unwrap();
+        assert!(!run(src.path(), &decompress).success(), "{name}");
+        assert_eq!(fs::read(out.path().join(name)).unwrap(), b"edited");
+    }
+
+    ttare(src.path(), &[&decompress[..], &["--overwrite"]].concat());
+    assert_eq!(
+        fs::read(out.path().join("dir/text.txt")).unwrap(),
+        b"archived ".repeat(500)
+    );
+    assert_eq!(fs::read(out.path().join("noise.bin")).unwrap(), noise(4096));
+}
+
+#[test]
+fn histogram_prints_the_counts_of_each_byte() {
+    let src = TempDir::new().unwrap();
+    fs::write(src.path().join("text.txt"), b"aab".repeat(1000)).unwrap();
+
+    let csv = ttare_stdout(src.path(), &["histogram", "text.txt"]);
+    let lines: Vec<&str> = csv.lines().collect();
+    assert_eq!(lines.len(), 257);
+    assert_eq!(lines[0], "byte,count");
+    assert_eq!(lines[1 + b'a' as usize], "97,2000");
+    assert_eq!(lines[1 + b'b' as usize], "98,1000");
+
+    ttare(
+        src.path(),
+        &["histogram", "--full-entropy", "text.txt", "-o", "text.csv"],
+    );
+    assert_eq!(
+        fs::read_to_string(src.path().join("text.csv")).unwrap(),
+        csv
+    );
+}
+
+#[test]
+fn plain_targz_is_a_tar_gz_when_every_file_is_compressible() {
+    let src = TempDir::new().unwrap();
+    let out = TempDir::new().unwrap();
+
+    let first = b"compressible ".repeat(1000);
+    let second = b"also compressible ".repeat(1000);
+    fs::create_dir(src.path().join("dir")).unwrap();
+    fs::write(src.path().join("dir/first.txt"), &first).unwrap();
+    fs::write(src.path().join("second.txt"), &second).unwrap();
+
+    ttare(
+        src.path(),
+        &[
+            "compress",
+            "--plain-targz",
+            "-r",
+            "-o",
+            "archive.tar.gz",
+            "dir",
+            "second.txt",
+        ],
+    );
+
+    // tar reads it without knowing about ttare
+    let archive = fs::File::open(src.path().join("archive.tar.gz")).unwrap();
+    let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(archive));
+    let names: Vec<String> = archive
+        .entries()
+        .unwrap()
+        .map(|entry| entry.unwrap().path().unwrap().display().to_string())
+        .collect();
+    assert_eq!(names, vec!["dir", "dir/first.txt", "second.txt"]);
+
+    ttare(src.path(), &["verify", "archive.tar.gz"]);
+    ttare(
+        src.path(),
+        &[
+            "decompress",
+            "archive.tar.gz",
+            "-o",
+            out.path().to_str().unwrap(),
+        ],
+    );
+    assert_eq!(fs::read(out.path().join("dir/first.txt")).unwrap(), first);
+    assert_eq!(fs::read(out.path().join("second.txt")).unwrap(), second);
+}
+
+#[test]
+fn plain_targz_only_uses_the_ttare_layout_for_mixed_files() {
+    let src = TempDir::new().unwrap();
+    let out = TempDir::new().unwrap();
+
+    let text = b"compressible ".repeat(1000);
+    let raw = noise(64 * 1024);
+    fs::write(src.path().join("text.txt"), &text).unwrap();
+    fs::write(src.path().join("noise.bin"), &raw).unwrap();
+
+    // Nothing to compress makes a tar that isn't compressed at all
+    ttare(
+        src.path(),
+        &["compress", "--plain-targz", "-o", "raw.tar", "noise.bin"],
+    );
+    assert_eq!(root_entries(&src.path().join("raw.tar")), vec!["noise.bin"]);
+
+    ttare(
+        src.path(),
+        &[
+            "compress",
+            "--plain-targz",
+            "-o",
+            "mixed.ttare",
+            "text.txt",
+            "noise.bin",
+        ],
+    );
+    assert_eq!(
+        root_entries(&src.path().join("mixed.ttare")),
+        vec![".ttare.meta", "noise.bin", ".ttare.crc32", ".ttare.tar.gz"]
+    );
+
+    for archive in ["raw.tar", "mixed.ttare"] {
+        let restored = out.path().join(archive);
+        ttare(
+            src.path(),
+            &["decompress", archive, "-o", restored.to_str().unwrap()],
+        );
+        assert_eq!(fs::read(restored.join("noise.bin")).unwrap(), raw);
+    }
+    assert_eq!(
+        fs::read(out.path().join("mixed.ttare/text.txt")).unwrap(),
+        text
+    );
+
+    // A plain tar.gz has no room for what ttare adds
+    assert!(!run(
+        src.path(),
+        &[
+            "compress",
+            "--plain-targz",
+            "--codec",
+            "zstd",
+            "-o",
+            "zstd.tar",
+            "text.txt"
+        ],
+    )
+    .success());
+    assert!(!src.path().join("zstd.tar").exists());
+}
+
+#[test]
+fn appending_to_a_plain_targz_fails_without_touching_it() {
+    let src = TempDir::new().unwrap();
+    fs::write(src.path().join("first.txt"), b"compressible ".repeat(700)).unwrap();
+    fs::write(
+        src.path().join("second.txt"),
+        b"also compressible ".repeat(500),
+    )
+    .unwrap();
+    fs::write(
+        src.path().join("third.txt"),
+        b"compressible too ".repeat(500),
+    )
+    .unwrap();
+    ttare(
+        src.path(),
+        &[
+            "compress",
+            "--plain-targz",
+            "-o",
+            "archive.tar.gz",
+            "first.txt",
+            "second.txt",
+        ],
+    );
+    let before = fs::read(src.path().join("archive.tar.gz")).unwrap();
+
+    // Its files are compressed, along with the whole of it
+    let listing = ttare_stdout(src.path(), &["list", "archive.tar.gz"]);
+    assert!(
+        listing.lines().all(|line| line.starts_with('C')),
+        "{listing}"
+    );
+
+    let status = run(src.path(), &["append", "archive.tar.gz", "third.txt"]);
+    assert_eq!(status.code(), Some(2));
+    assert_eq!(fs::read(src.path().join("archive.tar.gz")).unwrap(), before);
+}
+
+#[test]
+fn appending_to_a_split_archive_fails_without_touching_it() {
+    let src = TempDir::new().unwrap();
+    fs::write(src.path().join("noise.bin"), noise(64 * 1024)).unwrap();
+    fs::write(src.path().join("text.txt"), b"appended text ".repeat(100)).unwrap();
+    ttare(
+        src.path(),
+        &[
+            "compress",
+            "--split-size",
+            "20000",
+            "-o",
+            "split.ttare",
+            "noise.bin",
+        ],
+    );
+    let parts = || {
+        (1..=4)
+            .map(|i| fs::read(src.path().join(format!("split.ttare.{i:03}"))).unwrap())
+            .collect::<Vec<_>>()
+    };
+    let before = parts();
+
+    // Either the path it was split from or its first part
+    for archive in ["split.ttare", "split.ttare.001"] {
+        let status = run(src.path(), &["append", archive, "text.txt"]);
+        assert_eq!(status.code(), Some(2), "{archive}");
+        assert_eq!(parts(), before);
+        assert!(!src.path().join("split.ttare").exists());
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn interrupting_compress_leaves_no_archive_behind() {
+    use std::{
+        thread,
+        time::{Duration, Instant},
+    };
+
+    let src = TempDir::new().unwrap();
+    fs::write(src.path().join("noise.bin"), noise(1_000_000)).unwrap();
+
+    // A finished archive is renamed from its partial path
+    ttare(src.path(), &["compress", "-o", "done.ttare", "noise.bin"]);
+    assert!(src.path().join("done.ttare").exists());
+    assert!(!src.path().join("done.ttare.partial").exists());
+
+    // Throttled, so that it's still writing when it's interrupted
+    let mut child = Command::new(env!("CARGO_BIN_EXE_ttare"))
+        .current_dir(src.path())
+        .args([
+            "compress",
+            "-o",
+            "archive.ttare",
+            "--throttle-mbps",
+            "0.2",
+            "noise.bin",
+        ])
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    let partial = src.path().join("archive.ttare.partial");
+    let start = Instant::now();
+    while !partial.exists() {
+        assert!(
+            start.elapsed() < Duration::from_secs(10),
+            "no partial archive"
+        );
+        thread::sleep(Duration::from_millis(10));
+    }
+    thread::sleep(Duration::from_millis(200));
+
+    // SAFETY: the child is still running, since it takes seconds to write the archive
+    assert_eq!(unsafe { libc::kill(child.id() as i32, libc::SIGINT) }, 0);
+    let status = child.wait().unwrap();
+
+    assert_eq!(status.code(), Some(130));
+    assert!(!partial.exists());
+    assert!(!src.path().join("archive.ttare").exists());
+}
+
+/// Runs ttare with the environment variable `var` set to `value`.
+fn ttare_with_env(dir: &Path, var: &str, value: &str, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_ttare"))
+        .current_dir(dir)
+        .env(var, value)
+        .args(args)
+        .output()
+        .expect("failed to run ttare")
+}
+
+#[test]
+fn environment_variables_set_defaults_that_flags_override() {
+    let src = TempDir::new().unwrap();
+    fs::write(src.path().join("text.txt"), b"some text ".repeat(200)).unwrap();
+    let archive = src.path().join("archive.ttare");
+    let compress = ["compress", "--force", "-o", "archive.ttare", "text.txt"];
+
+    // Nothing is below a threshold of 1 bit per byte, so the text is stored as-is
+    let output = ttare_with_env(src.path(), "TTARE_ENTROPY_THRESHOLD", "1", &compress);
+    assert!(output.status.success());
+    assert!(root_entries(&archive).contains(&"text.txt".to_string()));
+
+    let args = [&compress[..], &["--entropy-threshold", "6.5"]].concat();
+    let output = ttare_with_env(src.path(), "TTARE_ENTROPY_THRESHOLD", "1", &args);
+    assert!(output.status.success());
+    assert!(!root_entries(&archive).contains(&"text.txt".to_string()));
+
+    // The whole file is read anyway
+    let args = [&compress[..], &["--full-entropy"]].concat();
+    let output = ttare_with_env(src.path(), "TTARE_SAMPLE_PERCENTAGE", "0.1", &args);
+    assert!(output.status.success());
+
+    for (var, value) in [
+        ("TTARE_ENTROPY_THRESHOLD", "high"),
+        ("TTARE_ENTROPY_THRESHOLD", "NaN"),
+        ("TTARE_SAMPLE_PERCENTAGE", "-0.5"),
+    ] {
+        let output = ttare_with_env(src.path(), var, value, &compress);
+        assert!(!output.status.success(), "{var}={value}");
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(stderr.contains(&format!("in ${var}")), "{stderr}");
+    }
+
+    // A valid flag doesn't read the variable
+    let args = [&compress[..], &["--sample-percentage", "0.5"]].concat();
+    let output = ttare_with_env(src.path(), "TTARE_SAMPLE_PERCENTAGE", "-0.5", &args);
+    assert!(output.status.success());
+}
+
+#[test]
+fn no_compress_only_tars() {
+    let src = TempDir::new().unwrap();
+    let out = TempDir::new().unwrap();
+
+    let text = b"compressible ".repeat(1000);
+    let raw = noise(16 * 1024);
+    fs::create_dir(src.path().join("tree")).unwrap();
+    fs::write(src.path().join("tree/text.txt"), &text).unwrap();
+    fs::write(src.path().join("tree/small.txt"), b"small").unwrap();
+    fs::write(src.path().join("noise.bin"), &raw).unwrap();
+
+    ttare(
+        src.path(),
+        &[
+            "compress",
+            "--no-compress",
+            "-r",
+            "-o",
+            "archive.ttare",
+            "tree",
+            "noise.bin",
+        ],
+    );
+
+    // Files that would otherwise be compressed, even the small ones, are stored as they are
+    let mut entries = root_entries(&src.path().join("archive.ttare"));
+    entries[2..].sort();
+    assert_eq!(
+        entries,
+        [
+            ".ttare.meta",
+            "tree",
+            "noise.bin",
+            "tree/small.txt",
+            "tree/text.txt"
+        ]
+    );
+
+    // Appending sticks to how the archive was written
+    fs::write(src.path().join("later.txt"), &text).unwrap();
+    ttare(src.path(), &["append", "archive.ttare", "later.txt"]);
+    let entries = root_entries(&src.path().join("archive.ttare"));
+    assert!(entries.contains(&"later.txt".to_string()));
+    assert!(!entries.iter().any(|entry| entry.starts_with(".ttare.tar")));
+
+    ttare(
+        src.path(),
+        &[
+            "decompress",
+            "archive.ttare",
+            "-o",
+            out.path().to_str().unwrap(),
+        ],
+    );
+    assert_eq!(fs::read(out.path().join("tree/text.txt")).unwrap(), text);
+    assert_eq!(
+        fs::read(out.path().join("tree/small.txt")).unwrap(),
+        b"small"
+    );
+    assert_eq!(fs::read(out.path().join("noise.bin")).unwrap(), raw);
+    assert_eq!(fs::read(out.path().join("later.txt")).unwrap(), text);
+}
+
+#[test]
+fn solid_and_per_file_archives_round_trip() {
+    let src = TempDir::new().unwrap();
+
+    let first = b"the first text ".repeat(1000);
+    let second = b"the second text ".repeat(1000);
+    let raw = noise(16 * 1024);
+    fs::write(src.path().join("first.txt"), &first).unwrap();
+    fs::write(src.path().join("second.txt"), &second).unwrap();
+    fs::write(src.path().join("noise.bin"), &raw).unwrap();
+
+    for (mode, root, said) in [
+        (
+            "--solid",
+            &[".ttare.meta", "noise.bin", ".ttare.crc32", ".ttare.tar.gz"][..],
+            "solid: the compressible files are compressed together",
+        ),
+        (
+            "--no-solid",
+            &[".ttare.meta", "first.txt.gz", "second.txt.gz", "noise.bin"][..],
+            "not solid: each compressible file is compressed on its own",
+        ),
+        // The last one given wins
+        (
+            "--per-file-compression --solid",
+            &[".ttare.meta", "noise.bin", ".ttare.crc32", ".ttare.tar.gz"][..],
+            "solid: the compressible files are compressed together",
+        ),
+    ] {
+        let args = [
+            &["compress", "-f", "-o", "archive.ttare"][..],
+            &mode.split(' ').collect::<Vec<_>>(),
+            &["first.txt", "second.txt", "noise.bin"],
+        ]
+        .concat();
+        ttare(src.path(), &args);
+        assert_eq!(
+            root_entries(&src.path().join("archive.ttare")),
+            root,
+            "{mode}"
+        );
+
+        // The archive says which it is
+        let output = Command::new(env!("CARGO_BIN_EXE_ttare"))
+            .current_dir(src.path())
+            .args(["list", "archive.ttare"])
+            .output()
+            .expect("failed to run ttare");
+        assert!(output.status.success());
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(stderr.lines().any(|line| line == said), "{mode}: {stderr}");
+
+        let out = TempDir::new().unwrap();
+        ttare(
+            src.path(),
+            &[
+                "decompress",
+                "archive.ttare",
+                "-o",
+                out.path().to_str().unwrap(),
+            ],
+        );
+        assert_eq!(fs::read(out.path().join("first.txt")).unwrap(), first);
+        assert_eq!(fs::read(out.path().join("second.txt")).unwrap(), second);
+        assert_eq!(fs::read(out.path().join("noise.bin")).unwrap(), raw);
+    }
+}
+
+#[test]
+fn exit_codes_tell_failures_apart() {
+    let src = TempDir::new().unwrap();
+    fs::write(src.path().join("text.txt"), b"exit codes ".repeat(500)).unwrap();
+    let code = |args: &[&str]| run(src.path(), args).code();
+
+    assert_eq!(code(&["compress", "--no-such-flag"]), Some(2));
+    assert_eq!(
+        code(&["compress", "-o", "a.ttare", "-c", "gzip", "-l", "10", "text.txt"]),
+        Some(2)
+    );
+    assert_eq!(
+        code(&["compress", "-o", "-", "--json", "text.txt"]),
+        Some(2)
+    );
+
+    assert_eq!(code(&["compress", "-o", "a.ttare", "missing.txt"]), Some(3));
+    assert_eq!(code(&["list", "missing.ttare"]), Some(3));
+
+    // The noise is stored as it is, so changing a byte of it breaks its checksum in the manifest
+    let stored = noise(4096);
+    fs::write(src.path().join("noise.bin"), &stored).unwrap();
+    ttare(
+        src.path(),
+        &["compress", "-o", "archive.ttare", "--manifest", "noise.bin"],
+    );
+    let mut archive = fs::read(src.path().join("archive.ttare")).unwrap();
+    let start = archive
+        .windows(64)
+        .position(|window| window == &stored[..64])
+        .unwrap();
+    archive[start + 100] ^= 0xff;
+    fs::write(src.path().join("corrupt.ttare"), archive).unwrap();
+    assert_eq!(code(&["verify", "corrupt.ttare"]), Some(4));
+
+    assert_eq!(
+        code(&[
+            "compress",
+            "-o",
+            "partial.ttare",
+            "--skip-errors",
+            "text.txt",
+            "missing.txt"
+        ]),
+        Some(5)
+    );
+    assert!(src.path().join("partial.ttare").exists());
+}
+
+#[test]
+fn strip_components_drops_leading_directories() {
+    let src = TempDir::new().unwrap();
+    fs::create_dir_all(src.path().join("top/sub")).unwrap();
+    let text = b"stripped text ".repeat(500);
+    let stored = noise(4096);
+    fs::write(src.path().join("top/text.txt"), &text).unwrap();
+    fs::write(src.path().join("top/sub/noise.bin"), &stored).unwrap();
+    fs::write(src.path().join("shallow.txt"), &text).unwrap();
+
+    for extra in [&[][..], &["--per-file-compression"], &["--manifest"]] {
+        let mut args = vec![
+            "compress",
+            "-o",
+            "archive.ttare",
+            "--force",
+            "--recursive",
+            "top",
+            "shallow.txt",
+        ];
+        args.extend_from_slice(extra);
+        ttare(src.path(), &args);
+
+        let out = TempDir::new().unwrap();
+        let out_dir = out.path().to_str().unwrap();
+        ttare(
+            src.path(),
+            &[
+                "decompress",
+                "archive.ttare",
+                "-o",
+                out_dir,
+                "--strip-components",
+                "0",
+            ],
+        );
+        assert_eq!(fs::read(out.path().join("top/text.txt")).unwrap(), text);
+        assert_eq!(
+            fs::read(out.path().join("top/sub/noise.bin")).unwrap(),
+            stored
+        );
+        assert_eq!(fs::read(out.path().join("shallow.txt")).unwrap(), text);
+
+        let out = TempDir::new().unwrap();
+        let out_dir = out.path().to_str().unwrap();
+        ttare(
+            src.path(),
+            &[
+                "decompress",
+                "archive.ttare",
+                "-o",
+                out_dir,
+                "--strip-components",
+                "1",
+            ],
+        );
+        assert_eq!(fs::read(out.path().join("text.txt")).unwrap(), text);
+        assert_eq!(fs::read(out.path().join("sub/noise.bin")).unwrap(), stored);
+        // It has a single component, which leaves nothing once it's stripped
+        assert!(!out.path().join("shallow.txt").exists());
+        assert!(!out.path().join("top").exists());
+        let mut names: Vec<_> = fs::read_dir(out.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["sub", "text.txt"], "{:?}", extra);
+    }
+}
+
+#[test]
+fn ttare_archives_are_only_archived_again_when_allowed() {
+    let src = TempDir::new().unwrap();
+    fs::write(src.path().join("text.txt"), b"nested text ".repeat(500)).unwrap();
+    ttare(src.path(), &["compress", "-o", "inner.ttare", "text.txt"]);
+    // Only the name tells it's a ttare archive, but it's the contents that are checked
+    fs::copy(
+        src.path().join("inner.ttare"),
+        src.path().join("renamed.bin"),
+    )
+    .unwrap();
+
+    for input in ["inner.ttare", "renamed.bin"] {
+        let output = Command::new(env!("CARGO_BIN_EXE_ttare"))
+            .current_dir(src.path())
+            .args(["compress", "-o", "outer.ttare", input])
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(2));
+        assert!(String::from_utf8_lossy(&output.stderr).contains("--allow-nested"));
+        assert!(!src.path().join("outer.ttare").exists());
+    }
+
+    ttare(
+        src.path(),
+        &[
+            "compress",
+            "-o",
+            "outer.ttare",
+            "--allow-nested",
+            "inner.ttare",
+        ],
+    );
+    let out = TempDir::new().unwrap();
+    ttare(
+        src.path(),
+        &[
+            "decompress",
+            "outer.ttare",
+            "-o",
+            out.path().to_str().unwrap(),
+        ],
+    );
+    assert_eq!(
+        fs::read(out.path().join("inner.ttare")).unwrap(),
+        fs::read(src.path().join("inner.ttare")).unwrap()
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn hard_links_survive_the_round_trip() {
+    use std::os::unix::fs::MetadataExt;
+
+    let src = TempDir::new().unwrap();
+    fs::create_dir_all(src.path().join("tree/sub")).unwrap();
+    let text = b"linked text ".repeat(500);
+    let stored = noise(64 * 1024);
+    fs::write(src.path().join("tree/text.txt"), &text).unwrap();
+    fs::hard_link(
+        src.path().join("tree/text.txt"),
+        src.path().join("tree/sub/text.txt"),
+    )
+    .unwrap();
+    fs::write(src.path().join("tree/noise.bin"), &stored).unwrap();
+    fs::hard_link(
+        src.path().join("tree/noise.bin"),
+        src.path().join("tree/sub/noise.bin"),
+    )
+    .unwrap();
+
+    ttare(
+        src.path(),
+        &[
+            "compress",
+            "-o",
+            "dereferenced.ttare",
+            "--hard-dereference",
+            "--recursive",
+            "tree",
+        ],
+    );
+    for extra in [&[][..], &["--per-file-compression"]] {
+        let mut args = vec![
+            "compress",
+            "-o",
+            "archive.ttare",
+            "--force",
+            "--recursive",
+            "tree",
+        ];
+        args.extend_from_slice(extra);
+        ttare(src.path(), &args);
+
+        // The noise is only stored once
+        let size = |name: &str| fs::metadata(src.path().join(name)).unwrap().len();
+        assert!(
+            size("archive.ttare") + stored.len() as u64 / 2 < size("dereferenced.ttare"),
+            "{:?}",
+            extra
+        );
+        assert_eq!(
+            ttare_stdout(src.path(), &["list", "archive.ttare"])
+                .lines()
+                .count(),
+            4
+        );
+
+        let out = TempDir::new().unwrap();
+        ttare(
+            src.path(),
+            &[
+                "decompress",
+                "archive.ttare",
+                "-o",
+                out.path().to_str().unwrap(),
+            ],
+        );
+        for (name, contents) in [("text.txt", &text), ("noise.bin", &stored)] {
+            let first = out.path().join("tree").join(name);
+            let second = out.path().join("tree/sub").join(name);
+            assert_eq!(&fs::read(&first).unwrap(), contents);
+            assert_eq!(&fs::read(&second).unwrap(), contents);
+            let (first, second) = (fs::metadata(first).unwrap(), fs::metadata(second).unwrap());
+            assert_eq!(first.ino(), second.ino(), "{} {:?}", name, extra);
+            assert_eq!(first.nlink(), 2);
+        }
+
+        assert_eq!(
+            ttare_stdout(
+                src.path(),
+                &["compare", "archive.ttare", "dereferenced.ttare"]
+            ),
+            ""
+        );
+    }
+}
+
+#[test]
+fn one_job_works_through_the_files_in_order() {
+    let src = TempDir::new().unwrap();
+    fs::create_dir(src.path().join("tree")).unwrap();
+    for i in 0..20 {
+        let text = format!("file {} ", i).repeat(100 * i + 1);
+        fs::write(src.path().join(format!("tree/{:02}.txt", i)), text).unwrap();
+        fs::write(
+            src.path().join(format!("tree/{:02}.bin", i)),
+            noise(1024 * i),
+        )
+        .unwrap();
+    }
+
+    // What is logged while compressing, and the archive
+    let compress = |jobs: &str, name: &str| {
+        let output = Command::new(env!("CARGO_BIN_EXE_ttare"))
+            .current_dir(src.path())
+            .args([
+                "-vv",
+                "-j",
+                jobs,
+                "compress",
+                "-o",
+                name,
+                "--mtime",
+                "1234567890",
+                "--per-file-compression",
+                "--recursive",
+                "tree",
+            ])
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "-j {}", jobs);
+        (
+            String::from_utf8(output.stderr).unwrap(),
+            fs::read(src.path().join(name)).unwrap(),
+        )
+    };
+
+    let (log, archive) = compress("1", "first.ttare");
+    assert_eq!(compress("1", "second.ttare"), (log, archive.clone()));
+    assert_eq!(compress("0", "all.ttare").1, archive);
+    assert!(!run(src.path(), &["-j", "-1", "list", "first.ttare"]).success());
+}
+
+#[test]
+fn best_effort_salvages_what_a_damaged_member_still_holds() {
+    let src = TempDir::new().unwrap();
+    fs::create_dir(src.path().join("tree")).unwrap();
+    let stored = noise(8192);
+    fs::write(src.path().join("tree/noise.bin"), &stored).unwrap();
+    // Text that doesn't compress too well, so that each file takes up its own part of the member
+    let texts: Vec<Vec<u8>> = noise(6 * 20_000)
+        .chunks(20_000)
+        .map(|chunk| {
+            chunk
+                .iter()
+                .map(|byte| b"abcdefgh"[(byte % 8) as usize])
+                .collect()
+        })
+        .collect();
+    for (i, text) in texts.iter().enumerate() {
+        fs::write(src.path().join(format!("tree/{}.txt", i)), text).unwrap();
+    }
+    ttare(
+        src.path(),
+        &[
+            "compress",
+            "-o",
+            "archive.ttare",
+            "--manifest",
+            "--recursive",
+            "tree",
+        ],
+    );
+
+    // Wipe out the second half of the compressed member
+    let mut archive = fs::read(src.path().join("archive.ttare")).unwrap();
+    let (start, len) = tar::Archive::new(archive.as_slice())
+        .entries()
+        .unwrap()
+        .map(|entry| entry.unwrap())
+        .find(|entry| {
+            entry
+                .path()
+                .unwrap()
+                .to_string_lossy()
+                .starts_with(".ttare.tar")
+        })
+        .map(|entry| (entry.raw_file_position() as usize, entry.size() as usize))
+        .unwrap();
+    archive[start + len / 2..start + len].fill(0);
+    fs::write(src.path().join("damaged.ttare"), archive).unwrap();
+
+    let out = TempDir::new().unwrap();
+    let out_dir = out.path().to_str().unwrap();
+    assert!(!run(src.path(), &["decompress", "damaged.ttare", "-o", out_dir]).success());
+
+    let out = TempDir::new().unwrap();
+    let out_dir = out.path().to_str().unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_ttare"))
+        .current_dir(src.path())
+        .args([
+            "decompress",
+            "damaged.ttare",
+            "-o",
+            out_dir,
+            "--best-effort",
+        ])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(4));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    assert_eq!(fs::read(out.path().join("tree/noise.bin")).unwrap(), stored);
+    // The manifest names the files that were in the part that was lost, and those that were
+    // only extracted in part
+    let mut intact = 0;
+    for (i, text) in texts.iter().enumerate() {
+        let name = format!("tree/{}.txt", i);
+        match fs::read(out.path().join(&name)) {
+            Ok(extracted) if extracted == *text => intact += 1,
+            _ => assert!(
+                stderr.contains(&name),
+                "{} isn't reported: {}",
+                name,
+                stderr
+            ),
+        }
+    }
+    assert!(intact > 0);
+    assert!(stderr.contains("tree/5.txt"), "{}", stderr);
+    assert!(!out.path().join("tree/5.txt").exists());
+}
+
+#[test]
+fn mapped_names_survive_decompress() {
+    let src = TempDir::new().unwrap();
+    fs::create_dir_all(src.path().join("build/lib")).unwrap();
+    fs::create_dir(src.path().join("docs")).unwrap();
+    let binary = noise(4096);
+    let text = b"mapped text ".repeat(300);
+    fs::write(src.path().join("build/out.bin"), &binary).unwrap();
+    fs::write(src.path().join("build/lib/core.txt"), &text).unwrap();
+    fs::write(src.path().join("docs/readme.txt"), &text).unwrap();
+
+    let absolute_build = src.path().join("build");
+    let out_map = format!("{}=release", absolute_build.display());
+    let absolute_out = absolute_build.join("out.bin");
+    let absolute_out = absolute_out.to_str().unwrap();
+    ttare(
+        src.path(),
+        &[
+            "compress",
+            "-o",
+            "archive.ttare",
+            "--map",
+            &out_map,
+            "--map",
+            "build/lib=release/lib/renamed",
+            "--map",
+            "./docs/readme.txt=README",
+            "--recursive",
+            absolute_out,
+            "build/lib",
+            "docs/readme.txt",
+        ],
+    );
+
+    let mut listing: Vec<String> = ttare_stdout(src.path(), &["list", "archive.ttare"])
+        .lines()
+        .map(|line| line.rsplit(' ').next().unwrap().to_string())
+        .collect();
+    listing.sort();
+    assert_eq!(
+        listing,
+        ["README", "release/lib/renamed/core.txt", "release/out.bin"]
+    );
+
+    let out = TempDir::new().unwrap();
+    ttare(
+        src.path(),
+        &[
+            "decompress",
+            "archive.ttare",
+            "-o",
+            out.path().to_str().unwrap(),
+        ],
+    );
+    assert_eq!(
+        fs::read(out.path().join("release/out.bin")).unwrap(),
+        binary
+    );
+    assert_eq!(
+        fs::read(out.path().join("release/lib/renamed/core.txt")).unwrap(),
+        text
+    );
+    assert_eq!(fs::read(out.path().join("README")).unwrap(), text);
+    assert!(!out.path().join("build").exists());
+    assert!(!out.path().join("docs").exists());
+
+    // Two files can't be given the same name, and names can't leave the output directory
+    for map in [
+        &[
+            "--map",
+            "build/out.bin=same",
+            "--map",
+            "docs/readme.txt=same",
+        ][..],
+        &["--map", "build/out.bin=../out.bin"],
+        &["--map", "build/out.bin=/out.bin"],
+    ] {
+        let mut args = vec!["compress", "-o", "bad.ttare"];
+        args.extend_from_slice(map);
+        args.extend(["build/out.bin", "docs/readme.txt"]);
+        assert_eq!(run(src.path(), &args).code(), Some(2), "{:?}", map);
+        assert!(!src.path().join("bad.ttare").exists());
+    }
+}
+
+#[test]
+fn verify_after_checks_the_archive_it_wrote() {
+    let src = TempDir::new().unwrap();
+    fs::write(src.path().join("text.txt"), b"plain text ".repeat(1000)).unwrap();
+    fs::write(src.path().join("noise.bin"), noise(8192)).unwrap();
+
+    let compress = |output: &str| {
+        run(
+            src.path(),
+            &[
+                "compress",
+                "--verify-after",
+                "-o",
+                output,
+                "text.txt",
+                "noise.bin",
+            ],
+        )
+    };
+    assert!(compress("out.ttare").success());
+    assert!(src.path().join("out.ttare").exists());
+
+    // There is no archive to read back on stdout
+    assert_eq!(compress("-").code(), Some(2));
+}
+
+#[test]
+fn estimate_prints_to_stderr_before_compressing() {
+    let src = TempDir::new().unwrap();
+    fs::write(src.path().join("text.txt"), b"plain text ".repeat(1000)).unwrap();
+    fs::write(src.path().join("noise.bin"), noise(8192)).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_ttare"))
+        .current_dir(src.path())
+        .args([
+            "compress",
+            "--estimate",
+            "-o",
+            "out.ttare",
+            "text.txt",
+            "noise.bin",
+        ])
+        .output()
+        .expect("failed to run ttare");
+    assert!(output.status.success());
+
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.starts_with("estimate: about "),
+        "unexpected stderr {:?}",
+        stderr
+    );
+    assert!(
+        stderr.contains("compressing 1 files of 10.74 KiB"),
+        "{:?}",
+        stderr
+    );
+    assert!(src.path().join("out.ttare").exists());
+
+    // It goes along with a dry run, which doesn't write anything
+    let output = Command::new(env!("CARGO_BIN_EXE_ttare"))
+        .current_dir(src.path())
+        .args(["compress", "--estimate", "--dry-run", "text.txt"])
+        .output()
+        .expect("failed to run ttare");
+    assert!(output.status.success());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .starts_with("estimate: "));
+}
+
+#[test]
+fn trace_times_each_phase_and_each_file_with_verbose() {
+    let src = TempDir::new().unwrap();
+    fs::write(src.path().join("text.txt"), b"plain text ".repeat(1000)).unwrap();
+    fs::write(src.path().join("noise.bin"), noise(8192)).unwrap();
+
+    let timings = |args: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_ttare"))
+            .current_dir(src.path())
+            .args(args)
+            .output()
+            .expect("failed to run ttare");
+        assert!(output.status.success(), "ttare {:?} failed", args);
+        String::from_utf8(output.stderr)
+            .unwrap()
+            .lines()
+            .filter_map(|line| line.strip_prefix("timing: "))
+            .map(|line| line.split(" took ").next().unwrap().to_string())
+            .collect::<Vec<_>>()
+    };
+
+    let compress = [
+        "compress",
+        "--force",
+        "-o",
+        "out.ttare",
+        "text.txt",
+        "noise.bin",
+    ];
+    let phases = timings(&[&["--trace"], &compress[..]].concat());
+    assert_eq!(
+        phases,
+        [
+            "gather paths=2",
+            "  analyze files=2",
+            "  write",
+            "  finish",
+            "compress"
+        ]
+    );
+
+    let phases = timings(&["--trace", "-v", "decompress", "out.ttare", "-o", "out"]);
+    assert!(
+        phases.contains(&"    extract path=text.txt".to_string()),
+        "{:?}",
+        phases
+    );
+    assert!(
+        phases.contains(&"  extract path=noise.bin".to_string()),
+        "{:?}",
+        phases
+    );
+    assert!(
+        phases.contains(&"  member codec=gzip".to_string()),
+        "{:?}",
+        phases
+    );
+    assert_eq!(phases.last().unwrap(), "decompress");
+
+    // Nothing is timed without it
+    assert!(timings(&[&["-v"], &compress[..]].concat()).is_empty());
+}
+
+#[test]
+fn show_entropy_prints_each_decision_to_stderr() {
+    let src = TempDir::new().unwrap();
+    fs::write(src.path().join("text.txt"), b"plain text ".repeat(1000)).unwrap();
+    fs::write(src.path().join("noise.bin"), noise(8192)).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_ttare"))
+        .current_dir(src.path())
+        .args([
+            "compress",
+            "--show-entropy",
+            "-o",
+            "-",
+            "text.txt",
+            "noise.bin",
+        ])
+        .output()
+        .expect("failed to run ttare");
+    assert!(output.status.success());
+
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let line_of = |name: &str| {
+        stderr
+            .lines()
+            .find(|line| line.starts_with(name))
+            .unwrap_or_else(|| panic!("no line for {} in {:?}", name, stderr))
+            .to_string()
+    };
+    assert!(line_of("text.txt").ends_with(", Compress"));
+    assert!(line_of("noise.bin").ends_with(", DontCompress"));
+    assert!(line_of("noise.bin").contains("entropy 7.9"));
+
+    // stdout only holds the archive
+    fs::write(src.path().join("archive.ttare"), &output.stdout).unwrap();
+    let out = TempDir::new().unwrap();
+    ttare(
+        src.path(),
+        &[
+            "decompress",
+            "archive.ttare",
+            "-o",
+            out.path().to_str().unwrap(),
+        ],
+    );
+    assert_eq!(fs::read(out.path().join("noise.bin")).unwrap(), noise(8192));
+
+    // Nothing is printed without it
+    let output = Command::new(env!("CARGO_BIN_EXE_ttare"))
+        .current_dir(src.path())
+        .args(["compress", "-o", "-", "text.txt", "noise.bin"])
+        .output()
+        .expect("failed to run ttare");
+    assert!(!String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("entropy"));
+}
+
+#[test]
+fn compress_all_compresses_what_shrinks_past_the_threshold() {
+    let src = TempDir::new().unwrap();
+    let text = b"text over a strict threshold ".repeat(2000);
+    fs::write(src.path().join("text.txt"), &text).unwrap();
+    fs::write(src.path().join("noise.bin"), noise(32 * 1024)).unwrap();
+    let archive = src.path().join("archive.ttare");
+
+    ttare(
+        src.path(),
+        &[
+            "compress",
+            "--compress-all",
+            "--entropy-threshold",
+            "0.5",
+            "-o",
+            "archive.ttare",
+            "text.txt",
+            "noise.bin",
+        ],
+    );
+    let entries = root_entries(&archive);
+    assert!(
+        entries.contains(&"text.txt.gz".to_string()),
+        "{:?}",
+        entries
+    );
+    assert!(entries.contains(&"noise.bin".to_string()), "{:?}", entries);
+    assert!(fs::metadata(&archive).unwrap().len() < 40 * 1024);
+
+    let out = TempDir::new().unwrap();
+    ttare(
+        src.path(),
+        &[
+            "decompress",
+            "archive.ttare",
+            "-o",
+            out.path().to_str().unwrap(),
+        ],
+    );
+    assert_eq!(fs::read(out.path().join("text.txt")).unwrap(), text);
+
+    // Nothing is compressed with --no-compress, so the two can't go together
+    let args = [
+        "compress",
+        "--compress-all",
+        "--no-compress",
+        "-o",
+        "x",
+        "text.txt",
+    ];
+    assert_eq!(run(src.path(), &args).code(), Some(2));
+}
+
+#[test]
+fn recompress_changes_the_codec_but_not_the_classification() {
+    let src = TempDir::new().unwrap();
+    let text = b"some text to recompress ".repeat(2000);
+    fs::write(src.path().join("text.txt"), &text).unwrap();
+    fs::write(src.path().join("noise.bin"), noise(32 * 1024)).unwrap();
+    let compress = [
+        "compress",
+        "--entropy-threshold",
+        "1",
+        "-o",
+        "gzip.ttare",
+        "text.txt",
+        "noise.bin",
+    ];
+    ttare(src.path(), &compress);
+
+    ttare(
+        src.path(),
+        &[
+            "recompress",
+            "gzip.ttare",
+            "--codec",
+            "zstd",
+            "-o",
+            "zstd.ttare",
+        ],
+    );
+    // The text is over the threshold it was first compressed with, so it's stored as-is again
+    let entries = root_entries(&src.path().join("zstd.ttare"));
+    assert!(entries.contains(&"text.txt".to_string()), "{:?}", entries);
+    assert!(
+        entries.iter().all(|entry| !entry.ends_with(".gz")),
+        "{:?}",
+        entries
+    );
+
+    let out = TempDir::new().unwrap();
+    ttare(
+        src.path(),
+        &[
+            "decompress",
+            "zstd.ttare",
+            "-o",
+            out.path().to_str().unwrap(),
+        ],
+    );
+    assert_eq!(fs::read(out.path().join("text.txt")).unwrap(), text);
+
+    // The output isn't replaced without --force
+    let again = ["recompress", "gzip.ttare", "-o", "zstd.ttare"];
+    assert!(!run(src.path(), &again).success());
+    ttare(src.path(), &[&again[..], &["--force", "-e", "7"]].concat());
+    let entries = root_entries(&src.path().join("zstd.ttare"));
+    assert!(!entries.contains(&"text.txt".to_string()), "{:?}", entries);
+}
+
+#[test]
+fn cat_prints_files_to_stdout() {
+    let src = TempDir::new().unwrap();
+    fs::write(src.path().join("a.txt"), b"first file\n".repeat(500)).unwrap();
+    fs::write(src.path().join("b.txt"), b"second\n").unwrap();
+    ttare(
+        src.path(),
+        &["compress", "-o", "archive.ttare", "a.txt", "b.txt"],
+    );
+
+    let printed = ttare_stdout(src.path(), &["cat", "archive.ttare", "b.txt", "a.txt"]);
+    assert_eq!(printed, format!("second\n{}", "first file\n".repeat(500)));
+
+    assert!(!run(src.path(), &["cat", "archive.ttare", "b.txt", "c.txt"]).success());
+    assert!(!run(src.path(), &["cat", "archive.ttare"]).success());
+}
+
+#[test]
+fn cat_ends_quietly_when_its_reader_stops() {
+    use std::io::Read;
+
+    let src = TempDir::new().unwrap();
+    fs::write(src.path().join("text.txt"), b"some text ".repeat(200_000)).unwrap();
+    ttare(src.path(), &["compress", "-o", "archive.ttare", "text.txt"]);
+
+    // Like `ttare cat archive.ttare text.txt | head -c 4`, with more to write than a pipe holds
+    let mut child = Command::new(env!("CARGO_BIN_EXE_ttare"))
+        .current_dir(src.path())
+        .args(["cat", "archive.ttare", "text.txt"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut head = [0; 4];
+    child.stdout.take().unwrap().read_exact(&mut head).unwrap();
+    assert_eq!(&head, b"some");
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    assert!(
+        output.stderr.is_empty(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+#[test]
+fn the_threshold_can_be_a_percentage() {
+    let src = TempDir::new().unwrap();
+    fs::write(src.path().join("text.txt"), b"some text ".repeat(200)).unwrap();
+    let archive = src.path().join("archive.ttare");
+    let compress = ["compress", "--force", "-o", "archive.ttare", "text.txt"];
+
+    // 10% is 0.8 bits per byte, which the text is above
+    ttare(
+        src.path(),
+        &[&compress[..], &["--entropy-threshold-pct", "10"]].concat(),
+    );
+    assert!(root_entries(&archive).contains(&"text.txt".to_string()));
+
+    // It takes precedence over the variable
+    let args = [&compress[..], &["--entropy-threshold-pct", "81.25"]].concat();
+    let output = ttare_with_env(src.path(), "TTARE_ENTROPY_THRESHOLD", "1", &args);
+    assert!(output.status.success());
+    assert!(!root_entries(&archive).contains(&"text.txt".to_string()));
+
+    // It can't be given with the threshold in bits per byte, or above 100%
+    for extra in [
+        &["--entropy-threshold-pct", "50", "--entropy-threshold", "4"][..],
+        &["--entropy-threshold-pct", "101"],
+        &["--entropy-threshold-pct", "-1"],
+    ] {
+        let args = [&compress[..], extra].concat();
+        assert_eq!(run(src.path(), &args).code(), Some(2), "{:?}", extra);
+    }
+}
+
+#[test]
+fn the_index_spares_decompressing_the_member() {
+    let src = TempDir::new().unwrap();
+    let text = b"text that is indexed ".repeat(2000);
+    let raw = noise(32 * 1024);
+    fs::write(src.path().join("text.txt"), &text).unwrap();
+    fs::write(src.path().join("noise.bin"), &raw).unwrap();
+    for archive in ["indexed.ttare", "scanned.ttare"] {
+        let mut args = vec!["compress", "-o", archive, "text.txt", "noise.bin"];
+        if archive == "indexed.ttare" {
+            args.push("--index");
+        }
+        ttare(src.path(), &args);
+    }
+
+    let indexed = src.path().join("indexed.ttare");
+    assert_eq!(root_entries(&indexed).last().unwrap(), ".ttare.index");
+    assert!(!root_entries(&src.path().join("scanned.ttare")).contains(&".ttare.index".to_string()));
+
+    // Garbling the middle of the member leaves its header, which is all the index needs
+    let listing = |archive: &str| ttare_stdout(src.path(), &["list", archive]);
+    let before = listing("indexed.ttare");
+    for archive in ["indexed.ttare", "scanned.ttare"] {
+        let path = src.path().join(archive);
+        let mut bytes = fs::read(&path).unwrap();
+        let (offset, size) = tar::Archive::new(bytes.as_slice())
+            .entries()
+            .unwrap()
+            .map(Result::unwrap)
+            .find(|entry| {
+                let path = entry.path().unwrap().to_string_lossy().into_owned();
+                path.starts_with(".ttare.tar")
+            })
+            .map(|entry| (entry.raw_file_position() as usize, entry.size() as usize))
+            .unwrap();
+        for byte in &mut bytes[offset + size / 4..offset + size * 3 / 4] {
+            *byte = !*byte;
+        }
+        fs::write(&path, bytes).unwrap();
+    }
+    assert_eq!(listing("indexed.ttare"), before);
+    assert!(!run(src.path(), &["list", "scanned.ttare"]).success());
+    let extracted = src.path().join("extracted.bin");
+    let extracted_arg = extracted.to_str().unwrap();
+    ttare(
+        src.path(),
+        &["extract", "indexed.ttare", "noise.bin", "-o", extracted_arg],
+    );
+    assert_eq!(fs::read(&extracted).unwrap(), raw);
+
+    // Appending to an archive with an index brings the index up to date
+    let src = TempDir::new().unwrap();
+    fs::write(src.path().join("text.txt"), &text).unwrap();
+    fs::write(src.path().join("noise.bin"), &raw).unwrap();
+    fs::write(src.path().join("later.txt"), b"added later ".repeat(100)).unwrap();
+    ttare(
+        src.path(),
+        &[
+            "compress",
+            "--index",
+            "-o",
+            "archive.ttare",
+            "text.txt",
+            "noise.bin",
+        ],
+    );
+    ttare(src.path(), &["append", "archive.ttare", "later.txt"]);
+    let archive = src.path().join("archive.ttare");
+    assert_eq!(root_entries(&archive).last().unwrap(), ".ttare.index");
+    assert!(ttare_stdout(src.path(), &["list", "archive.ttare"]).contains("later.txt"));
+    let out = TempDir::new().unwrap();
+    let extracted = out.path().join("later.txt");
+    ttare(
+        src.path(),
+        &[
+            "extract",
+            "archive.ttare",
+            "later.txt",
+            "-o",
+            extracted.to_str().unwrap(),
+        ],
+    );
+    assert_eq!(fs::read(extracted).unwrap(), b"added later ".repeat(100));
+    ttare(src.path(), &["verify", "archive.ttare"]);
+}
+
+#[test]
+fn paths_beyond_the_ustar_name_limit_survive_the_round_trip() {
+    let src = TempDir::new().unwrap();
+
+    // ustar names hold 100 bytes, or 255 when split at a slash, so these need GNU's long names
+    let deep: PathBuf = (0..12)
+        .map(|level| format!("directory-{level:02}"))
+        .collect();
+    let long_name = format!("{}.txt", "a-very-long-file-name-".repeat(8));
+    let text_path = deep.join(&long_name);
+    let noise_path = deep.join(format!("{}.bin", "n".repeat(150)));
+    assert!(text_path.as_os_str().len() > 255);
+    fs::create_dir_all(src.path().join(&deep)).unwrap();
+    let text = b"text under a long path ".repeat(500);
+    let raw = noise(8192);
+    fs::write(src.path().join(&text_path), &text).unwrap();
+    fs::write(src.path().join(&noise_path), &raw).unwrap();
+    #[cfg(unix)]
+    std::os::unix::fs::symlink(&long_name, src.path().join(deep.join("link"))).unwrap();
+
+    let text_name = text_path.to_str().unwrap();
+    for extra in [&[][..], &["--per-file-compression"], &["--index"]] {
+        let mut args = vec![
+            "compress",
+            "--force",
+            "-o",
+            "archive.ttare",
+            "-r",
+            "directory-00",
+        ];
+        args.extend_from_slice(extra);
+        ttare(src.path(), &args);
+
+        let listing = ttare_stdout(src.path(), &["list", "archive.ttare"]);
+        assert!(listing.contains(text_name), "{extra:?}: {listing}");
+
+        let out = TempDir::new().unwrap();
+        ttare(
+            src.path(),
+            &[
+                "decompress",
+                "archive.ttare",
+                "-o",
+                out.path().to_str().unwrap(),
+            ],
+        );
+        assert_eq!(fs::read(out.path().join(&text_path)).unwrap(), text);
+        assert_eq!(fs::read(out.path().join(&noise_path)).unwrap(), raw);
+        #[cfg(unix)]
+        assert_eq!(
+            fs::read_link(out.path().join(deep.join("link"))).unwrap(),
+            Path::new(&long_name)
+        );
+
+        let extracted = out.path().join("extracted.txt");
+        ttare(
+            src.path(),
+            &[
+                "extract",
+                "archive.ttare",
+                text_name,
+                "-o",
+                extracted.to_str().unwrap(),
+            ],
+        );
+        assert_eq!(fs::read(extracted).unwrap(), text);
+        ttare(src.path(), &["verify", "archive.ttare"]);
+    }
+}
+
+#[test]
+fn size_filters_take_human_readable_sizes() {
+    let src = TempDir::new().unwrap();
+    fs::create_dir(src.path().join("tree")).unwrap();
+    for (name, len) in [
+        ("small", 1023),
+        ("kib", 1024),
+        ("large", 1025),
+        ("mib", 1 << 20),
+    ] {
+        fs::write(src.path().join("tree").join(name), vec![b'x'; len]).unwrap();
+    }
+
+    let names = |filters: &[&str]| -> Vec<String> {
+        let mut args = vec!["compress", "--force", "-o", "archive.ttare", "-r", "tree"];
+        args.extend_from_slice(filters);
+        ttare(src.path(), &args);
+        let mut names: Vec<String> = ttare_stdout(src.path(), &["list", "archive.ttare"])
+            .lines()
+            .map(|line| line.rsplit('/').next().unwrap().to_string())
+            .collect();
+        names.sort();
+        names
+    };
+
+    assert_eq!(names(&["--exclude-larger-than", "1K"]), ["kib", "small"]);
+    assert_eq!(
+        names(&["--exclude-smaller-than", "1kib"]),
+        ["kib", "large", "mib"]
+    );
+    assert_eq!(
+        names(&[
+            "--exclude-smaller-than",
+            "1024",
+            "--exclude-larger-than",
+            "1M"
+        ]),
+        ["kib", "large", "mib"]
+    );
+    assert_eq!(
+        names(&["--exclude-larger-than", "1MB", "--exclude", "large"]),
+        ["kib", "mib", "small"]
+    );
+
+    for size in ["10Q", "M", "-1", "99999999999T"] {
+        let args = [
+            "compress",
+            "-o",
+            "bad.ttare",
+            "-r",
+            "tree",
+            "--exclude-larger-than",
+            size,
+        ];
+        assert_eq!(run(src.path(), &args).code(), Some(2), "{size}");
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn paths_that_arent_utf8_survive_the_round_trip() {
+    use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+    let ttare_os = |dir: &Path, args: &[&OsStr]| -> Output {
+        let output = Command::new(env!("CARGO_BIN_EXE_ttare"))
+            .current_dir(dir)
+            .args(args)
+            .output()
+            .expect("failed to run ttare");
+        assert!(output.status.success(), "ttare {:?} failed", args);
+        output
+    };
+
+    let src = TempDir::new().unwrap();
+    let text_name = OsStr::from_bytes(b"caf\xe9.txt");
+    let noise_name = OsStr::from_bytes(b"n\xffise.bin");
+    let text = b"latin-1 text ".repeat(500);
+    let stored = noise(16 * 1024);
+    fs::write(src.path().join(text_name), &text).unwrap();
+    fs::write(src.path().join(noise_name), &stored).unwrap();
+
+    for extra in [
+        &["--manifest", "--index"][..],
+        &["--per-file-compression", "--manifest", "--index"],
+    ] {
+        let mut args: Vec<&OsStr> = ["compress", "--force", "-o", "archive.ttare"]
+            .iter()
+            .chain(extra)
+            .map(OsStr::new)
+            .collect();
+        args.extend([text_name, noise_name]);
+        ttare_os(src.path(), &args);
+
+        let listed = ttare_os(
+            src.path(),
+            &[OsStr::new("list"), OsStr::new("archive.ttare")],
+        );
+        // Listing is for reading, so the names are printed as best as they can be
+        let listed = String::from_utf8(listed.stdout).unwrap();
+        assert!(listed.contains("caf\u{fffd}.txt"), "{listed}");
+        assert!(listed.contains("n\u{fffd}ise.bin"), "{listed}");
+
+        let out = TempDir::new().unwrap();
+        let decompress = ["decompress", "archive.ttare", "-o"].map(OsStr::new);
+        ttare_os(
+            src.path(),
+            &[&decompress[..], &[out.path().as_os_str()]].concat(),
+        );
+        assert_eq!(fs::read(out.path().join(text_name)).unwrap(), text);
+        assert_eq!(fs::read(out.path().join(noise_name)).unwrap(), stored);
+
+        let printed = ttare_os(
+            src.path(),
+            &[
+                OsStr::new("cat"),
+                OsStr::new("archive.ttare"),
+                noise_name,
+                text_name,
+            ],
+        );
+        assert_eq!(printed.stdout, [&stored[..], &text[..]].concat());
+    }
+}
+
+#[test]
+fn strip_trailer_saves_the_blocks_that_end_the_tar() {
+    let src = TempDir::new().unwrap();
+    fs::write(src.path().join("a.txt"), b"small\n".repeat(10)).unwrap();
+    fs::write(src.path().join("b.bin"), noise(2048)).unwrap();
+    let compress = [
+        "compress",
+        "-o",
+        "archive.ttare",
+        "--force",
+        "a.txt",
+        "b.bin",
+    ];
+
+    ttare(src.path(), &compress);
+    let kept = fs::metadata(src.path().join("archive.ttare"))
+        .unwrap()
+        .len();
+    ttare(src.path(), &[&compress[..], &["--strip-trailer"]].concat());
+    let stripped = fs::metadata(src.path().join("archive.ttare"))
+        .unwrap()
+        .len();
+    assert!(kept - stripped >= 1024, "{kept} and {stripped} bytes");
+
+    let out = TempDir::new().unwrap();
+    let out_dir = out.path().to_str().unwrap();
+    ttare(src.path(), &["decompress", "archive.ttare", "-o", out_dir]);
+    assert_eq!(
+        fs::read(out.path().join("b.bin")).unwrap(),
+        fs::read(src.path().join("b.bin")).unwrap()
+    );
+    ttare(src.path(), &["verify", "archive.ttare"]);
+
+    let plain = [&compress[..], &["--strip-trailer", "--plain-targz"]].concat();
+    assert_eq!(run(src.path(), &plain).code(), Some(2));
+}
+
+#[test]
+fn check_formats_warns_about_compressed_formats_that_compress() {
+    let src = TempDir::new().unwrap();
+    let text = b"text behind a misleading name ".repeat(200);
+    fs::write(src.path().join("photo.jpg"), &text).unwrap();
+    fs::write(
+        src.path().join("image.dat"),
+        [&b"\x89PNG\r\n\x1a\n"[..], &text].concat(),
+    )
+    .unwrap();
+    fs::write(src.path().join("real.jpg"), noise(16 * 1024)).unwrap();
+    let compress = [
+        "compress",
+        "--force",
+        "-o",
+        "archive.ttare",
+        "--sniff",
+        "--check-formats",
+        "photo.jpg",
+        "image.dat",
+        "real.jpg",
+    ];
+
+    let stderr = |args: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_ttare"))
+            .current_dir(src.path())
+            .args(args)
+            .output()
+            .expect("failed to run ttare");
+        assert!(output.status.success(), "ttare {:?} failed", args);
+        String::from_utf8(output.stderr).unwrap()
+    };
+
+    let warnings = stderr(&compress);
+    for name in ["photo.jpg", "image.dat"] {
+        assert!(
+            warnings.contains(&format!(
+                "{name} is stored as-is as an already compressed format"
+            )),
+            "{warnings}"
+        );
+    }
+    assert!(!warnings.contains("real.jpg"), "{warnings}");
+
+    // They are still stored as-is
+    let entries = root_entries(&src.path().join("archive.ttare"));
+    for name in ["photo.jpg", "image.dat", "real.jpg"] {
+        assert!(entries.contains(&name.to_string()), "{entries:?}");
+    }
+
+    let quiet = [&["--quiet"][..], &compress].concat();
+    assert!(!stderr(&quiet).contains("photo.jpg"));
+}
+
+#[test]
+fn stats_prints_how_a_directory_would_be_stored() {
+    let src = TempDir::new().unwrap();
+    fs::create_dir_all(src.path().join("data/sub")).unwrap();
+    fs::write(
+        src.path().join("data/text.txt"),
+        b"text to count ".repeat(500),
+    )
+    .unwrap();
+    fs::write(src.path().join("data/sub/noise.bin"), noise(16 * 1024)).unwrap();
+
+    // Directories are walked without --recursive, and nothing is written
+    let printed = ttare_stdout(src.path(), &["stats", "data"]);
+    assert!(
+        printed.contains("1 files compressed, 6.84 KiB"),
+        "{printed}"
+    );
+    assert!(printed.contains("1 files stored, 16.00 KiB"), "{printed}");
+    assert!(printed.contains("a threshold of 6.500"), "{printed}");
+    assert!(
+        printed.contains("compressing could save about"),
+        "{printed}"
+    );
+    assert!(!printed.contains("ENTROPY"), "{printed}");
+    assert_eq!(fs::read_dir(src.path()).unwrap().count(), 1);
+
+    let printed = ttare_stdout(src.path(), &["stats", "data", "--histogram", "-c", "zstd"]);
+    assert!(printed.contains("a threshold of 7.000"), "{printed}");
+    assert!(printed.contains("ENTROPY   FILES"), "{printed}");
+    assert_eq!(printed.lines().filter(|line| line.contains('#')).count(), 2);
+    assert!(
+        printed.contains("7.5-8.0       1  16.00 KiB  #"),
+        "{printed}"
+    );
+
+    let json: serde_json::Value =
+        serde_json::from_str(&ttare_stdout(src.path(), &["stats", "--json", "data"])).unwrap();
+    assert_eq!(json["compressed"]["files"], 1);
+    assert_eq!(json["stored"]["bytes"], 16 * 1024);
+    assert_eq!(json["histogram"].as_array().unwrap().len(), 16);
+
+    assert!(!run(src.path(), &["stats"]).success());
+    assert!(!run(src.path(), &["stats", "missing"]).success());
+}
+
+#[test]
+fn checkpoint_writes_the_same_archive_and_cleans_up_after_itself() {
+    let src = TempDir::new().unwrap();
+    fs::write(
+        src.path().join("a.txt"),
+        b"some text to compress\n".repeat(100),
+    )
+    .unwrap();
+    fs::write(src.path().join("b.bin"), noise(8192)).unwrap();
+    let compress = [
+        "compress",
+        "--force",
+        "--mtime",
+        "1700000000",
+        "-o",
+        "archive.ttare",
+        "a.txt",
+        "b.bin",
+    ];
+
+    ttare(src.path(), &compress);
+    let plain = fs::read(src.path().join("archive.ttare")).unwrap();
+    ttare(
+        src.path(),
+        &[&compress[..], &["--checkpoint", "archive.checkpoint"]].concat(),
+    );
+    assert!(fs::read(src.path().join("archive.ttare")).unwrap() == plain);
+    for leftover in [
+        "archive.checkpoint",
+        "archive.ttare.partial",
+        "archive.ttare.member.partial",
+    ] {
+        assert!(!src.path().join(leftover).exists(), "{leftover}");
+    }
+
+    // There is nothing to resume, or nothing that was written by a run
+    let resume = [&compress[..], &["--resume", "archive.checkpoint"]].concat();
+    assert_eq!(run(src.path(), &resume).code(), Some(3));
+    fs::write(src.path().join("archive.checkpoint"), b"not a checkpoint\n").unwrap();
+    assert_eq!(run(src.path(), &resume).code(), Some(2));
+
+    let split = [
+        &compress[..],
+        &["--checkpoint", "archive.checkpoint", "--split-size", "4096"],
+    ]
+    .concat();
+    assert_eq!(run(src.path(), &split).code(), Some(2));
+}